@@ -1,3 +1,5 @@
+#![allow(clippy::too_many_arguments)]
+
 use alloy::sol;
 
 sol! {
@@ -17,6 +19,7 @@ sol! {
         event Transfer(address indexed from, address indexed to, uint256 value);
         event Approval(address indexed owner, address indexed spender, uint256 value);
 
+        function decimals() external view returns (uint8);
         function totalSupply() external view returns (uint256);
         function balanceOf(address account) external view returns (uint256);
         function transfer(address to, uint256 value) external returns (bool);
@@ -53,7 +56,7 @@ sol! {
 }
 
 sol! {
-    #[derive(Debug, PartialEq, Eq)]
+    #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
     #[sol(rpc, abi)]
     interface IUniswapV3Factory {
         event OwnerChanged(address indexed oldOwner, address indexed newOwner);
@@ -84,7 +87,7 @@ sol! {
 }
 
 sol! {
-    #[derive(Debug, PartialEq, Eq)]
+    #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
     #[sol(rpc, abi)]
     interface UniswapV3Pool {
         event Initialize(uint160 sqrtPriceX96, int24 tick);
@@ -199,6 +202,11 @@ sol! {
                 uint160 secondsPerLiquidityCumulativeX128,
                 bool initialized
             );
+        function observe(uint32[] calldata secondsAgos)
+            external
+            view
+            returns (int56[] memory tickCumulatives, uint160[] memory secondsPerLiquidityCumulativeX128s);
+        function increaseObservationCardinalityNext(uint16 observationCardinalityNext) external;
         function factory() external view returns (address);
         function token0() external view returns (address);
         function token1() external view returns (address);
@@ -237,16 +245,24 @@ sol! {
         function exactOutputSingle(ExactOutputSingleParams calldata params) external payable returns (uint256 amountIn);
 
         function exactInputSingle(ExactInputSingleParams calldata params) external payable returns (uint256 amountOut);
+
+        function multicall(bytes[] calldata data) external payable returns (bytes[] memory results);
+
+        function refundETH() external payable;
+
+        function factory() external view returns (address);
+        function WETH9() external view returns (address);
     }
 }
 
 sol! {
-    #[derive(Debug, PartialEq, Eq)]
+    #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
     #[sol(rpc, abi)]
     interface INonfungiblePositionManager {
         event IncreaseLiquidity(uint256 indexed tokenId, uint128 liquidity, uint256 amount0, uint256 amount1);
         event DecreaseLiquidity(uint256 indexed tokenId, uint128 liquidity, uint256 amount0, uint256 amount1);
         event Collect(uint256 indexed tokenId, address recipient, uint256 amount0, uint256 amount1);
+        event Transfer(address indexed from, address indexed to, uint256 indexed tokenId);
 
         struct MintParams {
             address token0;
@@ -331,6 +347,8 @@ sol! {
         function collect(CollectParams calldata params) external payable returns (uint256 amount0, uint256 amount1);
         function burn(uint256 tokenId) external payable;
         function balanceOf(address account) external view returns (uint256);
+        function factory() external view returns (address);
+        function WETH9() external view returns (address);
     }
 }
 
@@ -338,6 +356,9 @@ sol! {
     #[derive(Debug, PartialEq, Eq)]
     #[sol(rpc, abi)]
     interface IQuoterV2 {
+        function factory() external view returns (address);
+        function WETH9() external view returns (address);
+
         function quoteExactInput(bytes memory path, uint256 amountIn)
             external
             returns (
@@ -0,0 +1,542 @@
+use std::{future::Future, sync::Arc};
+
+use alloy::{
+    eips::BlockId,
+    primitives::{aliases::I24, Address, Log as AbiLog, U160, U256},
+    sol_types::SolEvent,
+};
+use eyre::Result;
+use tracing::error;
+
+use crate::{
+    abi::{
+        INonfungiblePositionManager::{
+            Collect, CollectParams, DecreaseLiquidityParams, INonfungiblePositionManagerInstance,
+        },
+        UniswapV3Pool::UniswapV3PoolInstance,
+        Weth,
+    },
+    error::FeeAnalyzerError,
+    fee_analyzer::{ArcAnvilHttpProvider, HttpClient},
+};
+
+use super::{
+    fee_growth,
+    valuation::{spot_value, TokenValuer},
+    PoolConfig,
+};
+
+// the reads and transactions the position accounting in collect.rs makes
+// against the pool and the position manager. AnvilBackend sends them to the
+// fork, anything else implementing it can stand in for the chain. the
+// futures are Send so the end of run close out can value records on their
+// own tasks
+pub(crate) trait PoolBackend {
+    fn position_manager_address(&self) -> Address;
+
+    fn pool_address(&self) -> Address;
+
+    // (sqrtPriceX96, tick)
+    fn slot0(&self) -> impl Future<Output = Result<(U160, I24)>> + Send;
+
+    // (sqrtPriceX96, tick) as of an earlier fork block, from the fork's history
+    fn slot0_at(&self, fork_block: u64) -> impl Future<Output = Result<(U160, I24)>> + Send;
+
+    fn balance_of(
+        &self,
+        token: Address,
+        account: Address,
+    ) -> impl Future<Output = Result<U256>> + Send;
+
+    // the pool's (token0, token1) fee growth inside the range, Q128.128
+    fn fee_growth_inside(
+        &self,
+        lower_tick: I24,
+        upper_tick: I24,
+    ) -> impl Future<Output = Result<(U256, U256)>> + Send;
+
+    // the position manager's (feeGrowthInside0LastX128,
+    // feeGrowthInside1LastX128) for the token id
+    fn fee_growth_inside_last(
+        &self,
+        token_id: U256,
+    ) -> impl Future<Output = Result<(U256, U256)>> + Send;
+
+    // collects everything owed to the token id and sends it to `recipient`,
+    // returns the (amount0, amount1) collected and the gas used
+    fn collect(
+        &self,
+        token_id: U256,
+        minter: Address,
+        recipient: Address,
+    ) -> impl Future<Output = Result<(U256, U256, u64)>> + Send;
+
+    // the (amount0, amount1) removing `liquidity` from the token id would
+    // return, without sending the decrease
+    fn quote_decrease_liquidity(
+        &self,
+        token_id: U256,
+        minter: Address,
+        liquidity: u128,
+    ) -> impl Future<Output = Result<(U256, U256)>> + Send;
+}
+
+// the token to weth conversions the position accounting makes. TokenValuer
+// prices them on the fork with the configured valuation method
+pub(crate) trait TokenValuation {
+    fn token_to_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount: U256,
+    ) -> impl Future<Output = Result<U256>> + Send;
+
+    // what selling the whole amount in one go would pay
+    fn executable_token_to_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount: U256,
+    ) -> impl Future<Output = Result<U256>> + Send;
+}
+
+impl TokenValuation for TokenValuer {
+    async fn token_to_weth(&self, pool_config: &PoolConfig, token_amount: U256) -> Result<U256> {
+        TokenValuer::token_to_weth(self, pool_config, token_amount).await
+    }
+
+    async fn executable_token_to_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount: U256,
+    ) -> Result<U256> {
+        TokenValuer::executable_token_to_weth(self, pool_config, token_amount).await
+    }
+}
+
+// values the token at a fixed pool price, with no price impact
+pub(crate) struct SpotValuation {
+    pub sqrt_price_x96: U160,
+}
+
+impl TokenValuation for SpotValuation {
+    async fn token_to_weth(&self, pool_config: &PoolConfig, token_amount: U256) -> Result<U256> {
+        Ok(spot_value(
+            token_amount,
+            self.sqrt_price_x96,
+            pool_config.clanker_is_token0(),
+        ))
+    }
+
+    async fn executable_token_to_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount: U256,
+    ) -> Result<U256> {
+        self.token_to_weth(pool_config, token_amount).await
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct AnvilBackend {
+    pub pool: Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
+    pub position_manager:
+        Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
+}
+
+impl PoolBackend for AnvilBackend {
+    fn position_manager_address(&self) -> Address {
+        *self.position_manager.address()
+    }
+
+    fn pool_address(&self) -> Address {
+        *self.pool.address()
+    }
+
+    async fn slot0(&self) -> Result<(U160, I24)> {
+        let slot0 = self.pool.slot0().call().await?;
+        Ok((slot0.sqrtPriceX96, slot0.tick))
+    }
+
+    async fn slot0_at(&self, fork_block: u64) -> Result<(U160, I24)> {
+        let slot0 = self
+            .pool
+            .slot0()
+            .block(BlockId::number(fork_block))
+            .call()
+            .await?;
+        Ok((slot0.sqrtPriceX96, slot0.tick))
+    }
+
+    async fn balance_of(&self, token: Address, account: Address) -> Result<U256> {
+        Ok(Weth::new(token, self.pool.provider().clone())
+            .balanceOf(account)
+            .call()
+            .await?
+            ._0)
+    }
+
+    async fn fee_growth_inside(&self, lower_tick: I24, upper_tick: I24) -> Result<(U256, U256)> {
+        fee_growth::fee_growth_inside(&self.pool, lower_tick, upper_tick).await
+    }
+
+    async fn fee_growth_inside_last(&self, token_id: U256) -> Result<(U256, U256)> {
+        let position = self.position_manager.positions(token_id).call().await?;
+        Ok((
+            position.feeGrowthInside0LastX128,
+            position.feeGrowthInside1LastX128,
+        ))
+    }
+
+    // retries a reverted collect a few times, the amounts are read from the
+    // collect's log
+    async fn collect(
+        &self,
+        token_id: U256,
+        minter: Address,
+        recipient: Address,
+    ) -> Result<(U256, U256, u64)> {
+        let collect_params = CollectParams {
+            tokenId: token_id,
+            recipient,
+            amount0Max: u128::MAX,
+            amount1Max: u128::MAX,
+        };
+
+        let mut attempts = 0;
+        let max_attempts = 4;
+        let mut receipt = None;
+
+        while attempts < max_attempts {
+            match self
+                .position_manager
+                .collect(collect_params.clone())
+                .from(minter)
+                .send()
+                .await?
+                .get_receipt()
+                .await
+            {
+                Ok(r) => {
+                    if r.inner.status() {
+                        receipt = Some(r);
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to mint, retrying: {:?}", e);
+                }
+            }
+            attempts += 1;
+        }
+
+        let collect_receipt = receipt.ok_or_else(|| {
+            FeeAnalyzerError::CollectFailed(format!(
+                "Failed to collect fees after {} attempts",
+                max_attempts
+            ))
+        })?;
+
+        let collect_log = collect_receipt
+            .inner
+            .logs()
+            .iter()
+            .find(|log| log.inner.topics()[0] == Collect::SIGNATURE_HASH)
+            .and_then(|log| {
+                let log = AbiLog::new(
+                    log.address(),
+                    log.topics().to_vec(),
+                    log.data().data.clone(),
+                )
+                .unwrap_or_default();
+                Collect::decode_log(&log, true).ok()
+            })
+            .ok_or_else(|| {
+                FeeAnalyzerError::CollectFailed("Failed to decode collect event".to_string())
+            })?;
+
+        Ok((
+            collect_log.amount0,
+            collect_log.amount1,
+            collect_receipt.gas_used,
+        ))
+    }
+
+    async fn quote_decrease_liquidity(
+        &self,
+        token_id: U256,
+        minter: Address,
+        liquidity: u128,
+    ) -> Result<(U256, U256)> {
+        let decrease_liquidity_params = DecreaseLiquidityParams {
+            tokenId: token_id,
+            liquidity,
+            amount0Min: U256::ZERO,
+            amount1Min: U256::ZERO,
+            deadline: U256::MAX,
+        };
+
+        let decrease_liquidity_return = self
+            .position_manager
+            .decreaseLiquidity(decrease_liquidity_params)
+            .from(minter)
+            .call()
+            .await?;
+        Ok((
+            decrease_liquidity_return.amount0,
+            decrease_liquidity_return.amount1,
+        ))
+    }
+}
+
+// canned chain answers for testing the position accounting without a fork
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use alloy::primitives::{aliases::U24, TxHash};
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::{
+        abi::UniswapV3Pool::Mint,
+        chain_interactions::collect::{create_position_info_from_mint_event, PositionInfo},
+        fee_analyzer::simulation_events::{Event, SimulationEvent},
+    };
+
+    pub(crate) const TOKEN: Address = Address::repeat_byte(0x11);
+    pub(crate) const WETH: Address = Address::repeat_byte(0x22);
+
+    // an 18 decimal token paired with weth in the 1% tier
+    pub(crate) fn pool_config(clanker_is_token0: bool) -> PoolConfig {
+        let (token0, token1) = if clanker_is_token0 {
+            (TOKEN, WETH)
+        } else {
+            (WETH, TOKEN)
+        };
+        PoolConfig {
+            token0,
+            token1,
+            fee: U24::from(10_000),
+            clanker_is_token0,
+            token_decimals: 18,
+            weth_decimals: 18,
+        }
+    }
+
+    // a record opened at block 10 over [-600, 600) with 1000 liquidity from
+    // 500 token and 300 weth, with the token as token0
+    pub(crate) async fn open_position(
+        backend: &MockBackend,
+        valuer: &MockValuer,
+        token_id: U256,
+    ) -> PositionInfo {
+        let mint = SimulationEvent {
+            block: 10,
+            tx_hash: TxHash::ZERO,
+            log_index: 0,
+            pool_address: backend.pool_address(),
+            from: Address::repeat_byte(0x33),
+            block_time: DateTime::UNIX_EPOCH,
+            event: Event::Mint(Mint {
+                sender: backend.position_manager_address(),
+                owner: backend.position_manager_address(),
+                tickLower: I24::try_from(-600).unwrap(),
+                tickUpper: I24::try_from(600).unwrap(),
+                amount: 1000,
+                amount0: U256::from(500),
+                amount1: U256::from(300),
+            }),
+        };
+        create_position_info_from_mint_event(
+            backend,
+            &pool_config(true),
+            valuer,
+            mint,
+            token_id,
+            token_id,
+        )
+        .await
+        .unwrap()
+    }
+
+    // an opened record with the given id and index, for the helpers that only
+    // read a few of its fields
+    pub(crate) async fn record(original_token_id: u64, index: u64) -> PositionInfo {
+        let backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 1 };
+        let mut position = open_position(&backend, &valuer, U256::from(original_token_id)).await;
+        position.index = index;
+        position
+    }
+
+    #[derive(Clone)]
+    pub(crate) struct MockBackend {
+        pub token0: Address,
+        pub token1: Address,
+        pub slot0: (U160, I24),
+        // slot0 as of earlier fork blocks
+        pub slot0_history: BTreeMap<u64, (U160, I24)>,
+        pub fee_growth_inside: (U256, U256),
+        pub fee_growth_inside_last: (U256, U256),
+        // (amount0, amount1) every collect returns, credited to the recipient
+        pub collect: (U256, U256),
+        // credits the minter instead of the recipient, like a position
+        // manager that ignores the recipient
+        pub pays_minter: bool,
+        pub collect_gas: u64,
+        // how long every collect takes
+        pub collect_delay: Duration,
+        // (amount0, amount1) quoted for any decrease, none fails the quote
+        pub decrease_quote: Option<(U256, U256)>,
+        // quotes for single token ids, ahead of decrease_quote
+        pub decrease_quotes: BTreeMap<U256, (U256, U256)>,
+        // token ids whose collects or decrease quotes revert
+        pub failing_collects: Vec<U256>,
+        pub failing_quotes: Vec<U256>,
+        // shared by clones, like the fork's balances
+        pub collects: Arc<AtomicU64>,
+        // (token0, token1) collected into each account
+        pub credited: Arc<Mutex<HashMap<Address, (U256, U256)>>>,
+    }
+
+    impl MockBackend {
+        pub(crate) fn new(pool_config: &PoolConfig) -> Self {
+            MockBackend {
+                token0: pool_config.token0,
+                token1: pool_config.token1,
+                slot0: (U160::from(1) << 96, I24::ZERO),
+                slot0_history: BTreeMap::new(),
+                fee_growth_inside: (U256::ZERO, U256::ZERO),
+                fee_growth_inside_last: (U256::ZERO, U256::ZERO),
+                collect: (U256::ZERO, U256::ZERO),
+                pays_minter: false,
+                collect_gas: 0,
+                collect_delay: Duration::ZERO,
+                decrease_quote: None,
+                decrease_quotes: BTreeMap::new(),
+                failing_collects: Vec::new(),
+                failing_quotes: Vec::new(),
+                collects: Arc::new(AtomicU64::new(0)),
+                credited: Arc::new(Mutex::new(HashMap::new())),
+            }
+        }
+
+        pub(crate) fn collects(&self) -> u64 {
+            self.collects.load(Ordering::SeqCst)
+        }
+    }
+
+    impl PoolBackend for MockBackend {
+        fn position_manager_address(&self) -> Address {
+            Address::repeat_byte(0xaa)
+        }
+
+        fn pool_address(&self) -> Address {
+            Address::repeat_byte(0xbb)
+        }
+
+        async fn slot0(&self) -> Result<(U160, I24)> {
+            Ok(self.slot0)
+        }
+
+        async fn slot0_at(&self, fork_block: u64) -> Result<(U160, I24)> {
+            self.slot0_history
+                .get(&fork_block)
+                .copied()
+                .ok_or_else(|| eyre::eyre!("No slot0 at fork block {}", fork_block))
+        }
+
+        // only collects move balances
+        async fn balance_of(&self, token: Address, account: Address) -> Result<U256> {
+            let credited = self.credited.lock().unwrap();
+            let (amount0, amount1) = credited.get(&account).copied().unwrap_or_default();
+            if token == self.token0 {
+                Ok(amount0)
+            } else if token == self.token1 {
+                Ok(amount1)
+            } else {
+                Ok(U256::ZERO)
+            }
+        }
+
+        async fn fee_growth_inside(
+            &self,
+            _lower_tick: I24,
+            _upper_tick: I24,
+        ) -> Result<(U256, U256)> {
+            Ok(self.fee_growth_inside)
+        }
+
+        async fn fee_growth_inside_last(&self, _token_id: U256) -> Result<(U256, U256)> {
+            Ok(self.fee_growth_inside_last)
+        }
+
+        async fn collect(
+            &self,
+            token_id: U256,
+            minter: Address,
+            recipient: Address,
+        ) -> Result<(U256, U256, u64)> {
+            tokio::time::sleep(self.collect_delay).await;
+            if self.failing_collects.contains(&token_id) {
+                eyre::bail!(FeeAnalyzerError::CollectFailed(format!(
+                    "Collect for token id {} reverted",
+                    token_id
+                )));
+            }
+            self.collects.fetch_add(1, Ordering::SeqCst);
+            let payee = if self.pays_minter { minter } else { recipient };
+            let mut credited = self.credited.lock().unwrap();
+            let balances = credited.entry(payee).or_default();
+            balances.0 += self.collect.0;
+            balances.1 += self.collect.1;
+            Ok((self.collect.0, self.collect.1, self.collect_gas))
+        }
+
+        async fn quote_decrease_liquidity(
+            &self,
+            token_id: U256,
+            _minter: Address,
+            _liquidity: u128,
+        ) -> Result<(U256, U256)> {
+            if self.failing_quotes.contains(&token_id) {
+                eyre::bail!("Decrease quote for token id {} reverted", token_id);
+            }
+            self.decrease_quotes
+                .get(&token_id)
+                .copied()
+                .or(self.decrease_quote)
+                .ok_or_else(|| eyre::eyre!("Unexpected decrease quote"))
+        }
+    }
+
+    // values the token at a fixed number of wei of weth per token wei
+    #[derive(Clone)]
+    pub(crate) struct MockValuer {
+        pub weth_per_token: u64,
+    }
+
+    impl TokenValuation for MockValuer {
+        async fn token_to_weth(
+            &self,
+            _pool_config: &PoolConfig,
+            token_amount: U256,
+        ) -> Result<U256> {
+            Ok(token_amount * U256::from(self.weth_per_token))
+        }
+
+        async fn executable_token_to_weth(
+            &self,
+            _pool_config: &PoolConfig,
+            token_amount: U256,
+        ) -> Result<U256> {
+            Ok(token_amount * U256::from(self.weth_per_token))
+        }
+    }
+}
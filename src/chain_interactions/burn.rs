@@ -8,6 +8,7 @@ use alloy::{
 use eyre::{bail, ContextCompat, Result};
 use tracing::error;
 
+use crate::error::FeeAnalyzerError;
 use crate::{
     abi::{
         INonfungiblePositionManager::{
@@ -26,12 +27,13 @@ pub(crate) async fn pool_burn(
     minter: Address,
     burn_event: &Burn,
     decrease_liquidity_event: &DecreaseLiquidityWithParams,
-) -> Result<()> {
+    verify: bool,
+) -> Result<u64> {
     let decrease_liquidity_params = DecreaseLiquidityParams {
         tokenId: token_id,
         liquidity: decrease_liquidity_event.event.liquidity,
-        amount0Min: U256::ZERO,
-        amount1Min: U256::ZERO,
+        amount0Min: decrease_liquidity_event.amount_0_min,
+        amount1Min: decrease_liquidity_event.amount_1_min,
         deadline: U256::from_str("8737924142").unwrap(),
     };
 
@@ -65,9 +67,11 @@ pub(crate) async fn pool_burn(
         receipt.ok_or_else(|| eyre::eyre!("Failed to burn after {} attempts", max_attempts))?;
 
     // check burn outcomes
-    check_burn_outcomes(burn_event, &receipt).await?;
+    if verify {
+        check_burn_outcomes(burn_event, &receipt).await?;
+    }
 
-    Ok(())
+    Ok(receipt.gas_used)
 }
 
 async fn check_burn_outcomes(burn_event: &Burn, receipt: &TransactionReceipt) -> Result<()> {
@@ -96,7 +100,9 @@ async fn check_burn_outcomes(burn_event: &Burn, receipt: &TransactionReceipt) ->
         error!("Mismatch in burn outcomes");
         error!("burn event: {:?}", burn_event);
         error!("burn log: {:?}", burn_log);
-        bail!("Mismatch in burn outcomes");
+        bail!(FeeAnalyzerError::BurnMismatch(
+            "Mismatch in burn outcomes".to_string()
+        ));
     }
 
     Ok(())
@@ -1,30 +1,30 @@
-use std::{fmt, sync::Arc};
+use std::{fmt, str::FromStr};
 
-use alloy::{
-    primitives::{aliases::I24, Address, Log as AbiLog, I256, U160, U256},
-    sol_types::SolEvent,
+use alloy::primitives::{
+    aliases::I24,
+    utils::{format_units, ParseUnits},
+    Address, I256, U160, U256,
 };
-use eyre::{ContextCompat, Result};
-use tracing::{error, warn};
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
+use crate::error::FeeAnalyzerError;
 use crate::{
-    abi::{
-        INonfungiblePositionManager::{
-            Collect, CollectParams, DecreaseLiquidityParams, INonfungiblePositionManagerInstance,
-        },
-        ISwapRouter::{ExactInputSingleParams, ISwapRouterInstance},
-        UniswapV3Pool::{Mint, UniswapV3PoolInstance},
-    },
+    abi::UniswapV3Pool::Mint,
     fee_analyzer::simulation_events::{
         DecreaseLiquidityWithParams, IncreaseLiquidityWithParams, SimulationEvent,
     },
 };
 
-use crate::fee_analyzer::{ArcAnvilHttpProvider, HttpClient};
-
-use super::PoolConfig;
+use super::{
+    backend::{PoolBackend, SpotValuation, TokenValuation},
+    fee_growth::fees_from_growth,
+    valuation::amounts_for_liquidity,
+    PoolConfig,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum PositionAction {
     Open,
     IncreaseLiquidity,
@@ -43,16 +43,77 @@ impl fmt::Display for PositionAction {
     }
 }
 
-#[derive(Debug, Clone)]
+impl FromStr for PositionAction {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Open" => Ok(PositionAction::Open),
+            "IncreaseLiquidity" => Ok(PositionAction::IncreaseLiquidity),
+            "DecreaseLiquidity" => Ok(PositionAction::DecreaseLiquidity),
+            "ClosePosition" => Ok(PositionAction::ClosePosition),
+            _ => bail!("Unknown position action: {}", s),
+        }
+    }
+}
+
+// why a record stopped being the position's current record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum CloseReason {
+    // an on-chain decrease removed all of the position's liquidity
+    OnChainFullExit,
+    // the replay ended with the position open, closing it was only simulated
+    ForcedAtSimEnd,
+    // an on-chain increase or partial decrease changed the liquidity, the
+    // position stays open in the next record
+    StillOpenPartial,
+    // the end of run close out errored, see close_out_error
+    CloseOutFailed,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::OnChainFullExit => write!(f, "OnChainFullExit"),
+            CloseReason::ForcedAtSimEnd => write!(f, "ForcedAtSimEnd"),
+            CloseReason::StillOpenPartial => write!(f, "StillOpenPartial"),
+            CloseReason::CloseOutFailed => write!(f, "CloseOutFailed"),
+        }
+    }
+}
+
+impl FromStr for CloseReason {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "OnChainFullExit" => Ok(CloseReason::OnChainFullExit),
+            "ForcedAtSimEnd" => Ok(CloseReason::ForcedAtSimEnd),
+            "StillOpenPartial" => Ok(CloseReason::StillOpenPartial),
+            "CloseOutFailed" => Ok(CloseReason::CloseOutFailed),
+            _ => bail!("Unknown close reason: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct PositionInfo {
     // metadata
     pub token_id: U256,
     pub original_token_id: U256,
+    // on-chain owner of the original position. the Mint event's owner is the
+    // position manager for npm mints, so those use the tx sender instead
+    pub owner: Address,
     pub lower_tick: I24,
     pub upper_tick: I24,
     pub index: u64,
     pub position_action: PositionAction,
     pub closed: bool,
+    // none until the record is closed
+    pub close_reason: Option<CloseReason>,
+    // why the end of run close out failed, the record keeps whatever it had
+    // filled in before the failure
+    pub close_out_error: Option<String>,
     // opening info
     pub block_in: u64,
     pub token_amount_in: U256,
@@ -69,14 +130,117 @@ pub(crate) struct PositionInfo {
     // fees info
     pub fees_earned_token: U256,
     pub fees_earned_weth: U256,
+    // everything the record's collects returned, per the collect logs. a
+    // collect after a decrease also returns the withdrawn liquidity, which
+    // is taken out of the fees earned but left in here
+    pub collected_token: U256,
+    pub collected_weth: U256,
+    // fees derived from the pool's fee growth inside the range instead of the
+    // collect, (token0, token1) fee growth inside readings are Q128.128
+    pub fee_growth_inside_in: (U256, U256),
+    pub fee_growth_inside_out: (U256, U256),
+    // the position manager's own (feeGrowthInside0LastX128,
+    // feeGrowthInside1LastX128) for the token id after the opening action and
+    // after the closing collect, the checkpoints it settles tokensOwed from
+    pub fee_growth_inside_last_in: (U256, U256),
+    pub fee_growth_inside_last_out: (U256, U256),
+    pub fees_from_growth_token: U256,
+    pub fees_from_growth_weth: U256,
+    // mean pool liquidity over the swaps replayed while this record was open
+    pub avg_pool_liquidity: u128,
+    // weth volume of the swaps that touched this record's range while it was
+    // open, and weth fees earned as a fraction of it
+    pub volume_in_range_weth: U256,
+    pub fees_to_volume: f64,
+    // seconds the record was open, from the events' block times
+    pub holding_seconds: u64,
+    // fees earned per year held, as a fraction of approx_starting_weth. the
+    // token side is valued at the closing price, the combined apr is the sum
+    pub token_fee_apr: f64,
+    pub weth_fee_apr: f64,
+    pub fee_apr_in_weth: f64,
+    // blocks the record was open, and weth fees per weth of starting value
+    // per block held, for ranking records by capital efficiency
+    pub holding_blocks: u64,
+    pub fees_per_weth_block: f64,
     // approximate values for pnl calc
     // to try to represent impermanent loss
     // with fee offset
     pub approx_starting_weth: U256, // weth in + weth value of token in
     pub approx_ending_weth: U256,   // weth out + weth fees + weth value of (token out + token fees)
+    // approx_ending_weth with the token side valued two fixed ways whatever
+    // the valuation method: at the closing slot0 price, and by quoting the
+    // whole amount in one sale. the gap is the exit's liquidity haircut
+    pub approx_ending_weth_spot: U256,
+    pub approx_ending_weth_executable: U256,
     pub end_token_gain_separate: I256, // token out + token fees - token in
-    pub end_weth_gain_separate: I256, // weth out + weth fees - weth in
+    pub end_weth_gain_separate: I256,  // weth out + weth fees - weth in
     pub end_weth_gain_converted: I256, // approx_ending_weth - approx_starting_weth
+    // gas used on the simulated fork, not the original chain
+    pub gas_used_in: u64,  // mint, increase, or decrease that opened this record
+    pub gas_used_out: u64, // collect that closed this record
+    // decimals the amounts are scaled by in Display, the csv keeps raw amounts
+    pub token_decimals: u8,
+    pub weth_decimals: u8,
+}
+
+impl PositionInfo {
+    // whether the range was active at the tick, uniswap's ranges are half
+    // open so a tick on the upper bound is out of range and one on the lower
+    // bound is in range
+    fn in_range_at(&self, tick: I24) -> bool {
+        self.lower_tick <= tick && tick < self.upper_tick
+    }
+
+    pub(crate) fn in_range_at_entry(&self) -> bool {
+        self.in_range_at(self.tick_in)
+    }
+
+    // only meaningful once the record is closed
+    pub(crate) fn in_range_at_exit(&self) -> bool {
+        self.in_range_at(self.tick_out)
+    }
+
+    // records starting below the threshold are left out of the pnl summaries,
+    // a zero threshold makes nothing dust
+    pub(crate) fn is_dust(&self, threshold_weth: U256) -> bool {
+        self.approx_starting_weth < threshold_weth
+    }
+
+    // the (token, weth) amounts the record's liquidity holds at the price,
+    // without uncollected fees
+    pub(crate) fn amounts_at(&self, sqrt_price_x96: U160, clanker_is_token0: bool) -> (U256, U256) {
+        let (amount0, amount1) = amounts_for_liquidity(
+            self.liquidity_in,
+            sqrt_price_x96,
+            self.lower_tick,
+            self.upper_tick,
+        );
+        if clanker_is_token0 {
+            (amount0, amount1)
+        } else {
+            (amount1, amount0)
+        }
+    }
+}
+
+// formats an amount as `1.2345 (1234500000000000000)`: scaled by the token's
+// decimals with trailing zeros dropped, then the raw integer
+fn format_amount<T>(amount: T, decimals: u8) -> String
+where
+    T: Into<ParseUnits> + fmt::Display + Copy,
+{
+    match format_units(amount, decimals) {
+        Ok(scaled) => {
+            let scaled = if scaled.contains('.') {
+                scaled.trim_end_matches('0').trim_end_matches('.')
+            } else {
+                &scaled
+            };
+            format!("{} ({})", scaled, amount)
+        }
+        Err(_) => amount.to_string(),
+    }
 }
 
 impl fmt::Display for PositionInfo {
@@ -85,8 +249,10 @@ impl fmt::Display for PositionInfo {
             f,
             "\nPosition Info:\n\
              ├─ Token ID:                  {}\n\
+             ├─ Owner:                     {}\n\
              ├─ Token Action Index:        {}\n\
              ├─ Action Taken:              {}\n\
+             ├─ Close Reason:              {}\n\
              ├─ Lower Tick:                {}\n\
              ├─ Upper Tick:                {}\n\
              ├─ Opening info:\n\
@@ -105,76 +271,66 @@ impl fmt::Display for PositionInfo {
              ├─ Position PNL ---\n\
              │  token fees earned:                   {}\n\
              │  weth fees earned:                    {}\n\
+             │  token / weth collected:              {} / {}\n\
+             │  token fees from fee growth:          {}\n\
+             │  weth fees from fee growth:           {}\n\
+             │  avg pool liquidity:                  {}\n\
+             │  weth volume in range:                {}\n\
+             │  weth fees / volume:                  {}\n\
+             │  fee apr token / weth / combined:     {:.4} / {:.4} / {:.4}\n\
+             │  weth fees per weth-block:            {:e}\n\
              │  net token gain (if position closed): {}\n\
              │  net weth gain (if position closed):  {}\n\
              │  approx starting weth:  {}\n\
              │  approx ending weth:    {}\n\
-             └─ net pnl in weth:       {}",
+             │  ending weth spot / executable: {} / {}\n\
+             │  net pnl in weth:       {}\n\
+             └─ Gas used (in/out):     {} / {}",
             self.original_token_id,
+            self.owner,
             self.index,
             self.position_action,
+            self.close_reason
+                .map_or_else(|| "-".to_string(), |reason| reason.to_string()),
             self.lower_tick,
             self.upper_tick,
             self.block_in,
-            self.token_amount_in,
-            self.weth_amount_in,
+            format_amount(self.token_amount_in, self.token_decimals),
+            format_amount(self.weth_amount_in, self.weth_decimals),
             self.sqrt_price_limit_x96_in,
             self.tick_in,
             self.liquidity_in,
             self.block_out,
-            self.token_amount_out,
-            self.weth_amount_out,
+            format_amount(self.token_amount_out, self.token_decimals),
+            format_amount(self.weth_amount_out, self.weth_decimals),
             self.sqrt_price_limit_x96_out,
             self.tick_out,
-            self.fees_earned_token,
-            self.fees_earned_weth,
-            self.end_token_gain_separate,
-            self.end_weth_gain_separate,
-            self.approx_starting_weth,
-            self.approx_ending_weth,
-            self.end_weth_gain_converted,
+            format_amount(self.fees_earned_token, self.token_decimals),
+            format_amount(self.fees_earned_weth, self.weth_decimals),
+            format_amount(self.collected_token, self.token_decimals),
+            format_amount(self.collected_weth, self.weth_decimals),
+            format_amount(self.fees_from_growth_token, self.token_decimals),
+            format_amount(self.fees_from_growth_weth, self.weth_decimals),
+            self.avg_pool_liquidity,
+            format_amount(self.volume_in_range_weth, self.weth_decimals),
+            self.fees_to_volume,
+            self.token_fee_apr,
+            self.weth_fee_apr,
+            self.fee_apr_in_weth,
+            self.fees_per_weth_block,
+            format_amount(self.end_token_gain_separate, self.token_decimals),
+            format_amount(self.end_weth_gain_separate, self.weth_decimals),
+            format_amount(self.approx_starting_weth, self.weth_decimals),
+            format_amount(self.approx_ending_weth, self.weth_decimals),
+            format_amount(self.approx_ending_weth_spot, self.weth_decimals),
+            format_amount(self.approx_ending_weth_executable, self.weth_decimals),
+            format_amount(self.end_weth_gain_converted, self.weth_decimals),
+            self.gas_used_in,
+            self.gas_used_out,
         )
     }
 }
 
-// simulates the amount of weth that would be received from swapping the given token amount,
-// used to approximate the starting and ending weth value of the positions. note that this is
-// not 100% accurate because sometimes this is ran when the position is still open and could
-// be consumed during the swap.
-async fn sim_swap_token_for_weth(
-    swap_router: Arc<ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
-    pool_config: &PoolConfig,
-    token_amount_out: U256,
-    swap_account: Address,
-) -> Result<U256> {
-    if token_amount_out == U256::ZERO {
-        return Ok(U256::ZERO);
-    }
-
-    let (clanker_address, weth_address) = if pool_config.clanker_is_token0 {
-        (pool_config.token0, pool_config.token1)
-    } else {
-        (pool_config.token1, pool_config.token0)
-    };
-
-    let exact_input_params = ExactInputSingleParams {
-        tokenIn: clanker_address,
-        tokenOut: weth_address,
-        fee: pool_config.fee,
-        recipient: swap_account,
-        amountIn: token_amount_out,
-        amountOutMinimum: U256::from(0),
-        sqrtPriceLimitX96: U160::from(0),
-    };
-
-    let swap_router_call = swap_router
-        .exactInputSingle(exact_input_params)
-        .from(swap_account)
-        .call()
-        .await?;
-    Ok(swap_router_call.amountOut)
-}
-
 #[derive(Debug, Clone)]
 struct DecreaseLiquidityResult {
     token_out: U256,
@@ -182,109 +338,76 @@ struct DecreaseLiquidityResult {
 }
 
 async fn sim_decrease_liquidity(
-    position_manager: Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
+    backend: &impl PoolBackend,
     pool_config: &PoolConfig,
     token_id: U256,
     minter: Address,
     liquidity: u128,
 ) -> Result<DecreaseLiquidityResult> {
-    let decrease_liquidity_params = DecreaseLiquidityParams {
-        tokenId: token_id,
-        liquidity: liquidity,
-        amount0Min: U256::ZERO,
-        amount1Min: U256::ZERO,
-        deadline: U256::MAX,
-    };
-
-    let decrease_liquidity_return = position_manager
-        .decreaseLiquidity(decrease_liquidity_params)
-        .from(minter)
-        .call()
+    let (amount0, amount1) = backend
+        .quote_decrease_liquidity(token_id, minter, liquidity)
         .await?;
 
     if pool_config.clanker_is_token0 {
         Ok(DecreaseLiquidityResult {
-            token_out: decrease_liquidity_return.amount0,
-            weth_out: decrease_liquidity_return.amount1,
+            token_out: amount0,
+            weth_out: amount1,
         })
     } else {
         Ok(DecreaseLiquidityResult {
-            token_out: decrease_liquidity_return.amount1,
-            weth_out: decrease_liquidity_return.amount0,
+            token_out: amount1,
+            weth_out: amount0,
         })
     }
 }
 
+// collects everything owed to the position and sends it to `recipient`,
+// checking that the recipient's balances grew by the collected amounts.
+// returns the (amount0, amount1) collected and the gas used
 async fn collect_max_fees(
-    position_manager: Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
+    backend: &impl PoolBackend,
+    pool_config: &PoolConfig,
     token_id: U256,
     minter: Address,
-) -> Result<AbiLog<Collect>> {
-    let collect_params = CollectParams {
-        tokenId: token_id,
-        recipient: minter,
-        amount0Max: u128::MAX,
-        amount1Max: u128::MAX,
-    };
+    recipient: Address,
+) -> Result<(U256, U256, u64)> {
+    let balance0_before = backend.balance_of(pool_config.token0, recipient).await?;
+    let balance1_before = backend.balance_of(pool_config.token1, recipient).await?;
 
-    let mut attempts = 0;
-    let max_attempts = 4;
-    let mut receipt = None;
-
-    while attempts < max_attempts {
-        match position_manager
-            .collect(collect_params.clone())
-            .from(minter)
-            .send()
-            .await?
-            .get_receipt()
-            .await
-        {
-            Ok(r) => {
-                if r.inner.status() {
-                    receipt = Some(r);
-                    break;
-                }
-            }
-            Err(e) => {
-                error!("Failed to mint, retrying: {:?}", e);
-            }
-        }
-        attempts += 1;
-    }
-
-    let collect_receipt = receipt
-        .ok_or_else(|| eyre::eyre!("Failed to collect fees after {} attempts", max_attempts))?;
-
-    let collect_log = collect_receipt
-        .inner
-        .logs()
-        .iter()
-        .find(|log| log.inner.topics()[0] == Collect::SIGNATURE_HASH)
-        .and_then(|log| {
-            let log = AbiLog::new(
-                log.address(),
-                log.topics().to_vec(),
-                log.data().data.clone(),
-            )
-            .unwrap_or_default();
-            Collect::decode_log(&log, true).ok()
-        })
-        .context("Failed to decode collect event")?;
+    let (amount0, amount1, gas_used) = backend.collect(token_id, minter, recipient).await?;
 
-    Ok(collect_log)
+    let received0 = backend
+        .balance_of(pool_config.token0, recipient)
+        .await?
+        .saturating_sub(balance0_before);
+    let received1 = backend
+        .balance_of(pool_config.token1, recipient)
+        .await?
+        .saturating_sub(balance1_before);
+    if received0 != amount0 || received1 != amount1 {
+        bail!(FeeAnalyzerError::CollectFailed(format!(
+            "Collect for token id {} logged ({}, {}) but recipient {} received ({}, {})",
+            token_id, amount0, amount1, recipient, received0, received1
+        )));
+    }
+
+    Ok((amount0, amount1, gas_used))
 }
 
 pub async fn create_position_info_from_mint_event(
-    pool: Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
+    backend: &impl PoolBackend,
     pool_config: &PoolConfig,
-    swap_router: Arc<ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
-    swap_account: Address,
+    valuer: &impl TokenValuation,
     original_mint_event: SimulationEvent,
     token_id: U256,
     original_token_id: U256,
 ) -> Result<PositionInfo> {
     let mint_event = Mint::try_from(original_mint_event.clone())?;
+    let owner = if mint_event.owner == backend.position_manager_address() {
+        original_mint_event.from
+    } else {
+        mint_event.owner
+    };
 
     let (token_amount_in, weth_amount_in) = if pool_config.clanker_is_token0 {
         (mint_event.amount0, mint_event.amount1)
@@ -295,34 +418,47 @@ pub async fn create_position_info_from_mint_event(
     // approximate the starting value of the position in weth
     // by converting the starting token amount into weth
 
-    // check that this isn't the first mint event using fee growth as proxy
-    let fee_growth_check = if pool_config.clanker_is_token0 {
-        pool.feeGrowthGlobal0X128().call().await?._0
+    // the token side can only be valued once the pool holds weth from
+    // someone other than this mint, otherwise there's nothing to sell into.
+    // this skips the deployer's single sided first mint but not early mints
+    // that land before the first swap
+    let weth = if pool_config.clanker_is_token0 {
+        pool_config.token1
     } else {
-        pool.feeGrowthGlobal1X128().call().await?._0
+        pool_config.token0
     };
+    let pool_weth = backend.balance_of(weth, backend.pool_address()).await?;
+    let pool_weth_before_mint = pool_weth.saturating_sub(weth_amount_in);
 
-    let token_converted_to_weth = if token_amount_in > U256::ZERO && fee_growth_check > U256::ZERO {
-        sim_swap_token_for_weth(swap_router, pool_config, token_amount_in, swap_account).await?
+    let token_sellable = token_amount_in > U256::ZERO && pool_weth_before_mint > U256::ZERO;
+    let approx_starting_weth = if token_sellable {
+        value_in_weth(valuer, pool_config, token_amount_in, weth_amount_in).await?
     } else {
-        U256::ZERO
+        weth_amount_in
     };
 
-    let slot0 = pool.slot0().call().await?;
+    let (sqrt_price_x96, tick) = backend.slot0().await?;
+    let fee_growth_inside_in = backend
+        .fee_growth_inside(mint_event.tickLower, mint_event.tickUpper)
+        .await?;
+    let fee_growth_inside_last_in = backend.fee_growth_inside_last(token_id).await?;
 
     let position_info = PositionInfo {
         token_id,
         original_token_id,
+        owner,
         index: 0,
         lower_tick: mint_event.tickLower,
         upper_tick: mint_event.tickUpper,
-        tick_in: slot0.tick,
+        tick_in: tick,
         tick_out: I24::ZERO,
         closed: false,
+        close_reason: None,
+        close_out_error: None,
         block_in: original_mint_event.block,
         token_amount_in,
         weth_amount_in,
-        sqrt_price_limit_x96_in: slot0.sqrtPriceX96,
+        sqrt_price_limit_x96_in: sqrt_price_x96,
         liquidity_in: mint_event.amount,
         block_out: 0,
         token_amount_out: U256::ZERO,
@@ -330,72 +466,243 @@ pub async fn create_position_info_from_mint_event(
         sqrt_price_limit_x96_out: U160::ZERO,
         fees_earned_token: U256::ZERO,
         fees_earned_weth: U256::ZERO,
+        fee_growth_inside_in,
+        fee_growth_inside_out: (U256::ZERO, U256::ZERO),
+        fee_growth_inside_last_in,
+        fee_growth_inside_last_out: (U256::ZERO, U256::ZERO),
+        fees_from_growth_token: U256::ZERO,
+        fees_from_growth_weth: U256::ZERO,
+        avg_pool_liquidity: 0,
+        volume_in_range_weth: U256::ZERO,
+        fees_to_volume: 0.0,
+        holding_seconds: 0,
+        token_fee_apr: 0.0,
+        weth_fee_apr: 0.0,
+        fee_apr_in_weth: 0.0,
+        holding_blocks: 0,
+        fees_per_weth_block: 0.0,
+        collected_token: U256::ZERO,
+        collected_weth: U256::ZERO,
         position_action: PositionAction::Open,
         approx_ending_weth: U256::ZERO,
-        approx_starting_weth: token_converted_to_weth + weth_amount_in,
+        approx_ending_weth_spot: U256::ZERO,
+        approx_ending_weth_executable: U256::ZERO,
+        approx_starting_weth,
         end_token_gain_separate: I256::ZERO,
         end_weth_gain_separate: I256::ZERO,
         end_weth_gain_converted: I256::ZERO,
+        gas_used_in: 0,
+        gas_used_out: 0,
+        token_decimals: pool_config.token_decimals,
+        weth_decimals: pool_config.weth_decimals,
     };
 
     Ok(position_info)
 }
 
+// `weth_amount` plus `token_amount` valued in weth by `valuer`. records value
+// their starting and ending tokens with the configured valuation method, and
+// their spot ending value and value_records_at with SpotValuation
+pub(crate) async fn value_in_weth(
+    valuer: &impl TokenValuation,
+    pool_config: &PoolConfig,
+    token_amount: U256,
+    weth_amount: U256,
+) -> Result<U256> {
+    Ok(valuer.token_to_weth(pool_config, token_amount).await? + weth_amount)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn close_out_position_info(
-    position_manager: Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
-    pool: Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
-    swap_router: Arc<ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
+    backend: &impl PoolBackend,
+    valuer: &impl TokenValuation,
     pool_config: &PoolConfig,
     minter: Address,
-    swap_account: Address,
+    fee_recipient: Address,
     token_id: U256,
     position_info: &mut PositionInfo,
     block_out: u64,
     decrease_liquidity_event: Option<DecreaseLiquidityWithParams>,
+) -> Result<()> {
+    collect_close_out_fees(
+        backend,
+        pool_config,
+        minter,
+        fee_recipient,
+        token_id,
+        position_info,
+        block_out,
+        decrease_liquidity_event.as_ref(),
+    )
+    .await?;
+    value_closed_out_position(
+        backend,
+        valuer,
+        pool_config,
+        minter,
+        token_id,
+        position_info,
+        decrease_liquidity_event.as_ref(),
+    )
+    .await
+}
+
+// the state changing half of a close out, the collect that sends the
+// position's fees to the fee recipient
+#[allow(clippy::too_many_arguments)]
+async fn collect_close_out_fees(
+    backend: &impl PoolBackend,
+    pool_config: &PoolConfig,
+    minter: Address,
+    fee_recipient: Address,
+    token_id: U256,
+    position_info: &mut PositionInfo,
+    block_out: u64,
+    decrease_liquidity_event: Option<&DecreaseLiquidityWithParams>,
 ) -> Result<()> {
     // set position as closed and record the block number
     position_info.closed = true;
     position_info.block_out = block_out;
 
     // collect all of the fees earned by the position
-    let collect_log = collect_max_fees(position_manager.clone(), token_id, minter).await?;
+    let (amount0, amount1, collect_gas_used) =
+        collect_max_fees(backend, pool_config, token_id, minter, fee_recipient).await?;
+    position_info.gas_used_out = collect_gas_used;
+    position_info.fee_growth_inside_last_out = backend.fee_growth_inside_last(token_id).await?;
+    let (collected_token, collected_weth) = if pool_config.clanker_is_token0 {
+        (amount0, amount1)
+    } else {
+        (amount1, amount0)
+    };
+    let (fees_token, fees_weth) = fees_net_of_decrease(
+        pool_config,
+        position_info.original_token_id,
+        (collected_token, collected_weth),
+        decrease_liquidity_event,
+    )?;
+    // added to whatever pokes already collected while the record was open
+    position_info.collected_token += collected_token;
+    position_info.collected_weth += collected_weth;
+    position_info.fees_earned_token += fees_token;
+    position_info.fees_earned_weth += fees_weth;
+    Ok(())
+}
+
+// the fee part of a collect's (token, weth) amounts. if the position had a
+// decrease, the decreased token amounts are included in the collect, we need
+// to subtract them to get the fees earned
+fn fees_net_of_decrease(
+    pool_config: &PoolConfig,
+    original_token_id: U256,
+    (collected_token, collected_weth): (U256, U256),
+    decrease_liquidity_event: Option<&DecreaseLiquidityWithParams>,
+) -> Result<(U256, U256)> {
+    let Some(decrease_liquidity_event) = decrease_liquidity_event else {
+        return Ok((collected_token, collected_weth));
+    };
+    let (dl_token_amount_out, dl_weth_amount_out) =
+        decreased_amounts(pool_config, decrease_liquidity_event);
+    let fees_token = collected_token
+        .checked_sub(dl_token_amount_out)
+        .ok_or_else(|| {
+            FeeAnalyzerError::CollectFailed(format!(
+                "Collected {} tokens for token id {}, less than the {} decreased",
+                collected_token, original_token_id, dl_token_amount_out
+            ))
+        })?;
+    let fees_weth = collected_weth
+        .checked_sub(dl_weth_amount_out)
+        .ok_or_else(|| {
+            FeeAnalyzerError::CollectFailed(format!(
+                "Collected {} weth for token id {}, less than the {} decreased",
+                collected_weth, original_token_id, dl_weth_amount_out
+            ))
+        })?;
+    Ok((fees_token, fees_weth))
+}
+
+// replays a poke, the zero liquidity burn and collect an lp sends to take
+// their fees without changing the position. the fees collected so far are
+// added to the open record instead of opening a new one, returns the gas used
+pub(crate) async fn pool_poke_collect(
+    backend: &impl PoolBackend,
+    pool_config: &PoolConfig,
+    minter: Address,
+    fee_recipient: Address,
+    token_id: U256,
+    position_info: &mut PositionInfo,
+) -> Result<u64> {
+    let (amount0, amount1, gas_used) =
+        collect_max_fees(backend, pool_config, token_id, minter, fee_recipient).await?;
+    let (collected_token, collected_weth) = if pool_config.clanker_is_token0 {
+        (amount0, amount1)
+    } else {
+        (amount1, amount0)
+    };
+    // a poke withdraws no liquidity, all of it is fees
+    position_info.collected_token += collected_token;
+    position_info.collected_weth += collected_weth;
+    position_info.fees_earned_token += collected_token;
+    position_info.fees_earned_weth += collected_weth;
+    Ok(gas_used)
+}
+
+// the read only half of a close out, run after the collect: fee growth, the
+// closing price, the simulated decrease and the valuation of what comes out
+async fn value_closed_out_position(
+    backend: &impl PoolBackend,
+    valuer: &impl TokenValuation,
+    pool_config: &PoolConfig,
+    minter: Address,
+    token_id: U256,
+    position_info: &mut PositionInfo,
+    decrease_liquidity_event: Option<&DecreaseLiquidityWithParams>,
+) -> Result<()> {
+    // recompute the fees from the pool's fee growth without relying on the
+    // collect, the two should agree up to rounding in the position manager
+    position_info.fee_growth_inside_out = backend
+        .fee_growth_inside(position_info.lower_tick, position_info.upper_tick)
+        .await?;
+    let fees_from_growth0 = fees_from_growth(
+        position_info.fee_growth_inside_in.0,
+        position_info.fee_growth_inside_out.0,
+        position_info.liquidity_in,
+    );
+    let fees_from_growth1 = fees_from_growth(
+        position_info.fee_growth_inside_in.1,
+        position_info.fee_growth_inside_out.1,
+        position_info.liquidity_in,
+    );
     if pool_config.clanker_is_token0 {
-        position_info.fees_earned_token = collect_log.amount0;
-        position_info.fees_earned_weth = collect_log.amount1;
+        position_info.fees_from_growth_token = fees_from_growth0;
+        position_info.fees_from_growth_weth = fees_from_growth1;
     } else {
-        position_info.fees_earned_token = collect_log.amount1;
-        position_info.fees_earned_weth = collect_log.amount0;
-    }
-
-    // if the position had a decrease, the decreased token amounts
-    // are included in the collect, we need to subtract them to get the
-    // fees earned
-    if let Some(decrease_liquidity_event) = decrease_liquidity_event.clone() {
-        if pool_config.clanker_is_token0 {
-            position_info.fees_earned_token = position_info
-                .fees_earned_token
-                .checked_sub(decrease_liquidity_event.event.amount0)
-                .expect("token fees earned less than decreased token amount");
-            position_info.fees_earned_weth = position_info
-                .fees_earned_weth
-                .checked_sub(decrease_liquidity_event.event.amount1)
-                .expect("weth fees earned less than decreased token amount");
-        } else {
-            position_info.fees_earned_token = position_info
-                .fees_earned_token
-                .checked_sub(decrease_liquidity_event.event.amount1)
-                .expect("token fees earned less than decreased token amount");
-            position_info.fees_earned_weth = position_info
-                .fees_earned_weth
-                .checked_sub(decrease_liquidity_event.event.amount0)
-                .expect("weth fees earned less than decreased token amount");
-        }
+        position_info.fees_from_growth_token = fees_from_growth1;
+        position_info.fees_from_growth_weth = fees_from_growth0;
+    }
+    if position_info
+        .fees_from_growth_token
+        .abs_diff(position_info.fees_earned_token)
+        > U256::from(1)
+        || position_info
+            .fees_from_growth_weth
+            .abs_diff(position_info.fees_earned_weth)
+            > U256::from(1)
+    {
+        warn!(
+            "fee growth fees ({}, {}) differ from collected fees ({}, {}) for token id {}",
+            position_info.fees_from_growth_token,
+            position_info.fees_from_growth_weth,
+            position_info.fees_earned_token,
+            position_info.fees_earned_weth,
+            position_info.original_token_id,
+        );
     }
 
     // get the closing price and tick of the position
-    let slot0 = pool.slot0().call().await?;
-    position_info.sqrt_price_limit_x96_out = slot0.sqrtPriceX96;
-    position_info.tick_out = slot0.tick;
+    let (sqrt_price_x96, tick) = backend.slot0().await?;
+    position_info.sqrt_price_limit_x96_out = sqrt_price_x96;
+    position_info.tick_out = tick;
 
     // figure out ending token and weth balances if position was closed out
     //
@@ -405,17 +712,8 @@ async fn close_out_position_info(
     // (3) position was not closed out, simulate closing it fully out
     if let Some(decrease_liquidity_event) = decrease_liquidity_event {
         // case (1) and (2)
-        let (dl_token_out_amount, dl_weth_out_amount) = if pool_config.clanker_is_token0 {
-            (
-                decrease_liquidity_event.event.amount0,
-                decrease_liquidity_event.event.amount1,
-            )
-        } else {
-            (
-                decrease_liquidity_event.event.amount1,
-                decrease_liquidity_event.event.amount0,
-            )
-        };
+        let (dl_token_out_amount, dl_weth_out_amount) =
+            decreased_amounts(pool_config, decrease_liquidity_event);
 
         if position_info.liquidity_in == decrease_liquidity_event.event.liquidity {
             // case (1)
@@ -424,11 +722,11 @@ async fn close_out_position_info(
         } else {
             // case (2)
             let decrease_liquidity_result = sim_decrease_liquidity(
-                position_manager.clone(),
+                backend,
                 pool_config,
                 token_id,
                 minter,
-                position_info.liquidity_in - decrease_liquidity_event.event.liquidity,
+                remaining_liquidity(position_info, decrease_liquidity_event)?,
             )
             .await?;
 
@@ -439,7 +737,7 @@ async fn close_out_position_info(
     } else {
         // case (3)
         let decrease_liquidity_result = sim_decrease_liquidity(
-            position_manager.clone(),
+            backend,
             pool_config,
             token_id,
             minter,
@@ -453,12 +751,20 @@ async fn close_out_position_info(
     // simulate selling the token for weth for pnl estimate
     // and add the weth out amount to get the total weth amount
     let token_amount_to_sell = position_info.token_amount_out + position_info.fees_earned_token;
-    let token_converted_to_weth =
-        sim_swap_token_for_weth(swap_router, pool_config, token_amount_to_sell, swap_account)
-            .await?;
-
+    let weth_side = position_info.weth_amount_out + position_info.fees_earned_weth;
     position_info.approx_ending_weth =
-        token_converted_to_weth + position_info.weth_amount_out + position_info.fees_earned_weth;
+        value_in_weth(valuer, pool_config, token_amount_to_sell, weth_side).await?;
+    position_info.approx_ending_weth_spot = value_in_weth(
+        &SpotValuation { sqrt_price_x96 },
+        pool_config,
+        token_amount_to_sell,
+        weth_side,
+    )
+    .await?;
+    position_info.approx_ending_weth_executable = weth_side
+        + valuer
+            .executable_token_to_weth(pool_config, token_amount_to_sell)
+            .await?;
 
     position_info.end_weth_gain_separate = I256::try_from(position_info.weth_amount_out).unwrap()
         - I256::try_from(position_info.weth_amount_in).unwrap()
@@ -472,31 +778,31 @@ async fn close_out_position_info(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn pool_collect_fees_post_increase_liquidity(
-    position_manager: Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
-    pool: Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
-    swap_router: Arc<ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
+    backend: &impl PoolBackend,
+    valuer: &impl TokenValuation,
     pool_config: &PoolConfig,
     minter: Address,
-    swap_account: Address,
+    fee_recipient: Address,
     token_id: U256,
     position_info: &mut PositionInfo,
     block_out: u64,
     increase_liquidity_event: IncreaseLiquidityWithParams,
 ) -> Result<PositionInfo> {
     close_out_position_info(
-        position_manager,
-        pool,
-        swap_router.clone(),
+        backend,
+        valuer,
         pool_config,
         minter,
-        swap_account,
+        fee_recipient,
         token_id,
         position_info,
         block_out,
         None,
     )
     .await?;
+    position_info.close_reason = Some(CloseReason::StillOpenPartial);
 
     // create new position info for the tokenid
     let (token_amount_increase, weth_amount_increase) = if pool_config.clanker_is_token0 {
@@ -514,19 +820,21 @@ pub async fn pool_collect_fees_post_increase_liquidity(
     // get new position value by adding the increase amounts to the starting values
     let token_start = position_info.token_amount_in + token_amount_increase;
     let weth_start = position_info.weth_amount_in + weth_amount_increase;
-    let token_converted_to_weth =
-        sim_swap_token_for_weth(swap_router, pool_config, token_start, swap_account).await?;
+    let token_converted_to_weth = valuer.token_to_weth(pool_config, token_start).await?;
     let starting_weth = token_converted_to_weth + weth_start;
 
     let new_position_info = PositionInfo {
-        token_id: token_id,
+        token_id,
         original_token_id: position_info.original_token_id,
+        owner: position_info.owner,
         index: position_info.index + 1,
         lower_tick: position_info.lower_tick,
         upper_tick: position_info.upper_tick,
         tick_in: position_info.tick_out,
         tick_out: I24::ZERO,
         closed: false,
+        close_reason: None,
+        close_out_error: None,
         block_in: block_out,
         token_amount_in: token_start,
         weth_amount_in: weth_start,
@@ -538,37 +846,62 @@ pub async fn pool_collect_fees_post_increase_liquidity(
         sqrt_price_limit_x96_out: U160::ZERO,
         fees_earned_token: U256::ZERO,
         fees_earned_weth: U256::ZERO,
+        fee_growth_inside_in: position_info.fee_growth_inside_out,
+        fee_growth_inside_out: (U256::ZERO, U256::ZERO),
+        fee_growth_inside_last_in: position_info.fee_growth_inside_last_out,
+        fee_growth_inside_last_out: (U256::ZERO, U256::ZERO),
+        fees_from_growth_token: U256::ZERO,
+        fees_from_growth_weth: U256::ZERO,
+        avg_pool_liquidity: 0,
+        volume_in_range_weth: U256::ZERO,
+        fees_to_volume: 0.0,
+        holding_seconds: 0,
+        token_fee_apr: 0.0,
+        weth_fee_apr: 0.0,
+        fee_apr_in_weth: 0.0,
+        holding_blocks: 0,
+        fees_per_weth_block: 0.0,
+        collected_token: U256::ZERO,
+        collected_weth: U256::ZERO,
         position_action: PositionAction::IncreaseLiquidity,
         approx_starting_weth: starting_weth,
         approx_ending_weth: U256::ZERO,
+        approx_ending_weth_spot: U256::ZERO,
+        approx_ending_weth_executable: U256::ZERO,
         end_token_gain_separate: I256::ZERO,
         end_weth_gain_separate: I256::ZERO,
         end_weth_gain_converted: I256::ZERO,
+        gas_used_in: 0,
+        gas_used_out: 0,
+        token_decimals: position_info.token_decimals,
+        weth_decimals: position_info.weth_decimals,
     };
 
     Ok(new_position_info)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn pool_collect_fees_post_decrease_liquidity(
-    position_manager: Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
-    pool: Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
-    swap_router: Arc<ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
+    backend: &impl PoolBackend,
+    valuer: &impl TokenValuation,
     pool_config: &PoolConfig,
     minter: Address,
-    swap_account: Address,
+    fee_recipient: Address,
     token_id: U256,
     position_info: &mut PositionInfo,
     block_out: u64,
     decrease_liquidity_event: DecreaseLiquidityWithParams,
 ) -> Result<PositionInfo> {
+    // fail before collecting if the decrease doesn't fit the tracked position
+    remaining_liquidity(position_info, &decrease_liquidity_event)?;
+
     // close out positon
     close_out_position_info(
-        position_manager,
-        pool,
-        swap_router.clone(),
+        backend,
+        valuer,
         pool_config,
         minter,
-        swap_account,
+        fee_recipient,
         token_id,
         position_info,
         block_out,
@@ -579,15 +912,19 @@ pub(crate) async fn pool_collect_fees_post_decrease_liquidity(
     // create next position info based on if the position was fully closed out
     if position_info.liquidity_in == decrease_liquidity_event.event.liquidity {
         warn!("position is fully closed, closing out");
+        position_info.close_reason = Some(CloseReason::OnChainFullExit);
         // create new position info with zero amounts in case
         // same position is used again in future (don't expect people to do this)
         Ok(PositionInfo {
-            token_id: token_id,
+            token_id,
             original_token_id: position_info.original_token_id,
+            owner: position_info.owner,
             index: position_info.index + 1,
             lower_tick: position_info.lower_tick,
             upper_tick: position_info.upper_tick,
             closed: true,
+            close_reason: Some(CloseReason::OnChainFullExit),
+            close_out_error: None,
             block_in: block_out,
             token_amount_in: U256::ZERO,
             weth_amount_in: U256::ZERO,
@@ -601,46 +938,77 @@ pub(crate) async fn pool_collect_fees_post_decrease_liquidity(
             tick_out: I24::ZERO,
             fees_earned_token: U256::ZERO,
             fees_earned_weth: U256::ZERO,
+            fee_growth_inside_in: (U256::ZERO, U256::ZERO),
+            fee_growth_inside_out: (U256::ZERO, U256::ZERO),
+            fee_growth_inside_last_in: (U256::ZERO, U256::ZERO),
+            fee_growth_inside_last_out: (U256::ZERO, U256::ZERO),
+            fees_from_growth_token: U256::ZERO,
+            fees_from_growth_weth: U256::ZERO,
+            avg_pool_liquidity: 0,
+            volume_in_range_weth: U256::ZERO,
+            fees_to_volume: 0.0,
+            holding_seconds: 0,
+            token_fee_apr: 0.0,
+            weth_fee_apr: 0.0,
+            fee_apr_in_weth: 0.0,
+            holding_blocks: 0,
+            fees_per_weth_block: 0.0,
+            collected_token: U256::ZERO,
+            collected_weth: U256::ZERO,
             position_action: PositionAction::ClosePosition,
             approx_ending_weth: U256::ZERO,
+            approx_ending_weth_spot: U256::ZERO,
+            approx_ending_weth_executable: U256::ZERO,
             approx_starting_weth: U256::ZERO,
             end_token_gain_separate: I256::ZERO,
             end_weth_gain_separate: I256::ZERO,
             end_weth_gain_converted: I256::ZERO,
+            gas_used_in: 0,
+            gas_used_out: 0,
+            token_decimals: position_info.token_decimals,
+            weth_decimals: position_info.weth_decimals,
         })
     } else {
         warn!("position is partially closed, creating new position");
+        position_info.close_reason = Some(CloseReason::StillOpenPartial);
+        let liquidity_left = remaining_liquidity(position_info, &decrease_liquidity_event)?;
         // grab closed out token amounts to remove from the previous position
-        let (dl_token_amount_out, dl_weth_amount_out) = if pool_config.clanker_is_token0 {
-            (
-                decrease_liquidity_event.event.amount0,
-                decrease_liquidity_event.event.amount1,
-            )
-        } else {
-            (
-                decrease_liquidity_event.event.amount1,
-                decrease_liquidity_event.event.amount0,
-            )
-        };
+        let (dl_token_amount_out, dl_weth_amount_out) =
+            decreased_amounts(pool_config, &decrease_liquidity_event);
 
+        // after a price move a decrease can return more of one side than was
+        // deposited, the remaining position then starts with none of it
+        if dl_token_amount_out > position_info.token_amount_in
+            || dl_weth_amount_out > position_info.weth_amount_in
+        {
+            warn!(
+                "Decrease of token id {} withdrew ({}, {}), more than the ({}, {}) deposited, \
+                 the remaining position's starting amounts are floored at zero",
+                position_info.original_token_id,
+                dl_token_amount_out,
+                dl_weth_amount_out,
+                position_info.token_amount_in,
+                position_info.weth_amount_in
+            );
+        }
         let token_start = position_info
             .token_amount_in
-            .checked_sub(dl_token_amount_out)
-            .expect("token decrease larger than starting token amount");
+            .saturating_sub(dl_token_amount_out);
         let weth_start = position_info
             .weth_amount_in
-            .checked_sub(dl_weth_amount_out)
-            .expect("weth decrease larger than starting weth amount");
-        let token_converted_to_weth =
-            sim_swap_token_for_weth(swap_router, pool_config, token_start, swap_account).await?;
+            .saturating_sub(dl_weth_amount_out);
+        let token_converted_to_weth = valuer.token_to_weth(pool_config, token_start).await?;
         let starting_weth = token_converted_to_weth + weth_start;
 
         // positional partially closed, create new position with the remaining liquidity
         Ok(PositionInfo {
-            token_id: token_id,
+            token_id,
             original_token_id: position_info.original_token_id,
+            owner: position_info.owner,
             index: position_info.index + 1,
             closed: false,
+            close_reason: None,
+            close_out_error: None,
             lower_tick: position_info.lower_tick,
             upper_tick: position_info.upper_tick,
             tick_in: position_info.tick_out,
@@ -649,47 +1017,583 @@ pub(crate) async fn pool_collect_fees_post_decrease_liquidity(
             token_amount_in: token_start,
             weth_amount_in: weth_start,
             sqrt_price_limit_x96_in: position_info.sqrt_price_limit_x96_out,
-            liquidity_in: position_info.liquidity_in - decrease_liquidity_event.event.liquidity,
+            liquidity_in: liquidity_left,
             block_out: 0,
             token_amount_out: U256::ZERO,
             weth_amount_out: U256::ZERO,
             sqrt_price_limit_x96_out: U160::ZERO,
             fees_earned_token: U256::ZERO,
             fees_earned_weth: U256::ZERO,
+            fee_growth_inside_in: position_info.fee_growth_inside_out,
+            fee_growth_inside_out: (U256::ZERO, U256::ZERO),
+            fee_growth_inside_last_in: position_info.fee_growth_inside_last_out,
+            fee_growth_inside_last_out: (U256::ZERO, U256::ZERO),
+            fees_from_growth_token: U256::ZERO,
+            fees_from_growth_weth: U256::ZERO,
+            avg_pool_liquidity: 0,
+            volume_in_range_weth: U256::ZERO,
+            fees_to_volume: 0.0,
+            holding_seconds: 0,
+            token_fee_apr: 0.0,
+            weth_fee_apr: 0.0,
+            fee_apr_in_weth: 0.0,
+            holding_blocks: 0,
+            fees_per_weth_block: 0.0,
+            collected_token: U256::ZERO,
+            collected_weth: U256::ZERO,
             position_action: PositionAction::DecreaseLiquidity,
             approx_starting_weth: starting_weth,
             approx_ending_weth: U256::ZERO,
+            approx_ending_weth_spot: U256::ZERO,
+            approx_ending_weth_executable: U256::ZERO,
             end_token_gain_separate: I256::ZERO,
             end_weth_gain_separate: I256::ZERO,
             end_weth_gain_converted: I256::ZERO,
+            gas_used_in: 0,
+            gas_used_out: 0,
+            token_decimals: position_info.token_decimals,
+            weth_decimals: position_info.weth_decimals,
         })
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn pool_close_out_position(
-    position_manager: Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
-    pool: Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
-    swap_router: Arc<ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
+    backend: &impl PoolBackend,
+    valuer: &impl TokenValuation,
     pool_config: &PoolConfig,
     minter: Address,
-    swap_account: Address,
+    fee_recipient: Address,
     token_id: U256,
     position_info: &mut PositionInfo,
     block_out: u64,
 ) -> Result<()> {
     close_out_position_info(
-        position_manager,
-        pool,
-        swap_router,
+        backend,
+        valuer,
+        pool_config,
+        minter,
+        fee_recipient,
+        token_id,
+        position_info,
+        block_out,
+        None,
+    )
+    .await?;
+    position_info.close_reason = Some(CloseReason::ForcedAtSimEnd);
+
+    Ok(())
+}
+
+// the end of run close out in two halves so the collects, which are
+// transactions from the mint account, can run one at a time while the
+// valuations run concurrently. the valuation only reads the fork, as long as
+// the valuer doesn't send transactions of its own
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn pool_close_out_collect(
+    backend: &impl PoolBackend,
+    pool_config: &PoolConfig,
+    minter: Address,
+    fee_recipient: Address,
+    token_id: U256,
+    position_info: &mut PositionInfo,
+    block_out: u64,
+) -> Result<()> {
+    collect_close_out_fees(
+        backend,
         pool_config,
         minter,
-        swap_account,
+        fee_recipient,
         token_id,
         position_info,
         block_out,
         None,
     )
     .await?;
+    position_info.close_reason = Some(CloseReason::ForcedAtSimEnd);
 
     Ok(())
 }
+
+pub(crate) async fn pool_close_out_value(
+    backend: &impl PoolBackend,
+    valuer: &impl TokenValuation,
+    pool_config: &PoolConfig,
+    minter: Address,
+    token_id: U256,
+    position_info: &mut PositionInfo,
+) -> Result<()> {
+    value_closed_out_position(
+        backend,
+        valuer,
+        pool_config,
+        minter,
+        token_id,
+        position_info,
+        None,
+    )
+    .await
+}
+
+// liquidity the position keeps after the decrease, an error instead of an
+// underflow when the event decreases more than the tracked record holds (a
+// missed increase or mismatched input data)
+fn remaining_liquidity(
+    position_info: &PositionInfo,
+    decrease_liquidity_event: &DecreaseLiquidityWithParams,
+) -> Result<u128> {
+    position_info
+        .liquidity_in
+        .checked_sub(decrease_liquidity_event.event.liquidity)
+        .ok_or_else(|| {
+            FeeAnalyzerError::BurnMismatch(format!(
+                "Decrease of {} liquidity for token id {} exceeds the tracked liquidity {}",
+                decrease_liquidity_event.event.liquidity,
+                position_info.original_token_id,
+                position_info.liquidity_in
+            ))
+            .into()
+        })
+}
+
+// (token, weth) amounts withdrawn by the decrease
+fn decreased_amounts(
+    pool_config: &PoolConfig,
+    decrease_liquidity_event: &DecreaseLiquidityWithParams,
+) -> (U256, U256) {
+    if pool_config.clanker_is_token0 {
+        (
+            decrease_liquidity_event.event.amount0,
+            decrease_liquidity_event.event.amount1,
+        )
+    } else {
+        (
+            decrease_liquidity_event.event.amount1,
+            decrease_liquidity_event.event.amount0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        abi::INonfungiblePositionManager::DecreaseLiquidity,
+        chain_interactions::backend::mock::{self, pool_config, MockBackend, MockValuer},
+    };
+
+    const MINTER: Address = Address::repeat_byte(0x33);
+    const RECIPIENT: Address = Address::repeat_byte(0x44);
+    const TOKEN_ID: U256 = U256::from_limbs([7, 0, 0, 0]);
+
+    fn u(amount: u64) -> U256 {
+        U256::from(amount)
+    }
+
+    // a decrease of `liquidity` withdrawing (amount0, amount1)
+    fn decrease(liquidity: u128, amount0: u64, amount1: u64) -> DecreaseLiquidityWithParams {
+        DecreaseLiquidityWithParams {
+            amount_0_min: U256::ZERO,
+            amount_1_min: U256::ZERO,
+            event: DecreaseLiquidity {
+                tokenId: TOKEN_ID,
+                liquidity,
+                amount0: u(amount0),
+                amount1: u(amount1),
+            },
+        }
+    }
+
+    async fn open_position(backend: &MockBackend, valuer: &MockValuer) -> PositionInfo {
+        mock::open_position(backend, valuer, TOKEN_ID).await
+    }
+
+    #[tokio::test]
+    async fn amounts_display_scaled_then_raw() {
+        assert_eq!(
+            format_amount(u(1_234_500_000_000_000_000), 18),
+            "1.2345 (1234500000000000000)"
+        );
+        assert_eq!(
+            format_amount(u(2_000_000_000_000_000_000), 18),
+            "2 (2000000000000000000)"
+        );
+        assert_eq!(format_amount(u(1_500_000), 6), "1.5 (1500000)");
+        assert_eq!(format_amount(U256::ZERO, 18), "0 (0)");
+        assert_eq!(
+            format_amount(I256::try_from(-250_000_000_000_000_000_i64).unwrap(), 18),
+            "-0.25 (-250000000000000000)"
+        );
+
+        let mut position = mock::record(1, 0).await;
+        position.token_decimals = 6;
+        position.token_amount_in = u(1_234_500);
+        position.weth_amount_in = u(1_234_500_000_000_000_000);
+        let display = position.to_string();
+        assert!(display.contains("Token Amount In:           1.2345 (1234500)\n"));
+        assert!(display.contains("WETH Amount In:            1.2345 (1234500000000000000)\n"));
+    }
+
+    #[test]
+    fn fees_net_of_decrease_subtracts_the_withdrawn_liquidity() {
+        let decrease = decrease(400, 200, 120);
+        // the token is token0, so amount0 is the token side
+        assert_eq!(
+            fees_net_of_decrease(
+                &pool_config(true),
+                TOKEN_ID,
+                (u(205), u(124)),
+                Some(&decrease)
+            )
+            .unwrap(),
+            (u(5), u(4))
+        );
+        // the token is token1, so amount1 is the token side
+        assert_eq!(
+            fees_net_of_decrease(
+                &pool_config(false),
+                TOKEN_ID,
+                (u(126), u(203)),
+                Some(&decrease)
+            )
+            .unwrap(),
+            (u(6), u(3))
+        );
+        // without a decrease everything collected is fees
+        assert_eq!(
+            fees_net_of_decrease(&pool_config(true), TOKEN_ID, (u(9), u(2)), None).unwrap(),
+            (u(9), u(2))
+        );
+    }
+
+    #[test]
+    fn fees_net_of_decrease_fails_when_less_was_collected_than_decreased() {
+        let error = fees_net_of_decrease(
+            &pool_config(true),
+            TOKEN_ID,
+            (u(199), u(124)),
+            Some(&decrease(400, 200, 120)),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FeeAnalyzerError>(),
+            Some(FeeAnalyzerError::CollectFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn remaining_liquidity_fails_on_a_decrease_past_the_tracked_liquidity() {
+        let backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let position = open_position(&backend, &valuer).await;
+
+        assert_eq!(
+            remaining_liquidity(&position, &decrease(400, 0, 0)).unwrap(),
+            600
+        );
+        assert_eq!(
+            remaining_liquidity(&position, &decrease(1000, 0, 0)).unwrap(),
+            0
+        );
+        let error = remaining_liquidity(&position, &decrease(1001, 0, 0)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FeeAnalyzerError>(),
+            Some(FeeAnalyzerError::BurnMismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn full_decrease_closes_the_record_with_fees_net_of_the_withdrawal() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let mut position = open_position(&backend, &valuer).await;
+        assert_eq!(position.approx_starting_weth, u(300));
+        // the withdrawn (480, 320) plus (7, 3) of fees. no decrease quote, a
+        // full exit values what the decrease returned
+        backend.collect = (u(487), u(323));
+        backend.collect_gas = 21_000;
+
+        let next = pool_collect_fees_post_decrease_liquidity(
+            &backend,
+            &valuer,
+            &pool_config(true),
+            MINTER,
+            RECIPIENT,
+            TOKEN_ID,
+            &mut position,
+            20,
+            decrease(1000, 480, 320),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(backend.collects(), 1);
+        assert!(position.closed);
+        assert_eq!(position.block_out, 20);
+        assert_eq!(position.close_reason, Some(CloseReason::OnChainFullExit));
+        assert_eq!(position.gas_used_out, 21_000);
+        assert_eq!(
+            (position.fees_earned_token, position.fees_earned_weth),
+            (u(7), u(3))
+        );
+        assert_eq!(
+            (position.collected_token, position.collected_weth),
+            (u(487), u(323))
+        );
+        assert_eq!(
+            (position.token_amount_out, position.weth_amount_out),
+            (u(480), u(320))
+        );
+        // (480 + 7) token at 2 weth each, plus 320 + 3 weth
+        assert_eq!(position.approx_ending_weth, u(1297));
+        assert_eq!(
+            position.end_weth_gain_converted,
+            I256::try_from(997).unwrap()
+        );
+
+        assert!(next.closed);
+        assert_eq!(next.index, 1);
+        assert_eq!(next.liquidity_in, 0);
+        assert!(matches!(
+            next.position_action,
+            PositionAction::ClosePosition
+        ));
+    }
+
+    #[tokio::test]
+    async fn partial_decrease_values_the_rest_and_opens_the_remainder() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let mut position = open_position(&backend, &valuer).await;
+        // the withdrawn (200, 120) plus (5, 4) of fees, and what the 600
+        // liquidity left would return
+        backend.collect = (u(205), u(124));
+        backend.decrease_quote = Some((u(300), u(180)));
+
+        let next = pool_collect_fees_post_decrease_liquidity(
+            &backend,
+            &valuer,
+            &pool_config(true),
+            MINTER,
+            RECIPIENT,
+            TOKEN_ID,
+            &mut position,
+            20,
+            decrease(400, 200, 120),
+        )
+        .await
+        .unwrap();
+
+        assert!(position.closed);
+        assert_eq!(position.close_reason, Some(CloseReason::StillOpenPartial));
+        assert_eq!(
+            (position.fees_earned_token, position.fees_earned_weth),
+            (u(5), u(4))
+        );
+        assert_eq!(
+            (position.collected_token, position.collected_weth),
+            (u(205), u(124))
+        );
+        // the quoted rest plus the decreased amounts
+        assert_eq!(
+            (position.token_amount_out, position.weth_amount_out),
+            (u(500), u(300))
+        );
+
+        assert!(!next.closed);
+        assert_eq!(next.index, 1);
+        assert_eq!(next.block_in, 20);
+        assert_eq!(next.liquidity_in, 600);
+        assert!(matches!(
+            next.position_action,
+            PositionAction::DecreaseLiquidity
+        ));
+        assert_eq!(
+            (next.token_amount_in, next.weth_amount_in),
+            (u(300), u(180))
+        );
+        assert_eq!(next.approx_starting_weth, u(780));
+    }
+
+    #[tokio::test]
+    async fn fee_growth_checkpoints_carry_over_and_only_grow() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        backend.fee_growth_inside_last = (u(10), u(20));
+        let mut position = open_position(&backend, &valuer).await;
+        assert_eq!(position.fee_growth_inside_last_in, (u(10), u(20)));
+
+        // the partial decrease's collect checkpoints the first record, and the
+        // rest starts from there
+        backend.fee_growth_inside_last = (u(40), u(60));
+        backend.collect = (u(205), u(124));
+        backend.decrease_quote = Some((u(300), u(180)));
+        let mut next = pool_collect_fees_post_decrease_liquidity(
+            &backend,
+            &valuer,
+            &pool_config(true),
+            MINTER,
+            RECIPIENT,
+            TOKEN_ID,
+            &mut position,
+            20,
+            decrease(400, 200, 120),
+        )
+        .await
+        .unwrap();
+        assert_eq!(position.fee_growth_inside_last_out, (u(40), u(60)));
+        assert_eq!(next.fee_growth_inside_last_in, (u(40), u(60)));
+
+        backend.fee_growth_inside_last = (u(90), u(60));
+        pool_close_out_position(
+            &backend,
+            &valuer,
+            &pool_config(true),
+            MINTER,
+            RECIPIENT,
+            TOKEN_ID,
+            &mut next,
+            30,
+        )
+        .await
+        .unwrap();
+        assert_eq!(next.fee_growth_inside_last_out, (u(90), u(60)));
+
+        // fee growth inside never goes down while the position stays open
+        for record in [&position, &next] {
+            let (last_in, last_out) = (
+                record.fee_growth_inside_last_in,
+                record.fee_growth_inside_last_out,
+            );
+            assert!(last_out.0 >= last_in.0 && last_out.1 >= last_in.1);
+        }
+    }
+
+    #[tokio::test]
+    async fn decrease_past_the_tracked_liquidity_fails_before_collecting() {
+        let backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let mut position = open_position(&backend, &valuer).await;
+
+        let error = pool_collect_fees_post_decrease_liquidity(
+            &backend,
+            &valuer,
+            &pool_config(true),
+            MINTER,
+            RECIPIENT,
+            TOKEN_ID,
+            &mut position,
+            20,
+            decrease(1001, 500, 300),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<FeeAnalyzerError>(),
+            Some(FeeAnalyzerError::BurnMismatch(_))
+        ));
+        assert_eq!(backend.collects(), 0);
+        assert!(!position.closed);
+    }
+
+    #[tokio::test]
+    async fn poke_collects_add_to_the_open_record() {
+        // the token is token1 here, so the collect's amount1 is the token side
+        let mut backend = MockBackend::new(&pool_config(false));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let mut position = open_position(&backend, &valuer).await;
+        backend.collect = (u(9), u(4));
+        backend.collect_gas = 50_000;
+
+        for _ in 0..2 {
+            let gas_used = pool_poke_collect(
+                &backend,
+                &pool_config(false),
+                MINTER,
+                RECIPIENT,
+                TOKEN_ID,
+                &mut position,
+            )
+            .await
+            .unwrap();
+            assert_eq!(gas_used, 50_000);
+        }
+
+        assert!(!position.closed);
+        assert_eq!(
+            (position.fees_earned_token, position.fees_earned_weth),
+            (u(8), u(18))
+        );
+        assert_eq!(
+            (position.collected_token, position.collected_weth),
+            (u(8), u(18))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_mint_before_the_first_swap_values_its_token_side() {
+        let backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let pool_holds = |amounts| {
+            backend
+                .credited
+                .lock()
+                .unwrap()
+                .insert(backend.pool_address(), amounts);
+        };
+
+        // the pool's only weth is this mint's, there's nothing to sell into
+        pool_holds((u(500), u(300)));
+        let first = open_position(&backend, &valuer).await;
+        assert_eq!(first.approx_starting_weth, u(300));
+
+        // an earlier mint's weth is in the pool, still without any swap
+        pool_holds((u(1000), u(600)));
+        let second = open_position(&backend, &valuer).await;
+        assert_eq!(second.approx_starting_weth, u(500 * 2 + 300));
+    }
+
+    #[tokio::test]
+    async fn collects_are_checked_against_the_recipient() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        backend.collect = (u(9), u(4));
+        let collected = collect_max_fees(&backend, &pool_config(true), TOKEN_ID, MINTER, RECIPIENT)
+            .await
+            .unwrap();
+        assert_eq!((collected.0, collected.1), (u(9), u(4)));
+        let balances = backend.credited.lock().unwrap().clone();
+        assert_eq!(balances.get(&RECIPIENT), Some(&(u(9), u(4))));
+        assert_eq!(balances.get(&MINTER), None);
+
+        // the amounts landing anywhere but the recipient fail the collect
+        let backend = MockBackend {
+            pays_minter: true,
+            collect: (u(9), u(4)),
+            ..MockBackend::new(&pool_config(true))
+        };
+        let error = collect_max_fees(&backend, &pool_config(true), TOKEN_ID, MINTER, RECIPIENT)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FeeAnalyzerError>(),
+            Some(FeeAnalyzerError::CollectFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn ranges_are_half_open() {
+        // over [-600, 600)
+        let mut position = mock::record(1, 0).await;
+        let tick = |tick: i32| I24::try_from(tick).unwrap();
+        assert!(!position.in_range_at(tick(-601)));
+        assert!(position.in_range_at(tick(-600)));
+        assert!(position.in_range_at(tick(599)));
+        assert!(!position.in_range_at(tick(600)));
+
+        position.tick_in = tick(-600);
+        position.tick_out = tick(600);
+        assert!(position.in_range_at_entry());
+        assert!(!position.in_range_at_exit());
+    }
+}
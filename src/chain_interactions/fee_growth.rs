@@ -0,0 +1,246 @@
+use std::collections::BTreeSet;
+
+use alloy::primitives::{aliases::I24, U256, U512};
+use eyre::Result;
+
+use crate::{
+    abi::UniswapV3Pool::UniswapV3PoolInstance,
+    fee_analyzer::{ArcAnvilHttpProvider, HttpClient},
+};
+
+// reads the pool's current fee growth inside the tick range for token0 and
+// token1 (Q128.128 per unit of liquidity). mirrors Tick.getFeeGrowthInside,
+// all of the subtractions wrap like they do in the contract.
+pub(crate) async fn fee_growth_inside(
+    pool: &UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>,
+    lower_tick: I24,
+    upper_tick: I24,
+) -> Result<(U256, U256)> {
+    let current_tick = pool.slot0().call().await?.tick;
+    let global0 = pool.feeGrowthGlobal0X128().call().await?._0;
+    let global1 = pool.feeGrowthGlobal1X128().call().await?._0;
+    let lower = pool.ticks(lower_tick).call().await?;
+    let upper = pool.ticks(upper_tick).call().await?;
+
+    let inside = |global: U256, lower_outside: U256, upper_outside: U256| {
+        growth_inside(
+            current_tick,
+            (lower_tick, lower_outside),
+            (upper_tick, upper_outside),
+            global,
+        )
+    };
+
+    Ok((
+        inside(
+            global0,
+            lower.feeGrowthOutside0X128,
+            upper.feeGrowthOutside0X128,
+        ),
+        inside(
+            global1,
+            lower.feeGrowthOutside1X128,
+            upper.feeGrowthOutside1X128,
+        ),
+    ))
+}
+
+// Tick.getFeeGrowthInside for one token, given each bound's tick and its fee
+// growth outside
+fn growth_inside(
+    current_tick: I24,
+    (lower_tick, lower_outside): (I24, U256),
+    (upper_tick, upper_outside): (I24, U256),
+    global: U256,
+) -> U256 {
+    let below = if current_tick >= lower_tick {
+        lower_outside
+    } else {
+        global.wrapping_sub(lower_outside)
+    };
+    let above = if current_tick < upper_tick {
+        upper_outside
+    } else {
+        global.wrapping_sub(upper_outside)
+    };
+    global.wrapping_sub(below).wrapping_sub(above)
+}
+
+// fee growth inside one span between neighbouring initialized ticks, with
+// the liquidity active across it
+pub(crate) struct TickRangeFeeGrowth {
+    pub lower_tick: I24,
+    pub upper_tick: I24,
+    pub liquidity: u128,
+    // token0, token1
+    pub fee_growth_inside: (U256, U256),
+}
+
+// splits the pool at every still initialized tick among `boundaries` and reads
+// the fee growth inside each span, along with the pool's global fee growth.
+// the spans partition the occupied ticks, so their growth adds up to the
+// global growth accrued while price was among them
+pub(crate) async fn fee_growth_by_tick_range(
+    pool: &UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>,
+    boundaries: &BTreeSet<I24>,
+) -> Result<((U256, U256), Vec<TickRangeFeeGrowth>)> {
+    let current_tick = pool.slot0().call().await?.tick;
+    let global0 = pool.feeGrowthGlobal0X128().call().await?._0;
+    let global1 = pool.feeGrowthGlobal1X128().call().await?._0;
+
+    // a tick whose liquidity all left has been cleared, along with its fee
+    // growth outside, so it no longer bounds anything
+    let mut ticks = Vec::new();
+    for &tick in boundaries {
+        let info = pool.ticks(tick).call().await?;
+        if info.liquidityGross > 0 {
+            ticks.push(InitializedTick {
+                tick,
+                liquidity_net: info.liquidityNet,
+                fee_growth_outside: (info.feeGrowthOutside0X128, info.feeGrowthOutside1X128),
+            });
+        }
+    }
+    Ok((
+        (global0, global1),
+        split_fee_growth(current_tick, (global0, global1), &ticks),
+    ))
+}
+
+// the parts of an initialized tick the spans are read from
+struct InitializedTick {
+    tick: I24,
+    liquidity_net: i128,
+    // token0, token1
+    fee_growth_outside: (U256, U256),
+}
+
+// the spans between neighbouring ticks, in tick order, with the liquidity
+// active across each and the fee growth inside it
+fn split_fee_growth(
+    current_tick: I24,
+    (global0, global1): (U256, U256),
+    ticks: &[InitializedTick],
+) -> Vec<TickRangeFeeGrowth> {
+    let mut liquidity: i128 = 0;
+    let mut ranges = Vec::new();
+    for pair in ticks.windows(2) {
+        let (lower, upper) = (&pair[0], &pair[1]);
+        liquidity += lower.liquidity_net;
+        ranges.push(TickRangeFeeGrowth {
+            lower_tick: lower.tick,
+            upper_tick: upper.tick,
+            liquidity: liquidity.max(0) as u128,
+            fee_growth_inside: (
+                growth_inside(
+                    current_tick,
+                    (lower.tick, lower.fee_growth_outside.0),
+                    (upper.tick, upper.fee_growth_outside.0),
+                    global0,
+                ),
+                growth_inside(
+                    current_tick,
+                    (lower.tick, lower.fee_growth_outside.1),
+                    (upper.tick, upper.fee_growth_outside.1),
+                    global1,
+                ),
+            ),
+        });
+    }
+    ranges
+}
+
+// fees accrued by `liquidity` between two fee growth inside readings, same
+// math as the position manager's tokensOwed update
+pub(crate) fn fees_from_growth(growth_in: U256, growth_out: U256, liquidity: u128) -> U256 {
+    let delta = U512::from(growth_out.wrapping_sub(growth_in));
+    U256::saturating_from((delta * U512::from(liquidity)) >> 128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(tick: i32, liquidity_net: i128, outside0: u64, outside1: u64) -> InitializedTick {
+        InitializedTick {
+            tick: I24::try_from(tick).unwrap(),
+            liquidity_net,
+            fee_growth_outside: (U256::from(outside0), U256::from(outside1)),
+        }
+    }
+
+    #[test]
+    fn fee_growth_concentrates_where_the_swaps_traded() {
+        // positions over [-600, 600) and [-1200, 1200). the swaps traded around
+        // tick 0, accruing (900, 600) of global growth, and once crossed up into
+        // [600, 1200) accruing (100, 50) before crossing back down. tick 600's
+        // outside growth flipped to what accrued above it
+        let ticks = [
+            tick(-1200, 2_000, 0, 0),
+            tick(-600, 1_000, 0, 0),
+            tick(600, -1_000, 100, 50),
+            tick(1200, -2_000, 0, 0),
+        ];
+        let global = (U256::from(1_000), U256::from(650));
+        let ranges = split_fee_growth(I24::ZERO, global, &ticks);
+
+        let spans = ranges
+            .iter()
+            .map(|range| {
+                (
+                    range.lower_tick.as_i32(),
+                    range.upper_tick.as_i32(),
+                    range.liquidity,
+                    range.fee_growth_inside,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                (-1200, -600, 2_000, (U256::ZERO, U256::ZERO)),
+                (-600, 600, 3_000, (U256::from(900), U256::from(600))),
+                (600, 1200, 2_000, (U256::from(100), U256::from(50))),
+            ]
+        );
+        // the spans add up to the global growth
+        let total = ranges
+            .iter()
+            .fold((U256::ZERO, U256::ZERO), |total, range| {
+                (
+                    total.0 + range.fee_growth_inside.0,
+                    total.1 + range.fee_growth_inside.1,
+                )
+            });
+        assert_eq!(total, global);
+    }
+
+    #[test]
+    fn fees_are_the_growth_delta_times_liquidity_in_q128() {
+        let q128 = U256::from(1) << 128;
+        let start = U256::from(7) * q128;
+        assert_eq!(
+            fees_from_growth(start, start + U256::from(3) * q128, 5),
+            U256::from(15)
+        );
+        // fractions of a unit are floored like mulDiv
+        assert_eq!(
+            fees_from_growth(start, start + (q128 >> 1), 3),
+            U256::from(1)
+        );
+        assert_eq!(
+            fees_from_growth(start, start + q128 - U256::from(1), 1),
+            U256::ZERO
+        );
+        assert_eq!(fees_from_growth(start, start, u128::MAX), U256::ZERO);
+        // growth counters overflow by design, so a reading past the wrap
+        // still gives the growth in between
+        let before_wrap = U256::ZERO.wrapping_sub(U256::from(2) * q128);
+        assert_eq!(fees_from_growth(before_wrap, q128, 5), U256::from(15));
+        // the product doesn't overflow for the largest liquidity
+        assert_eq!(
+            fees_from_growth(U256::ZERO, q128, u128::MAX),
+            U256::from(u128::MAX)
+        );
+    }
+}
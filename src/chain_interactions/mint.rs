@@ -1,13 +1,14 @@
 use std::{str::FromStr, sync::Arc};
 
 use alloy::{
-    primitives::{Address, Log as AbiLog, U256},
+    primitives::{aliases::I24, Address, Log as AbiLog, U256},
     rpc::types::TransactionReceipt,
     sol_types::SolEvent,
 };
 use eyre::{bail, Context, ContextCompat, Result};
-use tracing::error;
+use tracing::{error, warn};
 
+use crate::error::FeeAnalyzerError;
 use crate::{
     abi::{
         ClankerToken::ClankerTokenInstance,
@@ -27,49 +28,65 @@ pub(crate) async fn send_clanker_tokens(
     token: Arc<ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>>,
     pool_config: &PoolConfig,
     minter: Address,
-    swap_account: &Address,
+    token_source: &Address,
     mint_event: &Mint,
 ) -> Result<()> {
     // send needed clanker tokens for mint
-    let transfer = if pool_config.clanker_is_token0 {
-        if mint_event.amount0 == U256::ZERO {
-            return Ok(());
-        }
-        token
-            .transfer(minter, mint_event.amount0)
-            .from(swap_account.clone())
-            .send()
-            .await?
-            .get_receipt()
-            .await?
+    let amount = if pool_config.clanker_is_token0 {
+        mint_event.amount0
     } else {
-        if mint_event.amount1 == U256::ZERO {
-            return Ok(());
-        }
-        token
-            .transfer(minter, mint_event.amount1)
-            .from(swap_account.clone())
-            .send()
-            .await?
-            .get_receipt()
-            .await?
+        mint_event.amount1
     };
+    if amount == U256::ZERO {
+        return Ok(());
+    }
+
+    let balance_before = token.balanceOf(minter).call().await?._0;
+    let transfer = token
+        .transfer(minter, amount)
+        .from(*token_source)
+        .send()
+        .await?
+        .get_receipt()
+        .await?;
 
     if !transfer.inner.status() {
         error!("Failed to transfer clanker tokens");
         bail!("Failed to transfer clanker tokens");
     }
 
+    let received = token
+        .balanceOf(minter)
+        .call()
+        .await?
+        ._0
+        .saturating_sub(balance_before);
+    warn_on_transfer_shortfall(amount, received);
+
     Ok(())
 }
 
+// the replay assumes transfers move exact amounts, fee-on-transfer or
+// rebasing tokens break every amount downstream of this
+fn warn_on_transfer_shortfall(amount: U256, received: U256) {
+    if received != amount {
+        warn!(
+            "Clanker token transfer sent {} but the minter received {}, fee-on-transfer or \
+             rebasing tokens are not modeled and position amounts will be off",
+            amount, received
+        );
+    }
+}
+
 pub(crate) async fn pool_mint(
     position_manager: Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
     pool_config: &PoolConfig,
     minter: Address,
     mint_event: &Mint,
     increase_liquidity_event: &IncreaseLiquidityWithParams,
-) -> Result<U256> {
+    amount_tolerance: U256,
+    verify: bool,
+) -> Result<(U256, u64)> {
     let mint_params = MintParams {
         token0: pool_config.token0,
         token1: pool_config.token1,
@@ -78,12 +95,66 @@ pub(crate) async fn pool_mint(
         tickUpper: mint_event.tickUpper,
         amount0Desired: increase_liquidity_event.amount_0_desired,
         amount1Desired: increase_liquidity_event.amount_1_desired,
+        amount0Min: increase_liquidity_event.amount_0_min,
+        amount1Min: increase_liquidity_event.amount_1_min,
+        recipient: minter,
+        deadline: U256::from_str("8737924142").unwrap(),
+    };
+
+    let (token_id, receipt) = send_mint(position_manager, minter, mint_params).await?;
+
+    if verify {
+        check_mint_outcomes(mint_event, &receipt, amount_tolerance).await?;
+    }
+
+    Ok((token_id, receipt.gas_used))
+}
+
+// mints a new position with the mint event's deposited amounts as the desired
+// amounts but in a different tick range, without checking the outcome against
+// the event. returns the token id, the replayed mint, and the gas used
+pub(crate) async fn pool_mint_with_ticks(
+    position_manager: Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
+    pool_config: &PoolConfig,
+    minter: Address,
+    mint_event: &Mint,
+    lower_tick: I24,
+    upper_tick: I24,
+) -> Result<(U256, Mint, u64)> {
+    if lower_tick >= upper_tick {
+        bail!(
+            "Lower tick {} must be below upper tick {}",
+            lower_tick,
+            upper_tick
+        );
+    }
+    let mint_params = MintParams {
+        token0: pool_config.token0,
+        token1: pool_config.token1,
+        fee: pool_config.fee,
+        tickLower: lower_tick,
+        tickUpper: upper_tick,
+        amount0Desired: mint_event.amount0,
+        amount1Desired: mint_event.amount1,
         amount0Min: U256::ZERO,
         amount1Min: U256::ZERO,
         recipient: minter,
         deadline: U256::from_str("8737924142").unwrap(),
     };
 
+    let (token_id, receipt) = send_mint(position_manager, minter, mint_params).await?;
+    let mint_log = decode_mint_log(&receipt)?;
+
+    Ok((token_id, mint_log, receipt.gas_used))
+}
+
+// mints through the position manager with retries, returning the new token id
+// and the receipt without checking the outcome
+async fn send_mint(
+    position_manager: Arc<INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>>,
+    minter: Address,
+    mint_params: MintParams,
+) -> Result<(U256, TransactionReceipt)> {
     // simulate mint first to grab result
     let token_id = position_manager
         .mint(mint_params.clone())
@@ -122,9 +193,7 @@ pub(crate) async fn pool_mint(
     let receipt =
         receipt.ok_or_else(|| eyre::eyre!("Failed to mint after {} attempts", max_attempts))?;
 
-    check_mint_outcomes(mint_event, &receipt).await?;
-
-    Ok(token_id)
+    Ok((token_id, receipt))
 }
 
 pub(crate) async fn pool_increase_liquidity(
@@ -133,13 +202,15 @@ pub(crate) async fn pool_increase_liquidity(
     mint_event: &Mint,
     increase_liquidity_event: &IncreaseLiquidityWithParams,
     token_id: U256,
-) -> Result<()> {
+    amount_tolerance: U256,
+    verify: bool,
+) -> Result<u64> {
     let increase_liquidity_params = IncreaseLiquidityParams {
         tokenId: token_id,
         amount0Desired: increase_liquidity_event.amount_0_desired,
         amount1Desired: increase_liquidity_event.amount_1_desired,
-        amount0Min: U256::ZERO,
-        amount1Min: U256::ZERO,
+        amount0Min: increase_liquidity_event.amount_0_min,
+        amount1Min: increase_liquidity_event.amount_1_min,
         deadline: U256::from_str("8737924142").unwrap(),
     };
 
@@ -148,7 +219,7 @@ pub(crate) async fn pool_increase_liquidity(
     let mut receipt = None;
 
     while attempts < max_attempts {
-        match position_manager
+        if let Ok(r) = position_manager
             .increaseLiquidity(increase_liquidity_params.clone())
             .from(minter)
             .send()
@@ -156,13 +227,10 @@ pub(crate) async fn pool_increase_liquidity(
             .get_receipt()
             .await
         {
-            Ok(r) => {
-                if r.inner.status() {
-                    receipt = Some(r);
-                    break;
-                }
+            if r.inner.status() {
+                receipt = Some(r);
+                break;
             }
-            Err(_) => {}
         }
         attempts += 1;
     }
@@ -175,31 +243,30 @@ pub(crate) async fn pool_increase_liquidity(
     })?;
 
     // check increase liquidity outcomes
-    check_mint_outcomes(mint_event, &receipt).await?;
+    if verify {
+        check_mint_outcomes(mint_event, &receipt, amount_tolerance).await?;
+    }
 
-    Ok(())
+    Ok(receipt.gas_used)
 }
 
-async fn check_mint_outcomes(mint_event: &Mint, receipt: &TransactionReceipt) -> Result<()> {
-    let mint_log = receipt
-        .inner
-        .logs()
-        .iter()
-        .find(|log| log.inner.topics()[0] == Mint::SIGNATURE_HASH)
-        .and_then(|log| {
-            let log = AbiLog::new(
-                log.address(),
-                log.topics().to_vec(),
-                log.data().data.clone(),
-            )
-            .unwrap_or_default();
-            Mint::decode_log(&log, true).ok()
-        })
-        .context("Failed to decode mint event")?;
+async fn check_mint_outcomes(
+    mint_event: &Mint,
+    receipt: &TransactionReceipt,
+    amount_tolerance: U256,
+) -> Result<()> {
+    let mint_log = decode_mint_log(receipt)?;
+    compare_mint_outcomes(mint_event, &mint_log, amount_tolerance)
+}
 
-    // check mint outcomes
-    if mint_log.amount0 != mint_event.amount0
-        || mint_log.amount1 != mint_event.amount1
+// ticks and liquidity have to match exactly, the token amounts may differ by
+// `amount_tolerance` wei since the position manager rounds when converting
+// the desired amounts to liquidity
+fn compare_mint_outcomes(mint_event: &Mint, mint_log: &Mint, amount_tolerance: U256) -> Result<()> {
+    let amount0_diff = mint_log.amount0.abs_diff(mint_event.amount0);
+    let amount1_diff = mint_log.amount1.abs_diff(mint_event.amount1);
+    if amount0_diff > amount_tolerance
+        || amount1_diff > amount_tolerance
         || mint_log.tickLower != mint_event.tickLower
         || mint_log.tickUpper != mint_event.tickUpper
         || mint_log.amount != mint_event.amount
@@ -217,8 +284,94 @@ async fn check_mint_outcomes(mint_event: &Mint, receipt: &TransactionReceipt) ->
         error!("log   tickUpper: {:?}", mint_log.tickUpper);
         error!("event amount: {:?}", mint_event.amount);
         error!("log   amount: {:?}", mint_log.amount);
-        bail!("Mismatch in mint outcomes");
+        bail!(FeeAnalyzerError::MintMismatch(
+            "Mismatch in mint outcomes".to_string()
+        ));
+    }
+
+    if amount0_diff > U256::ZERO || amount1_diff > U256::ZERO {
+        warn!(
+            "Mint amounts ({}, {}) differ from the event's ({}, {}), accepted within the {} wei \
+             tolerance",
+            mint_log.amount0,
+            mint_log.amount1,
+            mint_event.amount0,
+            mint_event.amount1,
+            amount_tolerance
+        );
     }
 
     Ok(())
 }
+
+fn decode_mint_log(receipt: &TransactionReceipt) -> Result<Mint> {
+    receipt
+        .inner
+        .logs()
+        .iter()
+        .find(|log| log.inner.topics()[0] == Mint::SIGNATURE_HASH)
+        .and_then(|log| {
+            let log = AbiLog::new(
+                log.address(),
+                log.topics().to_vec(),
+                log.data().data.clone(),
+            )
+            .unwrap_or_default();
+            Mint::decode_log(&log, true).ok()
+        })
+        .map(|log| log.data)
+        .context("Failed to decode mint event")
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    fn mint(amount0: u64, amount1: u64, amount: u128) -> Mint {
+        Mint {
+            sender: Address::repeat_byte(0x03),
+            owner: Address::repeat_byte(0x03),
+            tickLower: I24::try_from(-600).unwrap(),
+            tickUpper: I24::try_from(600).unwrap(),
+            amount,
+            amount0: U256::from(amount0),
+            amount1: U256::from(amount1),
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn a_transfer_short_of_the_amount_sent_warns() {
+        warn_on_transfer_shortfall(U256::from(1_000), U256::from(1_000));
+        assert!(!logs_contain("fee-on-transfer"));
+
+        // a 1% transfer fee
+        warn_on_transfer_shortfall(U256::from(1_000), U256::from(990));
+        assert!(logs_contain(
+            "Clanker token transfer sent 1000 but the minter received 990"
+        ));
+    }
+
+    #[test]
+    fn mint_amounts_a_wei_off_pass_within_the_tolerance() {
+        let event = mint(500, 300, 1_000);
+        let one = U256::from(1);
+
+        compare_mint_outcomes(&event, &mint(500, 300, 1_000), U256::ZERO).unwrap();
+        compare_mint_outcomes(&event, &mint(499, 301, 1_000), one).unwrap();
+        for (log, tolerance) in [
+            (mint(499, 300, 1_000), U256::ZERO),
+            (mint(498, 300, 1_000), one),
+            // liquidity has to match exactly
+            (mint(500, 300, 1_001), one),
+        ] {
+            let error = compare_mint_outcomes(&event, &log, tolerance).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<FeeAnalyzerError>(),
+                Some(FeeAnalyzerError::MintMismatch(_))
+            ));
+        }
+    }
+}
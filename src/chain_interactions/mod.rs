@@ -1,60 +1,832 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use alloy::{
     node_bindings::{Anvil, AnvilInstance},
-    primitives::{aliases::U24, ruint::aliases::U256, Address, Log as AbiLog},
-    providers::{ext::AnvilApi, layers::AnvilProvider, ProviderBuilder},
+    primitives::{
+        aliases::{I24, U24},
+        keccak256,
+        ruint::aliases::U256,
+        Address, Bytes, Log as AbiLog, B256,
+    },
+    providers::{ext::AnvilApi, layers::AnvilProvider, Provider, ProviderBuilder, RootProvider},
+    rpc::{
+        client::RpcClient,
+        types::{BlockNumberOrTag, BlockTransactionsKind, TransactionRequest},
+    },
     sol_types::SolEvent,
-    transports::http::reqwest::Url,
+    transports::http::{
+        reqwest::{self, Url},
+        Http,
+    },
 };
-use eyre::{bail, ContextCompat, Result};
-use tracing::{error, info};
+use eyre::{bail, eyre, Context, ContextCompat, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
 
 use crate::abi::{
     ClankerToken::{self, ClankerTokenInstance},
+    INonfungiblePositionManager::INonfungiblePositionManagerInstance,
+    IQuoterV2::IQuoterV2Instance,
+    ISwapRouter::ISwapRouterInstance,
     IUniswapV3Factory::{IUniswapV3FactoryInstance, PoolCreated},
     UniswapV3Pool::{self, Initialize, UniswapV3PoolInstance},
-    Weth::WethInstance,
+    Weth,
 };
+use crate::error::FeeAnalyzerError;
 
+pub(crate) mod backend;
 pub(crate) mod burn;
 pub(crate) mod collect;
+pub(crate) mod fee_growth;
 pub(crate) mod mint;
+pub(crate) mod pool_state;
 pub(crate) mod swap;
+pub(crate) mod valuation;
+pub(crate) mod wrapped_native;
 
 use crate::fee_analyzer::{ArcAnvilHttpProvider, HttpClient};
+use wrapped_native::WrappedNative;
+
+// the bundled clanker token bytecode hard codes its decimals
+pub(crate) const STANDIN_TOKEN_DECIMALS: u8 = 18;
+
+// constructor arguments for the stand-in clanker token, and the original
+// token's decimals to check the stand-in against
+#[derive(Debug, Clone)]
+pub struct ClankerTokenParams {
+    pub name: String,
+    pub symbol: String,
+    // the original token's. the stand-in always has STANDIN_TOKEN_DECIMALS
+    pub decimals: u8,
+    // in raw units, needs to cover every mint and swap replayed
+    pub max_supply: U256,
+    pub fid: U256,
+    pub image: String,
+    pub cast_hash: String,
+}
+
+impl Default for ClankerTokenParams {
+    fn default() -> Self {
+        Self {
+            name: String::from("ClankerToken"),
+            symbol: String::from("CLNK"),
+            decimals: STANDIN_TOKEN_DECIMALS,
+            max_supply: U256::from_str("100000000000000000000000000000").unwrap(),
+            fid: U256::from(1),
+            image: String::from("0x1234567890"),
+            cast_hash: String::from("0x1234567890"),
+        }
+    }
+}
+
+impl ClankerTokenParams {
+    // the stand-in can't take the original's decimals, so a token with other
+    // decimals needs its real code on the fork instead
+    pub(crate) fn check_standin_decimals(&self) -> Result<()> {
+        if self.decimals != STANDIN_TOKEN_DECIMALS {
+            bail!(FeeAnalyzerError::PoolSetup(format!(
+                "CLANKER_TOKEN_DECIMALS is {} but the stand-in token always has {}, set \
+                 CLONE_TOKEN_CODE=true or POOL_SETUP_MODE=attach_existing to use the real token",
+                self.decimals, STANDIN_TOKEN_DECIMALS
+            )));
+        }
+        Ok(())
+    }
+}
+
+// which replayed actions are compared against their source events. skipping
+// the comparisons saves decoding receipts on large inputs that are trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerificationLevel {
+    // check swaps, mints, and burns
+    #[default]
+    Strict,
+    // only check swaps, which drive the pool price every other action sees
+    SwapsOnly,
+    // no checks, the replay only fails if a transaction reverts
+    None,
+}
 
+impl VerificationLevel {
+    pub(crate) fn checks_swaps(self) -> bool {
+        self != VerificationLevel::None
+    }
+
+    pub(crate) fn checks_liquidity(self) -> bool {
+        self == VerificationLevel::Strict
+    }
+}
+
+impl FromStr for VerificationLevel {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "strict" => Ok(VerificationLevel::Strict),
+            "swaps" | "swaps_only" => Ok(VerificationLevel::SwapsOnly),
+            "none" => Ok(VerificationLevel::None),
+            _ => bail!(
+                "Unknown verification level: {}, expected strict, swaps, or none",
+                s
+            ),
+        }
+    }
+}
+
+// how the simulation accounts approve the swap router and position manager
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ApprovalMode {
+    // approve type(uint256).max once at setup
+    #[default]
+    Max,
+    // approve exactly what each swap, mint, or simulated sale spends right
+    // before it, for flows that never leave an allowance standing
+    Exact,
+}
+
+impl FromStr for ApprovalMode {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "max" => Ok(ApprovalMode::Max),
+            "exact" => Ok(ApprovalMode::Exact),
+            _ => bail!("Unknown approval mode: {}, expected max or exact", s),
+        }
+    }
+}
+
+// what to do with a swap whose exact input quote reverts, usually because
+// the quote amount runs past the simulated pool's liquidity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SwapFailurePolicy {
+    // simulate the exact input swap and send it if it reproduces the event,
+    // otherwise send exact output
+    #[default]
+    Fallback,
+    // leave the swap out of the replay and record a skipped_swap warning
+    Skip,
+    // end the run with the quoter's error
+    Fail,
+}
+
+impl FromStr for SwapFailurePolicy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "fallback" => Ok(SwapFailurePolicy::Fallback),
+            "skip" => Ok(SwapFailurePolicy::Skip),
+            "fail" => Ok(SwapFailurePolicy::Fail),
+            _ => bail!(
+                "Unknown swap failure policy: {}, expected fallback, skip, or fail",
+                s
+            ),
+        }
+    }
+}
+
+// how the replay gets its pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoolSetupMode {
+    // deploy a stand-in token and a new pool, initialized like the original
+    #[default]
+    DeployStandin,
+    // use the original pool and tokens, which have to exist on the fork
+    AttachExisting,
+}
+
+impl FromStr for PoolSetupMode {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "deploy_standin" => Ok(PoolSetupMode::DeployStandin),
+            "attach_existing" => Ok(PoolSetupMode::AttachExisting),
+            _ => bail!(
+                "Unknown pool setup mode: {}, expected deploy_standin or attach_existing",
+                s
+            ),
+        }
+    }
+}
+
+// anvil's block gas limit unless configured, well above any chain's so large
+// multicall mints don't revert for running out of block gas
+pub(crate) const DEFAULT_ANVIL_GAS_LIMIT: u64 = 1_000_000_000;
+
+// anvil settings for forking l2 archive nodes, whose blocks don't look like
+// mainnet's. the presets are a starting point, each field can be overridden
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct L2Profile {
+    // chain id anvil reports instead of the upstream's
+    pub chain_id: Option<u64>,
+    // run with a zero base fee, l2 sequencers price gas their own way and the
+    // forked base fee can make replayed txs underpriced
+    pub disable_base_fee: bool,
+    // a fixed base fee in wei instead, takes precedence over disable_base_fee
+    pub base_fee: Option<u64>,
+    // anvil's block gas limit, none for anvil's own default
+    pub gas_limit: Option<u64>,
+    // anvil's op stack mode, for deposit txs and the l1 fee in receipts
+    pub optimism: bool,
+    // the chain's block interval, for turning block ranges into time
+    pub block_time_ms: Option<u64>,
+}
+
+impl L2Profile {
+    pub(crate) fn anvil_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(chain_id) = self.chain_id {
+            args.extend(["--chain-id".to_string(), chain_id.to_string()]);
+        }
+        match self.base_fee {
+            Some(base_fee) => args.extend(["--base-fee".to_string(), base_fee.to_string()]),
+            None if self.disable_base_fee => {
+                args.extend(["--base-fee".to_string(), "0".to_string()])
+            }
+            None => {}
+        }
+        if let Some(gas_limit) = self.gas_limit {
+            args.extend(["--gas-limit".to_string(), gas_limit.to_string()]);
+        }
+        if self.optimism {
+            args.push("--optimism".to_string());
+        }
+        args
+    }
+
+    // the time the chain took for the block range, none without a block time
+    pub(crate) fn elapsed(&self, from_block: u64, to_block: u64) -> Option<Duration> {
+        self.block_time_ms.map(|block_time_ms| {
+            Duration::from_millis(to_block.saturating_sub(from_block) * block_time_ms)
+        })
+    }
+}
+
+// replays succeed more often without a base fee and with room for large
+// transactions, the presets start from this
+impl Default for L2Profile {
+    fn default() -> Self {
+        L2Profile {
+            chain_id: None,
+            disable_base_fee: true,
+            base_fee: None,
+            gas_limit: Some(DEFAULT_ANVIL_GAS_LIMIT),
+            optimism: false,
+            block_time_ms: None,
+        }
+    }
+}
+
+impl FromStr for L2Profile {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(L2Profile::default()),
+            "mainnet" => Ok(L2Profile {
+                block_time_ms: Some(12_000),
+                ..L2Profile::default()
+            }),
+            // base and the other op stack chains
+            "optimism" | "op" | "base" => Ok(L2Profile {
+                optimism: true,
+                block_time_ms: Some(2_000),
+                ..L2Profile::default()
+            }),
+            // anvil has no arbitrum mode, gas is made free instead so the
+            // replay doesn't trip over arbitrum's l1 gas accounting
+            "arbitrum" => Ok(L2Profile {
+                disable_base_fee: true,
+                block_time_ms: Some(250),
+                ..L2Profile::default()
+            }),
+            _ => bail!(
+                "Unknown l2 profile: {}, expected none, mainnet, optimism, base, or arbitrum",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct PoolConfig {
     token0: Address,
     token1: Address,
     fee: U24,
     clanker_is_token0: bool,
+    // only used to format amounts for display
+    token_decimals: u8,
+    weth_decimals: u8,
+}
+
+impl PoolConfig {
+    // `token` in the original pool's place next to weth, in the same order
+    fn new(
+        pool_create_event: &PoolCreated,
+        weth: Address,
+        token: Address,
+        token_decimals: u8,
+        weth_decimals: u8,
+    ) -> Self {
+        let clanker_is_token0 = pool_create_event.token0 != weth;
+        let (token0, token1) = if clanker_is_token0 {
+            (token, weth)
+        } else {
+            (weth, token)
+        };
+        PoolConfig {
+            token0,
+            token1,
+            fee: pool_create_event.fee,
+            clanker_is_token0,
+            token_decimals,
+            weth_decimals,
+        }
+    }
+
+    pub(crate) fn clanker_is_token0(&self) -> bool {
+        self.clanker_is_token0
+    }
+
+    pub(crate) fn tokens(&self) -> (Address, Address) {
+        (self.token0, self.token1)
+    }
+
+    pub(crate) fn fee(&self) -> U24 {
+        self.fee
+    }
 }
 
 pub(crate) async fn anvil_connection(
     http_url: String,
     fork_block: u64,
+    l2_profile: &L2Profile,
+    rpc_timeout: Option<Duration>,
 ) -> Result<(Arc<AnvilInstance>, ArcAnvilHttpProvider)> {
     info!("Connecting to anvil...");
-    let parsed_url: Url = http_url.parse()?;
+    let parsed_url: Url = http_url
+        .parse()
+        .with_context(|| format!("HTTP_URL {:?} is not a valid url", http_url))?;
     info!("Parsed URL: {:?}", parsed_url);
     info!("Fork block: {:?}", fork_block);
 
+    // the upstream rpc can be flaky or rate limited, retry the fork with backoff
+    let max_attempts = 4;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match spawn_forked_anvil(&parsed_url, fork_block, l2_profile, rpc_timeout).await {
+            Ok(connection) => return Ok(connection),
+            Err(e) if attempts < max_attempts => {
+                let backoff = Duration::from_secs(1 << attempts);
+                warn!(
+                    "Failed to fork {} at block {}, retrying in {:?}: {:?}",
+                    parsed_url, fork_block, backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => {
+                return Err(e.wrap_err(format!(
+                    "Failed to fork {} at block {} after {} attempts",
+                    parsed_url, fork_block, max_attempts
+                )))
+            }
+        }
+    }
+}
+
+// starts a non-forking anvil at the cached fork block's number and time and
+// loads the cached state into it, so a cached run doesn't touch the upstream
+// rpc
+pub(crate) async fn anvil_from_fork_cache(
+    cache_path: &Path,
+    fork_block: u64,
+    l2_profile: &L2Profile,
+    rpc_timeout: Option<Duration>,
+) -> Result<(Arc<AnvilInstance>, ArcAnvilHttpProvider)> {
+    info!("Loading fork state from {:?}", cache_path);
+    let cache = read_fork_cache(cache_path)?;
+    if cache.fork_block != fork_block {
+        bail!(
+            "Fork cache {:?} was taken at block {}, the run forks at block {}",
+            cache_path,
+            cache.fork_block,
+            fork_block
+        );
+    }
+
+    let mut anvil = Anvil::new().args([
+        "--number".to_string(),
+        cache.fork_block.to_string(),
+        "--timestamp".to_string(),
+        cache.timestamp.to_string(),
+    ]);
+    // the upstream's chain id, unless the profile overrides it
+    if l2_profile.chain_id.is_none() {
+        anvil = anvil.chain_id(cache.chain_id);
+    }
+    let anvil = Arc::new(
+        anvil
+            .args(l2_profile.anvil_args())
+            .try_spawn()
+            .map_err(|e| eyre!("Anvil failed to start: {}", e))?,
+    );
+    info!("Anvil endpoint: {:?}", anvil.endpoint());
+    let anvil_provider = anvil_http_provider(anvil.clone(), rpc_timeout)?;
+
+    if !anvil_provider.anvil_load_state(cache.state).await? {
+        bail!("Anvil rejected the fork cache {:?}", cache_path);
+    }
+
+    // the replay's blocks are stamped from the fork block's number and time
+    let head = fork_head(&anvil_provider).await?;
+    if head != (cache.fork_block, cache.timestamp) {
+        bail!(
+            "Fork cache {:?} loaded at (block, timestamp) {:?}, expected {:?}",
+            cache_path,
+            head,
+            (cache.fork_block, cache.timestamp)
+        );
+    }
+
+    Ok((anvil, anvil_provider))
+}
+
+// http provider for the anvil instance. with a timeout, a request anvil doesn't
+// answer in time (say, stuck on the upstream rpc) errors instead of hanging
+fn anvil_http_provider(
+    anvil: Arc<AnvilInstance>,
+    rpc_timeout: Option<Duration>,
+) -> Result<ArcAnvilHttpProvider> {
+    let provider = http_provider(anvil.endpoint_url(), rpc_timeout)?;
+    Ok(Arc::new(AnvilProvider::new(provider, anvil)))
+}
+
+// each request errors once it takes longer than `rpc_timeout`
+fn http_provider(url: Url, rpc_timeout: Option<Duration>) -> Result<RootProvider<HttpClient>> {
+    let mut client = reqwest::Client::builder();
+    if let Some(rpc_timeout) = rpc_timeout {
+        client = client.timeout(rpc_timeout);
+    }
+    let http = Http::with_client(client.build()?, url);
+    Ok(ProviderBuilder::new().on_client(RpcClient::new(http, true)))
+}
+
+// whether the error comes from a request that ran past the rpc timeout
+pub(crate) fn is_rpc_timeout(e: &eyre::Report) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_timeout)
+    })
+}
+
+// anvil's state dump taken right after forking, with the block, timestamp,
+// and chain id the fork started at
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ForkCache {
+    pub fork_block: u64,
+    pub timestamp: u64,
+    pub chain_id: u64,
+    pub state: Bytes,
+}
+
+// the low storage slots pinned for each cached contract, where solidity puts
+// the plain state variables like owners and counters
+const PINNED_STORAGE_SLOTS: u64 = 32;
+
+// state a fork reads from the upstream rpc isn't part of anvil's state dump
+// until it's written locally. copies the accounts' code, balance, nonce, and
+// low storage slots, and the storage the calls touch, into the fork's own
+// state so the dump holds them
+pub(crate) async fn pin_upstream_state(
+    anvil_provider: &ArcAnvilHttpProvider,
+    addresses: &[Address],
+    calls: &[TransactionRequest],
+) -> Result<()> {
+    let mut slots: BTreeMap<Address, BTreeSet<U256>> = addresses
+        .iter()
+        .map(|address| {
+            (
+                *address,
+                (0..PINNED_STORAGE_SLOTS).map(U256::from).collect(),
+            )
+        })
+        .collect();
+    for call in calls {
+        let access_list = anvil_provider.create_access_list(call).await?.access_list;
+        for item in access_list.0 {
+            slots.entry(item.address).or_default().extend(
+                item.storage_keys
+                    .into_iter()
+                    .map(|key| U256::from_be_bytes(key.0)),
+            );
+        }
+    }
+
+    for (address, slots) in slots {
+        let code = anvil_provider.get_code_at(address).await?;
+        let balance = anvil_provider.get_balance(address).await?;
+        let nonce = anvil_provider.get_transaction_count(address).await?;
+        anvil_provider.anvil_set_code(address, code).await?;
+        anvil_provider.anvil_set_balance(address, balance).await?;
+        anvil_provider
+            .anvil_set_nonce(address, U256::from(nonce))
+            .await?;
+        for slot in slots {
+            let value = anvil_provider.get_storage_at(address, slot).await?;
+            anvil_provider
+                .anvil_set_storage_at(address, slot, B256::from(value))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+// dumps the fork's own state, see pin_upstream_state for getting what it read
+// from the upstream rpc into it. called right after forking, before the
+// replay changes anything
+pub(crate) async fn write_fork_cache(
+    anvil_provider: ArcAnvilHttpProvider,
+    cache_path: &Path,
+) -> Result<()> {
+    let (fork_block, timestamp) = fork_head(&anvil_provider).await?;
+    let cache = ForkCache {
+        fork_block,
+        timestamp,
+        chain_id: anvil_provider.get_chain_id().await?,
+        state: anvil_provider.anvil_dump_state().await?,
+    };
+    save_fork_cache(cache_path, &cache)?;
+    info!(
+        "Wrote fork state at block {} to {:?}",
+        fork_block, cache_path
+    );
+    Ok(())
+}
+
+fn save_fork_cache(cache_path: &Path, cache: &ForkCache) -> Result<()> {
+    if let Some(parent) = cache_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(cache_path, serde_json::to_string(cache)?)
+        .with_context(|| format!("Failed to write fork cache {:?}", cache_path))
+}
+
+pub(crate) fn read_fork_cache(cache_path: &Path) -> Result<ForkCache> {
+    let cache = std::fs::read_to_string(cache_path)
+        .with_context(|| format!("Failed to read fork cache {:?}", cache_path))?;
+    serde_json::from_str(&cache).with_context(|| {
+        format!(
+            "Fork cache {:?} didn't parse, caches from before the fork block and chain id \
+             were recorded have to be deleted and rewritten",
+            cache_path
+        )
+    })
+}
+
+// (number, timestamp) of the fork's latest block
+async fn fork_head(anvil_provider: &ArcAnvilHttpProvider) -> Result<(u64, u64)> {
+    let head = anvil_provider
+        .get_block_by_number(BlockNumberOrTag::Latest, BlockTransactionsKind::Hashes)
+        .await?
+        .context("Anvil has no latest block")?;
+    Ok((head.header.number, head.header.timestamp))
+}
+
+// spawns anvil and checks the fork actually took by comparing its head
+// against the fork block, so a bad upstream fails here instead of later
+async fn spawn_forked_anvil(
+    fork_url: &Url,
+    fork_block: u64,
+    l2_profile: &L2Profile,
+    rpc_timeout: Option<Duration>,
+) -> Result<(Arc<AnvilInstance>, ArcAnvilHttpProvider)> {
     let anvil = Arc::new(
         Anvil::new()
-            .fork(parsed_url)
+            .fork(fork_url.clone())
             .fork_block_number(fork_block)
-            .spawn(),
+            .args(l2_profile.anvil_args())
+            .try_spawn()
+            .map_err(|e| eyre!("Anvil failed to start: {}", e))?,
     );
 
     info!("Anvil endpoint: {:?}", anvil.endpoint());
 
-    let provider = ProviderBuilder::new().on_http(anvil.endpoint().parse().unwrap());
-    let anvil_provider = Arc::new(AnvilProvider::new(provider, anvil.clone()));
+    let anvil_provider = anvil_http_provider(anvil.clone(), rpc_timeout)?;
+
+    let block_number = anvil_provider
+        .get_block_number()
+        .await
+        .context("Anvil fork is not responding")?;
+    if block_number != fork_block {
+        bail!(
+            "Anvil fork is at block {}, expected fork block {}",
+            block_number,
+            fork_block
+        );
+    }
 
     Ok((anvil, anvil_provider))
 }
 
+// createPool reverts for fee tiers the factory doesn't know about. the original
+// pool's factory may have had extra tiers enabled, so enable the tier as the
+// factory owner with the tick spacing from the PoolCreated event.
+async fn ensure_fee_tier_enabled(
+    anvil_provider: ArcAnvilHttpProvider,
+    uniswap_factory: &IUniswapV3FactoryInstance<HttpClient, ArcAnvilHttpProvider>,
+    fee: U24,
+    tick_spacing: I24,
+) -> Result<()> {
+    let enabled_tick_spacing = uniswap_factory
+        .feeAmountTickSpacing(fee)
+        .call()
+        .await?
+        .tickSpacing;
+    if enabled_tick_spacing == tick_spacing {
+        return Ok(());
+    }
+    if enabled_tick_spacing != I24::ZERO {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Factory has fee tier {} with tick spacing {}, the original pool used {}",
+            fee, enabled_tick_spacing, tick_spacing
+        )));
+    }
+
+    let owner = uniswap_factory.owner().call().await?._0;
+    warn!(
+        "Fee tier {} is not enabled on the factory, enabling it with tick spacing {} as owner {:?}",
+        fee, tick_spacing, owner
+    );
+    anvil_provider
+        .anvil_set_balance(owner, U256::from(10).pow(U256::from(18)))
+        .await?;
+    anvil_provider.anvil_impersonate_account(owner).await?;
+    let receipt = uniswap_factory
+        .enableFeeAmount(fee, tick_spacing)
+        .from(owner)
+        .send()
+        .await?
+        .get_receipt()
+        .await?;
+    anvil_provider
+        .anvil_stop_impersonating_account(owner)
+        .await?;
+
+    if !receipt.inner.status() {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Failed to enable fee tier {} on the factory, create the pool on a factory with it enabled",
+            fee
+        )));
+    }
+    Ok(())
+}
+
+// calls a cheap view on each configured contract to fail fast on a wrong or
+// swapped address, and checks the periphery contracts agree on the factory
+// and weth. every problem found is reported with the env var to fix.
+pub(crate) async fn validate_contracts(
+    anvil_provider: ArcAnvilHttpProvider,
+    factory: &IUniswapV3FactoryInstance<HttpClient, ArcAnvilHttpProvider>,
+    position_manager: &INonfungiblePositionManagerInstance<HttpClient, ArcAnvilHttpProvider>,
+    swap_router: &ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>,
+    quoter: &IQuoterV2Instance<HttpClient, ArcAnvilHttpProvider>,
+    weth: Address,
+) -> Result<()> {
+    let mut problems = Vec::new();
+
+    if anvil_provider.get_code_at(weth).await?.is_empty() {
+        problems.push(format!("WETH_ADDRESS {} has no code", weth));
+    }
+    if factory
+        .feeAmountTickSpacing(U24::from(3000))
+        .call()
+        .await
+        .is_err()
+    {
+        problems.push(format!(
+            "UNISWAP_V3_FACTORY_ADDRESS {} does not respond to feeAmountTickSpacing",
+            factory.address()
+        ));
+    }
+
+    // (env var, address, factory() and WETH9()) for each periphery contract
+    let both = |factory: Result<_, _>, weth: Result<_, _>| factory.ok().zip(weth.ok());
+    let periphery = [
+        (
+            "UNISWAP_V3_POSITION_MANAGER_ADDRESS",
+            *position_manager.address(),
+            both(
+                position_manager.factory().call().await.map(|r| r._0),
+                position_manager.WETH9().call().await.map(|r| r._0),
+            ),
+        ),
+        (
+            "UNISWAP_V3_SWAP_ROUTER_ADDRESS",
+            *swap_router.address(),
+            both(
+                swap_router.factory().call().await.map(|r| r._0),
+                swap_router.WETH9().call().await.map(|r| r._0),
+            ),
+        ),
+        (
+            "UNISWAP_V3_QUOTER_ADDRESS",
+            *quoter.address(),
+            both(
+                quoter.factory().call().await.map(|r| r._0),
+                quoter.WETH9().call().await.map(|r| r._0),
+            ),
+        ),
+    ];
+    problems.extend(periphery_problems(periphery, *factory.address(), weth));
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("{}", problem);
+        }
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Contract validation failed: {}",
+            problems.join("; ")
+        )));
+    }
+    Ok(())
+}
+
+// checks each periphery contract's factory() and WETH9() answers, none when a
+// call failed, against the configured addresses
+fn periphery_problems(
+    periphery: impl IntoIterator<Item = (&'static str, Address, Option<(Address, Address)>)>,
+    factory: Address,
+    weth: Address,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (env_var, address, answers) in periphery {
+        let Some((periphery_factory, periphery_weth)) = answers else {
+            problems.push(format!(
+                "{} {} does not respond to factory()/WETH9()",
+                env_var, address
+            ));
+            continue;
+        };
+        if periphery_factory != factory {
+            problems.push(format!(
+                "{} {} uses factory {}, but UNISWAP_V3_FACTORY_ADDRESS is {}",
+                env_var, address, periphery_factory, factory
+            ));
+        }
+        if periphery_weth != weth {
+            problems.push(format!(
+                "{} {} uses WETH {}, but WETH_ADDRESS is {}",
+                env_var, address, periphery_weth, weth
+            ));
+        }
+    }
+    problems
+}
+
+// the pool's non-weth token. a pool without the configured weth on either
+// side means the weth address is wrong for the chain, and guessing would
+// mislabel every amount
+fn clanker_token_address(pool_create_event: &PoolCreated, weth: Address) -> Result<Address> {
+    if pool_create_event.token0 == weth {
+        Ok(pool_create_event.token1)
+    } else if pool_create_event.token1 == weth {
+        Ok(pool_create_event.token0)
+    } else {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Pool {} pairs {} and {}, neither is the configured weth {}, check WETH_ADDRESS",
+            pool_create_event.pool, pool_create_event.token0, pool_create_event.token1, weth
+        )));
+    }
+}
+
+// the pool's initialize reverts on a price outside TickMath's range, this
+// names the offending value before anything is deployed
+fn check_initialization_price(initialization_event: &Initialize) -> Result<()> {
+    let sqrt_price_x96 = initialization_event.sqrtPriceX96;
+    if !(valuation::MIN_SQRT_RATIO..valuation::MAX_SQRT_RATIO).contains(&sqrt_price_x96) {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Initialization price out of range: sqrtPriceX96 {} is outside [{}, {})",
+            sqrt_price_x96,
+            valuation::MIN_SQRT_RATIO,
+            valuation::MAX_SQRT_RATIO
+        )));
+    }
+    Ok(())
+}
+
+// `clone_token_from` is the upstream rpc and the pool creation block to copy
+// the real token's code from, otherwise a ClankerToken stands in for it
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn deploy_and_initialize_pool(
     anvil_provider: ArcAnvilHttpProvider,
     uniswap_factory: Arc<IUniswapV3FactoryInstance<HttpClient, ArcAnvilHttpProvider>>,
@@ -62,43 +834,66 @@ pub(crate) async fn deploy_and_initialize_pool(
     weth: Address,
     pool_create_event: PoolCreated,
     initialization_event: Initialize,
+    token_params: &ClankerTokenParams,
+    clone_token_from: Option<(&str, u64)>,
 ) -> Result<(
     Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
     Arc<ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>>,
     PoolConfig,
 )> {
+    check_initialization_price(&initialization_event)?;
+
     // deploy clanker token with token0/token1 in same order
-    let clanker_token_address = if pool_create_event.token0 == weth {
-        pool_create_event.token1
-    } else {
-        pool_create_event.token0
+    let clanker_token_address = clanker_token_address(&pool_create_event, weth)?;
+    let clanker_token = match clone_token_from {
+        Some((http_url, block)) => {
+            clone_token_code(
+                anvil_provider.clone(),
+                http_url,
+                block,
+                clanker_token_address,
+                deployer,
+                token_params.max_supply,
+            )
+            .await?
+        }
+        None => {
+            deploy_clanker_token(
+                anvil_provider.clone(),
+                deployer,
+                deployer,
+                clanker_token_address,
+                weth,
+                token_params,
+            )
+            .await?
+        }
     };
-    let clanker_token = deploy_clanker_token(
-        anvil_provider.clone(),
-        deployer,
-        deployer,
-        clanker_token_address,
+
+    let token_decimals = clanker_token.decimals().call().await?._0;
+    let weth_decimals = Weth::new(weth, anvil_provider.clone())
+        .decimals()
+        .call()
+        .await?
+        .decimals;
+
+    // sort tokens
+    let pool_config = PoolConfig::new(
+        &pool_create_event,
         weth,
+        *clanker_token.address(),
+        token_decimals,
+        weth_decimals,
+    );
+
+    ensure_fee_tier_enabled(
+        anvil_provider.clone(),
+        &uniswap_factory,
+        pool_create_event.fee,
+        pool_create_event.tickSpacing,
     )
     .await?;
 
-    // sort tokens
-    let pool_config = if pool_create_event.token0 == weth {
-        PoolConfig {
-            token0: weth,
-            token1: clanker_token.address().clone(),
-            fee: pool_create_event.fee,
-            clanker_is_token0: false,
-        }
-    } else {
-        PoolConfig {
-            token0: clanker_token.address().clone(),
-            token1: weth,
-            fee: pool_create_event.fee,
-            clanker_is_token0: true,
-        }
-    };
-
     // deploy pool
     let receipt = uniswap_factory
         .createPool(pool_config.token0, pool_config.token1, pool_config.fee)
@@ -109,7 +904,9 @@ pub(crate) async fn deploy_and_initialize_pool(
         .await?;
 
     if !receipt.inner.status() {
-        bail!("Failed to create pool");
+        bail!(FeeAnalyzerError::PoolSetup(
+            "Failed to create pool".to_string()
+        ));
     }
 
     // fetch pool
@@ -132,7 +929,9 @@ pub(crate) async fn deploy_and_initialize_pool(
         .await?;
 
     if !receipt.inner.status() {
-        bail!("Failed to initialize pool");
+        bail!(FeeAnalyzerError::PoolSetup(
+            "Failed to initialize pool".to_string()
+        ));
     }
 
     // ensure initialization log matches event we're copying
@@ -156,13 +955,144 @@ pub(crate) async fn deploy_and_initialize_pool(
         error!("Mismatch in initialization outcomes");
         error!("initialization event: {:?}", initialization_event);
         error!("initialization log: {:?}", initialization_log);
-        bail!("Mismatch in initialization outcomes");
+        bail!(FeeAnalyzerError::PoolSetup(
+            "Mismatch in initialization outcomes".to_string()
+        ));
     }
 
     info!("pool initialized");
     Ok((pool, clanker_token, pool_config))
 }
 
+// attaches to the original pool and its real tokens on the fork instead of
+// deploying stand-ins, for forks taken after the pool was created. the pool
+// has to exist, match the created event, and be initialized. `holder` is
+// credited the token's total supply so the replay can fund its accounts the
+// same way as with a stand-in
+pub(crate) async fn attach_existing_pool(
+    anvil_provider: ArcAnvilHttpProvider,
+    weth: Address,
+    pool_create_event: PoolCreated,
+    holder: Address,
+) -> Result<(
+    Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
+    Arc<ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>>,
+    PoolConfig,
+)> {
+    let token_address = clanker_token_address(&pool_create_event, weth)?;
+    let pool_address = pool_create_event.pool;
+    if anvil_provider.get_code_at(pool_address).await?.is_empty() {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Pool {} doesn't exist on the fork, attaching needs a fork block after its creation",
+            pool_address
+        )));
+    }
+    let pool = Arc::new(UniswapV3Pool::new(pool_address, anvil_provider.clone()));
+    let on_fork = (
+        pool.token0().call().await?._0,
+        pool.token1().call().await?._0,
+        pool.fee().call().await?._0,
+    );
+    if on_fork
+        != (
+            pool_create_event.token0,
+            pool_create_event.token1,
+            pool_create_event.fee,
+        )
+    {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Pool {} on the fork is ({}, {}, fee {}), the created event says ({}, {}, fee {})",
+            pool_address,
+            on_fork.0,
+            on_fork.1,
+            on_fork.2,
+            pool_create_event.token0,
+            pool_create_event.token1,
+            pool_create_event.fee
+        )));
+    }
+    let sqrt_price_x96 = pool.slot0().call().await?.sqrtPriceX96;
+    if sqrt_price_x96.is_zero() {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Pool {} isn't initialized on the fork",
+            pool_address
+        )));
+    }
+
+    let token = Arc::new(ClankerToken::new(token_address, anvil_provider.clone()));
+    let total_supply = token.totalSupply().call().await?._0;
+    let balance_slot = set_token_balance(&anvil_provider, &token, holder, total_supply).await?;
+    let pool_config =
+        attached_pool_config(&anvil_provider, &token, weth, &pool_create_event).await?;
+
+    info!(
+        "Attached to pool {} at sqrtPriceX96 {} (token {}, balances mapping at slot {})",
+        pool_address, sqrt_price_x96, token_address, balance_slot
+    );
+    Ok((pool, token, pool_config))
+}
+
+// attaches to the simulated pool an earlier run left on its kept-alive fork,
+// for resuming that run. no balances are set, the earlier run's accounts
+// still hold theirs
+pub(crate) async fn attach_resumed_pool(
+    anvil_provider: ArcAnvilHttpProvider,
+    weth: Address,
+    pool_address: Address,
+) -> Result<(
+    Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
+    Arc<ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>>,
+    PoolConfig,
+)> {
+    if anvil_provider.get_code_at(pool_address).await?.is_empty() {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Resumed pool {} doesn't exist on the fork, resuming needs the earlier run's \
+             kept-alive anvil",
+            pool_address
+        )));
+    }
+    let pool = Arc::new(UniswapV3Pool::new(pool_address, anvil_provider.clone()));
+    let pool_create_event = PoolCreated {
+        token0: pool.token0().call().await?._0,
+        token1: pool.token1().call().await?._0,
+        fee: pool.fee().call().await?._0,
+        tickSpacing: pool.tickSpacing().call().await?._0,
+        pool: pool_address,
+    };
+    let token_address = clanker_token_address(&pool_create_event, weth)?;
+    let token = Arc::new(ClankerToken::new(token_address, anvil_provider.clone()));
+    let pool_config =
+        attached_pool_config(&anvil_provider, &token, weth, &pool_create_event).await?;
+
+    info!(
+        "Resuming on pool {} (token {})",
+        pool_address, token_address
+    );
+    Ok((pool, token, pool_config))
+}
+
+// the pool config of a pool already on the fork, reading both tokens' decimals
+async fn attached_pool_config(
+    anvil_provider: &ArcAnvilHttpProvider,
+    token: &ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>,
+    weth: Address,
+    pool_create_event: &PoolCreated,
+) -> Result<PoolConfig> {
+    let token_decimals = token.decimals().call().await?._0;
+    let weth_decimals = Weth::new(weth, anvil_provider.clone())
+        .decimals()
+        .call()
+        .await?
+        .decimals;
+    Ok(PoolConfig::new(
+        pool_create_event,
+        weth,
+        *token.address(),
+        token_decimals,
+        weth_decimals,
+    ))
+}
+
 // Prepares an account for use in simulation by:
 // 1. Registering the account for impersonation
 // 2. Giving the account the native token
@@ -172,9 +1102,10 @@ pub(crate) async fn initialize_simulation_account(
     anvil_provider: ArcAnvilHttpProvider,
     address: Address,
     token: Option<Arc<ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>>>,
-    weth: Arc<WethInstance<HttpClient, ArcAnvilHttpProvider>>,
+    weth: &impl WrappedNative,
     swap_router: &Address,
     position_manager: &Address,
+    approval_mode: ApprovalMode,
 ) -> Result<()> {
     let initial_eth_amount = U256::from_str("1000000000000000000000000000000000000").unwrap();
     info!("Setting balance for account: {:?}", address);
@@ -185,17 +1116,20 @@ pub(crate) async fn initialize_simulation_account(
     anvil_provider.anvil_impersonate_account(address).await?;
     info!("Impersonated account: {:?}", address);
     // convert half of the native token to WETH
-    weth.deposit()
-        .from(address)
-        .value(initial_eth_amount.checked_div(U256::from(2)).unwrap())
-        .send()
-        .await?
-        .watch()
-        .await?;
+    weth.deposit(
+        address,
+        initial_eth_amount.checked_div(U256::from(2)).unwrap(),
+    )
+    .await?;
 
     // depositing?
     info!("Depositing WETH");
 
+    // exact approvals are made right before each spend instead
+    if approval_mode == ApprovalMode::Exact {
+        return Ok(());
+    }
+
     if let Some(token) = token {
         approve_token(token, position_manager, swap_router, address).await?;
     }
@@ -206,6 +1140,135 @@ pub(crate) async fn initialize_simulation_account(
     Ok(())
 }
 
+// transfers `account` whatever it's missing of `amount` clanker tokens from
+// `holder`, used when the supply isn't moved to the swap account up front
+pub(crate) async fn top_up_clanker_tokens(
+    token: &ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>,
+    holder: Address,
+    account: Address,
+    amount: U256,
+) -> Result<U256> {
+    let balance = token.balanceOf(account).call().await?._0;
+    if balance >= amount {
+        return Ok(U256::ZERO);
+    }
+    let receipt = token
+        .transfer(account, amount - balance)
+        .from(holder)
+        .send()
+        .await?
+        .get_receipt()
+        .await?;
+    if !receipt.inner.status() {
+        bail!(
+            "Failed to top up {} with {} clanker tokens",
+            account,
+            amount - balance
+        );
+    }
+    Ok(amount - balance)
+}
+
+// makes sure `account` holds at least `amount` of weth, or of native eth when
+// it pays in eth, minting the shortfall with anvil and wrapping it as needed.
+// returns how much was added
+pub(crate) async fn top_up_weth(
+    anvil_provider: &ArcAnvilHttpProvider,
+    weth: &Weth::WethInstance<HttpClient, ArcAnvilHttpProvider>,
+    account: Address,
+    amount: U256,
+    native: bool,
+) -> Result<U256> {
+    let eth_balance = anvil_provider.get_balance(account).await?;
+    let balance = if native {
+        eth_balance
+    } else {
+        weth.balanceOf(account).call().await?._0
+    };
+    if balance >= amount {
+        return Ok(U256::ZERO);
+    }
+    let shortfall = amount - balance;
+    anvil_provider
+        .anvil_set_balance(account, eth_balance.saturating_add(shortfall))
+        .await?;
+    if !native {
+        WrappedNative::deposit(weth, account, shortfall).await?;
+    }
+    Ok(shortfall)
+}
+
+// grows the pool oracle to hold `target` observations, enough for a twap over
+// that many seconds since at most one is written per block. each new slot is
+// initialized with a storage write, so it grows in steps that fit a block
+pub(crate) async fn grow_observation_cardinality(
+    pool: &UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>,
+    from: Address,
+    target: u16,
+) -> Result<()> {
+    const STEP: u16 = 1_000;
+    let mut cardinality_next = pool.slot0().call().await?.observationCardinalityNext;
+    while cardinality_next < target {
+        cardinality_next = cardinality_next.saturating_add(STEP).min(target);
+        let receipt = pool
+            .increaseObservationCardinalityNext(cardinality_next)
+            .from(from)
+            .send()
+            .await?
+            .get_receipt()
+            .await?;
+        if !receipt.inner.status() {
+            bail!(FeeAnalyzerError::PoolSetup(format!(
+                "Failed to grow the pool oracle to {} observations",
+                cardinality_next
+            )));
+        }
+    }
+    Ok(())
+}
+
+// sets `owner`'s allowance of `token` for `spender` to exactly `amount`. a
+// leftover allowance is reset to zero first since some tokens (USDT style)
+// revert when changing one nonzero allowance to another
+pub(crate) async fn approve_exact(
+    provider: ArcAnvilHttpProvider,
+    token: Address,
+    owner: Address,
+    spender: Address,
+    amount: U256,
+) -> Result<()> {
+    // only the erc20 approve and allowance functions are used
+    let token = Weth::new(token, provider);
+    let allowance = token.allowance(owner, spender).call().await?._0;
+    if allowance == amount {
+        return Ok(());
+    }
+
+    let mut values = Vec::new();
+    if allowance != U256::ZERO && amount != U256::ZERO {
+        values.push(U256::ZERO);
+    }
+    values.push(amount);
+    for value in values {
+        let receipt = token
+            .approve(spender, value)
+            .from(owner)
+            .send()
+            .await?
+            .get_receipt()
+            .await?;
+        if !receipt.inner.status() {
+            bail!(
+                "Failed to approve {} of {} for {}",
+                value,
+                token.address(),
+                spender
+            );
+        }
+    }
+    Ok(())
+}
+
 pub(crate) async fn approve_token(
     token: Arc<ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>>,
     position_manager: &Address,
@@ -215,25 +1278,29 @@ pub(crate) async fn approve_token(
     let max_approval = U256::MAX;
 
     let receipt = token
-        .approve(swap_router.clone(), max_approval)
+        .approve(*swap_router, max_approval)
         .from(approver)
         .send()
         .await?
         .get_receipt()
         .await?;
     if !receipt.inner.status() {
-        bail!("Failed to approve token for swap router");
+        bail!(FeeAnalyzerError::PoolSetup(
+            "Failed to approve token for swap router".to_string()
+        ));
     }
 
     let receipt = token
-        .approve(position_manager.clone(), max_approval)
+        .approve(*position_manager, max_approval)
         .from(approver)
         .send()
         .await?
         .get_receipt()
         .await?;
     if !receipt.inner.status() {
-        bail!("Failed to approve token for position manager");
+        bail!(FeeAnalyzerError::PoolSetup(
+            "Failed to approve token for position manager".to_string()
+        ));
     }
     Ok(())
 }
@@ -241,35 +1308,120 @@ pub(crate) async fn approve_token(
 // TODO combine with approve_token if have time to figure
 // out generics over the Sol types
 pub(crate) async fn approve_weth(
-    weth: Arc<WethInstance<HttpClient, ArcAnvilHttpProvider>>,
+    weth: &impl WrappedNative,
     position_manager: &Address,
     swap_router: &Address,
     approver: Address,
 ) -> Result<()> {
     let max_approval = U256::MAX;
 
-    let receipt = weth
-        .approve(swap_router.clone(), max_approval)
-        .from(approver)
-        .send()
-        .await?
-        .get_receipt()
-        .await?;
-    if !receipt.inner.status() {
-        bail!("Failed to approve weth for swap router");
+    weth.approve(approver, *swap_router, max_approval)
+        .await
+        .context("Failed to approve weth for swap router")?;
+    weth.approve(approver, *position_manager, max_approval)
+        .await
+        .context("Failed to approve weth for position manager")?;
+    Ok(())
+}
+
+// storage slots tried when looking for a token's balances mapping and total
+// supply, solidity lays out an erc20's own variables in the first few
+const CLONED_TOKEN_PROBE_SLOTS: u64 = 32;
+
+// credits `holder` with `amount` of the token by finding its balances mapping,
+// writing to candidate slots and reading them back with balanceOf. returns the
+// mapping's slot
+async fn set_token_balance(
+    anvil_provider: &ArcAnvilHttpProvider,
+    token: &ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>,
+    holder: Address,
+    amount: U256,
+) -> Result<u64> {
+    let token_address = *token.address();
+    for slot in 0..CLONED_TOKEN_PROBE_SLOTS {
+        let key = U256::from_be_bytes(
+            keccak256([holder.into_word(), B256::from(U256::from(slot))].concat()).0,
+        );
+        let previous = anvil_provider.get_storage_at(token_address, key).await?;
+        anvil_provider
+            .anvil_set_storage_at(token_address, key, B256::from(amount))
+            .await?;
+        if token.balanceOf(holder).call().await?._0 == amount {
+            return Ok(slot);
+        }
+        anvil_provider
+            .anvil_set_storage_at(token_address, key, B256::from(previous))
+            .await?;
     }
+    bail!(FeeAnalyzerError::PoolSetup(format!(
+        "No balances mapping found in the first {} slots of token {}",
+        CLONED_TOKEN_PROBE_SLOTS, token_address
+    )))
+}
 
-    let receipt = weth
-        .approve(position_manager.clone(), max_approval)
-        .from(approver)
-        .send()
-        .await?
-        .get_receipt()
-        .await?;
-    if !receipt.inner.status() {
-        bail!("Failed to approve weth for position manager");
+// puts the real token's runtime code, as of `block` on the upstream rpc, at
+// its own address on the fork, for tokens whose address matters (hooks,
+// allowlists). the code comes without the token's storage, so `holder` is
+// credited `supply` by finding the balances mapping and the total supply slot
+// through writing to candidate slots and reading them back with the erc20
+// getters. constructor set storage (owner, trading flags, ...) stays empty
+pub(crate) async fn clone_token_code(
+    anvil_provider: ArcAnvilHttpProvider,
+    http_url: &str,
+    block: u64,
+    token_address: Address,
+    holder: Address,
+    supply: U256,
+) -> Result<Arc<ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>>> {
+    let upstream = ProviderBuilder::new().on_http(http_url.parse()?);
+    let code = upstream
+        .get_code_at(token_address)
+        .number(block)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to read token {} code at block {}",
+                token_address, block
+            )
+        })?;
+    if code.is_empty() {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Token {} has no code at block {}, can't clone it",
+            token_address, block
+        )));
     }
-    Ok(())
+    anvil_provider.anvil_set_code(token_address, code).await?;
+
+    let token = ClankerToken::new(token_address, anvil_provider.clone());
+    let balance_slot = set_token_balance(&anvil_provider, &token, holder, supply).await?;
+
+    let mut supply_slot = None;
+    for slot in (0..CLONED_TOKEN_PROBE_SLOTS).filter(|slot| *slot != balance_slot) {
+        let slot = U256::from(slot);
+        let previous = anvil_provider.get_storage_at(token_address, slot).await?;
+        anvil_provider
+            .anvil_set_storage_at(token_address, slot, B256::from(supply))
+            .await?;
+        if token.totalSupply().call().await?._0 == supply {
+            supply_slot = Some(slot);
+            break;
+        }
+        anvil_provider
+            .anvil_set_storage_at(token_address, slot, B256::from(previous))
+            .await?;
+    }
+    let supply_slot = supply_slot.with_context(|| {
+        format!(
+            "No total supply found in the first {} slots of cloned token {}",
+            CLONED_TOKEN_PROBE_SLOTS, token_address
+        )
+    })?;
+
+    info!(
+        "Cloned token {} from block {} (balances mapping at slot {}, total supply at slot {})",
+        token_address, block, balance_slot, supply_slot
+    );
+    Ok(Arc::new(token))
 }
 
 pub(crate) async fn deploy_clanker_token(
@@ -278,29 +1430,43 @@ pub(crate) async fn deploy_clanker_token(
     fid_deployer: Address,
     target_address: Address,
     weth: Address,
+    token_params: &ClankerTokenParams,
 ) -> Result<Arc<ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>>> {
-    let mut contract: ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>;
-    loop {
-        contract = ClankerToken::new(
-            ClankerToken::deploy_builder(
-                anvil_provider.clone(),
-                String::from("ClankerToken"),
-                String::from("CLNK"),
-                U256::from_str("100000000000000000000000000000".into()).unwrap(),
-                fid_deployer,
-                U256::from(1),
-                String::from("0x1234567890"),
-                String::from("0x1234567890"),
-            )
-            .from(deployer)
-            .deploy()
-            .await?,
+    // the create address only depends on the deployer's nonce, so find the
+    // first nonce whose address sorts on the same side of weth as the original
+    // token and jump the deployer to it instead of deploying until one matches
+    let nonce = first_sorting_nonce(
+        deployer,
+        anvil_provider.get_transaction_count(deployer).await?,
+        weth,
+        target_address,
+    );
+    anvil_provider
+        .anvil_set_nonce(deployer, U256::from(nonce))
+        .await?;
+
+    let contract = ClankerToken::new(
+        ClankerToken::deploy_builder(
             anvil_provider.clone(),
+            token_params.name.clone(),
+            token_params.symbol.clone(),
+            token_params.max_supply,
+            fid_deployer,
+            token_params.fid,
+            token_params.image.clone(),
+            token_params.cast_hash.clone(),
+        )
+        .from(deployer)
+        .deploy()
+        .await?,
+        anvil_provider.clone(),
+    );
+    if contract.address() != &deployer.create(nonce) {
+        bail!(
+            "Clanker token deployed at {:?}, expected {:?}",
+            contract.address(),
+            deployer.create(nonce)
         );
-
-        if (weth < target_address) == (&weth < contract.address()) {
-            break;
-        }
     }
     info!(
         "New clanker token address: {:?}, original token address: {:?}",
@@ -309,3 +1475,554 @@ pub(crate) async fn deploy_clanker_token(
     );
     Ok(Arc::new(contract))
 }
+
+/// First nonce from `start` at which `deployer` creates a contract on the same
+/// side of `weth` as `target`.
+fn first_sorting_nonce(deployer: Address, start: u64, weth: Address, target: Address) -> u64 {
+    let mut nonce = start;
+    while (weth < target) != (weth < deployer.create(nonce)) {
+        nonce += 1;
+    }
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Instant};
+
+    use alloy::primitives::U160;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn a_call_past_the_rpc_timeout_errors() {
+        // takes the connection but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let provider = http_provider(url, Some(Duration::from_millis(200))).unwrap();
+
+        let start = Instant::now();
+        let error = eyre::Report::from(provider.get_block_number().await.unwrap_err());
+        assert!(is_rpc_timeout(&error), "{:?}", error);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn standin_only_takes_its_own_decimals() {
+        ClankerTokenParams::default()
+            .check_standin_decimals()
+            .unwrap();
+        let six_decimals = ClankerTokenParams {
+            decimals: 6,
+            ..ClankerTokenParams::default()
+        };
+        let error = six_decimals.check_standin_decimals().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FeeAnalyzerError>(),
+            Some(FeeAnalyzerError::PoolSetup(_))
+        ));
+    }
+
+    #[test]
+    fn periphery_on_another_weth_is_reported() {
+        let factory = Address::repeat_byte(0xfa);
+        let weth = Address::repeat_byte(0x42);
+        let other_weth = Address::repeat_byte(0x43);
+        let router = Address::repeat_byte(0x0b);
+        let problems = periphery_problems(
+            [
+                (
+                    "UNISWAP_V3_POSITION_MANAGER_ADDRESS",
+                    Address::repeat_byte(0x0a),
+                    Some((factory, weth)),
+                ),
+                (
+                    "UNISWAP_V3_SWAP_ROUTER_ADDRESS",
+                    router,
+                    Some((factory, other_weth)),
+                ),
+                (
+                    "UNISWAP_V3_QUOTER_ADDRESS",
+                    Address::repeat_byte(0x0c),
+                    None,
+                ),
+            ],
+            factory,
+            weth,
+        );
+        assert_eq!(problems.len(), 2);
+        assert_eq!(
+            problems[0],
+            format!(
+                "UNISWAP_V3_SWAP_ROUTER_ADDRESS {} uses WETH {}, but WETH_ADDRESS is {}",
+                router, other_weth, weth
+            )
+        );
+        assert!(problems[1].starts_with("UNISWAP_V3_QUOTER_ADDRESS"));
+    }
+
+    #[test]
+    fn verification_levels_drop_their_checks() {
+        let checks = |level: &str| {
+            let level = level.parse::<VerificationLevel>().unwrap();
+            (level.checks_swaps(), level.checks_liquidity())
+        };
+        assert_eq!(checks("strict"), (true, true));
+        assert_eq!(checks("swaps_only"), (true, false));
+        assert_eq!(checks("none"), (false, false));
+    }
+
+    // deploys the stand-in on a fresh local anvil, no fork needed
+    #[tokio::test]
+    #[ignore = "spawns anvil"]
+    async fn standin_decimals_match_the_bundled_bytecode() {
+        let anvil = Arc::new(Anvil::new().try_spawn().unwrap());
+        let deployer = anvil.addresses()[0];
+        let anvil_provider = anvil_http_provider(anvil, None).unwrap();
+        let weth = Address::repeat_byte(0x80);
+
+        for target in [Address::repeat_byte(0x01), Address::repeat_byte(0xff)] {
+            let token = deploy_clanker_token(
+                anvil_provider.clone(),
+                deployer,
+                deployer,
+                target,
+                weth,
+                &ClankerTokenParams::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                token.decimals().call().await.unwrap()._0,
+                STANDIN_TOKEN_DECIMALS
+            );
+            // on the original's side of weth
+            assert_eq!(*token.address() < weth, target < weth);
+        }
+    }
+
+    // a bare token with only approve and allowance, where approve reverts
+    // when changing one nonzero allowance to another, like USDT's
+    const STRICT_APPROVE_TOKEN_CODE: &str = concat!(
+        // dispatch on approve(address,uint256) and allowance(address,address)
+        "60003560e01c8063095ea7b314601d5763dd62ed3e14604a57600080fd",
+        // approve: revert unless the new or the current allowance is zero
+        "5b336000526004356020526040600020602435801582541517603d57600080fd",
+        "5b9055600160005260206000f3",
+        // allowance
+        "5b60043560005260243560205260406000205460005260206000f3",
+    );
+
+    #[tokio::test]
+    #[ignore = "spawns anvil"]
+    async fn exact_approvals_reset_a_strict_token_first() {
+        let anvil = Arc::new(Anvil::new().try_spawn().unwrap());
+        let owner = anvil.addresses()[0];
+        let spender = Address::repeat_byte(0x55);
+        let anvil_provider = anvil_http_provider(anvil, None).unwrap();
+        let token = Address::repeat_byte(0x77);
+        anvil_provider
+            .anvil_set_code(token, STRICT_APPROVE_TOKEN_CODE.parse().unwrap())
+            .await
+            .unwrap();
+        let weth = Weth::new(token, anvil_provider.clone());
+
+        approve_exact(
+            anvil_provider.clone(),
+            token,
+            owner,
+            spender,
+            U256::from(100),
+        )
+        .await
+        .unwrap();
+        // the token really does refuse a direct change
+        assert!(weth
+            .approve(spender, U256::from(50))
+            .from(owner)
+            .send()
+            .await
+            .is_err());
+
+        for amount in [50, 0, 7] {
+            approve_exact(
+                anvil_provider.clone(),
+                token,
+                owner,
+                spender,
+                U256::from(amount),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                weth.allowance(owner, spender).call().await.unwrap()._0,
+                U256::from(amount)
+            );
+        }
+    }
+
+    #[test]
+    fn the_sorting_nonce_is_the_first_on_the_target_side() {
+        let deployer = Address::repeat_byte(0x42);
+        // weth sits exactly on nonce 0's address, so nonce 0 sorts below any
+        // target above weth and has to be skipped
+        let weth = deployer.create(0);
+        let target = Address::repeat_byte(0xff);
+        let nonce = first_sorting_nonce(deployer, 0, weth, target);
+        assert!(nonce > 0);
+        assert!(deployer.create(nonce) > weth);
+        assert!((1..nonce).all(|skipped| deployer.create(skipped) < weth));
+        // an already matching start is kept
+        assert_eq!(first_sorting_nonce(deployer, nonce, weth, target), nonce);
+    }
+
+    #[tokio::test]
+    #[ignore = "spawns anvil"]
+    async fn the_standin_token_is_deployed_once() {
+        let anvil = Arc::new(Anvil::new().try_spawn().unwrap());
+        let deployer = anvil.addresses()[0];
+        let anvil_provider = anvil_http_provider(anvil, None).unwrap();
+        let start = anvil_provider
+            .get_transaction_count(deployer)
+            .await
+            .unwrap();
+        let weth = deployer.create(start);
+        let target = Address::repeat_byte(0xff);
+        let expected_nonce = first_sorting_nonce(deployer, start, weth, target);
+        assert!(expected_nonce > start);
+        let block_before = anvil_provider.get_block_number().await.unwrap();
+
+        let token = deploy_clanker_token(
+            anvil_provider.clone(),
+            deployer,
+            deployer,
+            target,
+            weth,
+            &ClankerTokenParams::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(token.address(), &deployer.create(expected_nonce));
+        assert!(token.address() > &weth);
+        // anvil mines one block per transaction, so a single deploy moves
+        // both the chain and the deployer on by exactly one
+        assert_eq!(
+            anvil_provider.get_block_number().await.unwrap(),
+            block_before + 1
+        );
+        assert_eq!(
+            anvil_provider
+                .get_transaction_count(deployer)
+                .await
+                .unwrap(),
+            expected_nonce + 1
+        );
+    }
+
+    #[test]
+    fn fork_cache_file_round_trips() {
+        let path = std::env::temp_dir().join(format!(
+            "fork_cache_round_trip_{}/state.json",
+            std::process::id()
+        ));
+        let cache = ForkCache {
+            fork_block: 25_000_000,
+            timestamp: 1_736_000_000,
+            chain_id: 8453,
+            state: Bytes::from(vec![0x1f, 0x8b, 0x08, 0x00]),
+        };
+        save_fork_cache(&path, &cache).unwrap();
+        assert_eq!(read_fork_cache(&path).unwrap(), cache);
+
+        // the plain hex dumps from before the fork block was recorded
+        std::fs::write(&path, "0x1f8b0800").unwrap();
+        let error = read_fork_cache(&path).unwrap_err();
+        assert!(format!("{}", error).contains("have to be deleted"));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_malformed_fork_url_names_the_setting() {
+        let Err(error) =
+            anvil_connection("not a url".to_string(), 1, &L2Profile::default(), None).await
+        else {
+            panic!("connected to a malformed url");
+        };
+        assert_eq!(
+            error.to_string(),
+            "HTTP_URL \"not a url\" is not a valid url"
+        );
+        // the parser's reason is kept underneath
+        assert!(error.chain().count() > 1);
+    }
+
+    // dumps a bare anvil with a marker contract on it as if it were a fork of
+    // a later block, then loads the dump without any upstream rpc
+    #[tokio::test]
+    #[ignore = "spawns anvil"]
+    async fn fork_cache_loads_at_the_fork_block_and_time() {
+        let l2_profile = L2Profile::default();
+        let path = std::env::temp_dir().join(format!(
+            "fork_cache_anvil_{}/state.json",
+            std::process::id()
+        ));
+        let marker = Address::repeat_byte(0x42);
+        let code = Bytes::from(vec![0x60, 0x00, 0x60, 0x00, 0xf3]);
+
+        let anvil = Arc::new(Anvil::new().try_spawn().unwrap());
+        let anvil_provider = anvil_http_provider(anvil, None).unwrap();
+        anvil_provider
+            .anvil_set_code(marker, code.clone())
+            .await
+            .unwrap();
+        let cache = ForkCache {
+            fork_block: 25_000_000,
+            timestamp: 1_736_000_000,
+            chain_id: 8453,
+            state: anvil_provider.anvil_dump_state().await.unwrap(),
+        };
+        save_fork_cache(&path, &cache).unwrap();
+
+        let (_anvil, anvil_provider) = anvil_from_fork_cache(&path, 25_000_000, &l2_profile, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            fork_head(&anvil_provider).await.unwrap(),
+            (25_000_000, 1_736_000_000)
+        );
+        assert_eq!(anvil_provider.get_chain_id().await.unwrap(), 8453);
+        assert_eq!(anvil_provider.get_code_at(marker).await.unwrap(), code);
+        assert!(anvil_from_fork_cache(&path, 25_000_001, &l2_profile, None)
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    // clones a plain erc20 deployed on the upstream chain, named by
+    // CLONE_TOKEN_TEST_ADDRESS, at BLOCK_FORK_NUMBER
+    #[tokio::test]
+    #[ignore = "forks HTTP_URL with anvil"]
+    async fn a_cloned_token_keeps_its_address_and_credits_the_holder() {
+        let (Ok(http_url), Ok(fork_block), Ok(token_address)) = (
+            std::env::var("HTTP_URL"),
+            std::env::var("BLOCK_FORK_NUMBER"),
+            std::env::var("CLONE_TOKEN_TEST_ADDRESS"),
+        ) else {
+            eprintln!(
+                "HTTP_URL, BLOCK_FORK_NUMBER or CLONE_TOKEN_TEST_ADDRESS is not set, skipping \
+                 the clone test"
+            );
+            return;
+        };
+        let fork_block: u64 = fork_block.parse().unwrap();
+        let token_address: Address = token_address.parse().unwrap();
+        let holder = Address::repeat_byte(0x33);
+        let supply = U256::from(1_000_000_000_000_000_000_000u128);
+
+        let (_anvil, anvil_provider) =
+            anvil_connection(http_url.clone(), fork_block, &L2Profile::default(), None)
+                .await
+                .unwrap();
+        let token = clone_token_code(
+            anvil_provider.clone(),
+            &http_url,
+            fork_block,
+            token_address,
+            holder,
+            supply,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*token.address(), token_address);
+        assert!(!anvil_provider
+            .get_code_at(token_address)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(token.balanceOf(holder).call().await.unwrap()._0, supply);
+        assert_eq!(token.totalSupply().call().await.unwrap()._0, supply);
+        // and the balance moves like a token's
+        let recipient = Address::repeat_byte(0x44);
+        anvil_provider
+            .anvil_impersonate_account(holder)
+            .await
+            .unwrap();
+        anvil_provider
+            .anvil_set_balance(holder, U256::from(10u128.pow(18)))
+            .await
+            .unwrap();
+        token
+            .transfer(recipient, U256::from(5))
+            .from(holder)
+            .send()
+            .await
+            .unwrap()
+            .get_receipt()
+            .await
+            .unwrap();
+        assert_eq!(
+            token.balanceOf(recipient).call().await.unwrap()._0,
+            U256::from(5)
+        );
+    }
+
+    // attaches to the pool named by ATTACH_TEST_POOL_ADDRESS, which has to be
+    // a weth pool that exists at BLOCK_FORK_NUMBER
+    #[tokio::test]
+    #[ignore = "forks HTTP_URL with anvil"]
+    async fn attaches_to_a_pool_that_exists_on_the_fork() {
+        let (Ok(http_url), Ok(fork_block), Ok(weth), Ok(pool_address)) = (
+            std::env::var("HTTP_URL"),
+            std::env::var("BLOCK_FORK_NUMBER"),
+            std::env::var("WETH_ADDRESS"),
+            std::env::var("ATTACH_TEST_POOL_ADDRESS"),
+        ) else {
+            eprintln!(
+                "HTTP_URL, BLOCK_FORK_NUMBER, WETH_ADDRESS or ATTACH_TEST_POOL_ADDRESS is not \
+                 set, skipping the attach test"
+            );
+            return;
+        };
+        let weth: Address = weth.parse().unwrap();
+        let pool_address: Address = pool_address.parse().unwrap();
+        let holder = Address::repeat_byte(0x33);
+        let (_anvil, anvil_provider) = anvil_connection(
+            http_url,
+            fork_block.parse().unwrap(),
+            &L2Profile::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // the created event as the pool on the fork was made
+        let existing = UniswapV3Pool::new(pool_address, anvil_provider.clone());
+        let pool_created = PoolCreated {
+            token0: existing.token0().call().await.unwrap()._0,
+            token1: existing.token1().call().await.unwrap()._0,
+            fee: existing.fee().call().await.unwrap()._0,
+            tickSpacing: existing.tickSpacing().call().await.unwrap()._0,
+            pool: pool_address,
+        };
+        let (pool, token, pool_config) =
+            attach_existing_pool(anvil_provider.clone(), weth, pool_created.clone(), holder)
+                .await
+                .unwrap();
+        assert_eq!(*pool.address(), pool_address);
+        assert_eq!(
+            *token.address(),
+            clanker_token_address(&pool_created, weth).unwrap()
+        );
+        assert_eq!(
+            pool_config.tokens(),
+            (pool_created.token0, pool_created.token1)
+        );
+        assert_eq!(
+            token.balanceOf(holder).call().await.unwrap()._0,
+            token.totalSupply().call().await.unwrap()._0
+        );
+
+        // an event that doesn't match the pool on the fork is refused
+        let other_fee = PoolCreated {
+            fee: pool_created.fee + U24::from(1),
+            ..pool_created.clone()
+        };
+        let Err(error) =
+            attach_existing_pool(anvil_provider.clone(), weth, other_fee, holder).await
+        else {
+            panic!("attached with the wrong fee");
+        };
+        assert!(matches!(
+            error.downcast_ref::<FeeAnalyzerError>(),
+            Some(FeeAnalyzerError::PoolSetup(_))
+        ));
+    }
+
+    #[test]
+    fn the_clanker_token_is_the_side_that_isnt_weth() {
+        let weth = Address::repeat_byte(0x80);
+        let token = Address::repeat_byte(0x01);
+        let pool_created = |token0, token1| PoolCreated {
+            token0,
+            token1,
+            fee: U24::from(10_000),
+            tickSpacing: I24::try_from(200).unwrap(),
+            pool: Address::repeat_byte(0x55),
+        };
+
+        assert_eq!(
+            clanker_token_address(&pool_created(token, weth), weth).unwrap(),
+            token
+        );
+        assert_eq!(
+            clanker_token_address(&pool_created(weth, token), weth).unwrap(),
+            token
+        );
+        let error = clanker_token_address(&pool_created(token, Address::repeat_byte(0x02)), weth)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FeeAnalyzerError>(),
+            Some(FeeAnalyzerError::PoolSetup(_))
+        ));
+        assert!(error.to_string().contains("check WETH_ADDRESS"));
+    }
+
+    #[test]
+    fn initialization_prices_stay_in_tick_math_range() {
+        let initialize = |sqrt_price_x96| Initialize {
+            sqrtPriceX96: sqrt_price_x96,
+            tick: I24::ZERO,
+        };
+
+        check_initialization_price(&initialize(valuation::MIN_SQRT_RATIO)).unwrap();
+        check_initialization_price(&initialize(valuation::MAX_SQRT_RATIO - U160::from(1))).unwrap();
+        for out_of_range in [
+            valuation::MIN_SQRT_RATIO - U160::from(1),
+            valuation::MAX_SQRT_RATIO,
+        ] {
+            let error = check_initialization_price(&initialize(out_of_range)).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<FeeAnalyzerError>(),
+                Some(FeeAnalyzerError::PoolSetup(_))
+            ));
+            assert!(error.to_string().contains(&out_of_range.to_string()));
+        }
+    }
+
+    #[test]
+    fn l2_profiles_map_to_anvil_args() {
+        assert_eq!(
+            L2Profile::default().anvil_args(),
+            ["--base-fee", "0", "--gas-limit", "1000000000"]
+        );
+        assert_eq!(
+            "base".parse::<L2Profile>().unwrap().anvil_args(),
+            ["--base-fee", "0", "--gas-limit", "1000000000", "--optimism"]
+        );
+
+        // a fixed base fee takes precedence over disabling it
+        let overridden = L2Profile {
+            chain_id: Some(8453),
+            base_fee: Some(7),
+            gas_limit: None,
+            ..L2Profile::default()
+        };
+        assert_eq!(
+            overridden.anvil_args(),
+            ["--chain-id", "8453", "--base-fee", "7"]
+        );
+        let forked_base_fee = L2Profile {
+            disable_base_fee: false,
+            gas_limit: None,
+            ..L2Profile::default()
+        };
+        assert!(forked_base_fee.anvil_args().is_empty());
+        assert!("solana".parse::<L2Profile>().is_err());
+    }
+}
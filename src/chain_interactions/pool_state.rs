@@ -0,0 +1,125 @@
+use alloy::{
+    eips::BlockId,
+    primitives::{aliases::I24, Address, U160, U256},
+    providers::{Provider, ProviderBuilder},
+    transports::http::reqwest::Url,
+};
+use eyre::{Context, Result};
+
+use crate::{
+    abi::UniswapV3Pool::{self, UniswapV3PoolInstance},
+    fee_analyzer::{ArcAnvilHttpProvider, HttpClient},
+};
+
+// the parts of a pool's state the end of run check compares
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PoolState {
+    pub sqrt_price_x96: U160,
+    pub tick: I24,
+    pub liquidity: u128,
+    pub fee_growth_global0_x128: U256,
+    pub fee_growth_global1_x128: U256,
+}
+
+async fn read_pool_state<P: Provider<HttpClient>>(
+    pool: &UniswapV3PoolInstance<HttpClient, P>,
+    block: BlockId,
+) -> Result<PoolState> {
+    let slot0 = pool.slot0().block(block).call().await?;
+    Ok(PoolState {
+        sqrt_price_x96: slot0.sqrtPriceX96,
+        tick: slot0.tick,
+        liquidity: pool.liquidity().block(block).call().await?._0,
+        fee_growth_global0_x128: pool.feeGrowthGlobal0X128().block(block).call().await?._0,
+        fee_growth_global1_x128: pool.feeGrowthGlobal1X128().block(block).call().await?._0,
+    })
+}
+
+// the simulated pool's current state
+pub(crate) async fn simulated_pool_state(
+    pool: &UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>,
+) -> Result<PoolState> {
+    read_pool_state(pool, BlockId::latest()).await
+}
+
+// the real pool's state after `block`, read from the archive endpoint the
+// fork was made from
+pub(crate) async fn upstream_pool_state(
+    http_url: &str,
+    pool_address: Address,
+    block: u64,
+) -> Result<PoolState> {
+    let url: Url = http_url.parse()?;
+    let provider = ProviderBuilder::new().on_http(url);
+    let pool = UniswapV3Pool::new(pool_address, provider);
+    read_pool_state(&pool, BlockId::number(block))
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to read pool {} at block {} from the archive endpoint",
+                pool_address, block
+            )
+        })
+}
+
+// one line per field that differs between the simulated and the real pool
+pub(crate) fn pool_state_divergences(simulated: &PoolState, real: &PoolState) -> Vec<String> {
+    let mut divergences = Vec::new();
+    let mut compare = |field: &str, simulated: String, real: String| {
+        if simulated != real {
+            divergences.push(format!("{}: simulated {}, real {}", field, simulated, real));
+        }
+    };
+    compare(
+        "sqrtPriceX96",
+        simulated.sqrt_price_x96.to_string(),
+        real.sqrt_price_x96.to_string(),
+    );
+    compare("tick", simulated.tick.to_string(), real.tick.to_string());
+    compare(
+        "liquidity",
+        simulated.liquidity.to_string(),
+        real.liquidity.to_string(),
+    );
+    compare(
+        "feeGrowthGlobal0X128",
+        simulated.fee_growth_global0_x128.to_string(),
+        real.fee_growth_global0_x128.to_string(),
+    );
+    compare(
+        "feeGrowthGlobal1X128",
+        simulated.fee_growth_global1_x128.to_string(),
+        real.fee_growth_global1_x128.to_string(),
+    );
+    divergences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_each_field_that_differs() {
+        let real = PoolState {
+            sqrt_price_x96: U160::from(1) << 96,
+            tick: I24::ZERO,
+            liquidity: 1_000,
+            fee_growth_global0_x128: U256::from(5),
+            fee_growth_global1_x128: U256::from(7),
+        };
+        assert!(pool_state_divergences(&real, &real.clone()).is_empty());
+
+        let simulated = PoolState {
+            tick: I24::try_from(-1).unwrap(),
+            fee_growth_global1_x128: U256::from(8),
+            ..real.clone()
+        };
+        assert_eq!(
+            pool_state_divergences(&simulated, &real),
+            [
+                "tick: simulated -1, real 0",
+                "feeGrowthGlobal1X128: simulated 8, real 7"
+            ]
+        );
+    }
+}
@@ -1,51 +1,185 @@
-use std::sync::Arc;
+use std::{fmt, sync::Arc};
 
 use alloy::{
     primitives::{aliases::U24, ruint::aliases::U256, Address, Log as AbiLog, I256, U160},
+    providers::ext::AnvilApi,
     rpc::types::TransactionReceipt,
-    sol_types::SolEvent,
+    sol_types::{SolCall, SolEvent},
 };
 use eyre::{bail, Context, ContextCompat, Result};
-use tracing::error;
+use tracing::{error, warn};
 
+use crate::error::FeeAnalyzerError;
 use crate::{
     abi::{
         IQuoterV2::{IQuoterV2Instance, QuoteExactInputSingleParams},
-        ISwapRouter::{ExactInputSingleParams, ExactOutputSingleParams, ISwapRouterInstance},
+        ISwapRouter::{
+            exactOutputSingleCall, refundETHCall, ExactInputSingleParams, ExactOutputSingleParams,
+            ISwapRouterInstance,
+        },
         UniswapV3Pool::{Swap, UniswapV3PoolInstance},
     },
-    fee_analyzer::{ArcAnvilHttpProvider, HttpClient},
+    fee_analyzer::{simulation_events::SwapWithParams, ArcAnvilHttpProvider, HttpClient},
 };
 
+use super::SwapFailurePolicy;
+
 struct SwapParams {
     token_in: Address,
     token_out: Address,
     amount_in: U256,
     amount_out: U256,
     fee: U24,
+    // the original call's slippage bounds, zero and unset unless enforced
+    amount_out_minimum: U256,
+    amount_in_maximum: Option<U256>,
 }
 
-enum SwapDirection {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SwapDirection {
     ExactInput,
     ExactOutput,
+    // the exact input quote is within the tolerance but not exact, rounding
+    // can put it off by a wei so either direction could have produced the event
+    Either,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SwapSettings {
+    // when set, swaps that pay in this token send native eth as msg.value and
+    // let the router wrap it, matching txs that swapped from eth
+    pub native_weth: Option<Address>,
+    // how many wei the exact input quote may differ from the event's amount
+    // out and still be considered an exact input swap
+    pub direction_tolerance: U256,
+    // extra input allowed on exact output swaps, in basis points of the
+    // event's amount in
+    pub exact_output_slack_bps: u32,
+    // compare each replayed swap's log against the event
+    pub verify: bool,
+    // send swaps as exact input without quoting first, reverting to a
+    // snapshot and taking the quoted path when the log doesn't match
+    pub optimistic_exact_input: bool,
+    // what to do when the exact input quote reverts
+    pub failure_policy: SwapFailurePolicy,
+}
+
+// how often the optimistic exact input swaps held up
+#[derive(Debug, Default)]
+pub(crate) struct SwapStats {
+    pub optimistic_hits: u64,
+    pub optimistic_fallbacks: u64,
+}
+
+impl fmt::Display for SwapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\nOptimistic Exact Input Swaps:\n\
+             ├─ Matched:      {}\n\
+             └─ Fell Back:    {}",
+            self.optimistic_hits, self.optimistic_fallbacks,
+        )
+    }
 }
 
-pub async fn pool_swap(
+// replays the swap event, returning its gas and the direction it was sent in,
+// or None when the swap failure policy skipped it
+pub(crate) async fn pool_swap(
     pool: Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
     swap_router: Arc<ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
     quoter: Arc<IQuoterV2Instance<HttpClient, ArcAnvilHttpProvider>>,
-    swap_event: &Swap,
+    swap: &SwapWithParams,
     swapper: Address,
-) -> Result<()> {
-    let swap_params = swap_params(swap_event, &pool).await?;
-    let swap_direction = swap_direction(&swap_params, &quoter).await?;
+    settings: &SwapSettings,
+    stats: &mut SwapStats,
+) -> Result<Option<(u64, SwapDirection)>> {
+    let swap_event = &swap.event;
+    let swap_params = SwapParams {
+        amount_out_minimum: swap.amount_out_minimum.unwrap_or_default(),
+        amount_in_maximum: swap.amount_in_maximum,
+        ..swap_params(swap_event, &pool).await?
+    };
+    let pay_native = settings.native_weth == Some(swap_params.token_in);
+
+    // most swaps are exact input, so try that first and skip the quote. the
+    // outcome check is what catches the exact output ones, so this needs it
+    if settings.optimistic_exact_input && settings.verify {
+        let provider = swap_router.provider().clone();
+        let snapshot = provider.anvil_snapshot().await?;
+        if let Ok(receipt) = send_exact_input(&swap_router, swapper, &swap_params, pay_native).await
+        {
+            if swap_log_matches(swap_event, &decode_swap_log(&receipt)?, U256::ZERO) {
+                stats.optimistic_hits += 1;
+                return Ok(Some((receipt.gas_used, SwapDirection::ExactInput)));
+            }
+        }
+        if !provider.anvil_revert(snapshot).await? {
+            bail!("Failed to revert the optimistic exact input swap");
+        }
+        stats.optimistic_fallbacks += 1;
+    }
+
+    let quoted = swap_direction(&swap_params, &quoter, settings.direction_tolerance).await;
+    let Some(quoted_direction) =
+        quoted_or_failure_policy(quoted, &swap_params, settings.failure_policy)?
+    else {
+        return Ok(None);
+    };
+    let swap_direction = match quoted_direction {
+        SwapDirection::Either => {
+            resolve_swap_direction(&swap_router, swapper, &swap_params, pay_native).await?
+        }
+        direction => direction,
+    };
 
-    match swap_direction {
+    let gas_used = match swap_direction {
         SwapDirection::ExactInput => {
-            pool_swap_exact_input(swap_router, swapper, swap_event, &swap_params).await
+            pool_swap_exact_input(
+                swap_router,
+                swapper,
+                swap_event,
+                &swap_params,
+                pay_native,
+                settings.verify,
+            )
+            .await?
         }
-        SwapDirection::ExactOutput => {
-            pool_swap_exact_output(swap_router, swapper, swap_event, &swap_params).await
+        SwapDirection::ExactOutput | SwapDirection::Either => {
+            pool_swap_exact_output(
+                swap_router,
+                swapper,
+                swap_event,
+                &swap_params,
+                pay_native,
+                settings.exact_output_slack_bps,
+                settings.verify,
+            )
+            .await?
+        }
+    };
+    Ok(Some((gas_used, swap_direction)))
+}
+
+// the quoted direction, or what the failure policy makes of a reverted
+// quote: the fallback resolves it by simulating, a skip is none
+fn quoted_or_failure_policy(
+    quoted: Result<SwapDirection>,
+    swap_params: &SwapParams,
+    failure_policy: SwapFailurePolicy,
+) -> Result<Option<SwapDirection>> {
+    match quoted {
+        Ok(direction) => Ok(Some(direction)),
+        Err(e) => {
+            warn!(
+                "Exact input quote reverted for swap {} -> {}, amount in {}: {:#}",
+                swap_params.token_in, swap_params.token_out, swap_params.amount_in, e
+            );
+            match failure_policy {
+                SwapFailurePolicy::Fallback => Ok(Some(SwapDirection::Either)),
+                SwapFailurePolicy::Skip => Ok(None),
+                SwapFailurePolicy::Fail => Err(e),
+            }
         }
     }
 }
@@ -81,12 +215,15 @@ async fn swap_params(
         amount_in: U256::try_from(amount_in).context("failed to convert amount_in to U256")?,
         amount_out: U256::try_from(amount_out).context("failed to convert amount_out to U256")?,
         fee,
+        amount_out_minimum: U256::ZERO,
+        amount_in_maximum: None,
     })
 }
 
 async fn swap_direction(
     swap_params: &SwapParams,
     quoter: &IQuoterV2Instance<HttpClient, ArcAnvilHttpProvider>,
+    tolerance: U256,
 ) -> Result<SwapDirection> {
     // get quote for swap exact in, if matches event's out, then swap ExactIn
     let quote_params = QuoteExactInputSingleParams {
@@ -103,15 +240,91 @@ async fn swap_direction(
         .await
         .context("failed to get quote for swap exact in")?;
 
-    if quote.amountOut == swap_params.amount_out {
-        Ok(SwapDirection::ExactInput)
+    Ok(classify_swap_direction(
+        quote.amountOut,
+        swap_params.amount_out,
+        tolerance,
+    ))
+}
+
+fn classify_swap_direction(
+    quoted_amount_out: U256,
+    event_amount_out: U256,
+    tolerance: U256,
+) -> SwapDirection {
+    if quoted_amount_out == event_amount_out {
+        SwapDirection::ExactInput
+    } else if quoted_amount_out.abs_diff(event_amount_out) <= tolerance {
+        SwapDirection::Either
     } else {
-        Ok(SwapDirection::ExactOutput)
+        SwapDirection::ExactOutput
     }
 }
 
-async fn check_swap_outcomes(swap_event: &Swap, tx_receipt: &TransactionReceipt) -> Result<()> {
-    let swap_log = tx_receipt
+// simulates the exact input swap against the router and only picks it if it
+// produces the event's amount out, otherwise falls back to exact output
+async fn resolve_swap_direction(
+    swap_router: &ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>,
+    swapper: Address,
+    swap_params: &SwapParams,
+    pay_native: bool,
+) -> Result<SwapDirection> {
+    let exact_input_params = ExactInputSingleParams {
+        tokenIn: swap_params.token_in,
+        tokenOut: swap_params.token_out,
+        fee: swap_params.fee,
+        recipient: swapper,
+        amountIn: swap_params.amount_in,
+        amountOutMinimum: U256::from(0),
+        sqrtPriceLimitX96: U160::from(0),
+    };
+    let value = if pay_native {
+        swap_params.amount_in
+    } else {
+        U256::ZERO
+    };
+
+    let simulated = swap_router
+        .exactInputSingle(exact_input_params)
+        .from(swapper)
+        .value(value)
+        .call()
+        .await;
+
+    match simulated {
+        Ok(r) if r.amountOut == swap_params.amount_out => Ok(SwapDirection::ExactInput),
+        _ => {
+            warn!("exact input swap does not reproduce the event's amount out, using exact output");
+            Ok(SwapDirection::ExactOutput)
+        }
+    }
+}
+
+// `input_slack` is how much more input than the event the replay may have
+// used. a replay that needed extra input ends at a slightly different price,
+// so in that case only the output amount has to match and the difference is
+// logged.
+async fn check_swap_outcomes(
+    swap_event: &Swap,
+    tx_receipt: &TransactionReceipt,
+    input_slack: U256,
+) -> Result<()> {
+    let swap_log = decode_swap_log(tx_receipt)?;
+
+    if !swap_log_matches(swap_event, &swap_log, input_slack) {
+        error!("Mismatch in swap outcomes");
+        error!("swap event: {:?}", swap_event);
+        error!("swap log: {:?}", swap_log);
+        bail!(FeeAnalyzerError::SwapMismatch(
+            "Mismatch in swap outcomes".to_string()
+        ));
+    }
+
+    Ok(())
+}
+
+fn decode_swap_log(tx_receipt: &TransactionReceipt) -> Result<Swap> {
+    tx_receipt
         .inner
         .logs()
         .iter()
@@ -125,21 +338,43 @@ async fn check_swap_outcomes(swap_event: &Swap, tx_receipt: &TransactionReceipt)
             .unwrap_or_default();
             Swap::decode_log(&log, true).ok()
         })
-        .context("Failed to find swap log in tx receipt")?;
-
-    if swap_log.amount0 != swap_event.amount0
-        || swap_log.amount1 != swap_event.amount1
-        || swap_log.sqrtPriceX96 != swap_event.sqrtPriceX96
-        || swap_log.liquidity != swap_event.liquidity
-        || swap_log.tick != swap_event.tick
-    {
-        error!("Mismatch in swap outcomes");
-        error!("swap event: {:?}", swap_event);
-        error!("swap log: {:?}", swap_log);
-        bail!("Mismatch in swap outcomes");
+        .map(|log| log.data)
+        .context("Failed to find swap log in tx receipt")
+}
+
+fn swap_log_matches(swap_event: &Swap, swap_log: &Swap, input_slack: U256) -> bool {
+    // the input side is the positive amount
+    let (log_in, event_in, log_out, event_out) = if swap_event.amount0 > I256::ZERO {
+        (
+            swap_log.amount0,
+            swap_event.amount0,
+            swap_log.amount1,
+            swap_event.amount1,
+        )
+    } else {
+        (
+            swap_log.amount1,
+            swap_event.amount1,
+            swap_log.amount0,
+            swap_event.amount0,
+        )
+    };
+    if log_in > event_in && log_out == event_out {
+        let extra_input = (log_in - event_in).into_raw();
+        if extra_input <= input_slack {
+            warn!(
+                "Replayed swap used {} input, event used {} (within slack)",
+                log_in, event_in
+            );
+            return true;
+        }
     }
 
-    Ok(())
+    swap_log.amount0 == swap_event.amount0
+        && swap_log.amount1 == swap_event.amount1
+        && swap_log.sqrtPriceX96 == swap_event.sqrtPriceX96
+        && swap_log.liquidity == swap_event.liquidity
+        && swap_log.tick == swap_event.tick
 }
 
 async fn pool_swap_exact_input(
@@ -147,14 +382,45 @@ async fn pool_swap_exact_input(
     swapper: Address,
     swap_event: &Swap,
     swap_params: &SwapParams,
-) -> Result<()> {
+    pay_native: bool,
+    verify: bool,
+) -> Result<u64> {
+    let receipt = send_exact_input(&swap_router, swapper, swap_params, pay_native).await?;
+
+    if verify {
+        check_swap_outcomes(swap_event, &receipt, U256::ZERO).await?;
+    }
+
+    Ok(receipt.gas_used)
+}
+
+// swaps the event's input amount in the event's direction without checking
+// the result against the event, for replays where the pool's liquidity no
+// longer matches the original (counterfactual positions)
+pub(crate) async fn pool_swap_unchecked(
+    pool: Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
+    swap_router: Arc<ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
+    swap_event: &Swap,
+    swapper: Address,
+) -> Result<u64> {
+    let swap_params = swap_params(swap_event, &pool).await?;
+    let receipt = send_exact_input(&swap_router, swapper, &swap_params, false).await?;
+    Ok(receipt.gas_used)
+}
+
+async fn send_exact_input(
+    swap_router: &ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>,
+    swapper: Address,
+    swap_params: &SwapParams,
+    pay_native: bool,
+) -> Result<TransactionReceipt> {
     let exact_input_params = ExactInputSingleParams {
         tokenIn: swap_params.token_in,
         tokenOut: swap_params.token_out,
         fee: swap_params.fee,
         recipient: swapper,
         amountIn: swap_params.amount_in,
-        amountOutMinimum: U256::from(0),
+        amountOutMinimum: swap_params.amount_out_minimum,
         sqrtPriceLimitX96: U160::from(0),
     };
 
@@ -163,31 +429,42 @@ async fn pool_swap_exact_input(
     let mut receipt = None;
 
     while attempts < max_attempts {
-        match swap_router
+        // the router wraps msg.value itself when paying in weth
+        let value = if pay_native {
+            swap_params.amount_in
+        } else {
+            U256::ZERO
+        };
+        if let Ok(r) = swap_router
             .exactInputSingle(exact_input_params.clone())
             .from(swapper)
+            .value(value)
             .send()
             .await?
             .get_receipt()
             .await
         {
-            Ok(r) => {
-                if r.inner.status() {
-                    receipt = Some(r);
-                    break;
-                }
+            if r.inner.status() {
+                receipt = Some(r);
+                break;
             }
-            Err(_) => {}
         }
         attempts += 1;
     }
 
-    let receipt =
-        receipt.ok_or_else(|| eyre::eyre!("Failed to swap after {} attempts", max_attempts))?;
-
-    check_swap_outcomes(swap_event, &receipt).await?;
+    receipt.ok_or_else(|| eyre::eyre!("Failed to swap after {} attempts", max_attempts))
+}
 
-    Ok(())
+// rounding differences can make the replay need slightly more input than
+// the original swap, allow a bit of slack so it doesn't revert. the original
+// call's bound replaces the slack when it's enforced. returns the slack and
+// the amountInMaximum to send
+fn exact_output_input_bound(swap_params: &SwapParams, slack_bps: u32) -> (U256, U256) {
+    let input_slack = swap_params.amount_in * U256::from(slack_bps) / U256::from(10_000);
+    let amount_in_maximum = swap_params
+        .amount_in_maximum
+        .unwrap_or(swap_params.amount_in + input_slack);
+    (input_slack, amount_in_maximum)
 }
 
 async fn pool_swap_exact_output(
@@ -195,14 +472,19 @@ async fn pool_swap_exact_output(
     swapper: Address,
     swap_event: &Swap,
     swap_params: &SwapParams,
-) -> Result<()> {
+    pay_native: bool,
+    slack_bps: u32,
+    verify: bool,
+) -> Result<u64> {
+    let (input_slack, amount_in_maximum) = exact_output_input_bound(swap_params, slack_bps);
+
     let exact_output_params = ExactOutputSingleParams {
         tokenIn: swap_params.token_in,
         tokenOut: swap_params.token_out,
         fee: swap_params.fee,
         recipient: swapper,
         amountOut: swap_params.amount_out,
-        amountInMaximum: swap_params.amount_in,
+        amountInMaximum: amount_in_maximum,
         sqrtPriceLimitX96: U160::from(0),
     };
 
@@ -211,21 +493,38 @@ async fn pool_swap_exact_output(
     let mut receipt = None;
 
     while attempts < max_attempts {
-        match swap_router
-            .exactOutputSingle(exact_output_params.clone())
-            .from(swapper)
-            .send()
-            .await?
-            .get_receipt()
-            .await
-        {
-            Ok(r) => {
-                if r.inner.status() {
-                    receipt = Some(r);
-                    break;
-                }
+        // exact output swaps paid in native eth can leave unspent eth in the
+        // router, refund it in the same tx like the periphery multicall flow
+        let result = if pay_native {
+            swap_router
+                .multicall(vec![
+                    exactOutputSingleCall {
+                        params: exact_output_params.clone(),
+                    }
+                    .abi_encode()
+                    .into(),
+                    refundETHCall {}.abi_encode().into(),
+                ])
+                .from(swapper)
+                .value(amount_in_maximum)
+                .send()
+                .await?
+                .get_receipt()
+                .await
+        } else {
+            swap_router
+                .exactOutputSingle(exact_output_params.clone())
+                .from(swapper)
+                .send()
+                .await?
+                .get_receipt()
+                .await
+        };
+        if let Ok(r) = result {
+            if r.inner.status() {
+                receipt = Some(r);
+                break;
             }
-            Err(_) => {}
         }
         attempts += 1;
     }
@@ -233,7 +532,150 @@ async fn pool_swap_exact_output(
     let receipt =
         receipt.ok_or_else(|| eyre::eyre!("Failed to swap after {} attempts", max_attempts))?;
 
-    check_swap_outcomes(swap_event, &receipt).await?;
+    if verify {
+        check_swap_outcomes(swap_event, &receipt, input_slack).await?;
+    }
 
-    Ok(())
+    Ok(receipt.gas_used)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::aliases::I24;
+
+    use super::*;
+
+    #[test]
+    fn a_quote_a_wei_off_could_be_either_direction() {
+        let event_out = U256::from(1_000_000);
+        let one = U256::from(1);
+        assert_eq!(
+            classify_swap_direction(event_out, event_out, one),
+            SwapDirection::ExactInput
+        );
+        for quoted in [event_out + one, event_out - one] {
+            assert_eq!(
+                classify_swap_direction(quoted, event_out, one),
+                SwapDirection::Either
+            );
+            assert_eq!(
+                classify_swap_direction(quoted, event_out, U256::ZERO),
+                SwapDirection::ExactOutput
+            );
+        }
+        assert_eq!(
+            classify_swap_direction(event_out + U256::from(2), event_out, one),
+            SwapDirection::ExactOutput
+        );
+    }
+
+    #[test]
+    fn exact_output_swaps_may_take_the_slack_and_no_more() {
+        let swap_params = SwapParams {
+            token_in: Address::repeat_byte(0x01),
+            token_out: Address::repeat_byte(0x02),
+            amount_in: U256::from(1_000_000),
+            amount_out: U256::from(900_000),
+            fee: U24::from(10_000),
+            amount_out_minimum: U256::ZERO,
+            amount_in_maximum: None,
+        };
+        // 10 bps of the input
+        let (slack, maximum) = exact_output_input_bound(&swap_params, 10);
+        assert_eq!((slack, maximum), (U256::from(1_000), U256::from(1_001_000)));
+        // an enforced original bound replaces it
+        let bounded = SwapParams {
+            amount_in_maximum: Some(U256::from(1_000_050)),
+            ..swap_params
+        };
+        assert_eq!(
+            exact_output_input_bound(&bounded, 10),
+            (U256::from(1_000), U256::from(1_000_050))
+        );
+
+        let event = Swap {
+            sender: Address::ZERO,
+            recipient: Address::ZERO,
+            amount0: I256::try_from(1_000_000).unwrap(),
+            amount1: I256::try_from(-900_000).unwrap(),
+            sqrtPriceX96: U160::from(1) << 96,
+            liquidity: 1_000,
+            tick: I24::ZERO,
+        };
+        // the output matches, the price fields don't need to
+        let log_taking = |input: i64| Swap {
+            amount0: I256::try_from(input).unwrap(),
+            sqrtPriceX96: U160::from(2) << 96,
+            ..event.clone()
+        };
+        assert!(swap_log_matches(
+            &event,
+            &log_taking(1_000_000 + 1_000),
+            slack
+        ));
+        assert!(!swap_log_matches(
+            &event,
+            &log_taking(1_000_000 + 1_001),
+            slack
+        ));
+        assert!(!swap_log_matches(
+            &event,
+            &log_taking(1_000_000 + 1),
+            U256::ZERO
+        ));
+        // less output than the event never matches
+        let short = Swap {
+            amount1: I256::try_from(-899_999).unwrap(),
+            ..log_taking(1_000_001)
+        };
+        assert!(!swap_log_matches(&event, &short, slack));
+        assert!(swap_log_matches(&event, &event, U256::ZERO));
+    }
+
+    #[test]
+    fn a_reverted_quote_follows_the_failure_policy() {
+        let swap_params = SwapParams {
+            token_in: Address::repeat_byte(0x01),
+            token_out: Address::repeat_byte(0x02),
+            amount_in: U256::from(1_000_000),
+            amount_out: U256::from(900_000),
+            fee: U24::from(10_000),
+            amount_out_minimum: U256::ZERO,
+            amount_in_maximum: None,
+        };
+        let reverted = || -> Result<SwapDirection> {
+            Err(eyre::eyre!("execution reverted: SPL"))
+                .context("failed to get quote for swap exact in")
+        };
+
+        assert_eq!(
+            quoted_or_failure_policy(reverted(), &swap_params, SwapFailurePolicy::Fallback)
+                .unwrap(),
+            Some(SwapDirection::Either)
+        );
+        assert_eq!(
+            quoted_or_failure_policy(reverted(), &swap_params, SwapFailurePolicy::Skip).unwrap(),
+            None
+        );
+        let error = quoted_or_failure_policy(reverted(), &swap_params, SwapFailurePolicy::Fail)
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("execution reverted: SPL"));
+
+        // a quote that went through is used whatever the policy
+        for failure_policy in [
+            SwapFailurePolicy::Fallback,
+            SwapFailurePolicy::Skip,
+            SwapFailurePolicy::Fail,
+        ] {
+            assert_eq!(
+                quoted_or_failure_policy(
+                    Ok(SwapDirection::ExactOutput),
+                    &swap_params,
+                    failure_policy
+                )
+                .unwrap(),
+                Some(SwapDirection::ExactOutput)
+            );
+        }
+    }
 }
@@ -0,0 +1,541 @@
+use std::{str::FromStr, sync::Arc};
+
+use alloy::primitives::{aliases::I24, uint, Address, I256, U160, U256, U512};
+use eyre::{bail, Context, Result};
+
+use crate::{
+    abi::{
+        ClankerToken::ClankerTokenInstance,
+        IQuoterV2::{IQuoterV2Instance, QuoteExactInputSingleParams},
+        ISwapRouter::{ExactInputSingleParams, ISwapRouterInstance},
+        UniswapV3Pool::UniswapV3PoolInstance,
+    },
+    fee_analyzer::{ArcAnvilHttpProvider, HttpClient},
+};
+
+use super::{approve_exact, top_up_clanker_tokens, ApprovalMode, PoolConfig};
+
+// how token amounts are converted into weth for the position pnl estimates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValuationMethod {
+    // simulate selling through the swap router, includes price impact
+    #[default]
+    Swap,
+    // ask the quoter for the sale, also includes price impact
+    Quoter,
+    // marginal value at the current slot0 price, no price impact
+    Spot,
+    // marginal value at the pool oracle's time weighted average price, falls
+    // back to spot while the oracle doesn't cover the window yet
+    Twap,
+}
+
+impl FromStr for ValuationMethod {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "swap" => Ok(ValuationMethod::Swap),
+            "quoter" => Ok(ValuationMethod::Quoter),
+            "spot" => Ok(ValuationMethod::Spot),
+            "twap" => Ok(ValuationMethod::Twap),
+            _ => bail!(
+                "Unknown valuation method: {}, expected swap, quoter, spot, or twap",
+                s
+            ),
+        }
+    }
+}
+
+// converts token amounts into weth using the configured valuation method
+#[derive(Clone)]
+pub(crate) struct TokenValuer {
+    pub pool: Arc<UniswapV3PoolInstance<HttpClient, ArcAnvilHttpProvider>>,
+    pub swap_router: Arc<ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
+    pub quoter: Arc<IQuoterV2Instance<HttpClient, ArcAnvilHttpProvider>>,
+    pub swap_account: Address,
+    pub method: ValuationMethod,
+    pub clanker_token: Arc<ClankerTokenInstance<HttpClient, ArcAnvilHttpProvider>>,
+    // holder to top the swap account up from before simulated sales, set
+    // when the token supply isn't transferred to the swap account up front
+    pub token_holder: Option<Address>,
+    pub approval_mode: ApprovalMode,
+    // seconds the twap valuation averages over
+    pub twap_window: u32,
+    // the swap and quoter valuations price 1 / slices of the amount and scale
+    // the output back up, 1 prices the whole amount in one sale
+    pub slices: u32,
+    // caps the priced sale at this many basis points of the pool's token
+    // balance, scaling the output up the same way
+    pub max_sale_bps: Option<u32>,
+}
+
+impl TokenValuer {
+    pub(crate) async fn token_to_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount: U256,
+    ) -> Result<U256> {
+        if token_amount == U256::ZERO {
+            return Ok(U256::ZERO);
+        }
+
+        match self.method {
+            ValuationMethod::Swap | ValuationMethod::Quoter => {
+                let sale = self.sale_size(token_amount).await?;
+                let sale_value = if self.method == ValuationMethod::Swap {
+                    self.sim_swap_token_for_weth(pool_config, sale).await?
+                } else {
+                    self.quote_token_for_weth(pool_config, sale).await?
+                };
+                Ok(scale_sale_value(sale_value, sale, token_amount))
+            }
+            ValuationMethod::Spot => self.spot_token_to_weth(pool_config, token_amount).await,
+            ValuationMethod::Twap => self.twap_token_to_weth(pool_config, token_amount).await,
+        }
+    }
+
+    // what selling the whole amount in one go would pay right now, from the
+    // quoter and without the configured slicing or sale cap
+    pub(crate) async fn executable_token_to_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount: U256,
+    ) -> Result<U256> {
+        if token_amount == U256::ZERO {
+            return Ok(U256::ZERO);
+        }
+        self.quote_token_for_weth(pool_config, token_amount).await
+    }
+
+    // whether valuing sends transactions, the swap valuation tops the swap
+    // account up from the token holder and may approve the exact amount
+    pub(crate) fn sends_transactions(&self) -> bool {
+        self.method == ValuationMethod::Swap
+            && (self.token_holder.is_some() || self.approval_mode == ApprovalMode::Exact)
+    }
+
+    // the largest sale the swap and quoter valuations price in one go, the
+    // amount split into `slices` and capped at `max_sale_bps` of the pool's
+    // token balance
+    async fn sale_size(&self, token_amount: U256) -> Result<U256> {
+        let cap = match self.max_sale_bps {
+            Some(bps) => {
+                let pool_balance = self
+                    .clanker_token
+                    .balanceOf(*self.pool.address())
+                    .call()
+                    .await?
+                    ._0;
+                Some(pool_balance * U256::from(bps) / U256::from(10_000))
+            }
+            None => None,
+        };
+        Ok(split_sale(token_amount, self.slices, cap))
+    }
+
+    // simulates the amount of weth that would be received from swapping the given token amount,
+    // used to approximate the starting and ending weth value of the positions. note that this is
+    // not 100% accurate because sometimes this is ran when the position is still open and could
+    // be consumed during the swap.
+    async fn sim_swap_token_for_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount_out: U256,
+    ) -> Result<U256> {
+        let (clanker_address, weth_address) = clanker_and_weth(pool_config);
+        if let Some(holder) = self.token_holder {
+            top_up_clanker_tokens(
+                &self.clanker_token,
+                holder,
+                self.swap_account,
+                token_amount_out,
+            )
+            .await?;
+        }
+        if self.approval_mode == ApprovalMode::Exact {
+            approve_exact(
+                self.pool.provider().clone(),
+                clanker_address,
+                self.swap_account,
+                *self.swap_router.address(),
+                token_amount_out,
+            )
+            .await?;
+        }
+
+        let exact_input_params = ExactInputSingleParams {
+            tokenIn: clanker_address,
+            tokenOut: weth_address,
+            fee: pool_config.fee,
+            recipient: self.swap_account,
+            amountIn: token_amount_out,
+            amountOutMinimum: U256::from(0),
+            sqrtPriceLimitX96: U160::from(0),
+        };
+
+        let swap_router_call = self
+            .swap_router
+            .exactInputSingle(exact_input_params)
+            .from(self.swap_account)
+            .call()
+            .await?;
+        Ok(swap_router_call.amountOut)
+    }
+
+    // same sale as sim_swap_token_for_weth but priced by the quoter
+    async fn quote_token_for_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount: U256,
+    ) -> Result<U256> {
+        let (clanker_address, weth_address) = clanker_and_weth(pool_config);
+
+        let quote = self
+            .quoter
+            .quoteExactInputSingle(QuoteExactInputSingleParams {
+                tokenIn: clanker_address,
+                tokenOut: weth_address,
+                fee: pool_config.fee,
+                amountIn: token_amount,
+                sqrtPriceLimitX96: U160::from(0),
+            })
+            .call()
+            .await
+            .context("failed to quote token for weth")?;
+        Ok(quote.amountOut)
+    }
+
+    // values the token amount at the pool's current price without moving it
+    async fn spot_token_to_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount: U256,
+    ) -> Result<U256> {
+        let sqrt_price_x96 = self.pool.slot0().call().await?.sqrtPriceX96;
+        Ok(spot_value(
+            token_amount,
+            sqrt_price_x96,
+            pool_config.clanker_is_token0,
+        ))
+    }
+
+    // values the token amount at the mean tick over the last `twap_window`
+    // seconds. observe reverts until the oracle holds an observation that old,
+    // which is expected early in the replay, so that falls back to spot
+    async fn twap_token_to_weth(
+        &self,
+        pool_config: &PoolConfig,
+        token_amount: U256,
+    ) -> Result<U256> {
+        let Ok(observation) = self.pool.observe(vec![self.twap_window, 0]).call().await else {
+            return self.spot_token_to_weth(pool_config, token_amount).await;
+        };
+        let (Some(start), Some(end)) = (
+            observation.tickCumulatives.first(),
+            observation.tickCumulatives.get(1),
+        ) else {
+            bail!("observe returned fewer than two tick cumulatives");
+        };
+        twap_value(
+            token_amount,
+            end.as_i64() - start.as_i64(),
+            self.twap_window,
+            pool_config.clanker_is_token0,
+        )
+    }
+}
+
+// the token amount valued at the mean tick of a `window` seconds long tick
+// cumulative delta
+fn twap_value(
+    token_amount: U256,
+    tick_cumulative_delta: i64,
+    window: u32,
+    clanker_is_token0: bool,
+) -> Result<U256> {
+    let mean_tick = mean_tick(tick_cumulative_delta, window)?;
+    let sqrt_price_x96: U160 = sqrt_price_at_tick(mean_tick).to();
+    Ok(spot_value(token_amount, sqrt_price_x96, clanker_is_token0))
+}
+
+// one of `slices` equal parts of the amount, rounded up, and at most `cap`
+// (but never nothing)
+fn split_sale(token_amount: U256, slices: u32, cap: Option<U256>) -> U256 {
+    let sale = token_amount.div_ceil(U256::from(slices.max(1)));
+    match cap {
+        Some(cap) => sale.min(cap.max(U256::from(1))),
+        None => sale,
+    }
+}
+
+// extrapolates the value of a `sale` sized part of `token_amount` linearly to
+// the whole amount, as if it were sold in equal parts with the price recovering
+// between them
+fn scale_sale_value(sale_value: U256, sale: U256, token_amount: U256) -> U256 {
+    if sale >= token_amount || sale == U256::ZERO {
+        return sale_value;
+    }
+    U256::saturating_from(U512::from(sale_value) * U512::from(token_amount) / U512::from(sale))
+}
+
+// the arithmetic mean tick over `window` seconds, rounded toward negative
+// infinity like OracleLibrary.consult
+fn mean_tick(tick_cumulative_delta: i64, window: u32) -> Result<I24> {
+    let window = i64::from(window);
+    let mut tick = tick_cumulative_delta / window;
+    if tick_cumulative_delta < 0 && tick_cumulative_delta % window != 0 {
+        tick -= 1;
+    }
+    I24::try_from(tick).context("mean tick out of range")
+}
+
+fn clanker_and_weth(pool_config: &PoolConfig) -> (Address, Address) {
+    if pool_config.clanker_is_token0 {
+        (pool_config.token0, pool_config.token1)
+    } else {
+        (pool_config.token1, pool_config.token0)
+    }
+}
+
+// converts a token amount into the other side of the pool at the given sqrt price,
+// price = (sqrtPriceX96 / 2^96)^2 is token1 per token0. done in 512 bits so the
+// squared price can't overflow.
+pub(crate) fn spot_value(amount: U256, sqrt_price_x96: U160, amount_is_token0: bool) -> U256 {
+    let amount = U512::from(amount);
+    let sqrt_price = U512::from(sqrt_price_x96);
+    let price_x192 = sqrt_price * sqrt_price;
+
+    let value = if amount_is_token0 {
+        (amount * price_x192) >> 192
+    } else {
+        if price_x192 == U512::ZERO {
+            return U256::ZERO;
+        }
+        (amount << 192) / price_x192
+    };
+
+    U256::saturating_from(value)
+}
+
+// how far a swap's execution price |amount1 / amount0| landed from the spot
+// price before it, in basis points of the spot price. includes the pool fee,
+// and is the same in both directions since the price always moves against
+// the swapper
+pub(crate) fn price_impact_bps(sqrt_price_before_x96: U160, amount0: I256, amount1: I256) -> f64 {
+    let sqrt_price = U512::from(sqrt_price_before_x96);
+    let spot_x192 = sqrt_price * sqrt_price;
+    let amount0 = U512::from(amount0.unsigned_abs());
+    if spot_x192 == U512::ZERO || amount0 == U512::ZERO {
+        return 0.0;
+    }
+    let execution_x192: U512 = (U512::from(amount1.unsigned_abs()) << 192) / amount0;
+
+    // hundredths of a basis point, kept in integers until the end
+    let impact = execution_x192.abs_diff(spot_x192) * U512::from(1_000_000) / spot_x192;
+    impact.saturating_to::<u64>() as f64 / 100.0
+}
+
+// TickMath's bounds, the ticks of the lowest and highest representable prices
+pub(crate) const MIN_TICK: i32 = -887272;
+pub(crate) const MAX_TICK: i32 = 887272;
+// and their sqrt prices, a pool's price stays in [MIN_SQRT_RATIO, MAX_SQRT_RATIO)
+pub(crate) const MIN_SQRT_RATIO: U160 = uint!(4295128739_U160);
+pub(crate) const MAX_SQRT_RATIO: U160 =
+    uint!(1461446703485210103287273052203988822378723970342_U160);
+
+// sqrt(1.0001^tick) * 2^96, same rounding as TickMath.getSqrtRatioAtTick
+pub(crate) fn sqrt_price_at_tick(tick: I24) -> U256 {
+    const MULTIPLIERS: [u128; 19] = [
+        0xfff97272373d413259a46990580e213a,
+        0xfff2e50f5f656932ef12357cf3c7fdcc,
+        0xffe5caca7e10e4e61c3624eaa0941cd0,
+        0xffcb9843d60f6159c9db58835c926644,
+        0xff973b41fa98c081472e6896dfb254c0,
+        0xff2ea16466c96a3843ec78b326b52861,
+        0xfe5dee046a99a2a811c461f1969c3053,
+        0xfcbe86c7900a88aedcffc83b479aa3a4,
+        0xf987a7253ac413176f2b074cf7815e54,
+        0xf3392b0822b70005940c7a398e4b70f3,
+        0xe7159475a2c29b7443b29c7fa6e889d9,
+        0xd097f3bdfd2022b8845ad8f792aa5825,
+        0xa9f746462d870fdf8a65dc1f90e061e5,
+        0x70d869a156d2a1b890bb3df62baf32f7,
+        0x31be135f97d08fd981231505542fcfa6,
+        0x9aa508b5b7a84e1c677de54f3e99bc9,
+        0x5d6af8dedb81196699c329225ee604,
+        0x2216e584f5fa1ea926041bedfe98,
+        0x48a170391f7dc42444e8fa2,
+    ];
+
+    let abs_tick = tick.unsigned_abs().to::<u32>();
+    let mut ratio = if abs_tick & 1 != 0 {
+        U256::from(0xfffcb933bd6fad37aa2d162d1a594001_u128)
+    } else {
+        U256::from(1) << 128
+    };
+    for (bit, multiplier) in MULTIPLIERS.iter().enumerate() {
+        if abs_tick & (2 << bit) != 0 {
+            ratio = (ratio * U256::from(*multiplier)) >> 128;
+        }
+    }
+    if tick > I24::ZERO {
+        ratio = U256::MAX / ratio;
+    }
+
+    // Q128.128 to Q64.96, rounding up
+    let remainder = ratio & U256::from(u32::MAX);
+    (ratio >> 32) + U256::from(u8::from(remainder != U256::ZERO))
+}
+
+// (amount0, amount1) held by `liquidity` in [lower_tick, upper_tick) at the
+// given price, rounded down like LiquidityAmounts.getAmountsForLiquidity
+pub(crate) fn amounts_for_liquidity(
+    liquidity: u128,
+    sqrt_price_x96: U160,
+    lower_tick: I24,
+    upper_tick: I24,
+) -> (U256, U256) {
+    let sqrt_price = U512::from(sqrt_price_x96);
+    let sqrt_lower = U512::from(sqrt_price_at_tick(lower_tick));
+    let sqrt_upper = U512::from(sqrt_price_at_tick(upper_tick));
+    let liquidity = U512::from(liquidity);
+
+    let amount0 = |from: U512, to: U512| ((liquidity << 96) * (to - from) / to) / from;
+    let amount1 = |from: U512, to: U512| (liquidity * (to - from)) >> 96;
+
+    let (amount0, amount1) = if sqrt_price <= sqrt_lower {
+        (amount0(sqrt_lower, sqrt_upper), U512::ZERO)
+    } else if sqrt_price < sqrt_upper {
+        (
+            amount0(sqrt_price, sqrt_upper),
+            amount1(sqrt_lower, sqrt_price),
+        )
+    } else {
+        (U512::ZERO, amount1(sqrt_lower, sqrt_upper))
+    };
+
+    (
+        U256::saturating_from(amount0),
+        U256::saturating_from(amount1),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // selling `amount` into a single range of `liquidity` at price 1, where
+    // the range is constant product: out = L * x / (L + x)
+    fn sale_out(liquidity: U256, amount: U256) -> U256 {
+        liquidity * amount / (liquidity + amount)
+    }
+
+    // what the swap and quoter valuations report for `token_amount`
+    fn valued(liquidity: U256, token_amount: U256, slices: u32, cap: Option<U256>) -> U256 {
+        let sale = split_sale(token_amount, slices, cap);
+        scale_sale_value(sale_out(liquidity, sale), sale, token_amount)
+    }
+
+    #[test]
+    fn slicing_matters_on_a_thin_pool_and_not_a_deep_one() {
+        let token_amount = U256::from(10u128.pow(21));
+
+        // a thin pool, the single sale walks the price halfway down
+        let thin = U256::from(10u128.pow(21));
+        let single = valued(thin, token_amount, 1, None);
+        let sliced = valued(thin, token_amount, 10, None);
+        assert_eq!(single, token_amount / U256::from(2));
+        assert!(sliced > single * U256::from(17) / U256::from(10));
+        // still under the spot value of the whole amount
+        assert!(sliced < token_amount);
+        // capping each sale at a tenth of the amount is the same as ten slices
+        assert_eq!(
+            valued(thin, token_amount, 1, Some(token_amount / U256::from(10))),
+            sliced
+        );
+
+        // a deep pool barely moves either way
+        let deep = U256::from(10u128.pow(24));
+        let single = valued(deep, token_amount, 1, None);
+        let sliced = valued(deep, token_amount, 10, None);
+        assert!(sliced >= single);
+        assert!(sliced - single < token_amount / U256::from(1_000));
+    }
+
+    #[test]
+    fn a_short_spike_moves_spot_but_barely_the_twap() {
+        let token_amount = U256::from(10u128.pow(18));
+        // tick 0 for 29 minutes, then a minute at tick 6932, about twice the
+        // price, right before valuing
+        let spike = 6932;
+        let spike_tick = I24::try_from(spike).unwrap();
+        let spot_after: U160 = sqrt_price_at_tick(spike_tick).to();
+        let spot = spot_value(token_amount, spot_after, true);
+        let twap = twap_value(token_amount, i64::from(spike) * 60, 1800, true).unwrap();
+
+        let within = |value: U256, expected: u128| {
+            value.abs_diff(U256::from(expected)) < U256::from(expected / 1_000)
+        };
+        assert!(within(spot, 2 * 10u128.pow(18)), "spot {}", spot);
+        // the mean tick is 231, 1.0001^231 of the price
+        assert!(within(twap, 1_023_371_000_000_000_000), "twap {}", twap);
+
+        // the other side of the pool values at the inverse price
+        let twap_token1 = twap_value(token_amount, i64::from(spike) * 60, 1800, false).unwrap();
+        assert!(
+            within(twap_token1, 977_163_000_000_000_000),
+            "twap {}",
+            twap_token1
+        );
+    }
+
+    #[test]
+    fn price_impact_is_the_execution_price_off_spot_in_bps() {
+        let price_one = U160::from(1) << 96;
+        let amount = |amount: i64| I256::try_from(amount).unwrap();
+        // token0 in, 1% less token1 out than spot pays
+        assert_eq!(
+            price_impact_bps(price_one, amount(1_000_000), amount(-990_000)),
+            100.0
+        );
+        // token1 in, paying 1000 for 990 token0 at an execution price of 1.0101
+        assert_eq!(
+            price_impact_bps(price_one, amount(-990_000), amount(1_000_000)),
+            101.01
+        );
+        // a large swap that halves the price it executes at
+        assert_eq!(
+            price_impact_bps(price_one, amount(10_000_000), amount(-5_000_000)),
+            5_000.0
+        );
+        // at price 4 the same 4:1 trade has no impact
+        assert_eq!(
+            price_impact_bps(U160::from(2) << 96, amount(1_000), amount(-4_000)),
+            0.0
+        );
+        assert_eq!(price_impact_bps(price_one, I256::ZERO, amount(5)), 0.0);
+        assert_eq!(price_impact_bps(U160::ZERO, amount(5), amount(-5)), 0.0);
+    }
+
+    #[test]
+    fn the_mean_tick_rounds_toward_negative_infinity() {
+        assert_eq!(mean_tick(7, 2).unwrap(), I24::try_from(3).unwrap());
+        assert_eq!(mean_tick(-7, 2).unwrap(), I24::try_from(-4).unwrap());
+        assert_eq!(mean_tick(-8, 2).unwrap(), I24::try_from(-4).unwrap());
+    }
+
+    #[test]
+    fn sales_round_up_and_never_shrink_to_nothing() {
+        assert_eq!(split_sale(U256::from(10), 3, None), U256::from(4));
+        assert_eq!(split_sale(U256::from(10), 0, None), U256::from(10));
+        assert_eq!(
+            split_sale(U256::from(10), 1, Some(U256::ZERO)),
+            U256::from(1)
+        );
+        assert_eq!(
+            scale_sale_value(U256::from(7), U256::from(10), U256::from(10)),
+            U256::from(7)
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use alloy::primitives::{Address, U256};
+use eyre::{bail, Result};
+
+use crate::{
+    abi::Weth::WethInstance,
+    error::FeeAnalyzerError,
+    fee_analyzer::{ArcAnvilHttpProvider, HttpClient},
+};
+
+// the wrapped native token calls the account setup makes. WETH9 is the
+// default, implement this for another wrapper's bindings when its deposit or
+// approve signatures differ (e.g. deposit taking an amount, or approve not
+// returning a bool) and pass it to initialize_simulation_account
+pub(crate) trait WrappedNative {
+    // wraps `amount` of `from`'s native balance
+    async fn deposit(&self, from: Address, amount: U256) -> Result<()>;
+
+    async fn approve(&self, owner: Address, spender: Address, amount: U256) -> Result<()>;
+}
+
+impl WrappedNative for WethInstance<HttpClient, ArcAnvilHttpProvider> {
+    async fn deposit(&self, from: Address, amount: U256) -> Result<()> {
+        let receipt = WethInstance::deposit(self)
+            .from(from)
+            .value(amount)
+            .send()
+            .await?
+            .get_receipt()
+            .await?;
+        if !receipt.inner.status() {
+            bail!(FeeAnalyzerError::PoolSetup(format!(
+                "Failed to deposit into weth for {}",
+                from
+            )));
+        }
+        Ok(())
+    }
+
+    async fn approve(&self, owner: Address, spender: Address, amount: U256) -> Result<()> {
+        let receipt = WethInstance::approve(self, spender, amount)
+            .from(owner)
+            .send()
+            .await?
+            .get_receipt()
+            .await?;
+        if !receipt.inner.status() {
+            bail!(FeeAnalyzerError::PoolSetup(format!(
+                "Failed to approve weth for {}",
+                spender
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use eyre::eyre;
+
+    use super::*;
+    use crate::chain_interactions::approve_weth;
+
+    // records the calls, refusing approvals for `refused_spender`
+    #[derive(Default)]
+    struct MockWrapper {
+        approvals: Mutex<Vec<(Address, Address, U256)>>,
+        refused_spender: Option<Address>,
+    }
+
+    impl WrappedNative for MockWrapper {
+        async fn deposit(&self, _from: Address, _amount: U256) -> Result<()> {
+            unreachable!("approvals don't deposit")
+        }
+
+        async fn approve(&self, owner: Address, spender: Address, amount: U256) -> Result<()> {
+            if self.refused_spender == Some(spender) {
+                return Err(eyre!("approve reverted"));
+            }
+            self.approvals
+                .lock()
+                .unwrap()
+                .push((owner, spender, amount));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn approvals_go_through_the_wrapper() {
+        let (position_manager, swap_router, approver) = (
+            Address::repeat_byte(0x01),
+            Address::repeat_byte(0x02),
+            Address::repeat_byte(0x03),
+        );
+        let wrapper = MockWrapper::default();
+        approve_weth(&wrapper, &position_manager, &swap_router, approver)
+            .await
+            .unwrap();
+        assert_eq!(
+            *wrapper.approvals.lock().unwrap(),
+            vec![
+                (approver, swap_router, U256::MAX),
+                (approver, position_manager, U256::MAX),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_refused_approval_names_the_spender() {
+        let (position_manager, swap_router) =
+            (Address::repeat_byte(0x01), Address::repeat_byte(0x02));
+        let wrapper = MockWrapper {
+            refused_spender: Some(position_manager),
+            ..MockWrapper::default()
+        };
+        let error = approve_weth(&wrapper, &position_manager, &swap_router, Address::ZERO)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to approve weth for position manager"
+        );
+        // the router's went through before it
+        assert_eq!(wrapper.approvals.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,552 @@
+use std::{path::PathBuf, time::Duration};
+
+use alloy::primitives::{Address, I256, U256};
+use chrono::Utc;
+use clap::{ArgAction, Args, Parser, Subcommand};
+
+use crate::{
+    chain_interactions::{
+        valuation::ValuationMethod, ApprovalMode, ClankerTokenParams, L2Profile, PoolSetupMode,
+        SwapFailurePolicy, VerificationLevel,
+    },
+    fee_analyzer::{
+        csv_input_reader::CSVReaderConfig, simulation_events::EventType, PoolAnalyzerConfig,
+    },
+};
+
+// every flag falls back to the env var of the same name, so the existing
+// `.env` setup keeps working and flags only need to be passed to override it
+#[derive(Debug, Parser)]
+#[command(about = "Replays a Uniswap V3 pool's events on an Anvil fork to analyze LP fees")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    // replay the pool and write the position pnl csv
+    #[command(about = "Replay the pool's events and write the position PnL CSV")]
+    Run(RunArgs),
+    // connect, check the contracts, and load the events without replaying
+    #[command(about = "Check the configuration and input files without replaying")]
+    Validate(RunArgs),
+    // only read the input csvs
+    #[command(about = "Summarize the input event CSVs")]
+    Summary(CsvArgs),
+    // only read the input csvs and write them back out normalized
+    #[command(about = "Write the normalized input events back out as per type CSVs")]
+    ExportEvents(ExportEventsArgs),
+    // only read the input csvs' headers and first rows
+    #[command(about = "Check the input CSVs' columns and first rows without replaying")]
+    ValidateCsv(CsvArgs),
+    // replay one position's swaps with a different tick range
+    #[command(about = "Simulate a position with the same deposit in a different tick range")]
+    Counterfactual(CounterfactualArgs),
+    // only read two runs' output csvs
+    #[command(about = "Compare two runs' position CSVs record by record")]
+    DiffPositions(DiffPositionsArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct CounterfactualArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+    // original (on-chain) token id of the position to re-range
+    #[arg(long)]
+    pub token_id: U256,
+    #[arg(long, allow_hyphen_values = true)]
+    pub lower_tick: i32,
+    #[arg(long, allow_hyphen_values = true)]
+    pub upper_tick: i32,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffPositionsArgs {
+    // the baseline run's positions csv
+    #[arg(long)]
+    pub a: PathBuf,
+    // the run with the changed settings, deltas are b minus a
+    #[arg(long)]
+    pub b: PathBuf,
+    #[arg(long, default_value = "diff.csv")]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportEventsArgs {
+    #[command(flatten)]
+    pub csv: CsvArgs,
+    // directory the per type csvs are written to
+    #[arg(long, env = "EXPORT_EVENTS_DIR")]
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct CsvArgs {
+    #[arg(
+        long,
+        env = "INITIALIZE_CSV_FILE_PATH",
+        required_unless_present = "unified_csv"
+    )]
+    pub initialize_csv: Option<String>,
+    #[arg(
+        long,
+        env = "SWAP_CSV_FILE_PATH",
+        required_unless_present = "unified_csv"
+    )]
+    pub swap_csv: Option<String>,
+    #[arg(
+        long,
+        env = "MINT_CSV_FILE_PATH",
+        required_unless_present = "unified_csv"
+    )]
+    pub mint_csv: Option<String>,
+    #[arg(
+        long,
+        env = "BURN_CSV_FILE_PATH",
+        required_unless_present = "unified_csv"
+    )]
+    pub burn_csv: Option<String>,
+    #[arg(
+        long,
+        env = "POOL_CREATED_CSV_FILE_PATH",
+        required_unless_present = "unified_csv"
+    )]
+    pub pool_created_csv: Option<String>,
+    #[arg(
+        long,
+        env = "COLLECT_POOL_CSV_FILE_PATH",
+        required_unless_present = "unified_csv"
+    )]
+    pub collect_pool_csv: Option<String>,
+    #[arg(
+        long,
+        env = "COLLECT_NPM_CSV_FILE_PATH",
+        required_unless_present = "unified_csv"
+    )]
+    pub collect_npm_csv: Option<String>,
+    #[arg(
+        long,
+        env = "INCREASE_LIQUIDITY_CSV_FILE_PATH",
+        required_unless_present = "unified_csv"
+    )]
+    pub increase_liquidity_csv: Option<String>,
+    #[arg(
+        long,
+        env = "DECREASE_LIQUIDITY_CSV_FILE_PATH",
+        required_unless_present = "unified_csv"
+    )]
+    pub decrease_liquidity_csv: Option<String>,
+    // optional, nft transfers for attributing records to their owner at the time
+    #[arg(long, env = "TRANSFER_CSV_FILE_PATH")]
+    pub transfer_csv: Option<String>,
+    #[arg(long, env = "CSV_HAS_HEADERS", default_value_t = true, action = ArgAction::Set)]
+    pub csv_has_headers: bool,
+    #[arg(long, env = "GAP_CHECK_MAX_BLOCKS")]
+    pub gap_check_max_blocks: Option<u64>,
+    // one csv with an event_type column instead of the nine files above
+    #[arg(long, env = "UNIFIED_EVENTS_CSV_FILE_PATH")]
+    pub unified_csv: Option<String>,
+    #[arg(long, env = "EVENT_CACHE_PATH")]
+    pub event_cache_path: Option<PathBuf>,
+}
+
+impl From<CsvArgs> for CSVReaderConfig {
+    fn from(args: CsvArgs) -> Self {
+        CSVReaderConfig {
+            initialize_events_path: args.initialize_csv.unwrap_or_default(),
+            swap_events_path: args.swap_csv.unwrap_or_default(),
+            mint_events_path: args.mint_csv.unwrap_or_default(),
+            burn_events_path: args.burn_csv.unwrap_or_default(),
+            collect_pool_events_path: args.collect_pool_csv.unwrap_or_default(),
+            collect_npm_events_path: args.collect_npm_csv.unwrap_or_default(),
+            increase_liquidity_events_path: args.increase_liquidity_csv.unwrap_or_default(),
+            decrease_liquidity_events_path: args.decrease_liquidity_csv.unwrap_or_default(),
+            pool_created_events_path: args.pool_created_csv.unwrap_or_default(),
+            transfer_events_path: args.transfer_csv,
+            has_headers: args.csv_has_headers,
+            gap_check_max_blocks: args.gap_check_max_blocks,
+            unified_events_path: args.unified_csv,
+            event_cache_path: args.event_cache_path,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    #[arg(long, env = "HTTP_URL")]
+    pub http_url: String,
+    #[arg(long, env = "BLOCK_FORK_NUMBER")]
+    pub fork_block: Option<u64>,
+    #[arg(long, env = "AUTO_FORK_BLOCK", default_value_t = false, action = ArgAction::Set)]
+    pub auto_fork_block: bool,
+    // none, mainnet, optimism (or base), or arbitrum. the settings below
+    // override the profile's
+    #[arg(long, env = "L2_PROFILE", default_value = "none")]
+    pub l2_profile: L2Profile,
+    #[arg(long, env = "ANVIL_CHAIN_ID")]
+    pub anvil_chain_id: Option<u64>,
+    #[arg(long, env = "DISABLE_BASE_FEE", action = ArgAction::Set)]
+    pub disable_base_fee: Option<bool>,
+    #[arg(long, env = "ANVIL_BASE_FEE")]
+    pub anvil_base_fee: Option<u64>,
+    // 0 for anvil's own default
+    #[arg(long, env = "ANVIL_GAS_LIMIT")]
+    pub anvil_gas_limit: Option<u64>,
+    #[arg(long, env = "BLOCK_TIME_MS")]
+    pub block_time_ms: Option<u64>,
+    #[arg(long, env = "UNISWAP_V3_FACTORY_ADDRESS")]
+    pub factory: Address,
+    #[arg(long, env = "UNISWAP_V3_POSITION_MANAGER_ADDRESS")]
+    pub position_manager: Address,
+    #[arg(long, env = "UNISWAP_V3_SWAP_ROUTER_ADDRESS")]
+    pub swap_router: Address,
+    #[arg(long, env = "UNISWAP_V3_QUOTER_ADDRESS")]
+    pub quoter: Address,
+    #[arg(long, env = "WETH_ADDRESS")]
+    pub weth: Address,
+    #[command(flatten)]
+    pub csv: CsvArgs,
+    #[arg(long, env = "OUTPUT_CSV_FILE_PATH")]
+    pub output_csv: String,
+    #[arg(long, env = "KEEP_ANVIL_ALIVE", default_value_t = false, action = ArgAction::Set)]
+    pub keep_anvil_alive: bool,
+    #[arg(long, env = "NATIVE_ETH_SWAPS", default_value_t = false, action = ArgAction::Set)]
+    pub native_eth_swaps: bool,
+    // swap, quoter, spot, or twap
+    #[arg(long, env = "VALUATION_METHOD", default_value = "swap")]
+    pub valuation_method: ValuationMethod,
+    #[arg(long, env = "TWAP_WINDOW_SECONDS", default_value_t = 1800)]
+    pub twap_window_seconds: u32,
+    #[arg(long, env = "VALUATION_SLICES", default_value_t = 1)]
+    pub valuation_slices: u32,
+    #[arg(long, env = "VALUATION_MAX_SALE_BPS")]
+    pub valuation_max_sale_bps: Option<u32>,
+    #[arg(long, env = "CLOSE_OUT_CONCURRENCY", default_value_t = 1)]
+    pub close_out_concurrency: usize,
+    // pools replayed at once when the input csvs hold several, each on its
+    // own fork. only read by `run`
+    #[arg(long, env = "POOL_CONCURRENCY", default_value_t = 1)]
+    pub pool_concurrency: usize,
+    #[arg(long, env = "DUST_THRESHOLD_WETH", default_value = "0")]
+    pub dust_threshold_weth: U256,
+    #[arg(long, env = "EXCLUDE_DUST_FROM_OUTPUT", default_value_t = false, action = ArgAction::Set)]
+    pub exclude_dust_from_output: bool,
+    #[arg(long, env = "SWAP_DIRECTION_TOLERANCE_WEI", default_value = "1")]
+    pub swap_direction_tolerance_wei: U256,
+    #[arg(long, env = "EXACT_OUTPUT_SLACK_BPS", default_value_t = 1)]
+    pub exact_output_slack_bps: u32,
+    // fallback, skip, or fail
+    #[arg(long, env = "SWAP_FAILURE_POLICY", default_value = "fallback")]
+    pub swap_failure_policy: SwapFailurePolicy,
+    #[arg(long, env = "CLANKER_TOKEN_NAME")]
+    pub clanker_token_name: Option<String>,
+    #[arg(long, env = "CLANKER_TOKEN_SYMBOL")]
+    pub clanker_token_symbol: Option<String>,
+    #[arg(long, env = "CLANKER_TOKEN_SUPPLY")]
+    pub clanker_token_supply: Option<U256>,
+    // the original token's decimals, the stand-in only has 18
+    #[arg(long, env = "CLANKER_TOKEN_DECIMALS")]
+    pub clanker_token_decimals: Option<u8>,
+    #[arg(long, env = "CLANKER_TOKEN_FID")]
+    pub clanker_token_fid: Option<U256>,
+    #[arg(long, env = "CLANKER_TOKEN_IMAGE")]
+    pub clanker_token_image: Option<String>,
+    #[arg(long, env = "CLANKER_TOKEN_CAST_HASH")]
+    pub clanker_token_cast_hash: Option<String>,
+    #[arg(long, env = "CLONE_TOKEN_CODE", default_value_t = false, action = ArgAction::Set)]
+    pub clone_token_code: bool,
+    #[arg(long, env = "POOL_SETUP_MODE", default_value = "deploy_standin")]
+    pub pool_setup_mode: PoolSetupMode,
+    #[arg(long, env = "FORK_CACHE_PATH")]
+    pub fork_cache_path: Option<PathBuf>,
+    #[arg(long, env = "RESUME_POSITIONS_PATH")]
+    pub resume_positions_path: Option<PathBuf>,
+    #[arg(long, env = "ADDRESS_MAP_PATH")]
+    pub address_map_path: Option<PathBuf>,
+    #[arg(long, env = "TOKEN_ID_MAP_PATH")]
+    pub token_id_map_path: Option<PathBuf>,
+    #[arg(long, env = "DEPLOYER_ACCOUNT")]
+    pub deployer_account: Option<Address>,
+    #[arg(long, env = "SWAP_ACCOUNT")]
+    pub swap_account: Option<Address>,
+    #[arg(long, env = "MINT_ACCOUNT")]
+    pub mint_account: Option<Address>,
+    #[arg(long, env = "INCREMENTAL_CSV", default_value_t = false, action = ArgAction::Set)]
+    pub incremental_csv: bool,
+    #[arg(long, env = "MAX_EVENTS")]
+    pub max_events: Option<usize>,
+    // comma separated, defaults to every type
+    #[arg(
+        long,
+        env = "ENABLED_EVENT_TYPES",
+        value_delimiter = ',',
+        default_value = "pool_created,mint,burn,swap,collect_pool,collect_npm,\
+                         increase_liquidity,decrease_liquidity,initialize,transfer"
+    )]
+    pub enabled_event_types: Vec<EventType>,
+    #[arg(long, env = "RUN_TIMEOUT_SECS")]
+    pub run_timeout_secs: Option<u64>,
+    #[arg(long, env = "RPC_TIMEOUT_SECS")]
+    pub rpc_timeout_secs: Option<u64>,
+    #[arg(long, env = "MINT_AMOUNT_TOLERANCE_WEI", default_value = "1")]
+    pub mint_amount_tolerance_wei: U256,
+    #[arg(long, env = "POOL_ADDRESS")]
+    pub pool_address: Option<Address>,
+    #[arg(long, env = "COLLECT_RECIPIENT")]
+    pub collect_recipient: Option<Address>,
+    #[arg(long, env = "LAZY_TOKEN_TRANSFERS", default_value_t = false, action = ArgAction::Set)]
+    pub lazy_token_transfers: bool,
+    #[arg(long, env = "PER_SWAPPER_ACCOUNTS", default_value_t = false, action = ArgAction::Set)]
+    pub per_swapper_accounts: bool,
+    // strict, swaps, or none
+    #[arg(long, env = "VERIFICATION_LEVEL", default_value = "strict")]
+    pub verification_level: VerificationLevel,
+    #[arg(long, env = "VERIFY_FINAL_POOL_STATE", default_value_t = false, action = ArgAction::Set)]
+    pub verify_final_pool_state: bool,
+    #[arg(long, env = "FEE_INVARIANT_CHECK", default_value_t = false, action = ArgAction::Set)]
+    pub fee_invariant_check: bool,
+    // max or exact
+    #[arg(long, env = "APPROVAL_MODE", default_value = "max")]
+    pub approval_mode: ApprovalMode,
+    #[arg(long, env = "OPTIMISTIC_EXACT_INPUT", default_value_t = false, action = ArgAction::Set)]
+    pub optimistic_exact_input: bool,
+    #[arg(long, env = "WRITE_WARNINGS_CSV", default_value_t = false, action = ArgAction::Set)]
+    pub write_warnings_csv: bool,
+    #[arg(long, env = "WRITE_EXACT_OUTPUT_SWAPS", default_value_t = false, action = ArgAction::Set)]
+    pub write_exact_output_swaps: bool,
+    #[arg(long, env = "VERIFY_AGAINST")]
+    pub verify_against: Option<PathBuf>,
+    #[arg(long, env = "VERIFY_TOLERANCE_WEI", default_value = "0")]
+    pub verify_tolerance_wei: U256,
+    #[cfg(feature = "sqlite")]
+    #[arg(long, env = "SQLITE_OUTPUT_PATH")]
+    pub sqlite_output_path: Option<PathBuf>,
+    // may hold `{timestamp}`, `{pool_address}` and `{fee_tier}` like the
+    // output path
+    #[cfg(feature = "sqlite")]
+    #[arg(
+        long,
+        env = "SQLITE_RUN_ID",
+        default_value = "{pool_address}-{timestamp}"
+    )]
+    pub sqlite_run_id: String,
+    #[arg(long, env = "GROUPED_OUTPUT", default_value_t = false, action = ArgAction::Set)]
+    pub grouped_output: bool,
+    #[arg(long, env = "MERGE_REOPENED_POSITIONS", default_value_t = false, action = ArgAction::Set)]
+    pub merge_reopened_positions: bool,
+    #[arg(long, env = "ENFORCE_MIN_AMOUNTS", default_value_t = false, action = ArgAction::Set)]
+    pub enforce_min_amounts: bool,
+    // comma separated, in weth wei, defaults to -1, -0.1, -0.01, 0, 0.01, 0.1, 1 weth
+    #[arg(
+        long,
+        env = "PNL_HISTOGRAM_BOUNDARIES",
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        default_value = "-1000000000000000000,-100000000000000000,-10000000000000000,0,\
+                         10000000000000000,100000000000000000,1000000000000000000"
+    )]
+    pub pnl_histogram_boundaries: Vec<I256>,
+    #[arg(long, env = "PNL_HISTOGRAM_CHART", default_value_t = false, action = ArgAction::Set)]
+    pub pnl_histogram_chart: bool,
+    #[arg(long, env = "CAPITAL_EFFICIENCY_TOP_N", default_value_t = 10)]
+    pub capital_efficiency_top_n: usize,
+    // comma separated original block numbers
+    #[arg(long, env = "VALUE_POSITIONS_AT_BLOCKS", value_delimiter = ',')]
+    pub value_positions_at_blocks: Vec<u64>,
+}
+
+impl From<RunArgs> for PoolAnalyzerConfig {
+    fn from(args: RunArgs) -> Self {
+        // one timestamp for the whole run, so every pool of a batch shares it
+        let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let default_token_params = ClankerTokenParams::default();
+        let clanker_token_params = ClankerTokenParams {
+            name: args.clanker_token_name.unwrap_or(default_token_params.name),
+            symbol: args
+                .clanker_token_symbol
+                .unwrap_or(default_token_params.symbol),
+            decimals: args
+                .clanker_token_decimals
+                .unwrap_or(default_token_params.decimals),
+            max_supply: args
+                .clanker_token_supply
+                .unwrap_or(default_token_params.max_supply),
+            fid: args.clanker_token_fid.unwrap_or(default_token_params.fid),
+            image: args
+                .clanker_token_image
+                .unwrap_or(default_token_params.image),
+            cast_hash: args
+                .clanker_token_cast_hash
+                .unwrap_or(default_token_params.cast_hash),
+        };
+
+        PoolAnalyzerConfig {
+            http_url: args.http_url,
+            fork_block: args.fork_block,
+            auto_fork_block: args.auto_fork_block,
+            l2_profile: L2Profile {
+                chain_id: args.anvil_chain_id.or(args.l2_profile.chain_id),
+                disable_base_fee: args
+                    .disable_base_fee
+                    .unwrap_or(args.l2_profile.disable_base_fee),
+                base_fee: args.anvil_base_fee.or(args.l2_profile.base_fee),
+                gas_limit: match args.anvil_gas_limit {
+                    Some(0) => None,
+                    Some(gas_limit) => Some(gas_limit),
+                    None => args.l2_profile.gas_limit,
+                },
+                block_time_ms: args.block_time_ms.or(args.l2_profile.block_time_ms),
+                ..args.l2_profile
+            },
+            uniswap_v3_factory_address: args.factory,
+            uniswap_v3_position_manager_address: args.position_manager,
+            uniswap_v3_swap_router_address: args.swap_router,
+            uniswap_v3_quoter_address: args.quoter,
+            weth_address: args.weth,
+            config: args.csv.into(),
+            output_csv_file_path: args.output_csv.replace("{timestamp}", &timestamp),
+            keep_alive: args.keep_anvil_alive,
+            native_eth: args.native_eth_swaps,
+            valuation_method: args.valuation_method,
+            twap_window: args.twap_window_seconds,
+            valuation_slices: args.valuation_slices,
+            valuation_max_sale_bps: args.valuation_max_sale_bps,
+            close_out_concurrency: args.close_out_concurrency,
+            dust_threshold_weth: args.dust_threshold_weth,
+            exclude_dust_from_output: args.exclude_dust_from_output,
+            swap_direction_tolerance: args.swap_direction_tolerance_wei,
+            exact_output_slack_bps: args.exact_output_slack_bps,
+            swap_failure_policy: args.swap_failure_policy,
+            clanker_token_params,
+            clone_token_code: args.clone_token_code,
+            pool_setup_mode: args.pool_setup_mode,
+            fork_cache_path: args.fork_cache_path,
+            resume_path: args.resume_positions_path,
+            address_map_path: args.address_map_path,
+            token_id_map_path: args.token_id_map_path,
+            deployer_account: args.deployer_account,
+            swap_account: args.swap_account,
+            mint_account: args.mint_account,
+            incremental_csv: args.incremental_csv,
+            max_events: args.max_events,
+            enabled_event_types: args.enabled_event_types.into_iter().collect(),
+            run_timeout: args.run_timeout_secs.map(Duration::from_secs),
+            rpc_timeout: args.rpc_timeout_secs.map(Duration::from_secs),
+            mint_amount_tolerance: args.mint_amount_tolerance_wei,
+            pool_address: args.pool_address,
+            collect_recipient: args.collect_recipient,
+            lazy_token_transfers: args.lazy_token_transfers,
+            per_swapper_accounts: args.per_swapper_accounts,
+            verification: args.verification_level,
+            verify_final_pool_state: args.verify_final_pool_state,
+            fee_invariant_check: args.fee_invariant_check,
+            approval_mode: args.approval_mode,
+            optimistic_exact_input: args.optimistic_exact_input,
+            write_warnings_csv: args.write_warnings_csv,
+            write_exact_output_swaps: args.write_exact_output_swaps,
+            verify_against: args.verify_against,
+            verify_tolerance_wei: args.verify_tolerance_wei,
+            #[cfg(feature = "sqlite")]
+            sqlite_output_path: args.sqlite_output_path,
+            #[cfg(feature = "sqlite")]
+            sqlite_run_id: args.sqlite_run_id.replace("{timestamp}", &timestamp),
+            grouped_output: args.grouped_output,
+            merge_reopened_positions: args.merge_reopened_positions,
+            enforce_min_amounts: args.enforce_min_amounts,
+            pnl_histogram_boundaries: args.pnl_histogram_boundaries,
+            pnl_histogram_chart: args.pnl_histogram_chart,
+            capital_efficiency_top_n: args.capital_efficiency_top_n,
+            value_positions_at_blocks: args.value_positions_at_blocks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUN: &[&str] = &[
+        "analyzer",
+        "run",
+        "--http-url",
+        "http://localhost:8545",
+        "--factory",
+        "0x33128a8fC17869897dcE68Ed026d694621f6FDfD",
+        "--position-manager",
+        "0x03a520b32C04BF3bEEf7BEb72E919cf822Ed34f1",
+        "--swap-router",
+        "0x2626664c2603336E57B271c5C0b26F421741e481",
+        "--quoter",
+        "0x3d4e44Eb1374240CE5F1B871ab261CD16335B76a",
+        "--unified-csv",
+        "events.csv",
+        "--output-csv",
+        "positions_{timestamp}.csv",
+    ];
+
+    fn run_args(cli: Cli) -> RunArgs {
+        match cli.command {
+            Command::Run(args) => args,
+            other => panic!("expected run, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn run_flags_fall_back_to_env_and_defaults() {
+        let weth = "0x4200000000000000000000000000000000000006";
+        // neither flag is given, only the env var
+        std::env::set_var("WETH_ADDRESS", weth);
+        let args = run_args(Cli::try_parse_from(RUN).unwrap());
+
+        assert_eq!(args.weth, weth.parse::<Address>().unwrap());
+        assert_eq!(args.l2_profile, L2Profile::default());
+        assert_eq!(args.valuation_method, ValuationMethod::Swap);
+        assert_eq!(args.swap_failure_policy, SwapFailurePolicy::Fallback);
+        assert_eq!(args.verification_level, VerificationLevel::Strict);
+        assert_eq!(args.twap_window_seconds, 1800);
+        assert_eq!(args.swap_direction_tolerance_wei, U256::from(1));
+        assert!(args.csv.csv_has_headers);
+        assert_eq!(args.enabled_event_types.len(), 10);
+        assert_eq!(args.pnl_histogram_boundaries.len(), 7);
+        assert!(args.value_positions_at_blocks.is_empty());
+
+        let config = PoolAnalyzerConfig::from(args);
+        assert!(!config.output_csv_file_path.contains("{timestamp}"));
+        assert_eq!(
+            config.config.unified_events_path.as_deref(),
+            Some("events.csv")
+        );
+    }
+
+    #[test]
+    fn flags_take_precedence_and_csv_paths_are_required() {
+        let mut argv = RUN.to_vec();
+        argv.extend([
+            "--weth",
+            "0x4200000000000000000000000000000000000006",
+            "--csv-has-headers",
+            "false",
+            "--value-positions-at-blocks",
+            "10,20",
+            "--pnl-histogram-boundaries",
+            "-5,5",
+        ]);
+        let args = run_args(Cli::try_parse_from(&argv).unwrap());
+        assert!(!args.csv.csv_has_headers);
+        assert_eq!(args.value_positions_at_blocks, [10, 20]);
+        assert_eq!(
+            args.pnl_histogram_boundaries,
+            [I256::try_from(-5).unwrap(), I256::try_from(5).unwrap()]
+        );
+
+        // without the unified csv each per type csv is required
+        let error = Cli::try_parse_from(["analyzer", "validate-csv"]).unwrap_err();
+        assert_eq!(
+            error.kind(),
+            clap::error::ErrorKind::MissingRequiredArgument
+        );
+        assert!(error.to_string().contains("--initialize-csv"));
+    }
+}
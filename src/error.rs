@@ -0,0 +1,30 @@
+use thiserror::Error;
+
+// failure categories a caller can act on. they travel inside eyre reports,
+// so match them with `report.downcast_ref::<FeeAnalyzerError>()`
+#[derive(Debug, Error)]
+pub enum FeeAnalyzerError {
+    // an input csv couldn't be opened or a row didn't parse
+    #[error("{path}: {reason}")]
+    CsvParse { path: String, reason: String },
+    // events are missing, duplicated, or not in the order the replay expects
+    #[error("{0}")]
+    EventOrdering(String),
+    // deploying, configuring, or funding the simulated pool failed
+    #[error("{0}")]
+    PoolSetup(String),
+    // a replayed action's logs don't match the original event
+    #[error("{0}")]
+    SwapMismatch(String),
+    #[error("{0}")]
+    MintMismatch(String),
+    #[error("{0}")]
+    BurnMismatch(String),
+    // a collect reverted, logged nothing, or didn't pay the recipient
+    #[error("{0}")]
+    CollectFailed(String),
+    // a tick range collected more fees than the pool's fee growth inside it
+    // accounts for, so something was counted twice
+    #[error("{0}")]
+    FeeInvariant(String),
+}
@@ -0,0 +1,180 @@
+use std::sync::atomic::AtomicBool;
+
+use alloy::primitives::{aliases::I24, U256};
+use eyre::{Context, ContextCompat, Result};
+use tracing::{info, warn};
+
+use crate::{
+    abi::UniswapV3Pool::Mint,
+    chain_interactions::{
+        collect::{create_position_info_from_mint_event, pool_close_out_position, PositionInfo},
+        mint::{pool_mint_with_ticks, send_clanker_tokens},
+        swap::pool_swap_unchecked,
+    },
+};
+
+use super::{
+    annotate_fee_yields, annotate_from_samples,
+    simulation_events::{Event, SimulationEvent},
+    PoolAnalyzer, RunDeadline,
+};
+
+impl PoolAnalyzer {
+    // what the position first minted as `original_token_id` would have done
+    // with the same deposited amounts in [lower_tick, upper_tick).
+    //
+    // events before the original mint are replayed as usual. the original mint
+    // is replaced by the counterfactual one, and from there on only swaps are
+    // replayed, with the event's input amounts and no outcome checks since the
+    // pool's liquidity no longer matches the original. other lps' mints and
+    // burns are skipped. the position is closed out where the original's
+    // first record ended (its first liquidity change, or the end of the
+    // events). because of the skipped liquidity changes, compare
+    // counterfactuals with each other (including one with the original ticks)
+    // rather than with the replayed position.
+    pub async fn simulate_counterfactual_position(
+        &mut self,
+        original_token_id: U256,
+        lower_tick: I24,
+        upper_tick: I24,
+    ) -> Result<PositionInfo> {
+        let mut events = self
+            .pool_simulation_events
+            .take()
+            .context("Events were already replayed")?;
+        // pool created and initialize are handled in initialize
+        events.drain(..2.min(events.len()));
+
+        // the position manager's increase liquidity directly follows the
+        // pool mint that opened the position
+        let increase_index = events
+            .iter()
+            .position(|event| {
+                matches!(&event.event, Event::IncreaseLiquidity(e) if e.event.tokenId == original_token_id)
+            })
+            .with_context(|| format!("No mint found for token id {}", original_token_id))?;
+        let mint_index = increase_index
+            .checked_sub(1)
+            .filter(|i| matches!(events[*i].event, Event::Mint(_)))
+            .with_context(|| {
+                format!("No pool mint before token id {}'s mint", original_token_id)
+            })?;
+        let after_open = events.split_off(increase_index + 1);
+        let original_mint = events[mint_index].clone();
+        events.truncate(mint_index);
+
+        info!("Replaying {} events up to the original mint", events.len());
+        self.replay_events(
+            &mut events.into_iter().peekable(),
+            &AtomicBool::new(false),
+            &RunDeadline::default(),
+        )
+        .await?;
+
+        let mint_event = Mint::try_from(original_mint.clone())?;
+        send_clanker_tokens(
+            self.clanker_token.clone(),
+            &self.pool_config,
+            self.mint_account,
+            &self.token_holder.unwrap_or(self.swap_account),
+            &mint_event,
+        )
+        .await?;
+        self.approve_mint_amounts(mint_event.amount0, mint_event.amount1)
+            .await?;
+        let (token_id, counterfactual_mint, gas_used) = pool_mint_with_ticks(
+            self.nonfungible_position_manager.clone(),
+            &self.pool_config,
+            self.mint_account,
+            &mint_event,
+            lower_tick,
+            upper_tick,
+        )
+        .await
+        .context("Failed to mint the counterfactual position")?;
+
+        let mut position = create_position_info_from_mint_event(
+            &self.backend,
+            &self.pool_config,
+            &self.valuer,
+            SimulationEvent {
+                event: Event::Mint(counterfactual_mint),
+                ..original_mint
+            },
+            token_id,
+            original_token_id,
+        )
+        .await?;
+        position.gas_used_in = gas_used;
+
+        // the original's first record ends at its first liquidity change
+        let is_original_change = |event: &SimulationEvent| match &event.event {
+            Event::IncreaseLiquidity(e) => e.event.tokenId == original_token_id,
+            Event::DecreaseLiquidity(e) => e.event.tokenId == original_token_id,
+            _ => false,
+        };
+        let close_index = after_open
+            .iter()
+            .position(is_original_change)
+            .unwrap_or(after_open.len());
+        let block_out = after_open.get(close_index).map_or(0, |event| event.block);
+        self.block_clock
+            .record(original_mint.block, original_mint.block_time);
+        for event in &after_open {
+            self.block_clock.record(event.block, event.block_time);
+        }
+
+        let mut skipped = 0;
+        for event in &after_open[..close_index] {
+            match &event.event {
+                Event::Swap(e) => {
+                    let slot0_before = self.pool.slot0().call().await?;
+                    let swapper = self.swapper(event).await?;
+                    self.top_up_swap_input(swapper, &e.event).await?;
+                    self.approve_swap_input(swapper, &e.event).await?;
+                    self.gas_used.swap += pool_swap_unchecked(
+                        self.pool.clone(),
+                        self.swap_router.clone(),
+                        &e.event,
+                        swapper,
+                    )
+                    .await?;
+                    self.sample_pool(
+                        event.block,
+                        slot0_before.sqrtPriceX96,
+                        slot0_before.tick,
+                        &e.event,
+                    )
+                    .await?;
+                }
+                _ => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            warn!(
+                "Skipped {} non-swap events while the counterfactual position was open",
+                skipped
+            );
+        }
+
+        pool_close_out_position(
+            &self.backend,
+            &self.valuer,
+            &self.pool_config,
+            self.mint_account,
+            self.fee_recipient,
+            token_id,
+            &mut position,
+            block_out,
+        )
+        .await?;
+        annotate_from_samples(&mut position, &self.liquidity_depth);
+        annotate_fee_yields(
+            &mut position,
+            &self.block_clock,
+            self.pool_config.clanker_is_token0(),
+        );
+
+        Ok(position)
+    }
+}
@@ -1,23 +1,33 @@
-use std::str::FromStr;
-
-use alloy::primitives::{
-    aliases::{I24, U24},
-    Address, TxHash, I256, U160, U256,
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
-use chrono::{DateTime, Utc};
-use eyre::{bail, Result};
-use serde::{Deserialize, Serialize};
-use tracing::info;
 
+use csv::{ReaderBuilder, StringRecord, Writer};
+
+use alloy::primitives::{aliases::I24, Address, Signed, TxHash, Uint, I256, U256};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use eyre::{bail, eyre, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use super::event_cache::{input_hash, read_event_cache, write_event_cache};
 use super::simulation_events::{
-    DecreaseLiquidityWithParams, Event, IncreaseLiquidityWithParams, SimulationEvent,
+    dedup_events, find_event_gaps, DecreaseLiquidityWithParams, Event, EventType,
+    IncreaseLiquidityWithParams, SimulationEvent, SwapWithParams,
 };
 use crate::abi::{
-    INonfungiblePositionManager::{Collect as CollectNpm, DecreaseLiquidity, IncreaseLiquidity},
+    INonfungiblePositionManager::{
+        Collect as CollectNpm, DecreaseLiquidity, IncreaseLiquidity, Transfer,
+    },
     IUniswapV3Factory::PoolCreated,
     UniswapV3Pool::{Burn, Collect as CollectPool, Initialize, Mint, Swap},
 };
+use crate::chain_interactions::valuation::{MAX_TICK, MIN_TICK};
+use crate::error::FeeAnalyzerError;
 
+#[derive(Clone)]
 pub struct CSVReaderConfig {
     pub initialize_events_path: String,
     pub swap_events_path: String,
@@ -28,39 +38,214 @@ pub struct CSVReaderConfig {
     pub pool_created_events_path: String,
     pub increase_liquidity_events_path: String,
     pub decrease_liquidity_events_path: String,
+    // position manager nft transfers, only needed to attribute records to the
+    // owner at the time
+    pub transfer_events_path: Option<String>,
+    // when false, rows are read positionally in the column order of the
+    // matching CSV*Event struct instead of by header name
+    pub has_headers: bool,
+    // when set, warn about stretches longer than this many blocks that contain
+    // position changes but no swaps, and about liquidity changes for token ids
+    // that were never minted
+    pub gap_check_max_blocks: Option<u64>,
+    // single csv holding every event type, read instead of the nine per type
+    // files when set
+    pub unified_events_path: Option<String>,
+    // binary cache of the parsed and sorted events, loaded instead of the
+    // csvs while their contents are unchanged
+    pub event_cache_path: Option<PathBuf>,
 }
 
 pub(crate) async fn pool_events(config: CSVReaderConfig) -> Result<Vec<SimulationEvent>> {
-    let initialize_events = read_initialize_events(&config.initialize_events_path)?;
-    let initialize_simulation_events = convert_initialize_events(initialize_events)?;
+    let simulation_events = match &config.event_cache_path {
+        Some(cache_path) => {
+            let input_hash = input_hash(&config)?;
+            match read_event_cache(cache_path, input_hash) {
+                Some(events) => events,
+                None => {
+                    let events = parse_events(&config)?;
+                    write_event_cache(cache_path, input_hash, &events)?;
+                    events
+                }
+            }
+        }
+        None => parse_events(&config)?,
+    };
 
-    let swap_events = read_swap_events(&config.swap_events_path)?;
-    let swap_simulation_events = convert_swap_events(swap_events)?;
+    if let Some(max_blocks_without_swap) = config.gap_check_max_blocks {
+        let gaps = find_event_gaps(&simulation_events, max_blocks_without_swap);
+        info!("Event gap check found {} possible gaps", gaps.len());
+    }
 
-    let mint_events = read_mint_events(&config.mint_events_path)?;
-    let mint_simulation_events = convert_mint_events(mint_events)?;
+    Ok(simulation_events)
+}
 
-    let burn_events = read_burn_events(&config.burn_events_path)?;
-    let burn_simulation_events = convert_burn_events(burn_events)?;
+// reads the input csvs into events sorted by block and log index
+fn parse_events(config: &CSVReaderConfig) -> Result<Vec<SimulationEvent>> {
+    let mut simulation_events = match &config.unified_events_path {
+        Some(path) => read_unified_events(path, None)?,
+        None => read_split_events(config)?,
+    };
 
-    let collect_pool_events = read_collect_pool_events(&config.collect_pool_events_path)?;
-    let collect_pool_simulation_events = convert_collect_pool_events(collect_pool_events)?;
+    // sort events by blocknumber and log index
+    simulation_events.sort();
+    let simulation_events = dedup_events(simulation_events)?;
+    check_tick_ranges(config, &simulation_events)?;
 
-    let collect_npm_events = read_collect_npm_events(&config.collect_npm_events_path)?;
-    let collect_npm_simulation_events = convert_collect_npm_events(collect_npm_events)?;
+    // every npm collect comes with the pool collect it triggered, positions
+    // minted directly against the pool add pool collects of their own
+    let count_of = |event_type: EventType| {
+        simulation_events
+            .iter()
+            .filter(|event| event.event.event_type() == event_type)
+            .count()
+    };
+    let (npm_collects, pool_collects) = (
+        count_of(EventType::CollectNpm),
+        count_of(EventType::CollectPool),
+    );
+    if npm_collects > pool_collects {
+        bail!(FeeAnalyzerError::EventOrdering("There are more collect npm events than collect pool events, check if the same block range is used for all events".to_string()));
+    }
+    if pool_collects > npm_collects {
+        warn!(
+            "{} pool collects have no position manager collect, expected only for positions \
+             minted directly against the pool",
+            pool_collects - npm_collects
+        );
+    }
 
-    let pool_created_events = read_pool_created_events(&config.pool_created_events_path)?;
-    let pool_created_simulation_events = convert_pool_created_events(pool_created_events)?;
+    Ok(simulation_events)
+}
 
-    let increase_liquidity_events =
-        read_increase_liquidity_events(&config.increase_liquidity_events_path)?;
-    let increase_liquidity_simulation_events =
-        convert_increase_liquidity_events(increase_liquidity_events)?;
+// position ticks must form a range on the pool's tick spacing, the pool
+// reverts anything else so such a row can't be replayed. pools without a
+// pool created event in the input are only checked for the ordering
+fn check_tick_ranges(config: &CSVReaderConfig, events: &[SimulationEvent]) -> Result<()> {
+    let mut tick_spacings = HashMap::new();
+    for event in events {
+        let (event_type, path, tick_lower, tick_upper) = match &event.event {
+            Event::PoolCreated(e) => {
+                tick_spacings.insert(e.pool, e.tickSpacing);
+                continue;
+            }
+            Event::Mint(e) => ("mint", &config.mint_events_path, e.tickLower, e.tickUpper),
+            Event::Burn(e) => ("burn", &config.burn_events_path, e.tickLower, e.tickUpper),
+            Event::CollectPool(e) => (
+                "collect",
+                &config.collect_pool_events_path,
+                e.tickLower,
+                e.tickUpper,
+            ),
+            _ => continue,
+        };
+        let tick_spacing = tick_spacings.get(&event.pool_address);
+        let problem = if tick_lower >= tick_upper {
+            Some("the lower tick isn't below the upper tick".to_string())
+        } else {
+            tick_spacing
+                .filter(|spacing| {
+                    !spacing.is_zero()
+                        && (tick_lower % **spacing != I24::ZERO
+                            || tick_upper % **spacing != I24::ZERO)
+                })
+                .map(|spacing| {
+                    format!("the ticks aren't multiples of the tick spacing {}", spacing)
+                })
+        };
+        if let Some(problem) = problem {
+            bail!(FeeAnalyzerError::CsvParse {
+                path: config.unified_events_path.as_ref().unwrap_or(path).clone(),
+                reason: format!(
+                    "{} at block {} log index {} (tx {}) has tick range [{}, {}], {}",
+                    event_type,
+                    event.block,
+                    event.log_index,
+                    event.tx_hash,
+                    tick_lower,
+                    tick_upper,
+                    problem
+                ),
+            });
+        }
+    }
+    Ok(())
+}
 
-    let decrease_liquidity_events =
-        read_decrease_liquidity_events(&config.decrease_liquidity_events_path)?;
-    let decrease_liquidity_simulation_events =
-        convert_decrease_liquidity_events(decrease_liquidity_events)?;
+// reads the nine per event type csvs, plus the transfers when given
+fn read_split_events(config: &CSVReaderConfig) -> Result<Vec<SimulationEvent>> {
+    let initialize_events =
+        read_events::<CSVInitializeEvent>(&config.initialize_events_path, config.has_headers)?;
+    let initialize_simulation_events = convert_rows(
+        &config.initialize_events_path,
+        initialize_events,
+        convert_initialize_events,
+    )?;
+
+    let swap_events = read_events::<CSVSwapEvent>(&config.swap_events_path, config.has_headers)?;
+    let swap_simulation_events =
+        convert_rows(&config.swap_events_path, swap_events, convert_swap_events)?;
+
+    let mint_events = read_events::<CSVMintEvent>(&config.mint_events_path, config.has_headers)?;
+    let mint_simulation_events =
+        convert_rows(&config.mint_events_path, mint_events, convert_mint_events)?;
+
+    let burn_events = read_events::<CSVBurnEvent>(&config.burn_events_path, config.has_headers)?;
+    let burn_simulation_events =
+        convert_rows(&config.burn_events_path, burn_events, convert_burn_events)?;
+
+    let collect_pool_events =
+        read_events::<CSVCollectPoolEvent>(&config.collect_pool_events_path, config.has_headers)?;
+    let collect_pool_simulation_events = convert_rows(
+        &config.collect_pool_events_path,
+        collect_pool_events,
+        convert_collect_pool_events,
+    )?;
+
+    let collect_npm_events =
+        read_events::<CSVCollectNpmEvent>(&config.collect_npm_events_path, config.has_headers)?;
+    let collect_npm_simulation_events = convert_rows(
+        &config.collect_npm_events_path,
+        collect_npm_events,
+        convert_collect_npm_events,
+    )?;
+
+    let pool_created_events =
+        read_events::<CSVPoolCreatedEvent>(&config.pool_created_events_path, config.has_headers)?;
+    let pool_created_simulation_events = convert_rows(
+        &config.pool_created_events_path,
+        pool_created_events,
+        convert_pool_created_events,
+    )?;
+
+    let increase_liquidity_events = read_events::<CSVIncreaseLiquidityEvent>(
+        &config.increase_liquidity_events_path,
+        config.has_headers,
+    )?;
+    let increase_liquidity_simulation_events = convert_rows(
+        &config.increase_liquidity_events_path,
+        increase_liquidity_events,
+        convert_increase_liquidity_events,
+    )?;
+
+    let decrease_liquidity_events = read_events::<CSVDecreaseLiquidityEvent>(
+        &config.decrease_liquidity_events_path,
+        config.has_headers,
+    )?;
+    let decrease_liquidity_simulation_events = convert_rows(
+        &config.decrease_liquidity_events_path,
+        decrease_liquidity_events,
+        convert_decrease_liquidity_events,
+    )?;
+
+    let transfer_simulation_events = match &config.transfer_events_path {
+        Some(path) => convert_rows(
+            path,
+            read_events::<CSVTransferEvent>(path, config.has_headers)?,
+            convert_transfer_events,
+        )?,
+        None => Vec::new(),
+    };
 
     info!("Initialize events: {:?}", initialize_simulation_events);
     info!("Pool created events: {:?}", pool_created_simulation_events);
@@ -82,12 +267,12 @@ pub(crate) async fn pool_events(config: CSVReaderConfig) -> Result<Vec<Simulatio
         "Decrease liquidity events lengeth: {:?}",
         decrease_liquidity_simulation_events.len()
     );
+    info!(
+        "Transfer events length: {:?}",
+        transfer_simulation_events.len()
+    );
 
-    if collect_npm_simulation_events.len() != collect_pool_simulation_events.len() {
-        bail!("Collect npm events and collect pool events have different lengths, check if the same block range is used for all events or if positions are being created without use of the position manager");
-    }
-
-    let mut simulation_events = [
+    Ok([
         initialize_simulation_events,
         pool_created_simulation_events,
         mint_simulation_events,
@@ -97,13 +282,738 @@ pub(crate) async fn pool_events(config: CSVReaderConfig) -> Result<Vec<Simulatio
         collect_npm_simulation_events,
         increase_liquidity_simulation_events,
         decrease_liquidity_simulation_events,
+        transfer_simulation_events,
+    ]
+    .concat())
+}
+
+// reads a single csv holding every event type. the `event_type` column names
+// the row's event (snake_case or PascalCase, e.g. `pool_created` or
+// `PoolCreated`) and the other columns are the union of the per type files'
+// columns, matched by header name. a row only needs its own type's columns,
+// the rest may be blank
+fn read_unified_events(path: &str, max_rows: Option<usize>) -> Result<Vec<SimulationEvent>> {
+    let csv_parse = |reason: String| FeeAnalyzerError::CsvParse {
+        path: path.to_string(),
+        reason,
+    };
+    let file = std::fs::File::open(path).map_err(|e| csv_parse(e.to_string()))?;
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(file);
+    let headers = rdr.headers().map_err(|e| csv_parse(e.to_string()))?.clone();
+    let type_index = headers
+        .iter()
+        .position(|header| header == "event_type")
+        .ok_or_else(|| csv_parse("missing the event_type column".to_string()))?;
+
+    let mut events = Vec::new();
+    for (row, result) in rdr
+        .records()
+        .take(max_rows.unwrap_or(usize::MAX))
+        .enumerate()
+    {
+        let record = result.map_err(|e| csv_parse(e.to_string()))?;
+        let event_type = record
+            .get(type_index)
+            .unwrap_or_default()
+            .trim()
+            .replace('_', "")
+            .to_lowercase();
+        let row_events = match event_type.as_str() {
+            "initialize" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_initialize_events,
+            )?,
+            "poolcreated" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_pool_created_events,
+            )?,
+            "swap" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_swap_events,
+            )?,
+            "mint" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_mint_events,
+            )?,
+            "burn" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_burn_events,
+            )?,
+            "collectpool" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_collect_pool_events,
+            )?,
+            "collectnpm" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_collect_npm_events,
+            )?,
+            "increaseliquidity" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_increase_liquidity_events,
+            )?,
+            "decreaseliquidity" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_decrease_liquidity_events,
+            )?,
+            "transfer" => convert_row(
+                path,
+                row,
+                unified_row(&headers, &record, row, path)?,
+                convert_transfer_events,
+            )?,
+            _ => bail!(csv_parse(format!(
+                "row {} has unknown event type {:?}",
+                row + 1,
+                record.get(type_index).unwrap_or_default()
+            ))),
+        };
+        events.extend(row_events);
+    }
+
+    info!("Read {} events from {}", events.len(), path);
+    Ok(events)
+}
+
+// converts a row on its own so a bad value is reported with its file and row,
+// `row` counts from 0 like the readers' enumerations
+fn convert_row<T>(
+    path: &str,
+    row: usize,
+    record: T,
+    convert: fn(Vec<T>) -> Result<Vec<SimulationEvent>>,
+) -> Result<Vec<SimulationEvent>> {
+    convert(vec![record]).map_err(|e| {
+        FeeAnalyzerError::CsvParse {
+            path: path.to_string(),
+            reason: format!("row {}: {:#}", row + 1, e),
+        }
+        .into()
+    })
+}
+
+fn convert_rows<T>(
+    path: &str,
+    records: Vec<T>,
+    convert: fn(Vec<T>) -> Result<Vec<SimulationEvent>>,
+) -> Result<Vec<SimulationEvent>> {
+    let mut events = Vec::with_capacity(records.len());
+    for (row, record) in records.into_iter().enumerate() {
+        events.extend(convert_row(path, row, record, convert)?);
+    }
+    Ok(events)
+}
+
+// picks the columns of `T` out of a unified csv row by header name
+fn unified_row<T: CSVEventRecord>(
+    headers: &StringRecord,
+    record: &StringRecord,
+    row: usize,
+    path: &str,
+) -> Result<T> {
+    let csv_parse = |reason: String| FeeAnalyzerError::CsvParse {
+        path: path.to_string(),
+        reason,
+    };
+    let mut values = StringRecord::new();
+    for column in T::COLUMNS {
+        let value = headers
+            .iter()
+            .position(|header| header == *column)
+            .and_then(|index| record.get(index))
+            .unwrap_or_default();
+        if value.trim().is_empty() {
+            bail!(csv_parse(format!(
+                "row {} is missing {} for its event type",
+                row + 1,
+                column
+            )));
+        }
+        values.push_field(value);
+    }
+    let mut value_headers = StringRecord::from(T::COLUMNS.to_vec());
+    for column in T::OPTIONAL_COLUMNS {
+        let value = headers
+            .iter()
+            .position(|header| header == *column)
+            .and_then(|index| record.get(index))
+            .unwrap_or_default();
+        if !value.trim().is_empty() {
+            value_headers.push_field(column);
+            values.push_field(value);
+        }
+    }
+    values
+        .deserialize(Some(&value_headers))
+        .map_err(|e| csv_parse(format!("row {}: {}", row + 1, e)).into())
+}
+
+// column layout of an input csv, in the order expected for headerless files
+trait CSVEventRecord: DeserializeOwned {
+    const COLUMNS: &'static [&'static str];
+    // trailing columns that may be left out, their fields default to None
+    const OPTIONAL_COLUMNS: &'static [&'static str] = &[];
+}
+
+fn read_events<T: CSVEventRecord>(path: &str, has_headers: bool) -> Result<Vec<T>> {
+    read_first_events(path, has_headers, None)
+}
+
+// reads up to `max_rows` rows, all of them when none
+fn read_first_events<T: CSVEventRecord>(
+    path: &str,
+    has_headers: bool,
+    max_rows: Option<usize>,
+) -> Result<Vec<T>> {
+    let max_rows = max_rows.unwrap_or(usize::MAX);
+    let csv_parse = |reason: String| FeeAnalyzerError::CsvParse {
+        path: path.to_string(),
+        reason,
+    };
+    let file = std::fs::File::open(path).map_err(|e| csv_parse(e.to_string()))?;
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(has_headers)
+        // column counts are checked per row below for headerless files
+        .flexible(!has_headers)
+        .from_reader(file);
+    let mut events = Vec::new();
+
+    if has_headers {
+        for result in rdr.deserialize().take(max_rows) {
+            let event: T = result.map_err(|e| csv_parse(e.to_string()))?;
+            events.push(event);
+        }
+        return Ok(events);
+    }
+
+    // headerless files are mapped positionally onto the documented column order
+    let headers: StringRecord = T::COLUMNS
+        .iter()
+        .chain(T::OPTIONAL_COLUMNS)
+        .copied()
+        .collect();
+    for (row, result) in rdr.records().take(max_rows).enumerate() {
+        let record = result.map_err(|e| csv_parse(e.to_string()))?;
+        if record.len() < T::COLUMNS.len() || record.len() > headers.len() {
+            bail!(csv_parse(format!(
+                "row {} has {} columns, expected {} ({})",
+                row + 1,
+                record.len(),
+                T::COLUMNS.len(),
+                T::COLUMNS.join(",")
+            )));
+        }
+        let event: T = record
+            .deserialize(Some(&headers))
+            .map_err(|e| csv_parse(e.to_string()))?;
+        events.push(event);
+    }
+
+    Ok(events)
+}
+
+// writes normalized events back out as per type csvs with headers, in the
+// input schema and named like the example data, and returns a config that
+// reads them. events don't keep the tx's `to`, it's written as the zero
+// address. reading the files back gives the same events in the same order
+pub(crate) fn write_events_to_csv(
+    events: &[SimulationEvent],
+    dir: &Path,
+) -> Result<CSVReaderConfig> {
+    std::fs::create_dir_all(dir)?;
+    let path_of = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    let config = CSVReaderConfig {
+        initialize_events_path: path_of("initialize_events.csv"),
+        swap_events_path: path_of("swap_events.csv"),
+        mint_events_path: path_of("mint_events.csv"),
+        burn_events_path: path_of("burn_events.csv"),
+        collect_pool_events_path: path_of("collect_events_pool.csv"),
+        collect_npm_events_path: path_of("collect_events_npm.csv"),
+        pool_created_events_path: path_of("pool_created_events.csv"),
+        increase_liquidity_events_path: path_of("increase_liquidity_events_with_params.csv"),
+        decrease_liquidity_events_path: path_of("decrease_liquidity_events_with_params.csv"),
+        transfer_events_path: Some(path_of("transfer_events_npm.csv")),
+        has_headers: true,
+        gap_check_max_blocks: None,
+        unified_events_path: None,
+        event_cache_path: None,
+    };
+
+    let mut initialize = Writer::from_path(&config.initialize_events_path)?;
+    let mut swap = Writer::from_path(&config.swap_events_path)?;
+    let mut mint = Writer::from_path(&config.mint_events_path)?;
+    let mut burn = Writer::from_path(&config.burn_events_path)?;
+    let mut collect_pool = Writer::from_path(&config.collect_pool_events_path)?;
+    let mut collect_npm = Writer::from_path(&config.collect_npm_events_path)?;
+    let mut pool_created = Writer::from_path(&config.pool_created_events_path)?;
+    let mut increase_liquidity = Writer::from_path(&config.increase_liquidity_events_path)?;
+    let mut decrease_liquidity = Writer::from_path(&config.decrease_liquidity_events_path)?;
+    let mut transfer = Writer::from_path(config.transfer_events_path.as_ref().unwrap())?;
+
+    for event in events {
+        let contract_address = event.pool_address.to_string();
+        let evt_tx_hash = event.tx_hash.to_string();
+        let evt_tx_from = event.from.to_string();
+        let evt_tx_to = Address::ZERO.to_string();
+        let evt_index = event.log_index;
+        let evt_block_time = event.block_time.to_rfc3339();
+        let evt_block_number = event.block;
+        match &event.event {
+            Event::Initialize(e) => initialize.serialize(CSVInitializeEvent {
+                contract_address,
+                evt_tx_hash,
+                evt_tx_from,
+                evt_tx_to,
+                evt_index,
+                evt_block_time,
+                evt_block_number,
+                sqrtPriceX96: e.sqrtPriceX96.to_string(),
+                tick: e.tick.to_string(),
+            })?,
+            Event::PoolCreated(e) => pool_created.serialize(CSVPoolCreatedEvent {
+                contract_address,
+                evt_tx_hash,
+                evt_tx_from,
+                evt_tx_to,
+                evt_index,
+                evt_block_time,
+                evt_block_number,
+                fee: e.fee.to_string(),
+                pool: e.pool.to_string(),
+                tickSpacing: e.tickSpacing.to_string(),
+                token0: e.token0.to_string(),
+                token1: e.token1.to_string(),
+            })?,
+            Event::Swap(e) => swap.serialize(CSVSwapEvent {
+                contract_address,
+                evt_tx_hash,
+                evt_tx_from,
+                evt_tx_to,
+                evt_index,
+                evt_block_time,
+                evt_block_number,
+                amount0: e.event.amount0.to_string(),
+                amount1: e.event.amount1.to_string(),
+                liquidity: e.event.liquidity.to_string(),
+                recipient: e.event.recipient.to_string(),
+                sender: e.event.sender.to_string(),
+                sqrtPriceX96: e.event.sqrtPriceX96.to_string(),
+                tick: e.event.tick.to_string(),
+                amountOutMinimum: e.amount_out_minimum.map(|amount| amount.to_string()),
+                amountInMaximum: e.amount_in_maximum.map(|amount| amount.to_string()),
+            })?,
+            Event::Mint(e) => mint.serialize(CSVMintEvent {
+                contract_address,
+                evt_tx_hash,
+                evt_tx_from,
+                evt_tx_to,
+                evt_index,
+                evt_block_time,
+                evt_block_number,
+                amount: e.amount.to_string(),
+                amount0: e.amount0.to_string(),
+                amount1: e.amount1.to_string(),
+                owner: e.owner.to_string(),
+                sender: e.sender.to_string(),
+                tickLower: e.tickLower.to_string(),
+                tickUpper: e.tickUpper.to_string(),
+            })?,
+            Event::Burn(e) => burn.serialize(CSVBurnEvent {
+                contract_address,
+                evt_tx_hash,
+                evt_tx_from,
+                evt_tx_to,
+                evt_index,
+                evt_block_time,
+                evt_block_number,
+                amount: e.amount.to_string(),
+                amount0: e.amount0.to_string(),
+                amount1: e.amount1.to_string(),
+                owner: e.owner.to_string(),
+                tickLower: e.tickLower.to_string(),
+                tickUpper: e.tickUpper.to_string(),
+            })?,
+            Event::CollectPool(e) => collect_pool.serialize(CSVCollectPoolEvent {
+                contract_address,
+                evt_tx_hash,
+                evt_tx_from,
+                evt_tx_to,
+                evt_index,
+                evt_block_time,
+                evt_block_number,
+                amount0: e.amount0.to_string(),
+                amount1: e.amount1.to_string(),
+                owner: e.owner.to_string(),
+                recipient: e.recipient.to_string(),
+                tickLower: e.tickLower.to_string(),
+                tickUpper: e.tickUpper.to_string(),
+            })?,
+            Event::IncreaseLiquidity(e) => {
+                increase_liquidity.serialize(CSVIncreaseLiquidityEvent {
+                    contract_address,
+                    evt_tx_hash,
+                    evt_tx_from,
+                    evt_tx_to,
+                    evt_index,
+                    evt_block_time,
+                    evt_block_number,
+                    tokenId: e.event.tokenId.to_string(),
+                    liquidity: e.event.liquidity.to_string(),
+                    amount0: e.event.amount0.to_string(),
+                    amount1: e.event.amount1.to_string(),
+                    amount0Desired: e.amount_0_desired.to_string(),
+                    amount1Desired: e.amount_1_desired.to_string(),
+                    amount0Min: Some(e.amount_0_min.to_string()),
+                    amount1Min: Some(e.amount_1_min.to_string()),
+                })?
+            }
+            Event::DecreaseLiquidity(e) => {
+                decrease_liquidity.serialize(CSVDecreaseLiquidityEvent {
+                    contract_address,
+                    evt_tx_hash,
+                    evt_tx_from,
+                    evt_tx_to,
+                    evt_index,
+                    evt_block_time,
+                    evt_block_number,
+                    amount0: e.event.amount0.to_string(),
+                    amount1: e.event.amount1.to_string(),
+                    liquidity: e.event.liquidity.to_string(),
+                    tokenId: e.event.tokenId.to_string(),
+                    amount0Min: e.amount_0_min.to_string(),
+                    amount1Min: e.amount_1_min.to_string(),
+                })?
+            }
+            Event::CollectNpm(e) => collect_npm.serialize(CSVCollectNpmEvent {
+                contract_address,
+                evt_tx_hash,
+                evt_tx_from,
+                evt_tx_to,
+                evt_index,
+                evt_block_time,
+                evt_block_number,
+                tokenId: e.tokenId.to_string(),
+                recipient: e.recipient.to_string(),
+                amount0: e.amount0.to_string(),
+                amount1: e.amount1.to_string(),
+            })?,
+            Event::Transfer(e) => transfer.serialize(CSVTransferEvent {
+                contract_address,
+                evt_tx_hash,
+                evt_tx_from,
+                evt_tx_to,
+                evt_index,
+                evt_block_time,
+                evt_block_number,
+                from: e.from.to_string(),
+                to: e.to.to_string(),
+                tokenId: e.tokenId.to_string(),
+            })?,
+        }
+    }
+    for writer in [
+        &mut initialize,
+        &mut swap,
+        &mut mint,
+        &mut burn,
+        &mut collect_pool,
+        &mut collect_npm,
+        &mut pool_created,
+        &mut increase_liquidity,
+        &mut decrease_liquidity,
+        &mut transfer,
+    ] {
+        writer.flush()?;
+    }
+
+    info!("Wrote {} events to {:?}", events.len(), dir);
+    Ok(config)
+}
+
+// rows parsed per file by validate_csv, enough to catch a wrong format
+// without reading the whole export
+const VALIDATE_SAMPLE_ROWS: usize = 5;
+
+// checks the input csvs without reading them in full or connecting to
+// anything: each file opens, its header has every expected column (extra
+// columns are only reported), and its first rows parse into events. every
+// problem is logged per file before failing
+pub(crate) fn validate_csv(config: &CSVReaderConfig) -> Result<()> {
+    let mut problems = Vec::new();
+    match &config.unified_events_path {
+        Some(path) => problems.extend(validate_unified_file(path)),
+        None => {
+            let has_headers = config.has_headers;
+            problems.extend(validate_file(
+                &config.initialize_events_path,
+                has_headers,
+                convert_initialize_events,
+            ));
+            problems.extend(validate_file(
+                &config.pool_created_events_path,
+                has_headers,
+                convert_pool_created_events,
+            ));
+            problems.extend(validate_file(
+                &config.swap_events_path,
+                has_headers,
+                convert_swap_events,
+            ));
+            problems.extend(validate_file(
+                &config.mint_events_path,
+                has_headers,
+                convert_mint_events,
+            ));
+            problems.extend(validate_file(
+                &config.burn_events_path,
+                has_headers,
+                convert_burn_events,
+            ));
+            problems.extend(validate_file(
+                &config.collect_pool_events_path,
+                has_headers,
+                convert_collect_pool_events,
+            ));
+            problems.extend(validate_file(
+                &config.collect_npm_events_path,
+                has_headers,
+                convert_collect_npm_events,
+            ));
+            problems.extend(validate_file(
+                &config.increase_liquidity_events_path,
+                has_headers,
+                convert_increase_liquidity_events,
+            ));
+            problems.extend(validate_file(
+                &config.decrease_liquidity_events_path,
+                has_headers,
+                convert_decrease_liquidity_events,
+            ));
+            if let Some(path) = &config.transfer_events_path {
+                problems.extend(validate_file(path, has_headers, convert_transfer_events));
+            }
+        }
+    }
+
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    if let Some(first) = problems.into_iter().next() {
+        bail!(first);
+    }
+    Ok(())
+}
+
+fn validate_file<T: CSVEventRecord>(
+    path: &str,
+    has_headers: bool,
+    convert: fn(Vec<T>) -> Result<Vec<SimulationEvent>>,
+) -> Vec<FeeAnalyzerError> {
+    let csv_parse = |reason: String| FeeAnalyzerError::CsvParse {
+        path: path.to_string(),
+        reason,
+    };
+    let mut problems = Vec::new();
+    if has_headers {
+        let headers = match ReaderBuilder::new()
+            .has_headers(true)
+            .from_path(path)
+            .and_then(|mut rdr| rdr.headers().cloned())
+        {
+            Ok(headers) => headers,
+            Err(e) => return vec![csv_parse(e.to_string())],
+        };
+        let missing: Vec<&str> = T::COLUMNS
+            .iter()
+            .filter(|column| !headers.iter().any(|header| header == **column))
+            .copied()
+            .collect();
+        let extra: Vec<&str> = headers
+            .iter()
+            .filter(|header| !T::COLUMNS.contains(header) && !T::OPTIONAL_COLUMNS.contains(header))
+            .collect();
+        if !extra.is_empty() {
+            info!("{}: ignoring extra columns {}", path, extra.join(", "));
+        }
+        if !missing.is_empty() {
+            // the rows can't parse without them, don't pile on
+            return vec![csv_parse(format!("missing columns {}", missing.join(", ")))];
+        }
+    }
+    let rows = match read_first_events::<T>(path, has_headers, Some(VALIDATE_SAMPLE_ROWS)) {
+        Ok(rows) => rows,
+        Err(e) => match e.downcast::<FeeAnalyzerError>() {
+            Ok(problem) => return vec![problem],
+            Err(e) => return vec![csv_parse(format!("{:#}", e))],
+        },
+    };
+    let row_count = rows.len();
+    // every bad row is reported, not only the first
+    for (row, record) in rows.into_iter().enumerate() {
+        if let Err(e) = convert_row(path, row, record, convert) {
+            match e.downcast::<FeeAnalyzerError>() {
+                Ok(problem) => problems.push(problem),
+                Err(e) => problems.push(csv_parse(format!("{:#}", e))),
+            }
+        }
+    }
+    if problems.is_empty() {
+        info!("{}: header and first {} rows ok", path, row_count);
+    }
+    problems
+}
+
+// the unified file needs `event_type`, and each column some event type reads
+// is reported when missing since rows of that type won't parse
+fn validate_unified_file(path: &str) -> Vec<FeeAnalyzerError> {
+    let csv_parse = |reason: String| FeeAnalyzerError::CsvParse {
+        path: path.to_string(),
+        reason,
+    };
+    let headers = match ReaderBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .and_then(|mut rdr| rdr.headers().cloned())
+    {
+        Ok(headers) => headers,
+        Err(e) => return vec![csv_parse(e.to_string())],
+    };
+    let known: Vec<&str> = [
+        CSVInitializeEvent::COLUMNS,
+        CSVPoolCreatedEvent::COLUMNS,
+        CSVSwapEvent::COLUMNS,
+        CSVSwapEvent::OPTIONAL_COLUMNS,
+        CSVMintEvent::COLUMNS,
+        CSVBurnEvent::COLUMNS,
+        CSVCollectPoolEvent::COLUMNS,
+        CSVCollectNpmEvent::COLUMNS,
+        CSVIncreaseLiquidityEvent::COLUMNS,
+        CSVIncreaseLiquidityEvent::OPTIONAL_COLUMNS,
+        CSVDecreaseLiquidityEvent::COLUMNS,
+        CSVTransferEvent::COLUMNS,
+        &["event_type"],
     ]
     .concat();
+    let mut missing: Vec<&str> = known
+        .iter()
+        .filter(|column| {
+            !CSVSwapEvent::OPTIONAL_COLUMNS.contains(column)
+                && !CSVIncreaseLiquidityEvent::OPTIONAL_COLUMNS.contains(column)
+                && !headers.iter().any(|header| header == **column)
+        })
+        .copied()
+        .collect();
+    missing.sort();
+    missing.dedup();
+    let extra: Vec<&str> = headers
+        .iter()
+        .filter(|header| !known.contains(header))
+        .collect();
+    if !extra.is_empty() {
+        info!("{}: ignoring extra columns {}", path, extra.join(", "));
+    }
+    let mut problems = Vec::new();
+    if !missing.is_empty() {
+        problems.push(csv_parse(format!(
+            "missing columns {}, rows of the event types that read them won't parse",
+            missing.join(", ")
+        )));
+    }
+    match read_unified_events(path, Some(VALIDATE_SAMPLE_ROWS)) {
+        Ok(events) => info!("{}: header and first {} rows ok", path, events.len()),
+        Err(e) => match e.downcast::<FeeAnalyzerError>() {
+            Ok(problem) => problems.push(problem),
+            Err(e) => problems.push(csv_parse(format!("{:#}", e))),
+        },
+    }
+    problems
+}
 
-    // sort events by blocknumber and log index
-    simulation_events.sort();
+// exporters write block times as rfc 3339, unix seconds, or
+// `YYYY-MM-DD HH:MM:SS` with optional fractional seconds and a trailing `UTC`
+// (dune's format). times without an offset are taken as utc
+pub(crate) fn parse_block_time(value: &str) -> Result<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<i64>() {
+        return DateTime::from_timestamp(seconds, 0)
+            .ok_or_else(|| eyre!("Block time {} is out of range", value));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let naive = value.strip_suffix("UTC").unwrap_or(value).trim_end();
+    for format in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(naive, format) {
+            return Ok(time.and_utc());
+        }
+    }
+    bail!(
+        "Invalid block time {:?}, expected RFC 3339, unix seconds, or YYYY-MM-DD HH:MM:SS",
+        value
+    )
+}
 
-    Ok(simulation_events)
+// event amounts, prices, and ticks are read from decimal or 0x prefixed hex,
+// exporters differ in which they write
+fn parse_uint<const BITS: usize, const LIMBS: usize>(value: &str) -> Result<Uint<BITS, LIMBS>> {
+    Uint::from_str(value).map_err(|e| eyre!("Invalid unsigned value {}: {}", value, e))
+}
+
+fn parse_tick(value: &str) -> Result<I24> {
+    // wide enough that an out of range tick is reported as one instead of as
+    // an overflow
+    let tick: I256 = parse_signed(value).map_err(|_| eyre!("Invalid tick {}", value))?;
+    if tick < I256::try_from(MIN_TICK).unwrap() || tick > I256::try_from(MAX_TICK).unwrap() {
+        bail!("Tick {} is outside [{}, {}]", value, MIN_TICK, MAX_TICK);
+    }
+    Ok(I24::try_from(tick.as_i32()).unwrap())
+}
+
+fn parse_address(value: &str) -> Result<Address> {
+    Address::from_str(value).map_err(|e| eyre!("Invalid address {}: {}", value, e))
+}
+
+fn parse_tx_hash(value: &str) -> Result<TxHash> {
+    TxHash::from_str(value).map_err(|e| eyre!("Invalid tx hash {}: {}", value, e))
+}
+
+fn parse_u128(value: &str) -> Result<u128> {
+    Ok(parse_uint::<128, 2>(value)?.to())
+}
+
+// negative hex is either a minus sign on the magnitude (`-0x10`) or the 256
+// bit two's complement word the abi encodes signed values as
+fn parse_signed<const BITS: usize, const LIMBS: usize>(value: &str) -> Result<Signed<BITS, LIMBS>> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => U256::from_str_radix(hex, 16)
+            .ok()
+            .and_then(|word| Signed::from_str(&I256::from_raw(word).to_string()).ok()),
+        None => Signed::from_str(value).ok(),
+    };
+    parsed.ok_or_else(|| eyre!("Invalid signed value {}", value))
 }
 
 #[allow(non_snake_case, dead_code)]
@@ -114,44 +1024,48 @@ struct CSVInitializeEvent {
     evt_tx_from: String,
     evt_tx_to: String,
     evt_index: u64,
-    evt_block_time: DateTime<Utc>,
+    evt_block_time: String,
     evt_block_number: u64,
     sqrtPriceX96: String,
     tick: String,
 }
 
-fn read_initialize_events(path: &str) -> Result<Vec<CSVInitializeEvent>> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut events = Vec::new();
-
-    for result in rdr.deserialize() {
-        let event: CSVInitializeEvent = result?;
-        events.push(event);
-    }
-
-    Ok(events)
+impl CSVEventRecord for CSVInitializeEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "sqrtPriceX96",
+        "tick",
+    ];
 }
 
 fn convert_initialize_events(events: Vec<CSVInitializeEvent>) -> Result<Vec<SimulationEvent>> {
-    Ok(events
+    events
         .into_iter()
-        .map(|event| SimulationEvent {
-            tx_hash: TxHash::from_str(&event.evt_tx_hash).unwrap(),
-            pool_address: Address::from_str(&event.contract_address).unwrap(),
-            block: event.evt_block_number,
-            log_index: event.evt_index,
-            from: Address::from_str(&event.evt_tx_from).unwrap(),
-            event: Event::Initialize(Initialize {
-                sqrtPriceX96: U160::from_str(&event.sqrtPriceX96).unwrap(),
-                tick: I24::from_dec_str(&event.tick).unwrap(),
-            }),
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::Initialize(Initialize {
+                    sqrtPriceX96: parse_uint(&event.sqrtPriceX96)?,
+                    tick: parse_tick(&event.tick)?,
+                }),
+            })
         })
-        .collect())
+        .collect()
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CSVPoolCreatedEvent {
     contract_address: String,
     evt_tx_hash: String,
@@ -167,41 +1081,48 @@ struct CSVPoolCreatedEvent {
     token1: String,
 }
 
-fn read_pool_created_events(path: &str) -> Result<Vec<CSVPoolCreatedEvent>> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut events = Vec::new();
-
-    for result in rdr.deserialize() {
-        let event: CSVPoolCreatedEvent = result?;
-        events.push(event);
-    }
-
-    Ok(events)
+impl CSVEventRecord for CSVPoolCreatedEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "fee",
+        "pool",
+        "tickSpacing",
+        "token0",
+        "token1",
+    ];
 }
 
 fn convert_pool_created_events(events: Vec<CSVPoolCreatedEvent>) -> Result<Vec<SimulationEvent>> {
-    Ok(events
+    events
         .into_iter()
-        .map(|event| SimulationEvent {
-            tx_hash: TxHash::from_str(&event.evt_tx_hash).unwrap(),
-            pool_address: Address::from_str(&event.contract_address).unwrap(),
-            block: event.evt_block_number,
-            log_index: event.evt_index,
-            from: Address::from_str(&event.evt_tx_from).unwrap(),
-            event: Event::PoolCreated(PoolCreated {
-                fee: U24::from_str(&event.fee).unwrap(),
-                tickSpacing: I24::from_dec_str(&event.tickSpacing).unwrap(),
-                pool: Address::from_str(&event.pool).unwrap(),
-                token0: Address::from_str(&event.token0).unwrap(),
-                token1: Address::from_str(&event.token1).unwrap(),
-            }),
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::PoolCreated(PoolCreated {
+                    fee: parse_uint(&event.fee)?,
+                    tickSpacing: parse_signed(&event.tickSpacing)?,
+                    pool: parse_address(&event.pool)?,
+                    token0: parse_address(&event.token0)?,
+                    token1: parse_address(&event.token1)?,
+                }),
+            })
         })
-        .collect())
+        .collect()
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CSVSwapEvent {
     contract_address: String,
     evt_tx_hash: String,
@@ -217,45 +1138,70 @@ struct CSVSwapEvent {
     sender: String,
     sqrtPriceX96: String,
     tick: String,
+    // the swap call's slippage bound, whichever applies to its direction
+    #[serde(default)]
+    amountOutMinimum: Option<String>,
+    #[serde(default)]
+    amountInMaximum: Option<String>,
 }
 
-fn read_swap_events(path: &str) -> Result<Vec<CSVSwapEvent>> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut events = Vec::new();
-
-    for result in rdr.deserialize() {
-        let event: CSVSwapEvent = result?;
-        events.push(event);
-    }
-
-    Ok(events)
+impl CSVEventRecord for CSVSwapEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "amount0",
+        "amount1",
+        "liquidity",
+        "recipient",
+        "sender",
+        "sqrtPriceX96",
+        "tick",
+    ];
+    const OPTIONAL_COLUMNS: &'static [&'static str] = &["amountOutMinimum", "amountInMaximum"];
 }
 
 fn convert_swap_events(events: Vec<CSVSwapEvent>) -> Result<Vec<SimulationEvent>> {
-    Ok(events
+    events
         .into_iter()
-        .map(|event| SimulationEvent {
-            tx_hash: TxHash::from_str(&event.evt_tx_hash).unwrap(),
-            pool_address: Address::from_str(&event.contract_address).unwrap(),
-            block: event.evt_block_number,
-            log_index: event.evt_index,
-            from: Address::from_str(&event.evt_tx_from).unwrap(),
-            event: Event::Swap(Swap {
-                amount0: I256::from_str(&event.amount0).unwrap(),
-                amount1: I256::from_str(&event.amount1).unwrap(),
-                liquidity: u128::from_str(&event.liquidity).unwrap(),
-                recipient: Address::from_str(&event.recipient).unwrap(),
-                sender: Address::from_str(&event.sender).unwrap(),
-                sqrtPriceX96: U160::from_str(&event.sqrtPriceX96).unwrap(),
-                tick: I24::from_dec_str(&event.tick).unwrap(),
-            }),
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::Swap(SwapWithParams {
+                    amount_out_minimum: event
+                        .amountOutMinimum
+                        .map(|amount| parse_uint(&amount))
+                        .transpose()?,
+                    amount_in_maximum: event
+                        .amountInMaximum
+                        .map(|amount| parse_uint(&amount))
+                        .transpose()?,
+                    event: Swap {
+                        amount0: parse_signed(&event.amount0)?,
+                        amount1: parse_signed(&event.amount1)?,
+                        liquidity: parse_u128(&event.liquidity)?,
+                        recipient: parse_address(&event.recipient)?,
+                        sender: parse_address(&event.sender)?,
+                        sqrtPriceX96: parse_uint(&event.sqrtPriceX96)?,
+                        tick: parse_tick(&event.tick)?,
+                    },
+                }),
+            })
         })
-        .collect())
+        .collect()
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CSVMintEvent {
     contract_address: String,
     evt_tx_hash: String,
@@ -273,43 +1219,52 @@ struct CSVMintEvent {
     tickUpper: String,
 }
 
-fn read_mint_events(path: &str) -> Result<Vec<CSVMintEvent>> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut events = Vec::new();
-
-    for result in rdr.deserialize() {
-        let event: CSVMintEvent = result?;
-        events.push(event);
-    }
-
-    Ok(events)
+impl CSVEventRecord for CSVMintEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "amount",
+        "amount0",
+        "amount1",
+        "owner",
+        "sender",
+        "tickLower",
+        "tickUpper",
+    ];
 }
 
 fn convert_mint_events(events: Vec<CSVMintEvent>) -> Result<Vec<SimulationEvent>> {
-    Ok(events
+    events
         .into_iter()
-        .map(|event| SimulationEvent {
-            tx_hash: TxHash::from_str(&event.evt_tx_hash).unwrap(),
-            pool_address: Address::from_str(&event.contract_address).unwrap(),
-            block: event.evt_block_number,
-            log_index: event.evt_index,
-            from: Address::from_str(&event.evt_tx_from).unwrap(),
-            event: Event::Mint(Mint {
-                amount: u128::from_str(&event.amount).unwrap(),
-                amount0: U256::from_str(&event.amount0).unwrap(),
-                amount1: U256::from_str(&event.amount1).unwrap(),
-                owner: Address::from_str(&event.owner).unwrap(),
-                sender: Address::from_str(&event.sender).unwrap(),
-                tickLower: I24::from_dec_str(&event.tickLower).unwrap(),
-                tickUpper: I24::from_dec_str(&event.tickUpper).unwrap(),
-            }),
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::Mint(Mint {
+                    amount: parse_u128(&event.amount)?,
+                    amount0: parse_uint(&event.amount0)?,
+                    amount1: parse_uint(&event.amount1)?,
+                    owner: parse_address(&event.owner)?,
+                    sender: parse_address(&event.sender)?,
+                    tickLower: parse_tick(&event.tickLower)?,
+                    tickUpper: parse_tick(&event.tickUpper)?,
+                }),
+            })
         })
-        .collect())
+        .collect()
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CSVBurnEvent {
     contract_address: String,
     evt_tx_hash: String,
@@ -326,42 +1281,50 @@ struct CSVBurnEvent {
     tickUpper: String,
 }
 
-fn read_burn_events(path: &str) -> Result<Vec<CSVBurnEvent>> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut events = Vec::new();
-
-    for result in rdr.deserialize() {
-        let event: CSVBurnEvent = result?;
-        events.push(event);
-    }
-
-    Ok(events)
+impl CSVEventRecord for CSVBurnEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "amount",
+        "amount0",
+        "amount1",
+        "owner",
+        "tickLower",
+        "tickUpper",
+    ];
 }
 
 fn convert_burn_events(events: Vec<CSVBurnEvent>) -> Result<Vec<SimulationEvent>> {
-    Ok(events
+    events
         .into_iter()
-        .map(|event| SimulationEvent {
-            tx_hash: TxHash::from_str(&event.evt_tx_hash).unwrap(),
-            pool_address: Address::from_str(&event.contract_address).unwrap(),
-            block: event.evt_block_number,
-            log_index: event.evt_index,
-            from: Address::from_str(&event.evt_tx_from).unwrap(),
-            event: Event::Burn(Burn {
-                amount: u128::from_str(&event.amount).unwrap(),
-                amount0: U256::from_str(&event.amount0).unwrap(),
-                amount1: U256::from_str(&event.amount1).unwrap(),
-                owner: Address::from_str(&event.owner).unwrap(),
-                tickLower: I24::from_dec_str(&event.tickLower).unwrap(),
-                tickUpper: I24::from_dec_str(&event.tickUpper).unwrap(),
-            }),
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::Burn(Burn {
+                    amount: parse_u128(&event.amount)?,
+                    amount0: parse_uint(&event.amount0)?,
+                    amount1: parse_uint(&event.amount1)?,
+                    owner: parse_address(&event.owner)?,
+                    tickLower: parse_tick(&event.tickLower)?,
+                    tickUpper: parse_tick(&event.tickUpper)?,
+                }),
+            })
         })
-        .collect())
+        .collect()
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CSVCollectPoolEvent {
     contract_address: String,
     evt_tx_hash: String,
@@ -378,42 +1341,50 @@ struct CSVCollectPoolEvent {
     tickUpper: String,
 }
 
-fn read_collect_pool_events(path: &str) -> Result<Vec<CSVCollectPoolEvent>> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut events = Vec::new();
-
-    for result in rdr.deserialize() {
-        let event: CSVCollectPoolEvent = result?;
-        events.push(event);
-    }
-
-    Ok(events)
+impl CSVEventRecord for CSVCollectPoolEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "amount0",
+        "amount1",
+        "owner",
+        "recipient",
+        "tickLower",
+        "tickUpper",
+    ];
 }
 
 fn convert_collect_pool_events(events: Vec<CSVCollectPoolEvent>) -> Result<Vec<SimulationEvent>> {
-    Ok(events
+    events
         .into_iter()
-        .map(|event| SimulationEvent {
-            tx_hash: TxHash::from_str(&event.evt_tx_hash).unwrap(),
-            pool_address: Address::from_str(&event.contract_address).unwrap(),
-            block: event.evt_block_number,
-            log_index: event.evt_index,
-            from: Address::from_str(&event.evt_tx_from).unwrap(),
-            event: Event::CollectPool(CollectPool {
-                amount0: u128::from_str(&event.amount0).unwrap(),
-                amount1: u128::from_str(&event.amount1).unwrap(),
-                owner: Address::from_str(&event.owner).unwrap(),
-                recipient: Address::from_str(&event.recipient).unwrap(),
-                tickLower: I24::from_dec_str(&event.tickLower).unwrap(),
-                tickUpper: I24::from_dec_str(&event.tickUpper).unwrap(),
-            }),
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::CollectPool(CollectPool {
+                    amount0: parse_u128(&event.amount0)?,
+                    amount1: parse_u128(&event.amount1)?,
+                    owner: parse_address(&event.owner)?,
+                    recipient: parse_address(&event.recipient)?,
+                    tickLower: parse_tick(&event.tickLower)?,
+                    tickUpper: parse_tick(&event.tickUpper)?,
+                }),
+            })
         })
-        .collect())
+        .collect()
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CSVIncreaseLiquidityEvent {
     contract_address: String,
     evt_tx_hash: String,
@@ -428,48 +1399,68 @@ struct CSVIncreaseLiquidityEvent {
     amount1: String,
     amount0Desired: String,
     amount1Desired: String,
+    // the mint or increase call's slippage bounds
+    #[serde(default)]
+    amount0Min: Option<String>,
+    #[serde(default)]
+    amount1Min: Option<String>,
 }
 
-fn read_increase_liquidity_events(path: &str) -> Result<Vec<CSVIncreaseLiquidityEvent>> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut events = Vec::new();
-
-    for result in rdr.deserialize() {
-        let event: CSVIncreaseLiquidityEvent = result?;
-        events.push(event);
-    }
-
-    Ok(events)
+impl CSVEventRecord for CSVIncreaseLiquidityEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "tokenId",
+        "liquidity",
+        "amount0",
+        "amount1",
+        "amount0Desired",
+        "amount1Desired",
+    ];
+    const OPTIONAL_COLUMNS: &'static [&'static str] = &["amount0Min", "amount1Min"];
 }
 
 fn convert_increase_liquidity_events(
     events: Vec<CSVIncreaseLiquidityEvent>,
 ) -> Result<Vec<SimulationEvent>> {
-    Ok(events
+    events
         .into_iter()
-        .map(|event| SimulationEvent {
-            tx_hash: TxHash::from_str(&event.evt_tx_hash).unwrap(),
-            pool_address: Address::from_str(&event.contract_address).unwrap(),
-            block: event.evt_block_number,
-            log_index: event.evt_index,
-            from: Address::from_str(&event.evt_tx_from).unwrap(),
-            event: Event::IncreaseLiquidity(IncreaseLiquidityWithParams {
-                amount_0_desired: U256::from_str(&event.amount0Desired).unwrap(),
-                amount_1_desired: U256::from_str(&event.amount1Desired).unwrap(),
-                event: IncreaseLiquidity {
-                    tokenId: U256::from_str(&event.tokenId).unwrap(),
-                    liquidity: u128::from_str(&event.liquidity).unwrap(),
-                    amount0: U256::from_str(&event.amount0).unwrap(),
-                    amount1: U256::from_str(&event.amount1).unwrap(),
-                },
-            }),
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::IncreaseLiquidity(IncreaseLiquidityWithParams {
+                    amount_0_desired: parse_uint(&event.amount0Desired)?,
+                    amount_1_desired: parse_uint(&event.amount1Desired)?,
+                    amount_0_min: event
+                        .amount0Min
+                        .map_or(Ok(U256::ZERO), |amount| parse_uint(&amount))?,
+                    amount_1_min: event
+                        .amount1Min
+                        .map_or(Ok(U256::ZERO), |amount| parse_uint(&amount))?,
+                    event: IncreaseLiquidity {
+                        tokenId: parse_uint(&event.tokenId)?,
+                        liquidity: parse_u128(&event.liquidity)?,
+                        amount0: parse_uint(&event.amount0)?,
+                        amount1: parse_uint(&event.amount1)?,
+                    },
+                }),
+            })
         })
-        .collect())
+        .collect()
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CSVDecreaseLiquidityEvent {
     contract_address: String,
     evt_tx_hash: String,
@@ -486,46 +1477,54 @@ struct CSVDecreaseLiquidityEvent {
     amount1Min: String,
 }
 
-fn read_decrease_liquidity_events(path: &str) -> Result<Vec<CSVDecreaseLiquidityEvent>> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut events = Vec::new();
-
-    for result in rdr.deserialize() {
-        let event: CSVDecreaseLiquidityEvent = result?;
-        events.push(event);
-    }
-
-    Ok(events)
+impl CSVEventRecord for CSVDecreaseLiquidityEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "amount0",
+        "amount1",
+        "liquidity",
+        "tokenId",
+        "amount0Min",
+        "amount1Min",
+    ];
 }
 
 fn convert_decrease_liquidity_events(
     events: Vec<CSVDecreaseLiquidityEvent>,
 ) -> Result<Vec<SimulationEvent>> {
-    Ok(events
+    events
         .into_iter()
-        .map(|event| SimulationEvent {
-            tx_hash: TxHash::from_str(&event.evt_tx_hash).unwrap(),
-            pool_address: Address::from_str(&event.contract_address).unwrap(),
-            block: event.evt_block_number,
-            log_index: event.evt_index,
-            from: Address::from_str(&event.evt_tx_from).unwrap(),
-            event: Event::DecreaseLiquidity(DecreaseLiquidityWithParams {
-                amount_0_min: U256::from_str(&event.amount0Min).unwrap(),
-                amount_1_min: U256::from_str(&event.amount1Min).unwrap(),
-                event: DecreaseLiquidity {
-                    tokenId: U256::from_str(&event.tokenId).unwrap(),
-                    liquidity: u128::from_str(&event.liquidity).unwrap(),
-                    amount0: U256::from_str(&event.amount0).unwrap(),
-                    amount1: U256::from_str(&event.amount1).unwrap(),
-                },
-            }),
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::DecreaseLiquidity(DecreaseLiquidityWithParams {
+                    amount_0_min: parse_uint(&event.amount0Min)?,
+                    amount_1_min: parse_uint(&event.amount1Min)?,
+                    event: DecreaseLiquidity {
+                        tokenId: parse_uint(&event.tokenId)?,
+                        liquidity: parse_u128(&event.liquidity)?,
+                        amount0: parse_uint(&event.amount0)?,
+                        amount1: parse_uint(&event.amount1)?,
+                    },
+                }),
+            })
         })
-        .collect())
+        .collect()
 }
 
 #[allow(non_snake_case, dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CSVCollectNpmEvent {
     contract_address: String,
     evt_tx_hash: String,
@@ -540,34 +1539,405 @@ struct CSVCollectNpmEvent {
     amount1: String,
 }
 
-fn read_collect_npm_events(path: &str) -> Result<Vec<CSVCollectNpmEvent>> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut events = Vec::new();
+impl CSVEventRecord for CSVCollectNpmEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "tokenId",
+        "recipient",
+        "amount0",
+        "amount1",
+    ];
+}
 
-    for result in rdr.deserialize() {
-        let event: CSVCollectNpmEvent = result?;
-        events.push(event);
-    }
+fn convert_collect_npm_events(events: Vec<CSVCollectNpmEvent>) -> Result<Vec<SimulationEvent>> {
+    events
+        .into_iter()
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::CollectNpm(CollectNpm {
+                    tokenId: parse_uint(&event.tokenId)?,
+                    recipient: parse_address(&event.recipient)?,
+                    amount0: parse_uint(&event.amount0)?,
+                    amount1: parse_uint(&event.amount1)?,
+                }),
+            })
+        })
+        .collect()
+}
 
-    Ok(events)
+#[allow(non_snake_case, dead_code)]
+#[derive(Debug, Deserialize, Serialize)]
+struct CSVTransferEvent {
+    contract_address: String,
+    evt_tx_hash: String,
+    evt_tx_from: String,
+    evt_tx_to: String,
+    evt_index: u64,
+    evt_block_time: String,
+    evt_block_number: u64,
+    from: String,
+    to: String,
+    tokenId: String,
 }
 
-fn convert_collect_npm_events(events: Vec<CSVCollectNpmEvent>) -> Result<Vec<SimulationEvent>> {
-    Ok(events
+impl CSVEventRecord for CSVTransferEvent {
+    const COLUMNS: &'static [&'static str] = &[
+        "contract_address",
+        "evt_tx_hash",
+        "evt_tx_from",
+        "evt_tx_to",
+        "evt_index",
+        "evt_block_time",
+        "evt_block_number",
+        "from",
+        "to",
+        "tokenId",
+    ];
+}
+
+fn convert_transfer_events(events: Vec<CSVTransferEvent>) -> Result<Vec<SimulationEvent>> {
+    events
         .into_iter()
-        .map(|event| SimulationEvent {
-            tx_hash: TxHash::from_str(&event.evt_tx_hash).unwrap(),
-            pool_address: Address::from_str(&event.contract_address).unwrap(),
-            block: event.evt_block_number,
-            log_index: event.evt_index,
-            from: Address::from_str(&event.evt_tx_from).unwrap(),
-            event: Event::CollectNpm(CollectNpm {
-                tokenId: U256::from_str(&event.tokenId).unwrap(),
-                recipient: Address::from_str(&event.recipient).unwrap(),
-                amount0: U256::from_str(&event.amount0).unwrap(),
-                amount1: U256::from_str(&event.amount1).unwrap(),
-            }),
+        .map(|event| {
+            Ok(SimulationEvent {
+                tx_hash: parse_tx_hash(&event.evt_tx_hash)?,
+                pool_address: parse_address(&event.contract_address)?,
+                block: event.evt_block_number,
+                log_index: event.evt_index,
+                from: parse_address(&event.evt_tx_from)?,
+                block_time: parse_block_time(&event.evt_block_time)?,
+                event: Event::Transfer(Transfer {
+                    from: parse_address(&event.from)?,
+                    to: parse_address(&event.to)?,
+                    tokenId: parse_uint(&event.tokenId)?,
+                }),
+            })
         })
-        .collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fee_analyzer::simulation_events::one_event_of_each_type;
+
+    const ROW: &str = "0xfdbaf04326acc24e3d1788333826b71e3291863a,\
+        0xa14b66258b4eb05ba4fce10fe1cef81948e601145da55a2014fde472dda141ef,\
+        0x8865910d6ca985782dc9cc521d23a10100fc800b,0x732560fa1d1a76350b1a500155ba978031b53833,\
+        738,2024-12-16 05:01:53.000 UTC,23767983,787149618249685149291181,-230400";
+
+    fn write_csv(name: &str, contents: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("read_events_{}_{}.csv", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn reads_rows_by_header_name() {
+        // the last two columns swapped, headered files are read by name
+        let swapped = ROW.rsplitn(3, ',').collect::<Vec<_>>();
+        let contents = format!(
+            "contract_address,evt_tx_hash,evt_tx_from,evt_tx_to,evt_index,evt_block_time,\
+             evt_block_number,tick,sqrtPriceX96\n{},{},{}\n",
+            swapped[2], swapped[0], swapped[1]
+        );
+        let path = write_csv("headers", &contents);
+
+        let events = read_events::<CSVInitializeEvent>(&path, true).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].sqrtPriceX96, "787149618249685149291181");
+        assert_eq!(events[0].tick, "-230400");
+        assert_eq!(events[0].evt_block_number, 23767983);
+    }
+
+    #[test]
+    fn reads_headerless_rows_positionally() {
+        let path = write_csv("headerless", &format!("{}\n{}\n", ROW, ROW));
+
+        let events = read_events::<CSVInitializeEvent>(&path, false).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].evt_index, 738);
+        assert_eq!(events[1].sqrtPriceX96, "787149618249685149291181");
+        assert_eq!(events[1].tick, "-230400");
+    }
+
+    #[test]
+    fn rejects_headerless_rows_with_the_wrong_column_count() {
+        let short_row = ROW.rsplit_once(',').unwrap().0;
+        let path = write_csv("short", &format!("{}\n{}\n", ROW, short_row));
+
+        let error = read_events::<CSVInitializeEvent>(&path, false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("row 2 has 8 columns, expected 9"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn amounts_parse_from_decimal_or_hex() {
+        assert_eq!(parse_uint::<160, 3>("4096").unwrap(), Uint::from(4096));
+        assert_eq!(parse_uint::<160, 3>("0x1000").unwrap(), Uint::from(4096));
+        assert!(parse_uint::<160, 3>("-1").is_err());
+        // 2^128
+        assert!(parse_uint::<128, 2>(&format!("0x1{}", "0".repeat(32))).is_err());
+
+        assert_eq!(
+            parse_signed::<256, 4>("-4096").unwrap(),
+            I256::try_from(-4096).unwrap()
+        );
+        assert_eq!(
+            parse_signed::<256, 4>("0x1000").unwrap(),
+            I256::try_from(4096).unwrap()
+        );
+        assert_eq!(
+            parse_signed::<256, 4>("-0x1000").unwrap(),
+            I256::try_from(-4096).unwrap()
+        );
+        // the abi's two's complement word for -4096
+        let word = format!("0x{}f000", "f".repeat(60));
+        assert_eq!(
+            parse_signed::<256, 4>(&word).unwrap(),
+            I256::try_from(-4096).unwrap()
+        );
+        assert_eq!(
+            parse_signed::<128, 2>(&word).unwrap(),
+            Signed::try_from(-4096).unwrap()
+        );
+        assert!(parse_signed::<256, 4>("0xg").is_err());
+    }
+
+    #[test]
+    fn block_times_parse_from_each_exporter_format() {
+        let time = DateTime::from_timestamp(1_734_325_313, 0).unwrap();
+        for value in [
+            "1734325313",
+            "2024-12-16T05:01:53Z",
+            "2024-12-16T07:01:53+02:00",
+            "2024-12-16 05:01:53.000 UTC",
+            "2024-12-16 05:01:53",
+            "2024-12-16T05:01:53",
+        ] {
+            assert_eq!(parse_block_time(value).unwrap(), time, "{}", value);
+        }
+        assert_eq!(
+            parse_block_time("2024-12-16 05:01:53.250 UTC").unwrap(),
+            DateTime::from_timestamp(1_734_325_313, 250_000_000).unwrap()
+        );
+        assert!(parse_block_time("16/12/2024 05:01").is_err());
+    }
+
+    #[test]
+    fn written_events_read_back_the_same_in_order() {
+        let events = one_event_of_each_type();
+        let dir = std::env::temp_dir().join(format!("write_events_{}", std::process::id()));
+
+        let config = write_events_to_csv(&events, &dir).unwrap();
+        let read = parse_events(&config).unwrap();
+        assert_eq!(read.len(), events.len());
+        assert_eq!(read, events);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unified_rows_read_as_their_event_type() {
+        let events = one_event_of_each_type();
+        let dir = std::env::temp_dir().join(format!("unified_events_{}", std::process::id()));
+        let config = write_events_to_csv(&events, &dir).unwrap();
+
+        // each per type file's rows under the union of their headers, both
+        // spellings of the event type
+        let mut rows: Vec<(String, HashMap<String, String>)> = Vec::new();
+        let mut columns: Vec<String> = Vec::new();
+        for (event_type, path) in [
+            ("pool_created", &config.pool_created_events_path),
+            ("Initialize", &config.initialize_events_path),
+            ("mint", &config.mint_events_path),
+            ("IncreaseLiquidity", &config.increase_liquidity_events_path),
+            ("transfer", config.transfer_events_path.as_ref().unwrap()),
+            ("Swap", &config.swap_events_path),
+            ("burn", &config.burn_events_path),
+            ("decrease_liquidity", &config.decrease_liquidity_events_path),
+            ("CollectPool", &config.collect_pool_events_path),
+            ("collect_npm", &config.collect_npm_events_path),
+        ] {
+            let mut rdr = ReaderBuilder::new().from_path(path).unwrap();
+            let headers = rdr.headers().unwrap().clone();
+            for header in &headers {
+                if !columns.iter().any(|column| column == header) {
+                    columns.push(header.to_string());
+                }
+            }
+            for record in rdr.records() {
+                let values = headers
+                    .iter()
+                    .zip(&record.unwrap())
+                    .map(|(header, value)| (header.to_string(), value.to_string()))
+                    .collect();
+                rows.push((event_type.to_string(), values));
+            }
+        }
+        let mut contents = format!("event_type,{}\n", columns.join(","));
+        for (event_type, values) in &rows {
+            let row = columns
+                .iter()
+                .map(|column| values.get(column).map_or("", String::as_str))
+                .collect::<Vec<_>>();
+            contents.push_str(&format!("{},{}\n", event_type, row.join(",")));
+        }
+        let path = write_csv("unified", &contents);
+
+        assert_eq!(read_unified_events(&path, None).unwrap(), events);
+        assert_eq!(read_unified_events(&path, Some(3)).unwrap(), events[..3]);
+
+        let unknown = write_csv("unified_unknown", &contents.replace("\nSwap,", "\nFlash,"));
+        assert!(read_unified_events(&unknown, None)
+            .unwrap_err()
+            .to_string()
+            .contains("row 6 has unknown event type \"Flash\""));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn input_problems_are_csv_parse_errors() {
+        let csv_parse_error = |error: eyre::Report| match error.downcast::<FeeAnalyzerError>() {
+            Ok(FeeAnalyzerError::CsvParse { path, reason }) => (path, reason),
+            other => panic!("expected a csv parse error, got {:?}", other),
+        };
+        let events = one_event_of_each_type();
+        let dir = std::env::temp_dir().join(format!("csv_parse_errors_{}", std::process::id()));
+        let config = write_events_to_csv(&events, &dir).unwrap();
+        validate_csv(&config).unwrap();
+        check_tick_ranges(&config, &events).unwrap();
+
+        // off the pool's tick spacing of 200
+        let mut off_spacing = events.clone();
+        if let Event::Mint(mint) = &mut off_spacing[2].event {
+            mint.tickLower = I24::try_from(-230_300).unwrap();
+        }
+        let (path, reason) = csv_parse_error(check_tick_ranges(&config, &off_spacing).unwrap_err());
+        assert_eq!(path, config.mint_events_path);
+        assert!(
+            reason.starts_with("mint at block 1 log index 2"),
+            "{}",
+            reason
+        );
+        assert!(
+            reason.ends_with("multiples of the tick spacing 200"),
+            "{}",
+            reason
+        );
+
+        let mut inverted = events.clone();
+        if let Event::Burn(burn) = &mut inverted[6].event {
+            burn.tickUpper = burn.tickLower;
+        }
+        let (path, reason) = csv_parse_error(check_tick_ranges(&config, &inverted).unwrap_err());
+        assert_eq!(path, config.burn_events_path);
+        assert!(
+            reason.ends_with("the lower tick isn't below the upper tick"),
+            "{}",
+            reason
+        );
+
+        // a swap row whose tick doesn't parse
+        let swaps = std::fs::read_to_string(&config.swap_events_path).unwrap();
+        std::fs::write(&config.swap_events_path, swaps.replace(",-229428", ",low")).unwrap();
+        let (path, reason) = csv_parse_error(validate_csv(&config).unwrap_err());
+        assert_eq!(path, config.swap_events_path);
+        assert!(reason.starts_with("row 1: Invalid tick low"), "{}", reason);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn validation_names_a_missing_required_column() {
+        let mut columns: Vec<&str> = [
+            CSVInitializeEvent::COLUMNS,
+            CSVPoolCreatedEvent::COLUMNS,
+            CSVSwapEvent::COLUMNS,
+            CSVMintEvent::COLUMNS,
+            CSVBurnEvent::COLUMNS,
+            CSVCollectPoolEvent::COLUMNS,
+            CSVCollectNpmEvent::COLUMNS,
+            CSVIncreaseLiquidityEvent::COLUMNS,
+            CSVDecreaseLiquidityEvent::COLUMNS,
+            CSVTransferEvent::COLUMNS,
+            &["event_type"],
+        ]
+        .concat();
+        columns.sort();
+        columns.dedup();
+        let complete = write_csv("validate_complete", &format!("{}\n", columns.join(",")));
+        columns.retain(|column| *column != "tick");
+        let missing_tick = write_csv("validate_missing", &format!("{}\n", columns.join(",")));
+        let dir = std::env::temp_dir().join(format!("validate_csv_{}", std::process::id()));
+        let mut config = write_events_to_csv(&[], &dir).unwrap();
+
+        config.unified_events_path = Some(complete);
+        validate_csv(&config).unwrap();
+
+        config.unified_events_path = Some(missing_tick.clone());
+        let error = validate_csv(&config).unwrap_err();
+        match error.downcast_ref::<FeeAnalyzerError>() {
+            Some(FeeAnalyzerError::CsvParse { path, reason }) => {
+                assert_eq!(path, &missing_tick);
+                assert!(reason.starts_with("missing columns tick,"), "{}", reason);
+            }
+            _ => panic!("expected a csv parse error, got {:?}", error),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bad_ticks_are_reported_with_their_file_and_row() {
+        assert_eq!(
+            parse_tick("-887272").unwrap(),
+            I24::try_from(MIN_TICK).unwrap()
+        );
+        assert!(parse_tick("887273")
+            .unwrap_err()
+            .to_string()
+            .contains("Tick 887273 is outside [-887272, 887272]"));
+        assert!(parse_tick("1e3")
+            .unwrap_err()
+            .to_string()
+            .contains("Invalid tick 1e3"));
+
+        for (name, tick, reason) in [
+            ("tick_range", "887273", "Tick 887273 is outside"),
+            ("tick_text", "low", "Invalid tick low"),
+        ] {
+            let bad_row = format!("{},{}", ROW.rsplit_once(',').unwrap().0, tick);
+            let path = write_csv(name, &format!("{}\n{}\n", ROW, bad_row));
+            let events = read_events::<CSVInitializeEvent>(&path, false).unwrap();
+
+            let error = convert_rows(&path, events, convert_initialize_events).unwrap_err();
+            match error.downcast_ref::<FeeAnalyzerError>() {
+                Some(FeeAnalyzerError::CsvParse {
+                    path: error_path,
+                    reason: error_reason,
+                }) => {
+                    assert_eq!(error_path, &path);
+                    assert!(error_reason.starts_with("row 2: "), "{}", error_reason);
+                    assert!(error_reason.contains(reason), "{}", error_reason);
+                }
+                _ => panic!("expected a csv parse error, got {:?}", error),
+            }
+        }
+    }
 }
@@ -1,14 +1,25 @@
-use std::path::Path;
+use std::{collections::BTreeMap, fs::File, path::Path, str::FromStr};
 
-use csv::WriterBuilder;
-use eyre::Result;
-use serde::Serialize;
+use alloy::primitives::{Address, U256};
+use csv::{Writer, WriterBuilder};
+use eyre::{eyre, Context, Result};
+use serde::{Deserialize, Serialize};
 
-use crate::chain_interactions::collect::PositionInfo;
+use crate::chain_interactions::{collect::PositionInfo, fee_growth::TickRangeFeeGrowth};
+
+use super::{
+    position_diff::PositionDiff, ExactOutputSwap, LiquiditySample, MaxDrawdown, PnlDistribution,
+    PnlSample, PoolSummary, PositionValue, RunWarning, Strategy,
+};
+
+// bump whenever the columns of CSVPositionInfo are added, removed, or change meaning
+pub const POSITION_CSV_SCHEMA_VERSION: u32 = 14;
 
 pub fn write_positions_to_csv(
     positions: Vec<PositionInfo>,
     path: &str,
+    denomination_token: Address,
+    timed_out: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let path = Path::new(path);
 
@@ -24,14 +35,558 @@ pub fn write_positions_to_csv(
         writer.serialize(convert_position_info_to_csv(position))?;
     }
     writer.flush()?;
+
+    write_output_metadata(path, denomination_token, timed_out)?;
+    Ok(())
+}
+
+// appends position records to the output csv as they're finalized, flushing
+// after each one so a crashed run still leaves every finished row on disk.
+// records with no liquidity are skipped like in write_positions_to_csv.
+pub struct PositionCsvWriter {
+    writer: Writer<File>,
+    // records starting below this are not written, zero writes all of them
+    dust_threshold_weth: U256,
+}
+
+impl PositionCsvWriter {
+    pub fn create(
+        path: &str,
+        dust_threshold_weth: U256,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Path::new(path);
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        Ok(Self {
+            writer: WriterBuilder::new().has_headers(true).from_path(path)?,
+            dust_threshold_weth,
+        })
+    }
+
+    pub(crate) fn write(&mut self, position: &PositionInfo) -> Result<()> {
+        if position.liquidity_in == 0 || position.is_dust(self.dust_threshold_weth) {
+            return Ok(());
+        }
+        self.writer
+            .serialize(convert_position_info_to_csv(position.clone()))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// writes the pool liquidity samples to `<output>.liquidity_depth.csv`
+pub fn write_liquidity_depth_to_csv(
+    samples: &[LiquiditySample],
+    positions_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(positions_path).with_extension("liquidity_depth.csv");
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+
+    for sample in samples {
+        writer.serialize(CSVLiquiditySample {
+            block: sample.block.to_string(),
+            liquidity: sample.liquidity.to_string(),
+            sqrt_price_x96: sample.sqrt_price_x96.to_string(),
+            tick_before: sample.tick_before.to_string(),
+            tick: sample.tick.to_string(),
+            weth_volume: sample.weth_volume.to_string(),
+            price_impact_bps: sample.price_impact_bps.to_string(),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CSVLiquiditySample {
+    block: String,
+    liquidity: String,
+    sqrt_price_x96: String,
+    tick_before: String,
+    tick: String,
+    weth_volume: String,
+    price_impact_bps: String,
+}
+
+// writes the fee growth inside each span between the occupied ticks still
+// initialized at the end of the replay to `<output>.tick_fee_distribution.csv`,
+// with each span's share of the pool's global fee growth. the simulated pool
+// starts the run with no fee growth, so the end values are the run's deltas
+pub(crate) fn write_tick_fee_distribution_to_csv(
+    ((global0, global1), ranges): ((U256, U256), Vec<TickRangeFeeGrowth>),
+    clanker_is_token0: bool,
+    positions_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(positions_path).with_extension("tick_fee_distribution.csv");
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+
+    let share = |inside: U256, global: U256| {
+        if global == U256::ZERO {
+            0.0
+        } else {
+            f64::from(inside) / f64::from(global)
+        }
+    };
+    for range in ranges {
+        let (growth0, growth1) = range.fee_growth_inside;
+        let (token_growth, weth_growth, token_global, weth_global) = if clanker_is_token0 {
+            (growth0, growth1, global0, global1)
+        } else {
+            (growth1, growth0, global1, global0)
+        };
+        writer.serialize(CSVTickRangeFeeGrowth {
+            lower_tick: range.lower_tick.to_string(),
+            upper_tick: range.upper_tick.to_string(),
+            liquidity: range.liquidity.to_string(),
+            token_fee_growth_inside_x128: token_growth.to_string(),
+            weth_fee_growth_inside_x128: weth_growth.to_string(),
+            token_share_of_global: share(token_growth, token_global).to_string(),
+            weth_share_of_global: share(weth_growth, weth_global).to_string(),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CSVTickRangeFeeGrowth {
+    lower_tick: String,
+    upper_tick: String,
+    liquidity: String,
+    token_fee_growth_inside_x128: String,
+    weth_fee_growth_inside_x128: String,
+    token_share_of_global: String,
+    weth_share_of_global: String,
+}
+
+// writes the position values to `<output>.position_values.csv`, one row per
+// position open at each valued block
+pub(crate) fn write_position_values_to_csv(
+    values: &[PositionValue],
+    positions_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(positions_path).with_extension("position_values.csv");
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+
+    for value in values {
+        writer.serialize(CSVPositionValue {
+            block: value.block.to_string(),
+            token_id: value.original_token_id.to_string(),
+            value_in_weth: value.value_in_weth.to_string(),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CSVPositionValue {
+    block: String,
+    token_id: String,
+    value_in_weth: String,
+}
+
+// writes the pnl samples to `<output>.pnl_timeseries.csv`, one row per block
+// where a position changed plus a final row after the end of run close out
+pub(crate) fn write_pnl_timeseries_to_csv(
+    samples: &[PnlSample],
+    positions_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(positions_path).with_extension("pnl_timeseries.csv");
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+
+    for sample in samples {
+        writer.serialize(CSVPnlSample {
+            block: sample.block.to_string(),
+            open_positions: sample.open_positions.to_string(),
+            realized_token_fees: sample.realized_token_fees.to_string(),
+            realized_weth_fees: sample.realized_weth_fees.to_string(),
+            realized_fees_in_weth: sample.realized_fees_in_weth.to_string(),
+            open_token_amount: sample.open_token_amount.to_string(),
+            open_weth_amount: sample.open_weth_amount.to_string(),
+            open_value_in_weth: sample.open_value_in_weth.to_string(),
+            pnl_in_weth: sample.pnl_in_weth.to_string(),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CSVPnlSample {
+    block: String,
+    open_positions: String,
+    realized_token_fees: String,
+    realized_weth_fees: String,
+    realized_fees_in_weth: String,
+    open_token_amount: String,
+    open_weth_amount: String,
+    open_value_in_weth: String,
+    pnl_in_weth: String,
+}
+
+// writes the run's warnings to `<output>.warnings.csv`, in the order they came up
+pub(crate) fn write_warnings_to_csv(
+    warnings: &[RunWarning],
+    positions_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(positions_path).with_extension("warnings.csv");
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+
+    for warning in warnings {
+        writer.serialize(CSVRunWarning {
+            block: warning.block.to_string(),
+            kind: warning.kind.to_string(),
+            detail: warning.detail.clone(),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CSVRunWarning {
+    block: String,
+    kind: String,
+    detail: String,
+}
+
+// writes the swaps replayed as exact output to
+// `<output>.exact_output_swaps.csv`, in replay order
+pub(crate) fn write_exact_output_swaps_to_csv(
+    swaps: &[ExactOutputSwap],
+    positions_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(positions_path).with_extension("exact_output_swaps.csv");
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+
+    for swap in swaps {
+        writer.serialize(CSVExactOutputSwap {
+            block: swap.block.to_string(),
+            tx_hash: swap.tx_hash.to_string(),
+            log_index: swap.log_index.to_string(),
+            sender: swap.swap.sender.to_string(),
+            recipient: swap.swap.recipient.to_string(),
+            amount0: swap.swap.amount0.to_string(),
+            amount1: swap.swap.amount1.to_string(),
+            sqrt_price_x96: swap.swap.sqrtPriceX96.to_string(),
+            liquidity: swap.swap.liquidity.to_string(),
+            tick: swap.swap.tick.to_string(),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CSVExactOutputSwap {
+    block: String,
+    tx_hash: String,
+    log_index: String,
+    sender: String,
+    recipient: String,
+    amount0: String,
+    amount1: String,
+    sqrt_price_x96: String,
+    liquidity: String,
+    tick: String,
+}
+
+// writes the per pool totals of a multi pool run to `<output>.pools.csv`
+pub(crate) fn write_pool_summary_to_csv(
+    summary: &PoolSummary,
+    output_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = output_path.with_extension("pools.csv");
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+
+    for totals in &summary.pools {
+        writer.serialize(CSVPoolTotals {
+            pool: totals.key.pool.to_string(),
+            token0: totals.key.token0.to_string(),
+            token1: totals.key.token1.to_string(),
+            fee: totals.key.fee.to_string(),
+            output_csv: totals.output_csv.clone(),
+            position_records: totals.records.to_string(),
+            weth_fees_earned: totals.fees_earned_weth.to_string(),
+            net_pnl_in_weth: totals.net_pnl_in_weth.to_string(),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CSVPoolTotals {
+    pool: String,
+    token0: String,
+    token1: String,
+    fee: String,
+    output_csv: String,
+    position_records: String,
+    weth_fees_earned: String,
+    net_pnl_in_weth: String,
+}
+
+#[derive(Serialize)]
+struct OutputMetadata {
+    crate_version: String,
+    schema_version: u32,
+    columns: Vec<String>,
+    denomination_token: Address,
+    // the replay stopped at a timeout, so the records only cover the events
+    // before it
+    timed_out: bool,
+}
+
+// writes `<output>.meta.json` next to the csv so downstream tooling can
+// detect column changes
+pub fn write_output_metadata(
+    csv_path: &Path,
+    denomination_token: Address,
+    timed_out: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = OutputMetadata {
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        schema_version: POSITION_CSV_SCHEMA_VERSION,
+        columns: position_csv_columns()?,
+        denomination_token,
+        timed_out,
+    };
+
+    let metadata_path = csv_path.with_extension("meta.json");
+    std::fs::write(metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+    Ok(())
+}
+
+// the positions csv's header, in column order
+pub(crate) fn position_csv_columns() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // serialize an empty row to pull the header names from CSVPositionInfo
+    let mut header_writer = WriterBuilder::new().has_headers(true).from_writer(vec![]);
+    header_writer.serialize(CSVPositionInfo::default())?;
+    let header_bytes = header_writer.into_inner()?;
+    Ok(csv::Reader::from_reader(header_bytes.as_slice())
+        .headers()?
+        .iter()
+        .map(String::from)
+        .collect())
+}
+
+// the record's positions csv row as text, in the order of
+// position_csv_columns
+#[cfg(feature = "sqlite")]
+pub(crate) fn position_csv_row(
+    position_info: PositionInfo,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut row_writer = WriterBuilder::new().has_headers(false).from_writer(vec![]);
+    row_writer.serialize(convert_position_info_to_csv(position_info))?;
+    let row_bytes = row_writer.into_inner()?;
+    let row = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(row_bytes.as_slice())
+        .records()
+        .next()
+        .ok_or("Serialized position produced no csv row")??;
+    Ok(row.iter().map(String::from).collect())
+}
+
+// writes the position records grouped by original token id to
+// `<output>.grouped.json`, each position's records in `index` order so its
+// lifecycle reads top to bottom
+pub(crate) fn write_grouped_positions_to_json(
+    positions: &[PositionInfo],
+    positions_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut grouped: BTreeMap<U256, Vec<&PositionInfo>> = BTreeMap::new();
+    for position in positions {
+        grouped
+            .entry(position.original_token_id)
+            .or_default()
+            .push(position);
+    }
+
+    let groups: Vec<GroupedPosition> = grouped
+        .into_iter()
+        .map(|(original_token_id, mut records)| {
+            records.sort_by_key(|record| record.index);
+            GroupedPosition {
+                token_id: original_token_id.to_string(),
+                owner: records[0].owner.to_string(),
+                records: records
+                    .into_iter()
+                    .map(|record| convert_position_info_to_csv(record.clone()))
+                    .collect(),
+            }
+        })
+        .collect();
+
+    let path = Path::new(positions_path).with_extension("grouped.json");
+    std::fs::write(path, serde_json::to_string_pretty(&groups)?)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CSVStrategy {
+    strategy_id: String,
+    owner: String,
+    lower_tick: String,
+    upper_tick: String,
+    // `;` separated, in mint order
+    token_ids: String,
+    position_records: String,
+    block_in: String,
+    block_out: String,
+    token_fees_earned: String,
+    weth_fees_earned: String,
+    net_pnl_in_weth: String,
+}
+
+// writes the merged position chains to `<output>.strategies.csv`
+pub(crate) fn write_strategies_to_csv(
+    strategies: &[Strategy],
+    positions_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(positions_path).with_extension("strategies.csv");
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+
+    for strategy in strategies {
+        writer.serialize(CSVStrategy {
+            strategy_id: strategy.strategy_id.to_string(),
+            owner: strategy.owner.to_string(),
+            lower_tick: strategy.lower_tick.to_string(),
+            upper_tick: strategy.upper_tick.to_string(),
+            token_ids: strategy
+                .token_ids
+                .iter()
+                .map(|token_id| token_id.to_string())
+                .collect::<Vec<_>>()
+                .join(";"),
+            position_records: strategy.records.to_string(),
+            block_in: strategy.block_in.to_string(),
+            block_out: strategy.block_out.to_string(),
+            token_fees_earned: strategy.fees_earned_token.to_string(),
+            weth_fees_earned: strategy.fees_earned_weth.to_string(),
+            net_pnl_in_weth: strategy.net_pnl_in_weth.to_string(),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct PnlBucket {
+    // None for the unbounded outer buckets
+    lower: Option<String>,
+    upper: Option<String>,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct CSVPnlSummary {
+    records: usize,
+    dust_records: usize,
+    histogram: Vec<PnlBucket>,
+    p10: Option<String>,
+    p50: Option<String>,
+    p90: Option<String>,
+    top_fees_per_weth_block: Vec<RankedRecord>,
+    max_drawdown: CSVMaxDrawdown,
+}
+
+#[derive(Serialize)]
+struct CSVMaxDrawdown {
+    drawdown_in_weth: String,
+    peak_block: Option<u64>,
+    peak_pnl_in_weth: String,
+    trough_block: Option<u64>,
+    trough_pnl_in_weth: String,
+    fraction_of_peak: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct RankedRecord {
+    token_id: String,
+    token_action_index: u64,
+    owner: String,
+    fees_per_weth_block: f64,
+    weth_fees_earned: String,
+    approx_starting_weth: String,
+    holding_blocks: u64,
+}
+
+// writes the net pnl distribution, the capital efficiency ranking, and the
+// max drawdown to `<output>.summary.json`, amounts in weth wei
+pub(crate) fn write_pnl_distribution_to_json(
+    distribution: &PnlDistribution,
+    top_fees_per_weth_block: &[&PositionInfo],
+    max_drawdown: &MaxDrawdown,
+    positions_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let boundary = |i: Option<usize>| {
+        i.and_then(|i| distribution.boundaries.get(i))
+            .map(|boundary| boundary.to_string())
+    };
+    let summary = CSVPnlSummary {
+        records: distribution.records,
+        dust_records: distribution.dust_records,
+        histogram: distribution
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| PnlBucket {
+                lower: boundary(bucket.checked_sub(1)),
+                upper: boundary(Some(bucket)),
+                count: *count,
+            })
+            .collect(),
+        p10: distribution.p10.map(|p| p.to_string()),
+        p50: distribution.p50.map(|p| p.to_string()),
+        p90: distribution.p90.map(|p| p.to_string()),
+        top_fees_per_weth_block: top_fees_per_weth_block
+            .iter()
+            .map(|position| RankedRecord {
+                token_id: position.original_token_id.to_string(),
+                token_action_index: position.index,
+                owner: position.owner.to_string(),
+                fees_per_weth_block: position.fees_per_weth_block,
+                weth_fees_earned: position.fees_earned_weth.to_string(),
+                approx_starting_weth: position.approx_starting_weth.to_string(),
+                holding_blocks: position.holding_blocks,
+            })
+            .collect(),
+        max_drawdown: CSVMaxDrawdown {
+            drawdown_in_weth: max_drawdown.drawdown_in_weth.to_string(),
+            peak_block: max_drawdown.peak_block,
+            peak_pnl_in_weth: max_drawdown.peak_pnl_in_weth.to_string(),
+            trough_block: max_drawdown.trough_block,
+            trough_pnl_in_weth: max_drawdown.trough_pnl_in_weth.to_string(),
+            fraction_of_peak: max_drawdown.fraction_of_peak,
+        },
+    };
+
+    let path = Path::new(positions_path).with_extension("summary.json");
+    std::fs::write(path, serde_json::to_string_pretty(&summary)?)?;
     Ok(())
 }
 
 #[derive(Serialize)]
+struct GroupedPosition {
+    token_id: String,
+    owner: String,
+    records: Vec<CSVPositionInfo>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
 struct CSVPositionInfo {
     token_id: String,
+    owner: String,
     token_action_index: String,
     action_taken: String,
+    close_reason: String,
     lower_tick: String,
     upper_tick: String,
     opening_block: String,
@@ -47,18 +602,231 @@ struct CSVPositionInfo {
     tick_out: String,
     token_fees_earned: String,
     weth_fees_earned: String,
+    token_fees_from_growth: String,
+    weth_fees_from_growth: String,
+    avg_pool_liquidity: String,
+    volume_in_range_weth: String,
+    fees_to_volume: String,
     net_token_gain: String,
     net_weth_gain: String,
     approx_starting_weth: String,
     approx_ending_weth: String,
     net_pnl_in_weth: String,
+    gas_used_in: String,
+    gas_used_out: String,
+    // the amount and fee columns above scaled by their token's decimals
+    token_amount_in_decimal: String,
+    weth_amount_in_decimal: String,
+    token_amount_out_decimal: String,
+    weth_amount_out_decimal: String,
+    token_fees_earned_decimal: String,
+    weth_fees_earned_decimal: String,
+    token_fees_from_growth_decimal: String,
+    weth_fees_from_growth_decimal: String,
+    in_range_at_entry: String,
+    in_range_at_exit: String,
+    // raw position manager checkpoints, by token0 and token1 rather than
+    // token and weth
+    fee_growth_inside_0_last_in: String,
+    fee_growth_inside_1_last_in: String,
+    fee_growth_inside_0_last_out: String,
+    fee_growth_inside_1_last_out: String,
+    close_out_error: String,
+    approx_ending_weth_spot: String,
+    approx_ending_weth_executable: String,
+    holding_seconds: String,
+    token_fee_apr: String,
+    weth_fee_apr: String,
+    fee_apr_in_weth: String,
+    holding_blocks: String,
+    fees_per_weth_block: String,
+    // raw collect log amounts, fees plus any withdrawn liquidity
+    token_collected: String,
+    weth_collected: String,
+}
+
+// reads back the records of a positions csv written by this schema version.
+// the simulated token id and the fee growth inside readings aren't in the
+// csv, so the token id is the original one and the readings are zero. the
+// decimals only matter for Display and are taken as 18
+pub(crate) fn read_positions_from_csv(path: &Path) -> Result<Vec<PositionInfo>> {
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("Failed to open positions csv {:?}", path))?;
+    reader
+        .deserialize::<CSVPositionInfo>()
+        .enumerate()
+        .map(|(i, row)| {
+            row.map_err(|e| eyre!(e))
+                .and_then(convert_csv_to_position_info)
+                .with_context(|| format!("{:?} row {}", path, i + 1))
+        })
+        .collect()
+}
+
+fn convert_csv_to_position_info(row: CSVPositionInfo) -> Result<PositionInfo> {
+    fn parse<T: FromStr>(column: &str, value: &str) -> Result<T> {
+        value
+            .parse()
+            .map_err(|_| eyre!("{} is not a valid value: {:?}", column, value))
+    }
+    let close_reason = match row.close_reason.as_str() {
+        "" => None,
+        reason => Some(reason.parse()?),
+    };
+    let original_token_id = parse("token_id", &row.token_id)?;
+    Ok(PositionInfo {
+        token_id: original_token_id,
+        original_token_id,
+        owner: parse("owner", &row.owner)?,
+        lower_tick: parse("lower_tick", &row.lower_tick)?,
+        upper_tick: parse("upper_tick", &row.upper_tick)?,
+        index: parse("token_action_index", &row.token_action_index)?,
+        position_action: row.action_taken.parse()?,
+        closed: close_reason.is_some(),
+        close_reason,
+        close_out_error: Some(row.close_out_error).filter(|error| !error.is_empty()),
+        block_in: parse("opening_block", &row.opening_block)?,
+        token_amount_in: parse("token_amount_in", &row.token_amount_in)?,
+        weth_amount_in: parse("weth_amount_in", &row.weth_amount_in)?,
+        sqrt_price_limit_x96_in: parse("sqrt_price_limit_x96_in", &row.sqrt_price_limit_x96_in)?,
+        tick_in: parse("tick_in", &row.tick_in)?,
+        liquidity_in: parse("liquidity_in", &row.liquidity_in)?,
+        block_out: parse("closing_block", &row.closing_block)?,
+        token_amount_out: parse("token_amount_out", &row.token_amount_out)?,
+        weth_amount_out: parse("weth_amount_out", &row.weth_amount_out)?,
+        sqrt_price_limit_x96_out: parse("sqrt_price_limit_x96_out", &row.sqrt_price_limit_x96_out)?,
+        tick_out: parse("tick_out", &row.tick_out)?,
+        fees_earned_token: parse("token_fees_earned", &row.token_fees_earned)?,
+        fees_earned_weth: parse("weth_fees_earned", &row.weth_fees_earned)?,
+        fee_growth_inside_in: (U256::ZERO, U256::ZERO),
+        fee_growth_inside_out: (U256::ZERO, U256::ZERO),
+        fee_growth_inside_last_in: (
+            parse(
+                "fee_growth_inside_0_last_in",
+                &row.fee_growth_inside_0_last_in,
+            )?,
+            parse(
+                "fee_growth_inside_1_last_in",
+                &row.fee_growth_inside_1_last_in,
+            )?,
+        ),
+        fee_growth_inside_last_out: (
+            parse(
+                "fee_growth_inside_0_last_out",
+                &row.fee_growth_inside_0_last_out,
+            )?,
+            parse(
+                "fee_growth_inside_1_last_out",
+                &row.fee_growth_inside_1_last_out,
+            )?,
+        ),
+        fees_from_growth_token: parse("token_fees_from_growth", &row.token_fees_from_growth)?,
+        fees_from_growth_weth: parse("weth_fees_from_growth", &row.weth_fees_from_growth)?,
+        avg_pool_liquidity: parse("avg_pool_liquidity", &row.avg_pool_liquidity)?,
+        volume_in_range_weth: parse("volume_in_range_weth", &row.volume_in_range_weth)?,
+        fees_to_volume: parse("fees_to_volume", &row.fees_to_volume)?,
+        holding_seconds: parse("holding_seconds", &row.holding_seconds)?,
+        token_fee_apr: parse("token_fee_apr", &row.token_fee_apr)?,
+        weth_fee_apr: parse("weth_fee_apr", &row.weth_fee_apr)?,
+        fee_apr_in_weth: parse("fee_apr_in_weth", &row.fee_apr_in_weth)?,
+        holding_blocks: parse("holding_blocks", &row.holding_blocks)?,
+        fees_per_weth_block: parse("fees_per_weth_block", &row.fees_per_weth_block)?,
+        collected_token: parse("token_collected", &row.token_collected)?,
+        collected_weth: parse("weth_collected", &row.weth_collected)?,
+        approx_starting_weth: parse("approx_starting_weth", &row.approx_starting_weth)?,
+        approx_ending_weth: parse("approx_ending_weth", &row.approx_ending_weth)?,
+        approx_ending_weth_spot: parse("approx_ending_weth_spot", &row.approx_ending_weth_spot)?,
+        approx_ending_weth_executable: parse(
+            "approx_ending_weth_executable",
+            &row.approx_ending_weth_executable,
+        )?,
+        end_token_gain_separate: parse("net_token_gain", &row.net_token_gain)?,
+        end_weth_gain_separate: parse("net_weth_gain", &row.net_weth_gain)?,
+        end_weth_gain_converted: parse("net_pnl_in_weth", &row.net_pnl_in_weth)?,
+        gas_used_in: parse("gas_used_in", &row.gas_used_in)?,
+        gas_used_out: parse("gas_used_out", &row.gas_used_out)?,
+        token_decimals: 18,
+        weth_decimals: 18,
+    })
+}
+
+// writes the record by record comparison of two runs, `b` minus `a` for the
+// deltas and blank values for a side the record is missing from
+pub(crate) fn write_position_diffs_to_csv(
+    diffs: &[PositionDiff],
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = WriterBuilder::new().has_headers(true).from_path(path)?;
+
+    fn or_blank<T: ToString>(value: Option<T>) -> String {
+        value.map(|value| value.to_string()).unwrap_or_default()
+    }
+    for diff in diffs {
+        writer.serialize(CSVPositionDiff {
+            token_id: diff.original_token_id.to_string(),
+            token_action_index: diff.index.to_string(),
+            present_in: diff.present_in().to_string(),
+            token_fees_earned_a: or_blank(diff.a.map(|a| a.fees_earned_token)),
+            token_fees_earned_b: or_blank(diff.b.map(|b| b.fees_earned_token)),
+            token_fees_earned_delta: or_blank(diff.fees_earned_token_delta()),
+            weth_fees_earned_a: or_blank(diff.a.map(|a| a.fees_earned_weth)),
+            weth_fees_earned_b: or_blank(diff.b.map(|b| b.fees_earned_weth)),
+            weth_fees_earned_delta: or_blank(diff.fees_earned_weth_delta()),
+            net_pnl_in_weth_a: or_blank(diff.a.map(|a| a.net_pnl_in_weth)),
+            net_pnl_in_weth_b: or_blank(diff.b.map(|b| b.net_pnl_in_weth)),
+            net_pnl_in_weth_delta: or_blank(diff.net_pnl_in_weth_delta()),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CSVPositionDiff {
+    token_id: String,
+    token_action_index: String,
+    present_in: String,
+    token_fees_earned_a: String,
+    token_fees_earned_b: String,
+    token_fees_earned_delta: String,
+    weth_fees_earned_a: String,
+    weth_fees_earned_b: String,
+    weth_fees_earned_delta: String,
+    net_pnl_in_weth_a: String,
+    net_pnl_in_weth_b: String,
+    net_pnl_in_weth_delta: String,
+}
+
+// renders an integer amount scaled by `decimals` as a fixed point decimal, by
+// placing the point in its digits rather than going through a float, with
+// trailing zeros dropped (1500000000000000000 at 18 decimals is `1.5`)
+fn scaled_decimal(amount: impl ToString, decimals: u8) -> String {
+    let raw = amount.to_string();
+    let (sign, digits) = match raw.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", raw.as_str()),
+    };
+    let decimals = decimals as usize;
+    let digits = format!("{:0>width$}", digits, width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        format!("{}{}", sign, whole)
+    } else {
+        format!("{}{}.{}", sign, whole, fraction)
+    }
 }
 
 fn convert_position_info_to_csv(position_info: PositionInfo) -> CSVPositionInfo {
     CSVPositionInfo {
         token_id: position_info.original_token_id.to_string(),
+        owner: position_info.owner.to_string(),
         token_action_index: position_info.index.to_string(),
         action_taken: position_info.position_action.to_string(),
+        close_reason: position_info
+            .close_reason
+            .map(|reason| reason.to_string())
+            .unwrap_or_default(),
         lower_tick: position_info.lower_tick.to_string(),
         upper_tick: position_info.upper_tick.to_string(),
         opening_block: position_info.block_in.to_string(),
@@ -74,10 +842,283 @@ fn convert_position_info_to_csv(position_info: PositionInfo) -> CSVPositionInfo
         tick_out: position_info.tick_out.to_string(),
         token_fees_earned: position_info.fees_earned_token.to_string(),
         weth_fees_earned: position_info.fees_earned_weth.to_string(),
+        token_fees_from_growth: position_info.fees_from_growth_token.to_string(),
+        weth_fees_from_growth: position_info.fees_from_growth_weth.to_string(),
+        avg_pool_liquidity: position_info.avg_pool_liquidity.to_string(),
+        volume_in_range_weth: position_info.volume_in_range_weth.to_string(),
+        fees_to_volume: position_info.fees_to_volume.to_string(),
         net_token_gain: position_info.end_token_gain_separate.to_string(),
         net_weth_gain: position_info.end_weth_gain_separate.to_string(),
         approx_starting_weth: position_info.approx_starting_weth.to_string(),
         approx_ending_weth: position_info.approx_ending_weth.to_string(),
         net_pnl_in_weth: position_info.end_weth_gain_converted.to_string(),
+        gas_used_in: position_info.gas_used_in.to_string(),
+        gas_used_out: position_info.gas_used_out.to_string(),
+        token_amount_in_decimal: scaled_decimal(
+            position_info.token_amount_in,
+            position_info.token_decimals,
+        ),
+        weth_amount_in_decimal: scaled_decimal(
+            position_info.weth_amount_in,
+            position_info.weth_decimals,
+        ),
+        token_amount_out_decimal: scaled_decimal(
+            position_info.token_amount_out,
+            position_info.token_decimals,
+        ),
+        weth_amount_out_decimal: scaled_decimal(
+            position_info.weth_amount_out,
+            position_info.weth_decimals,
+        ),
+        token_fees_earned_decimal: scaled_decimal(
+            position_info.fees_earned_token,
+            position_info.token_decimals,
+        ),
+        weth_fees_earned_decimal: scaled_decimal(
+            position_info.fees_earned_weth,
+            position_info.weth_decimals,
+        ),
+        token_fees_from_growth_decimal: scaled_decimal(
+            position_info.fees_from_growth_token,
+            position_info.token_decimals,
+        ),
+        weth_fees_from_growth_decimal: scaled_decimal(
+            position_info.fees_from_growth_weth,
+            position_info.weth_decimals,
+        ),
+        in_range_at_entry: position_info.in_range_at_entry().to_string(),
+        in_range_at_exit: position_info.in_range_at_exit().to_string(),
+        fee_growth_inside_0_last_in: position_info.fee_growth_inside_last_in.0.to_string(),
+        fee_growth_inside_1_last_in: position_info.fee_growth_inside_last_in.1.to_string(),
+        fee_growth_inside_0_last_out: position_info.fee_growth_inside_last_out.0.to_string(),
+        fee_growth_inside_1_last_out: position_info.fee_growth_inside_last_out.1.to_string(),
+        close_out_error: position_info.close_out_error.clone().unwrap_or_default(),
+        approx_ending_weth_spot: position_info.approx_ending_weth_spot.to_string(),
+        approx_ending_weth_executable: position_info.approx_ending_weth_executable.to_string(),
+        holding_seconds: position_info.holding_seconds.to_string(),
+        token_fee_apr: position_info.token_fee_apr.to_string(),
+        weth_fee_apr: position_info.weth_fee_apr.to_string(),
+        fee_apr_in_weth: position_info.fee_apr_in_weth.to_string(),
+        holding_blocks: position_info.holding_blocks.to_string(),
+        fees_per_weth_block: position_info.fees_per_weth_block.to_string(),
+        token_collected: position_info.collected_token.to_string(),
+        weth_collected: position_info.collected_weth.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::{aliases::I24, I256, U160};
+
+    use super::*;
+    use crate::chain_interactions::backend::mock;
+
+    #[tokio::test]
+    async fn grouped_json_keeps_each_position_in_index_order() {
+        let mut positions = Vec::new();
+        for (token_id, index) in [(2, 1), (1, 2), (2, 0), (1, 0), (1, 1)] {
+            positions.push(mock::record(token_id, index).await);
+        }
+        let positions_path = std::env::temp_dir().join(format!(
+            "uniswap_v3_analyze_fees_grouped_{}.csv",
+            std::process::id()
+        ));
+        write_grouped_positions_to_json(&positions, positions_path.to_str().unwrap()).unwrap();
+        let path = positions_path.with_extension("grouped.json");
+        let groups: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let groups = groups
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|group| {
+                let indexes = group["records"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|record| record["token_action_index"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>();
+                (group["token_id"].as_str().unwrap().to_string(), indexes)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            vec![
+                (
+                    "1".to_string(),
+                    vec!["0".to_string(), "1".to_string(), "2".to_string()]
+                ),
+                ("2".to_string(), vec!["0".to_string(), "1".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn liquidity_depth_has_a_row_per_swap_in_block_order() {
+        // two swaps in block 5, one in block 9
+        let samples: Vec<LiquiditySample> = [(5, 100), (5, 250), (9, 40)]
+            .into_iter()
+            .map(|(block, tick)| LiquiditySample {
+                block,
+                liquidity: 1_000 + u128::from(block),
+                sqrt_price_x96: U160::from(1) << 96,
+                tick_before: I24::ZERO,
+                tick: I24::try_from(tick).unwrap(),
+                weth_volume: U256::from(7),
+                price_impact_bps: 1.5,
+            })
+            .collect();
+        let positions_path = std::env::temp_dir().join(format!(
+            "uniswap_v3_analyze_fees_depth_{}.csv",
+            std::process::id()
+        ));
+        write_liquidity_depth_to_csv(&samples, positions_path.to_str().unwrap()).unwrap();
+        let path = positions_path.with_extension("liquidity_depth.csv");
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec![
+                "block",
+                "liquidity",
+                "sqrt_price_x96",
+                "tick_before",
+                "tick",
+                "weth_volume",
+                "price_impact_bps"
+            ]
+        );
+        let rows: Vec<(u64, String, String)> = reader
+            .records()
+            .map(|row| {
+                let row = row.unwrap();
+                (
+                    row[0].parse().unwrap(),
+                    row[1].to_string(),
+                    row[4].to_string(),
+                )
+            })
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            rows,
+            [
+                (5, "1005".to_string(), "100".to_string()),
+                (5, "1005".to_string(), "250".to_string()),
+                (9, "1009".to_string(), "40".to_string()),
+            ]
+        );
+        assert!(rows.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    }
+
+    #[tokio::test]
+    async fn incremental_rows_are_complete_when_the_run_dies() {
+        let path = std::env::temp_dir().join(format!(
+            "uniswap_v3_analyze_fees_incremental_{}.csv",
+            std::process::id()
+        ));
+        let mut writer = PositionCsvWriter::create(path.to_str().unwrap(), U256::ZERO).unwrap();
+        let mut empty = mock::record(3, 0).await;
+        empty.liquidity_in = 0;
+        let records = [mock::record(1, 0).await, empty, mock::record(2, 0).await];
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        // killed before the writer is dropped, nothing is flushed on the way out
+        std::mem::forget(writer);
+
+        let written = read_positions_from_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let token_ids: Vec<U256> = written.iter().map(|p| p.original_token_id).collect();
+        assert_eq!(token_ids, [U256::from(1), U256::from(2)]);
+        assert_eq!(written[0].liquidity_in, records[0].liquidity_in);
+        assert_eq!(
+            written[1].approx_starting_weth,
+            records[2].approx_starting_weth
+        );
+    }
+
+    #[tokio::test]
+    async fn owners_survive_into_the_csv() {
+        let owners = [Address::repeat_byte(0x44), Address::repeat_byte(0x55)];
+        let mut positions = Vec::new();
+        for (token_id, owner) in (1..).zip(owners) {
+            let mut position = mock::record(token_id, 0).await;
+            position.owner = owner;
+            positions.push(position);
+        }
+        let dir = std::env::temp_dir().join(format!("owner_column_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("positions.csv");
+        write_positions_to_csv(
+            positions,
+            path.to_str().unwrap(),
+            Address::repeat_byte(0x42),
+            false,
+        )
+        .unwrap();
+
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        let column = reader
+            .headers()
+            .unwrap()
+            .iter()
+            .position(|header| header == "owner")
+            .unwrap();
+        let written: Vec<String> = reader
+            .records()
+            .map(|record| record.unwrap()[column].to_string())
+            .collect();
+        assert_eq!(written, owners.map(|owner| owner.to_string()));
+        let read_back: Vec<Address> = read_positions_from_csv(&path)
+            .unwrap()
+            .iter()
+            .map(|position| position.owner)
+            .collect();
+        assert_eq!(read_back, owners);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn scaled_decimal_places_the_point_in_the_digits() {
+        assert_eq!(
+            scaled_decimal(U256::from(1_500_000_000_000_000_000u64), 18),
+            "1.5"
+        );
+        assert_eq!(
+            scaled_decimal(U256::from(1_000_000_000_000_000_000u64), 18),
+            "1"
+        );
+        assert_eq!(scaled_decimal(U256::from(1), 18), "0.000000000000000001");
+        assert_eq!(scaled_decimal(U256::ZERO, 18), "0");
+        assert_eq!(scaled_decimal(U256::from(42), 0), "42");
+        assert_eq!(
+            scaled_decimal(I256::try_from(-2_500_000).unwrap(), 6),
+            "-2.5"
+        );
+        assert_eq!(scaled_decimal(I256::try_from(-5).unwrap(), 2), "-0.05");
+    }
+
+    #[test]
+    fn metadata_is_written_next_to_the_csv() {
+        let dir = std::env::temp_dir().join(format!("output_metadata_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let weth = Address::repeat_byte(0x42);
+
+        write_output_metadata(&dir.join("positions.csv"), weth, true).unwrap();
+        let metadata: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.join("positions.meta.json")).unwrap())
+                .unwrap();
+        assert_eq!(metadata["crate_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(metadata["schema_version"], POSITION_CSV_SCHEMA_VERSION);
+        assert_eq!(metadata["denomination_token"], weth.to_string());
+        assert_eq!(metadata["timed_out"], true);
+        assert_eq!(
+            metadata["columns"].as_array().unwrap().len(),
+            position_csv_columns().unwrap().len()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
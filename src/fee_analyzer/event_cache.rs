@@ -0,0 +1,128 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use alloy::primitives::{keccak256, B256};
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::{csv_input_reader::CSVReaderConfig, simulation_events::SimulationEvent};
+
+// MessagePack rather than bincode, alloy's signed integers only deserialize
+// from self-describing formats. bump the version whenever SimulationEvent or
+// the parsing behind it changes, so caches from older builds are rebuilt
+const EVENT_CACHE_VERSION: u32 = 5;
+
+#[derive(Serialize, Deserialize)]
+struct EventCache {
+    version: u32,
+    input_hash: B256,
+    events: Vec<SimulationEvent>,
+}
+
+// hash of every input csv's contents plus the settings that change how they
+// are parsed, a cache is only used when it was built from the same hash
+pub(crate) fn input_hash(config: &CSVReaderConfig) -> Result<B256> {
+    let mut paths: Vec<&str> = match &config.unified_events_path {
+        Some(path) => vec![path.as_str()],
+        None => vec![
+            &config.initialize_events_path,
+            &config.swap_events_path,
+            &config.mint_events_path,
+            &config.burn_events_path,
+            &config.collect_pool_events_path,
+            &config.collect_npm_events_path,
+            &config.pool_created_events_path,
+            &config.increase_liquidity_events_path,
+            &config.decrease_liquidity_events_path,
+        ],
+    };
+    if config.unified_events_path.is_none() {
+        paths.extend(config.transfer_events_path.as_deref());
+    }
+
+    let mut hashes = vec![config.has_headers as u8];
+    for path in paths {
+        let contents =
+            fs::read(path).with_context(|| format!("Failed to read {} for hashing", path))?;
+        hashes.extend_from_slice(keccak256(&contents).as_slice());
+    }
+    Ok(keccak256(&hashes))
+}
+
+// the cached events if the cache exists and was built from `input_hash`
+pub(crate) fn read_event_cache(path: &Path, input_hash: B256) -> Option<Vec<SimulationEvent>> {
+    let file = File::open(path).ok()?;
+    let cache: EventCache = match rmp_serde::from_read(BufReader::new(file)) {
+        Ok(cache) => cache,
+        Err(e) => {
+            info!(
+                "Event cache {} is unreadable, rebuilding: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    if cache.version != EVENT_CACHE_VERSION || cache.input_hash != input_hash {
+        info!("Event cache {} is stale, rebuilding", path.display());
+        return None;
+    }
+    info!(
+        "Loaded {} events from cache {}",
+        cache.events.len(),
+        path.display()
+    );
+    Some(cache.events)
+}
+
+// writes the parsed and sorted events with the hash of the inputs they came from
+pub(crate) fn write_event_cache(
+    path: &Path,
+    input_hash: B256,
+    events: &[SimulationEvent],
+) -> Result<()> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create event cache {}", path.display()))?;
+    let cache = EventCache {
+        version: EVENT_CACHE_VERSION,
+        input_hash,
+        events: events.to_vec(),
+    };
+    rmp_serde::encode::write(&mut BufWriter::new(file), &cache)
+        .with_context(|| format!("Failed to write event cache {}", path.display()))?;
+    info!("Wrote {} events to cache {}", events.len(), path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fee_analyzer::{
+        csv_input_reader::write_events_to_csv, simulation_events::one_event_of_each_type,
+    };
+
+    #[test]
+    fn cached_events_read_back_unchanged() {
+        let events = one_event_of_each_type();
+        let dir = std::env::temp_dir().join(format!("event_cache_{}", std::process::id()));
+        let mut config = write_events_to_csv(&events, &dir).unwrap();
+        let path = dir.join("events.cache");
+        let hash = input_hash(&config).unwrap();
+
+        write_event_cache(&path, hash, &events).unwrap();
+        assert_eq!(read_event_cache(&path, hash), Some(events));
+
+        // a cache of other inputs is rebuilt
+        config.has_headers = false;
+        let other_hash = input_hash(&config).unwrap();
+        assert_ne!(other_hash, hash);
+        assert_eq!(read_event_cache(&path, other_hash), None);
+        fs::write(&path, b"not messagepack").unwrap();
+        assert_eq!(read_event_cache(&path, hash), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
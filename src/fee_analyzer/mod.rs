@@ -1,48 +1,89 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    future::Future,
+    hash::Hash,
+    iter::Peekable,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+    vec,
+};
 
+use crate::error::FeeAnalyzerError;
 use crate::{
     abi::IQuoterV2,
     chain_interactions::{
-        anvil_connection, approve_token,
+        anvil_connection, anvil_from_fork_cache, approve_exact, approve_token,
+        attach_existing_pool, attach_resumed_pool,
+        backend::{AnvilBackend, PoolBackend, SpotValuation, TokenValuation},
         burn::pool_burn,
         collect::{
-            create_position_info_from_mint_event, pool_close_out_position,
+            create_position_info_from_mint_event, pool_close_out_collect, pool_close_out_value,
             pool_collect_fees_post_decrease_liquidity, pool_collect_fees_post_increase_liquidity,
-            PositionInfo,
+            pool_poke_collect, value_in_weth, CloseReason, PositionInfo,
         },
-        deploy_and_initialize_pool, initialize_simulation_account,
+        deploy_and_initialize_pool,
+        fee_growth::fee_growth_by_tick_range,
+        grow_observation_cardinality, initialize_simulation_account, is_rpc_timeout,
         mint::{pool_increase_liquidity, pool_mint, send_clanker_tokens},
-        swap::pool_swap,
-        PoolConfig,
+        pin_upstream_state,
+        pool_state::{pool_state_divergences, simulated_pool_state, upstream_pool_state},
+        swap::{pool_swap, SwapDirection, SwapSettings, SwapStats},
+        top_up_clanker_tokens, top_up_weth, validate_contracts,
+        valuation::{price_impact_bps, spot_value, TokenValuer, ValuationMethod},
+        write_fork_cache, ApprovalMode, ClankerTokenParams, L2Profile, PoolConfig, PoolSetupMode,
+        SwapFailurePolicy, VerificationLevel,
     },
 };
 use alloy::{
     node_bindings::AnvilInstance,
-    primitives::{Address, U256},
-    providers::{layers::AnvilProvider, RootProvider},
+    primitives::{aliases::I24, Address, TxHash, I256, U160, U256},
+    providers::{layers::AnvilProvider, Provider, RootProvider},
     transports::http::{reqwest, Http},
 };
-use csv_input_reader::{pool_events, CSVReaderConfig};
-use csv_output_writer::write_positions_to_csv;
+use chrono::{DateTime, Utc};
+use csv_input_reader::{pool_events, write_events_to_csv, CSVReaderConfig};
+use csv_output_writer::{
+    read_positions_from_csv, write_exact_output_swaps_to_csv, write_grouped_positions_to_json,
+    write_liquidity_depth_to_csv, write_output_metadata, write_pnl_distribution_to_json,
+    write_pnl_timeseries_to_csv, write_pool_summary_to_csv, write_position_diffs_to_csv,
+    write_position_values_to_csv, write_positions_to_csv, write_strategies_to_csv,
+    write_tick_fee_distribution_to_csv, write_warnings_to_csv, PositionCsvWriter,
+};
 use eyre::{bail, eyre, Context, ContextCompat, Result};
+use position_diff::{diff_positions, PositionDiff};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use simulation_events::{
-    find_first_event, DecreaseLiquidityWithParams, Event, EventType, IncreaseLiquidityWithParams,
-    SimulationEvent,
+    check_enabled_event_types, filter_event_types, find_first_event, split_events_by_pool,
+    DecreaseLiquidityWithParams, Event, EventType, IncreaseLiquidityWithParams, PoolKey,
+    PositionOwners, SimulationEvent,
 };
-use tracing::{error, info, warn};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::abi::{
     ClankerToken::ClankerTokenInstance,
-    INonfungiblePositionManager::{self},
+    INonfungiblePositionManager::{self, DecreaseLiquidity, IncreaseLiquidity},
     ISwapRouter,
     IUniswapV3Factory::{self},
-    UniswapV3Pool::UniswapV3PoolInstance,
+    UniswapV3Pool::{Burn, Mint, Swap, UniswapV3PoolInstance},
     Weth,
 };
 
+mod counterfactual;
 pub mod csv_input_reader;
 pub mod csv_output_writer;
+mod event_cache;
+pub(crate) mod position_diff;
+#[cfg(test)]
+mod self_test;
 pub(crate) mod simulation_events;
+#[cfg(feature = "sqlite")]
+pub(crate) mod sqlite_output_writer;
 
 pub type HttpClient = Http<reqwest::Client>;
 pub type ArcAnvilHttpProvider = Arc<AnvilProvider<RootProvider<HttpClient>, HttpClient>>;
@@ -63,34 +104,878 @@ pub struct PoolAnalyzer {
     >,
     swap_router: Arc<ISwapRouter::ISwapRouterInstance<HttpClient, ArcAnvilHttpProvider>>,
     quoter: Arc<IQuoterV2::IQuoterV2Instance<HttpClient, ArcAnvilHttpProvider>>,
+    // the pool and position manager above, for the position accounting
+    backend: AnvilBackend,
     pool_simulation_events: Option<Vec<SimulationEvent>>,
     address_map: HashMap<Address, Address>,
     token_id_map: HashMap<U256, U256>,
+    // positions minted directly against the pool, keyed by owner and tick
+    // range, to the synthetic original token id they're tracked under
+    direct_positions: HashMap<(Address, I24, I24), U256>,
     clanker: Address,
+    // the account standing in for the clanker deployer
+    deployer: Address,
     swap_account: Address,
+    // with per swapper accounts, the original tx senders set up so far
+    swappers: Option<HashSet<Address>>,
     mint_account: Address,
+    fee_recipient: Address,
     pool_config: PoolConfig,
     position_info: HashMap<U256, Vec<PositionInfo>>,
+    // nft transfers from the input, records are attributed to the holder when
+    // they open
+    position_owners: PositionOwners,
     output_csv_file_path: String,
+    keep_alive: bool,
+    gas_used: GasUsed,
+    swap_stats: SwapStats,
+    valuer: TokenValuer,
+    swap_settings: SwapSettings,
+    clanker_token_params: ClankerTokenParams,
+    // upstream rpc to copy the real token's code from, see clone_token_code
+    clone_token_from: Option<String>,
+    liquidity_depth: Vec<LiquiditySample>,
+    pnl_timeseries: Vec<PnlSample>,
+    last_event_block: u64,
+    // the fork's block number after each replayed block's last event, for
+    // reading the simulated pool as it stood after an original block
+    fork_blocks: BTreeMap<u64, u64>,
+    block_clock: BlockClock,
+    incremental_csv: bool,
+    csv_writer: Option<PositionCsvWriter>,
+    max_events: Option<usize>,
+    enabled_event_types: HashSet<EventType>,
+    run_timeout: Option<Duration>,
+    // the replay stopped at the run or rpc timeout, marked in `<output>.meta.json`
+    timed_out: bool,
+    mint_amount_tolerance: U256,
+    // deployer holding the token supply when transfers are lazy
+    token_holder: Option<Address>,
+    verification: VerificationLevel,
+    // archive endpoint and the real pool's address for the end of run state
+    // check, when enabled
+    final_state_check: Option<(String, Address)>,
+    fee_invariant_check: bool,
+    approval_mode: ApprovalMode,
+    warnings: Vec<RunWarning>,
+    unhandled_events: UnhandledEvents,
+    write_warnings_csv: bool,
+    // swaps replayed as exact output, only kept when they're written out
+    exact_output_swaps: Option<Vec<ExactOutputSwap>>,
+    verify_tolerance_wei: U256,
+    #[cfg(feature = "sqlite")]
+    sqlite_output: Option<(PathBuf, sqlite_output_writer::SqliteRun)>,
+    grouped_output: bool,
+    merge_reopened_positions: bool,
+    pnl_histogram_boundaries: Vec<I256>,
+    pnl_histogram_chart: bool,
+    capital_efficiency_top_n: usize,
+    value_positions_at_blocks: Vec<u64>,
+    close_out_concurrency: usize,
+    dust_threshold_weth: U256,
+    exclude_dust_from_output: bool,
+    l2_profile: L2Profile,
+}
+
+// pool state sampled after each replayed swap
+#[derive(Debug, Clone)]
+pub(crate) struct LiquiditySample {
+    pub block: u64,
+    pub liquidity: u128,
+    pub sqrt_price_x96: U160,
+    pub tick_before: I24,
+    pub tick: I24,
+    // weth side of the swap, in or out
+    pub weth_volume: U256,
+    // execution price vs the spot price before the swap
+    pub price_impact_bps: f64,
+}
+
+// when RUN_TIMEOUT_SECS stops the replay. it's checked between events, so the
+// event in flight is always replayed in full
+#[derive(Debug, Default)]
+pub(crate) struct RunDeadline {
+    // (replay start, run timeout)
+    limit: Option<(Instant, Duration)>,
+}
+
+impl RunDeadline {
+    pub(crate) fn starting_now(run_timeout: Option<Duration>) -> Self {
+        RunDeadline {
+            limit: run_timeout.map(|run_timeout| (Instant::now(), run_timeout)),
+        }
+    }
+
+    // the run timeout, once the replay has run past it
+    pub(crate) fn passed(&self) -> Option<Duration> {
+        self.limit
+            .filter(|(start, run_timeout)| start.elapsed() >= *run_timeout)
+            .map(|(_, run_timeout)| run_timeout)
+    }
+}
+
+fn record_count(position_info: &HashMap<U256, Vec<PositionInfo>>) -> usize {
+    position_info.values().map(Vec::len).sum()
+}
+
+fn open_token_id_count(position_info: &HashMap<U256, Vec<PositionInfo>>) -> usize {
+    position_info
+        .values()
+        .filter_map(|infos| infos.last())
+        .filter(|info| info.liquidity_in > 0)
+        .count()
+}
+
+// the replay keeps the deployer's, swapper's, and minter's balances and
+// approvals apart, so overrides can't share an address
+fn check_distinct_accounts(
+    deployer_account: Option<Address>,
+    swap_account: Option<Address>,
+    mint_account: Option<Address>,
+) -> Result<()> {
+    let accounts = [
+        ("deployer", deployer_account),
+        ("swap", swap_account),
+        ("mint", mint_account),
+    ];
+    for (i, (name, account)) in accounts.iter().enumerate() {
+        for (other_name, other_account) in &accounts[i + 1..] {
+            if account.is_some() && account == other_account {
+                bail!(
+                    "The {} and {} accounts must differ, both are {}",
+                    name,
+                    other_name,
+                    account.unwrap()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// why the replay stopped before the events ran out
+#[derive(Debug, PartialEq)]
+enum ReplayStop {
+    Interrupted,
+    TimedOut(Duration),
+    MaxEvents,
+}
+
+fn replay_stop(
+    event_count: usize,
+    max_events: Option<usize>,
+    interrupted: &AtomicBool,
+    deadline: &RunDeadline,
+) -> Option<ReplayStop> {
+    if interrupted.load(Ordering::SeqCst) {
+        Some(ReplayStop::Interrupted)
+    } else if let Some(run_timeout) = deadline.passed() {
+        Some(ReplayStop::TimedOut(run_timeout))
+    } else if max_events.is_some_and(|max| event_count >= max) {
+        Some(ReplayStop::MaxEvents)
+    } else {
+        None
+    }
+}
+
+// takes the next event unless the replay stops first. the stop is only
+// checked while events are left, and a stopped replay leaves the event it
+// didn't take in the iterator
+fn next_event<I: Iterator>(
+    event_iter: &mut Peekable<I>,
+    stop: &mut Option<ReplayStop>,
+    check: impl FnOnce() -> Option<ReplayStop>,
+) -> Option<I::Item> {
+    event_iter.peek()?;
+    *stop = check();
+    if stop.is_some() {
+        return None;
+    }
+    event_iter.next()
+}
+
+// aborts the task when dropped, so it doesn't outlive a run that returns early
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// the block times of the replayed events, for turning a record's block range
+// into a holding time
+#[derive(Debug, Default)]
+pub(crate) struct BlockClock {
+    times: HashMap<u64, DateTime<Utc>>,
+    last_block: u64,
+    // for hand built events without real timestamps
+    block_time_ms: Option<u64>,
+}
+
+impl BlockClock {
+    pub(crate) fn new(block_time_ms: Option<u64>) -> Self {
+        BlockClock {
+            block_time_ms,
+            ..BlockClock::default()
+        }
+    }
+
+    pub(crate) fn record(&mut self, block: u64, time: DateTime<Utc>) {
+        self.times.entry(block).or_insert(time);
+        self.last_block = self.last_block.max(block);
+    }
+
+    // a block out of zero means closed at the end of the run
+    fn end_block(&self, block_out: u64) -> u64 {
+        if block_out == 0 {
+            self.last_block
+        } else {
+            block_out
+        }
+    }
+
+    // blocks from `block_in` to `block_out`, or to the last recorded block
+    pub(crate) fn blocks_between(&self, block_in: u64, block_out: u64) -> u64 {
+        self.end_block(block_out).saturating_sub(block_in)
+    }
+
+    // seconds from `block_in` to `block_out`, or to the last recorded block.
+    // falls back to the block time when the events' times don't tell the
+    // blocks apart
+    pub(crate) fn seconds_between(&self, block_in: u64, block_out: u64) -> Option<u64> {
+        let block_out = self.end_block(block_out);
+        let from_times = self
+            .times
+            .get(&block_in)
+            .zip(self.times.get(&block_out))
+            .and_then(|(time_in, time_out)| {
+                u64::try_from((*time_out - *time_in).num_seconds()).ok()
+            })
+            .filter(|seconds| *seconds > 0);
+        from_times.or_else(|| {
+            self.block_time_ms
+                .map(|block_time_ms| block_out.saturating_sub(block_in) * block_time_ms / 1000)
+                .filter(|seconds| *seconds > 0)
+        })
+    }
+}
+
+// all positions' pnl at a block where some position changed, amounts are
+// valued in weth at that block's spot price
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PnlSample {
+    pub block: u64,
+    pub open_positions: usize,
+    pub realized_token_fees: U256,
+    pub realized_weth_fees: U256,
+    pub realized_fees_in_weth: U256,
+    pub open_token_amount: U256,
+    pub open_weth_amount: U256,
+    pub open_value_in_weth: U256,
+    // closed records' net pnl plus the open records' value at this price less
+    // their starting value
+    pub pnl_in_weth: I256,
+}
+
+impl PnlSample {
+    fn new<'a>(
+        block: u64,
+        positions: impl Iterator<Item = &'a PositionInfo>,
+        sqrt_price: U160,
+        clanker_is_token0: bool,
+    ) -> Self {
+        let token_to_weth = |amount| spot_value(amount, sqrt_price, clanker_is_token0);
+
+        let mut sample = PnlSample {
+            block,
+            ..PnlSample::default()
+        };
+        for info in positions {
+            if info.closed {
+                sample.realized_token_fees += info.fees_earned_token;
+                sample.realized_weth_fees += info.fees_earned_weth;
+                sample.pnl_in_weth += info.end_weth_gain_converted;
+            } else if info.liquidity_in > 0 {
+                let (token, weth) = info.amounts_at(sqrt_price, clanker_is_token0);
+                sample.open_positions += 1;
+                sample.open_token_amount += token;
+                sample.open_weth_amount += weth;
+                sample.pnl_in_weth += I256::from_raw(weth + token_to_weth(token))
+                    - I256::from_raw(info.approx_starting_weth);
+            }
+        }
+        sample.realized_fees_in_weth =
+            sample.realized_weth_fees + token_to_weth(sample.realized_token_fees);
+        sample.open_value_in_weth =
+            sample.open_weth_amount + token_to_weth(sample.open_token_amount);
+        sample
+    }
+}
+
+// one sample per block, later changes in the same block replace it
+fn push_pnl_sample(timeseries: &mut Vec<PnlSample>, sample: PnlSample) {
+    if timeseries
+        .last()
+        .is_some_and(|last| last.block == sample.block)
+    {
+        timeseries.pop();
+    }
+    timeseries.push(sample);
+}
+
+// the worst peak to trough decline of the pnl timeseries' `pnl_in_weth`. the
+// run starts as a peak of zero, before any position was opened
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct MaxDrawdown {
+    pub drawdown_in_weth: U256,
+    // none for the run's start
+    pub peak_block: Option<u64>,
+    pub peak_pnl_in_weth: I256,
+    // none when the pnl never fell below an earlier peak
+    pub trough_block: Option<u64>,
+    pub trough_pnl_in_weth: I256,
+    // the drawdown over the peak's pnl, none for a peak of zero or below
+    pub fraction_of_peak: Option<f64>,
+}
+
+impl MaxDrawdown {
+    pub(crate) fn new(samples: &[PnlSample]) -> Self {
+        let mut peak = (I256::ZERO, None);
+        let mut max_drawdown = MaxDrawdown::default();
+        for sample in samples {
+            if sample.pnl_in_weth > peak.0 {
+                peak = (sample.pnl_in_weth, Some(sample.block));
+            }
+            let drawdown = (peak.0 - sample.pnl_in_weth).unsigned_abs();
+            if drawdown > max_drawdown.drawdown_in_weth {
+                max_drawdown = MaxDrawdown {
+                    drawdown_in_weth: drawdown,
+                    peak_block: peak.1,
+                    peak_pnl_in_weth: peak.0,
+                    trough_block: Some(sample.block),
+                    trough_pnl_in_weth: sample.pnl_in_weth,
+                    fraction_of_peak: (peak.0 > I256::ZERO)
+                        .then(|| f64::from(drawdown) / f64::from(peak.0.into_raw())),
+                };
+            }
+        }
+        max_drawdown
+    }
+}
+
+impl fmt::Display for MaxDrawdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(trough_block) = self.trough_block else {
+            return write!(f, "Max drawdown: none, the pnl never fell below a peak");
+        };
+        let peak_block = self
+            .peak_block
+            .map_or("the start".to_string(), |block| format!("block {}", block));
+        write!(
+            f,
+            "Max drawdown: {} wei WETH, from {} at {} to {} at block {}",
+            self.drawdown_in_weth,
+            self.peak_pnl_in_weth,
+            peak_block,
+            self.trough_pnl_in_weth,
+            trough_block
+        )?;
+        if let Some(fraction) = self.fraction_of_peak {
+            write!(f, " ({:.2}% of the peak)", fraction * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+// a position's spot value after an original block, see
+// PoolAnalyzer::value_position_at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PositionValue {
+    pub block: u64,
+    pub original_token_id: U256,
+    pub value_in_weth: U256,
+}
+
+// an anomaly the replay logged and worked around, kept so a run can be
+// audited for everything it glossed over
+#[derive(Debug, Clone)]
+pub(crate) struct RunWarning {
+    pub block: u64,
+    pub kind: RunWarningKind,
+    pub detail: String,
+}
+
+// a swap event the replay sent as exact output, usually price limited or on a
+// rounding edge, since most swaps are exact input
+#[derive(Debug, Clone)]
+pub(crate) struct ExactOutputSwap {
+    pub block: u64,
+    pub tx_hash: TxHash,
+    pub log_index: u64,
+    pub swap: Swap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RunWarningKind {
+    // a decrease left part of the position's liquidity in place
+    PartialClose,
+    // a record that held liquidity collected no fees on either side
+    ZeroCollect,
+    // fees from fee growth and collected fees differ by more than a wei
+    FeeGrowthMismatch,
+    // an event the replay doesn't act on
+    UnhandledEvent,
+    // a direct pool burn of a position minted before the replayed range
+    UntrackedBurn,
+    // the simulated pool differs from the real one after the last event
+    PoolStateDivergence,
+    // closing a position at the end of the run errored
+    CloseOutFailed,
+    // a swap left out of the replay after its exact input quote reverted
+    SkippedSwap,
+    // the replay stopped at the run or rpc timeout
+    TimedOut,
+}
+
+impl fmt::Display for RunWarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunWarningKind::PartialClose => write!(f, "partial_close"),
+            RunWarningKind::ZeroCollect => write!(f, "zero_collect"),
+            RunWarningKind::FeeGrowthMismatch => write!(f, "fee_growth_mismatch"),
+            RunWarningKind::UnhandledEvent => write!(f, "unhandled_event"),
+            RunWarningKind::UntrackedBurn => write!(f, "untracked_burn"),
+            RunWarningKind::PoolStateDivergence => write!(f, "pool_state_divergence"),
+            RunWarningKind::CloseOutFailed => write!(f, "close_out_failed"),
+            RunWarningKind::SkippedSwap => write!(f, "skipped_swap"),
+            RunWarningKind::TimedOut => write!(f, "timed_out"),
+        }
+    }
+}
+
+// events the replay loop didn't act on, counted by type with the first one
+// seen as a sample. collects are skipped on purpose, the collect after each
+// liquidity change replays them, and transfers only feed owner attribution.
+// any other type reaching the loop unhandled is unexpected
+#[derive(Debug, Default)]
+pub(crate) struct UnhandledEvents {
+    counts: HashMap<EventType, u64>,
+    samples: HashMap<EventType, String>,
+}
+
+impl UnhandledEvents {
+    pub(crate) fn is_skipped(event_type: &EventType) -> bool {
+        matches!(
+            event_type,
+            EventType::CollectPool | EventType::CollectNpm | EventType::Transfer
+        )
+    }
+
+    fn record(&mut self, event: &SimulationEvent) {
+        let event_type = event.event.event_type();
+        *self.counts.entry(event_type.clone()).or_default() += 1;
+        self.samples.entry(event_type).or_insert_with(|| {
+            format!(
+                "block {} tx {} log {}",
+                event.block, event.tx_hash, event.log_index
+            )
+        });
+    }
+
+    pub(crate) fn unexpected_count(&self) -> u64 {
+        self.counts
+            .iter()
+            .filter(|(event_type, _)| !Self::is_skipped(event_type))
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    // (type, count, sample) in a stable order
+    fn rows(&self, skipped: bool) -> Vec<(String, u64, &str)> {
+        let mut rows: Vec<_> = self
+            .counts
+            .iter()
+            .filter(|(event_type, _)| Self::is_skipped(event_type) == skipped)
+            .map(|(event_type, count)| {
+                (
+                    format!("{:?}", event_type),
+                    *count,
+                    self.samples[event_type].as_str(),
+                )
+            })
+            .collect();
+        rows.sort();
+        rows
+    }
+}
+
+impl fmt::Display for UnhandledEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = Vec::new();
+        for (label, skipped) in [("Skipped", true), ("Unexpected", false)] {
+            let rows = self.rows(skipped);
+            if rows.is_empty() {
+                lines.push(format!("{}: none", label));
+            }
+            for (event_type, count, sample) in rows {
+                lines.push(format!(
+                    "{} {}: {} (first at {})",
+                    label, event_type, count, sample
+                ));
+            }
+        }
+        write!(f, "\nUnhandled Events:")?;
+        for (i, line) in lines.iter().enumerate() {
+            let branch = if i + 1 == lines.len() {
+                "└─"
+            } else {
+                "├─"
+            };
+            write!(f, "\n{} {}", branch, line)?;
+        }
+        Ok(())
+    }
+}
+
+// gas used by the replayed actions, summed per action type. this is the gas
+// used on the simulated anvil fork and can differ from what the original
+// transactions used on chain (different callers, warm/cold storage, etc)
+#[derive(Debug, Default, Clone)]
+pub(crate) struct GasUsed {
+    pub mint: u64,
+    pub increase_liquidity: u64,
+    pub decrease_liquidity: u64,
+    pub collect: u64,
+    pub swap: u64,
+}
+
+impl fmt::Display for GasUsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\nSimulated Gas Used:\n\
+             ├─ Mint:               {}\n\
+             ├─ Increase Liquidity: {}\n\
+             ├─ Decrease Liquidity: {}\n\
+             ├─ Collect:            {}\n\
+             └─ Swap:               {}",
+            self.mint, self.increase_liquidity, self.decrease_liquidity, self.collect, self.swap,
+        )
+    }
 }
 
+#[derive(Clone)]
 pub struct PoolAnalyzerConfig {
     pub http_url: String,
-    pub fork_block: u64,
+    // unused with auto_fork_block
+    pub fork_block: Option<u64>,
+    // fork at the block before the pool's first event instead of fork_block
+    pub auto_fork_block: bool,
+    // anvil settings for l2 forks and the chain's block time
+    pub l2_profile: L2Profile,
     pub uniswap_v3_factory_address: Address,
     pub uniswap_v3_position_manager_address: Address,
     pub uniswap_v3_swap_router_address: Address,
     pub uniswap_v3_quoter_address: Address,
     pub weth_address: Address,
     pub config: CSVReaderConfig,
+    // may hold `{pool_address}` and `{fee_tier}`, filled in once the pool is
+    // picked, see expand_output_path. the cli fills in `{timestamp}`
     pub output_csv_file_path: String,
+    // keep the anvil fork running after the run until ctrl-c
+    pub keep_alive: bool,
+    // pay the weth side of swaps with native eth through the router
+    pub native_eth: bool,
+    // how clanker token amounts are valued in weth for the pnl estimates
+    pub valuation_method: ValuationMethod,
+    // seconds of pool oracle history the twap valuation averages over
+    pub twap_window: u32,
+    // number of equal parts the swap and quoter valuations price the amount
+    // in, see TokenValuer
+    pub valuation_slices: u32,
+    // cap on the priced sale in basis points of the pool's token balance
+    pub valuation_max_sale_bps: Option<u32>,
+    // positions valued at once in the end of run close out, the collects
+    // still run one at a time
+    pub close_out_concurrency: usize,
+    // records whose approx_starting_weth is below this many weth wei are
+    // counted as dust and left out of the pnl summaries, zero disables it
+    pub dust_threshold_weth: U256,
+    // also leave dust records out of the output files
+    pub exclude_dust_from_output: bool,
+    // wei difference between the exact input quote and a swap event's amount
+    // out that still counts as a possible exact input swap
+    pub swap_direction_tolerance: U256,
+    // extra input allowed on exact output swaps, in basis points
+    pub exact_output_slack_bps: u32,
+    // fall back, skip, or fail when a swap's exact input quote reverts
+    pub swap_failure_policy: SwapFailurePolicy,
+    // constructor arguments for the stand-in token deployed in place of the pool's token
+    pub clanker_token_params: ClankerTokenParams,
+    // put the real token's code from `http_url` at its own address instead of
+    // deploying a ClankerToken stand-in
+    pub clone_token_code: bool,
+    // deploy a stand-in pool, or attach to the original pool and tokens on a
+    // fork taken after the pool was created
+    pub pool_setup_mode: PoolSetupMode,
+    // load the starting state from this file instead of forking, or write it
+    // there after a live fork if the file doesn't exist yet
+    pub fork_cache_path: Option<PathBuf>,
+    // token id map and open positions written by an earlier run's
+    // `<output>.resume.json`, for analyzing a pool in block range chunks
+    pub resume_path: Option<PathBuf>,
+    // original to simulated maps written by an earlier run's
+    // `<output>.address_map.json` and `<output>.token_id_map.json`, merged
+    // into this run's maps at startup
+    pub address_map_path: Option<PathBuf>,
+    pub token_id_map_path: Option<PathBuf>,
+    // fixed addresses to impersonate instead of random ones, for replays where
+    // token behavior depends on the address. they must all differ
+    pub deployer_account: Option<Address>,
+    pub swap_account: Option<Address>,
+    pub mint_account: Option<Address>,
+    // append each position record to the output csv as soon as it's final
+    // instead of writing everything at the end
+    pub incremental_csv: bool,
+    // stop replaying after this many events and close out what's open, for
+    // quick smoke tests of a config
+    pub max_events: Option<usize>,
+    // event types the replay acts on, the rest are skipped. pool created and
+    // initialize are always replayed, the liquidity events are all or none
+    pub enabled_event_types: HashSet<EventType>,
+    // stop replaying once the replay has run this long and close out what's
+    // open, the output is marked as timed out
+    pub run_timeout: Option<Duration>,
+    // per request timeout for calls to anvil, a call that runs past it stops
+    // the replay like the run timeout
+    pub rpc_timeout: Option<Duration>,
+    // wei the replayed mint's token amounts may differ from the event's,
+    // ticks and liquidity always have to match
+    pub mint_amount_tolerance: U256,
+    // replay only this pool when the input csvs hold events for several
+    pub pool_address: Option<Address>,
+    // where collected fees and withdrawn liquidity are sent, defaults to the
+    // mint account
+    pub collect_recipient: Option<Address>,
+    // leave the token supply with the deployer and transfer what each mint,
+    // swap, or simulated sale needs on demand, for tokens whose supply is too
+    // large to move in one transfer
+    pub lazy_token_transfers: bool,
+    // replay each swap from its original tx sender instead of the shared swap
+    // account, for tokens with per address logic
+    pub per_swapper_accounts: bool,
+    // which replayed actions are checked against their events
+    pub verification: VerificationLevel,
+    // after the replay, compare the simulated pool's price, liquidity, and fee
+    // growth to the real pool's at the last event block via `http_url`
+    pub verify_final_pool_state: bool,
+    // after the close out, fail the run if any tick range collected more fees
+    // than its fee growth inside accounts for
+    pub fee_invariant_check: bool,
+    // max approvals once at setup, or exact approvals before every spend
+    pub approval_mode: ApprovalMode,
+    // try each swap as exact input before quoting, see SwapSettings
+    pub optimistic_exact_input: bool,
+    // also write the run's warnings to `<output>.warnings.csv`
+    pub write_warnings_csv: bool,
+    // also write the swaps replayed as exact output to
+    // `<output>.exact_output_swaps.csv`
+    pub write_exact_output_swaps: bool,
+    // after the run, compare the records with this earlier positions csv and
+    // fail on a divergence, see PoolAnalyzer::verify_against. several pools
+    // need `{pool_address}` in the path
+    pub verify_against: Option<PathBuf>,
+    // the largest fee or pnl difference in wei that still counts as matching
+    pub verify_tolerance_wei: U256,
+    // also append the position records to this sqlite database, under
+    // `sqlite_run_id` with the pool's `{pool_address}` and `{fee_tier}`
+    // filled in
+    #[cfg(feature = "sqlite")]
+    pub sqlite_output_path: Option<PathBuf>,
+    #[cfg(feature = "sqlite")]
+    pub sqlite_run_id: String,
+    // also write the records grouped by original token id to
+    // `<output>.grouped.json`
+    pub grouped_output: bool,
+    // also write `<output>.strategies.csv`, each owner's token ids at one tick
+    // range joined across full exits and re-mints
+    pub merge_reopened_positions: bool,
+    // send the original calls' min and max amounts instead of permissive
+    // bounds, a replay that breaks them reverts
+    pub enforce_min_amounts: bool,
+    // ascending net pnl bucket boundaries in weth wei for the summary histogram
+    pub pnl_histogram_boundaries: Vec<I256>,
+    // also log the histogram as a text bar chart
+    pub pnl_histogram_chart: bool,
+    // how many records the summary ranks by weth fees per weth-block
+    pub capital_efficiency_top_n: usize,
+    // original blocks to value every replayed position at, written to
+    // `<output>.position_values.csv`
+    pub value_positions_at_blocks: Vec<u64>,
 }
 
 impl PoolAnalyzer {
     pub async fn initialize(config: PoolAnalyzerConfig) -> Result<Self> {
-        let (anvil, anvil_provider) = anvil_connection(config.http_url, config.fork_block)
+        let events = pool_events(config.config.clone())
+            .await
+            .context("Failed to get pool events from CSV")?;
+        Self::initialize_with_events(config, events).await
+    }
+
+    // sets up the fork to replay `events` instead of the input csvs, which
+    // `config.config` is then ignored for. the events go through the same pool
+    // selection as csv input, so they need a pool created and initialize event
+    pub(crate) async fn initialize_with_events(
+        config: PoolAnalyzerConfig,
+        mut events: Vec<SimulationEvent>,
+    ) -> Result<Self> {
+        events.sort();
+        check_distinct_accounts(
+            config.deployer_account,
+            config.swap_account,
+            config.mint_account,
+        )?;
+        if config.valuation_slices == 0 {
+            bail!("VALUATION_SLICES must be at least 1");
+        }
+        if config.close_out_concurrency == 0 {
+            bail!("CLOSE_OUT_CONCURRENCY must be at least 1");
+        }
+        if config.valuation_max_sale_bps == Some(0) {
+            bail!("VALUATION_MAX_SALE_BPS must be above zero");
+        }
+        if config
+            .pnl_histogram_boundaries
+            .windows(2)
+            .any(|pair| pair[0] >= pair[1])
+        {
+            bail!("PNL_HISTOGRAM_BOUNDARIES must be strictly ascending");
+        }
+        check_enabled_event_types(&config.enabled_event_types)?;
+        if config.pool_setup_mode == PoolSetupMode::DeployStandin && !config.clone_token_code {
+            config.clanker_token_params.check_standin_decimals()?;
+        }
+
+        let mut pools = split_events_by_pool(events)?;
+        let (pool_key, mut pool_simulation_events) = match config.pool_address {
+            Some(pool_address) => pools
+                .into_iter()
+                .find(|(key, _)| key.pool == pool_address)
+                .with_context(|| format!("No pool created event for pool {}", pool_address))?,
+            None if pools.len() == 1 => pools.pop().unwrap(),
+            None => bail!(
+                "The input events hold {} pools ({}), set POOL_ADDRESS to pick one",
+                pools.len(),
+                pools
+                    .iter()
+                    .map(|(key, _)| key.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        if !config.enforce_min_amounts {
+            for event in &mut pool_simulation_events {
+                event.event.clear_min_amounts();
+            }
+        }
+        let output_csv_file_path =
+            expand_output_path(&config.output_csv_file_path, Some(&pool_key));
+        let position_owners = PositionOwners::take_from(&mut pool_simulation_events);
+        info!(
+            "Tracking owners through {} nft transfers",
+            position_owners.transfer_count()
+        );
+
+        let attach_existing = config.pool_setup_mode == PoolSetupMode::AttachExisting;
+        let fork_block = choose_fork_block(
+            &pool_simulation_events,
+            config.auto_fork_block,
+            config.fork_block,
+            attach_existing,
+        )?;
+
+        if attach_existing {
+            // everything up to the fork block already happened on the fork.
+            // the pool created and initialize events stay in front, where
+            // run_simulation skips them
+            let events = pool_simulation_events.len();
+            pool_simulation_events.retain(|event| {
+                event.block > fork_block
+                    || matches!(
+                        event.event.event_type(),
+                        EventType::PoolCreated | EventType::Initialize
+                    )
+            });
+            info!(
+                "Attaching to the pool on the fork, dropped {} events at or before block {}",
+                events - pool_simulation_events.len(),
+                fork_block
+            );
+        }
+
+        // a resumed run forks the earlier run's kept-alive anvil where its
+        // replay stopped and attaches to the simulated pool there, so the
+        // seeded token ids are the positions that run minted
+        let resume_state = match &config.resume_path {
+            Some(path) => Some(read_resume_state(path)?),
+            None => None,
+        };
+        let resume_fork = match &resume_state {
+            Some(resume_state) => {
+                if config.fork_cache_path.is_some() {
+                    bail!("FORK_CACHE_PATH can't be used when resuming, the earlier run's fork is");
+                }
+                Some(resume_state.fork.clone().with_context(|| {
+                    format!(
+                        "{:?} has no fork to resume on, the earlier run needs \
+                         KEEP_ANVIL_ALIVE=true",
+                        config.resume_path
+                    )
+                })?)
+            }
+            None => None,
+        };
+
+        // the cache only holds the uniswap contracts, an existing pool's ticks
+        // and positions aren't in it
+        if config.fork_cache_path.is_some() && attach_existing {
+            bail!("FORK_CACHE_PATH only works with POOL_SETUP_MODE=deploy_standin");
+        }
+        let cache_exists = config
+            .fork_cache_path
+            .as_ref()
+            .is_some_and(|path| path.exists());
+        let (anvil, anvil_provider) = if let Some(fork) = &resume_fork {
+            info!(
+                "Resuming on the earlier run's anvil at {}, block {}",
+                fork.anvil_endpoint, fork.block
+            );
+            anvil_connection(
+                fork.anvil_endpoint.clone(),
+                fork.block,
+                &config.l2_profile,
+                config.rpc_timeout,
+            )
+            .await
+        } else if cache_exists {
+            anvil_from_fork_cache(
+                config.fork_cache_path.as_ref().unwrap(),
+                fork_block,
+                &config.l2_profile,
+                config.rpc_timeout,
+            )
+            .await
+        } else {
+            anvil_connection(
+                config.http_url.clone(),
+                fork_block,
+                &config.l2_profile,
+                config.rpc_timeout,
+            )
             .await
-            .context("Failed to connect to anvil")?;
+        }
+        .context("Failed to connect to anvil")?;
         let weth = Arc::new(Weth::new(config.weth_address, anvil_provider.clone()));
         let factory = Arc::new(IUniswapV3Factory::new(
             config.uniswap_v3_factory_address,
@@ -108,17 +993,67 @@ impl PoolAnalyzer {
             config.uniswap_v3_quoter_address,
             anvil_provider.clone(),
         ));
-        let pool_simulation_events = pool_events(config.config)
-            .await
-            .context("Failed to get pool events from CSV")?;
+        validate_contracts(
+            anvil_provider.clone(),
+            &factory,
+            &nonfungible_position_manager,
+            &swap_router,
+            &quoter,
+            *weth.address(),
+        )
+        .await?;
 
         let create_event = find_first_event(&pool_simulation_events, EventType::PoolCreated)?;
+
+        // cache the fork before anything is deployed or funded on it
+        if let Some(path) = config.fork_cache_path.as_ref().filter(|_| !cache_exists) {
+            let fee_tier_calls = match &create_event.event {
+                Event::PoolCreated(e) => {
+                    vec![factory
+                        .feeAmountTickSpacing(e.fee)
+                        .into_transaction_request()]
+                }
+                _ => Vec::new(),
+            };
+            pin_upstream_state(
+                &anvil_provider,
+                &[
+                    config.uniswap_v3_factory_address,
+                    config.uniswap_v3_position_manager_address,
+                    config.uniswap_v3_swap_router_address,
+                    config.uniswap_v3_quoter_address,
+                    config.weth_address,
+                ],
+                &fee_tier_calls,
+            )
+            .await?;
+            write_fork_cache(anvil_provider.clone(), path).await?;
+        }
+
         let init_event = find_first_event(&pool_simulation_events, EventType::Initialize)?;
+        let final_state_check = match &create_event.event {
+            Event::PoolCreated(e) if config.verify_final_pool_state => {
+                Some((config.http_url.clone(), e.pool))
+            }
+            _ => None,
+        };
 
-        let mut address_map = HashMap::<Address, Address>::new();
+        let mut address_map = match &config.address_map_path {
+            Some(path) => read_address_map(path)?,
+            None => HashMap::new(),
+        };
 
+        // an earlier run's deployer keeps addresses stable across chunks
         let clanker = create_event.from;
-        let deployer = Address::random();
+        let deployer = match &resume_fork {
+            Some(fork) => {
+                resumed_account("DEPLOYER_ACCOUNT", config.deployer_account, fork.deployer)?
+            }
+            None => config
+                .deployer_account
+                .or_else(|| address_map.get(&clanker).copied())
+                .unwrap_or_else(Address::random),
+        };
         address_map.insert(clanker, deployer);
         info!("Deployer: {}", deployer);
         info!("Clanker: {}", clanker);
@@ -128,42 +1063,81 @@ impl PoolAnalyzer {
             anvil_provider.clone(),
             deployer,
             None,
-            weth.clone(),
+            weth.as_ref(),
             swap_router.address(),
             nonfungible_position_manager.address(),
+            config.approval_mode,
         )
         .await?;
 
         // deploy pool
-        let (pool, clanker_token, pool_config) = deploy_and_initialize_pool(
-            anvil_provider.clone(),
-            factory.clone(),
-            deployer,
-            weth.address().clone(),
-            create_event.try_into()?,
-            init_event.try_into()?,
-        )
-        .await?;
+        let pool_created_block = create_event.block;
+        let (pool, clanker_token, pool_config) = match (&resume_fork, config.pool_setup_mode) {
+            (Some(fork), _) => {
+                attach_resumed_pool(anvil_provider.clone(), *weth.address(), fork.pool).await?
+            }
+            (None, PoolSetupMode::DeployStandin) => {
+                deploy_and_initialize_pool(
+                    anvil_provider.clone(),
+                    factory.clone(),
+                    deployer,
+                    *weth.address(),
+                    create_event.try_into()?,
+                    init_event.try_into()?,
+                    &config.clanker_token_params,
+                    config
+                        .clone_token_code
+                        .then_some((config.http_url.as_str(), pool_created_block)),
+                )
+                .await?
+            }
+            (None, PoolSetupMode::AttachExisting) => {
+                if config.clone_token_code {
+                    warn!("CLONE_TOKEN_CODE is ignored when attaching, the real token is used");
+                }
+                attach_existing_pool(
+                    anvil_provider.clone(),
+                    *weth.address(),
+                    create_event.try_into()?,
+                    deployer,
+                )
+                .await?
+            }
+        };
+
+        if config.valuation_method == ValuationMethod::Twap {
+            if config.twap_window == 0 {
+                bail!("TWAP_WINDOW_SECONDS must be above zero");
+            }
+            let cardinality = u16::try_from(config.twap_window).unwrap_or(u16::MAX);
+            grow_observation_cardinality(&pool, deployer, cardinality).await?;
+        }
 
         // approve clanker token for position manager and swap router for deployer
-        approve_token(
-            clanker_token.clone(),
-            nonfungible_position_manager.address(),
-            swap_router.address(),
-            deployer,
-        )
-        .await?;
+        if config.approval_mode == ApprovalMode::Max {
+            approve_token(
+                clanker_token.clone(),
+                nonfungible_position_manager.address(),
+                swap_router.address(),
+                deployer,
+            )
+            .await?;
+        }
 
         // setup swap account, we use the same address for all swaps
         // because we don't care about swapper PNL in this simulation
-        let swap_account = Address::random();
+        let swap_account = match &resume_fork {
+            Some(fork) => resumed_account("SWAP_ACCOUNT", config.swap_account, fork.swap_account)?,
+            None => config.swap_account.unwrap_or_else(Address::random),
+        };
         initialize_simulation_account(
             anvil_provider.clone(),
             swap_account,
             Some(clanker_token.clone()),
-            weth.clone(),
+            weth.as_ref(),
             swap_router.address(),
             nonfungible_position_manager.address(),
+            config.approval_mode,
         )
         .await?;
 
@@ -172,30 +1146,129 @@ impl PoolAnalyzer {
         // address associated with the mint.
         //
         // we could use different addresses, but the simluations were being
-        // slowed down in the mint account setup flow and we didn't
-        // track NFT transfers (we could if needed for some other reason)
-        let mint_account = Address::random();
+        // slowed down in the mint account setup flow. owners are attributed
+        // from the input's nft transfers instead, see PositionOwners
+        let mint_account = match &resume_fork {
+            Some(fork) => resumed_account("MINT_ACCOUNT", config.mint_account, fork.mint_account)?,
+            None => config.mint_account.unwrap_or_else(Address::random),
+        };
         initialize_simulation_account(
             anvil_provider.clone(),
             mint_account,
             Some(clanker_token.clone()),
-            weth.clone(),
+            weth.as_ref(),
             swap_router.address(),
             nonfungible_position_manager.address(),
+            config.approval_mode,
         )
         .await?;
 
         // send all clanker tokens to swap account, tokens needed for minting
-        // are pulled from this account on a per mint basis
-        let total_supply = clanker_token.totalSupply().call().await?._0;
-        clanker_token
-            .transfer(swap_account, total_supply)
-            .from(deployer.clone())
-            .send()
-            .await?
-            .get_receipt()
-            .await?;
+        // are pulled from this account on a per mint basis. lazy runs keep the
+        // supply with the deployer and pull from it instead. a resumed run's
+        // accounts already hold what the earlier run left them
+        let token_holder = config.lazy_token_transfers.then_some(deployer);
+        if token_holder.is_none() && resume_fork.is_none() {
+            let total_supply = clanker_token.totalSupply().call().await?._0;
+            clanker_token
+                .transfer(swap_account, total_supply)
+                .from(deployer)
+                .send()
+                .await?
+                .get_receipt()
+                .await?;
+        }
+
+        // seed positions left open by an earlier block range, each has to be
+        // the position the earlier run left on the fork
+        let (mut token_id_map, position_info) = match resume_state {
+            Some(resume_state) => {
+                let (token_id_map, position_info) = seed_resumed_positions(resume_state);
+                for position in position_info.values().flatten() {
+                    let on_fork = nonfungible_position_manager
+                        .positions(position.token_id)
+                        .call()
+                        .await
+                        .with_context(|| {
+                            format!(
+                                "Resumed token id {} does not exist on the fork",
+                                position.token_id
+                            )
+                        })?;
+                    check_resumed_position(position, &pool_config, &on_fork)?;
+                }
+                info!(
+                    "Resumed {} open positions from {:?}",
+                    position_info.len(),
+                    config.resume_path
+                );
+                (token_id_map, position_info)
+            }
+            None => (HashMap::new(), HashMap::new()),
+        };
+        if let Some(path) = &config.token_id_map_path {
+            for (original, simulated) in read_token_id_map(path)? {
+                match token_id_map.insert(original, simulated) {
+                    Some(previous) if previous != simulated => bail!(
+                        "Token id {} maps to {} in the resume state but {} in {:?}",
+                        original,
+                        previous,
+                        simulated,
+                        path
+                    ),
+                    _ => {}
+                }
+            }
+            info!(
+                "Seeded {} token id mappings from {:?}",
+                token_id_map.len(),
+                path
+            );
+        }
+
+        let valuer = TokenValuer {
+            pool: pool.clone(),
+            swap_router: swap_router.clone(),
+            quoter: quoter.clone(),
+            swap_account,
+            method: config.valuation_method,
+            clanker_token: clanker_token.clone(),
+            token_holder,
+            approval_mode: config.approval_mode,
+            twap_window: config.twap_window,
+            slices: config.valuation_slices,
+            max_sale_bps: config.valuation_max_sale_bps,
+        };
+        // valuations that top up or approve send transactions from the swap
+        // account, which can't be sent concurrently
+        let close_out_concurrency =
+            if config.close_out_concurrency > 1 && valuer.sends_transactions() {
+                warn!(
+                    "The swap valuation sends transactions with a token holder or exact \
+                     approvals, closing out positions one at a time"
+                );
+                1
+            } else {
+                config.close_out_concurrency
+            };
+
+        let backend = AnvilBackend {
+            pool: pool.clone(),
+            position_manager: nonfungible_position_manager.clone(),
+        };
 
+        #[cfg(feature = "sqlite")]
+        let sqlite_output = config.sqlite_output_path.clone().map(|db_path| {
+            (
+                db_path,
+                sqlite_output_writer::SqliteRun {
+                    run_id: expand_output_path(&config.sqlite_run_id, Some(&pool_key)),
+                    pool: pool_key,
+                    denomination_token: config.weth_address,
+                    output_csv: output_csv_file_path.clone(),
+                },
+            )
+        });
         Ok(Self {
             anvil,
             anvil_provider,
@@ -206,35 +1279,435 @@ impl PoolAnalyzer {
             nonfungible_position_manager,
             swap_router,
             quoter,
+            backend,
             pool_simulation_events: Some(pool_simulation_events),
+            position_owners,
             address_map,
-            token_id_map: HashMap::new(),
+            token_id_map,
+            direct_positions: HashMap::new(),
             clanker,
+            deployer,
             swap_account,
+            swappers: config.per_swapper_accounts.then(HashSet::new),
             mint_account,
+            fee_recipient: config.collect_recipient.unwrap_or(mint_account),
             pool_config,
-            position_info: HashMap::new(),
-            output_csv_file_path: config.output_csv_file_path,
+            position_info,
+            output_csv_file_path,
+            keep_alive: config.keep_alive,
+            gas_used: GasUsed::default(),
+            swap_stats: SwapStats::default(),
+            liquidity_depth: Vec::new(),
+            pnl_timeseries: Vec::new(),
+            last_event_block: 0,
+            fork_blocks: BTreeMap::new(),
+            block_clock: BlockClock::new(config.l2_profile.block_time_ms),
+            incremental_csv: config.incremental_csv,
+            csv_writer: None,
+            max_events: config.max_events,
+            enabled_event_types: config.enabled_event_types,
+            run_timeout: config.run_timeout,
+            timed_out: false,
+            mint_amount_tolerance: config.mint_amount_tolerance,
+            token_holder,
+            verification: config.verification,
+            final_state_check,
+            fee_invariant_check: config.fee_invariant_check,
+            approval_mode: config.approval_mode,
+            warnings: Vec::new(),
+            unhandled_events: UnhandledEvents::default(),
+            write_warnings_csv: config.write_warnings_csv,
+            exact_output_swaps: config.write_exact_output_swaps.then(Vec::new),
+            verify_tolerance_wei: config.verify_tolerance_wei,
+            #[cfg(feature = "sqlite")]
+            sqlite_output,
+            grouped_output: config.grouped_output,
+            merge_reopened_positions: config.merge_reopened_positions,
+            pnl_histogram_boundaries: config.pnl_histogram_boundaries,
+            pnl_histogram_chart: config.pnl_histogram_chart,
+            capital_efficiency_top_n: config.capital_efficiency_top_n,
+            value_positions_at_blocks: config.value_positions_at_blocks,
+            close_out_concurrency,
+            dust_threshold_weth: config.dust_threshold_weth,
+            exclude_dust_from_output: config.exclude_dust_from_output,
+            l2_profile: config.l2_profile,
+            valuer,
+            swap_settings: SwapSettings {
+                native_weth: config.native_eth.then_some(config.weth_address),
+                direction_tolerance: config.swap_direction_tolerance,
+                exact_output_slack_bps: config.exact_output_slack_bps,
+                verify: config.verification.checks_swaps(),
+                optimistic_exact_input: config.optimistic_exact_input,
+                failure_policy: config.swap_failure_policy,
+            },
+            clanker_token_params: config.clanker_token_params,
+            clone_token_from: config.clone_token_code.then_some(config.http_url),
         })
     }
 
-    pub async fn run_simulation(&mut self) -> Result<()> {
-        // TODO: figure out how to make this prettier
-        let mut event_iter = self
-            .pool_simulation_events
-            .take()
-            .unwrap()
-            .into_iter()
-            .peekable();
-        // skip first two event, they are pool created and initialize TODO clean up
-        event_iter.next();
-        event_iter.next();
+    // http endpoint of the anvil fork the simulation runs against
+    pub fn endpoint(&self) -> String {
+        self.anvil.endpoint()
+    }
+
+    // number of position info records generated, one per open, increase, or decrease
+    pub fn position_count(&self) -> usize {
+        record_count(&self.position_info)
+    }
+
+    // number of token ids whose latest record still holds liquidity, positions
+    // the end of run close out only simulated closing count as open
+    pub fn open_position_count(&self) -> usize {
+        open_token_id_count(&self.position_info)
+    }
+
+    // number of distinct token ids minted during the replay
+    pub fn unique_token_ids(&self) -> usize {
+        self.token_id_map.len()
+    }
+
+    // anomalies the replay worked around, in the order they came up
+    pub(crate) fn warnings(&self) -> &[RunWarning] {
+        &self.warnings
+    }
+
+    // dust records are only left out of the output when asked to
+    fn output_dust_threshold(&self) -> U256 {
+        if self.exclude_dust_from_output {
+            self.dust_threshold_weth
+        } else {
+            U256::ZERO
+        }
+    }
+
+    fn record_warning(&mut self, block: u64, kind: RunWarningKind, detail: String) {
+        self.warnings.push(RunWarning {
+            block,
+            kind,
+            detail,
+        });
+    }
+
+    // compares the simulated pool to the real one after the last replayed
+    // block. divergences are logged, not fatal, since they point at input
+    // gaps rather than a broken run
+    async fn check_final_pool_state(&mut self) -> Result<()> {
+        let Some((http_url, real_pool)) = &self.final_state_check else {
+            return Ok(());
+        };
+        let simulated = simulated_pool_state(&self.pool).await?;
+        let real = upstream_pool_state(http_url, *real_pool, self.last_event_block).await?;
+        let divergences = pool_state_divergences(&simulated, &real);
+        if divergences.is_empty() {
+            info!(
+                "Simulated pool matches pool {} at block {}",
+                real_pool, self.last_event_block
+            );
+        } else {
+            warn!(
+                "Simulated pool diverges from pool {} at block {}:\n{}",
+                real_pool,
+                self.last_event_block,
+                divergences.join("\n")
+            );
+            let block = self.last_event_block;
+            for divergence in divergences {
+                self.record_warning(block, RunWarningKind::PoolStateDivergence, divergence);
+            }
+        }
+        Ok(())
+    }
+
+    // the account a swap is replayed from, the shared swap account unless
+    // swaps are replayed from their original tx sender. those are funded and
+    // approved the first time they swap, their token input comes from
+    // top_up_swap_input
+    async fn swapper(&mut self, event: &SimulationEvent) -> Result<Address> {
+        let Some(swappers) = self.swappers.as_mut() else {
+            return Ok(self.swap_account);
+        };
+        if swappers.insert(event.from) {
+            initialize_simulation_account(
+                self.anvil_provider.clone(),
+                event.from,
+                Some(self.clanker_token.clone()),
+                self.weth.as_ref(),
+                self.swap_router.address(),
+                self.nonfungible_position_manager.address(),
+                self.approval_mode,
+            )
+            .await?;
+        }
+        Ok(event.from)
+    }
+
+    // makes sure the swapper holds a swap's token input plus the exact output
+    // slack, the most an exact output swap can pull. weth is minted with
+    // anvil, clanker tokens come from the token holder. the shared swap
+    // account already holds the supply unless transfers are lazy, so it can
+    // only run short of them if the pool's sells outgrow its buys
+    async fn top_up_swap_input(&self, swapper: Address, swap: &Swap) -> Result<()> {
+        let (token0, token1) = self.pool_config.tokens();
+        let (token_in, amount_in) = if swap.amount0 > I256::ZERO {
+            (token0, swap.amount0.unsigned_abs())
+        } else {
+            (token1, swap.amount1.unsigned_abs())
+        };
+        let slack =
+            amount_in * U256::from(self.swap_settings.exact_output_slack_bps) / U256::from(10_000);
+        let needed = amount_in + slack;
+
+        if token_in == *self.weth.address() {
+            let native = self.swap_settings.native_weth == Some(token_in);
+            let added =
+                top_up_weth(&self.anvil_provider, &self.weth, swapper, needed, native).await?;
+            if added > U256::ZERO {
+                info!(
+                    "Topped up swapper {} with {} {} to cover a swap",
+                    swapper,
+                    added,
+                    if native { "eth" } else { "weth" }
+                );
+            }
+            return Ok(());
+        }
+
+        let holder = self.token_holder.unwrap_or(self.swap_account);
+        if holder == swapper {
+            let balance = self.clanker_token.balanceOf(swapper).call().await?._0;
+            if balance < needed {
+                warn!(
+                    "Swapper {} holds {} clanker tokens but the swap may pull {}, there is no \
+                     other holder to top it up from",
+                    swapper, balance, needed
+                );
+            }
+            return Ok(());
+        }
+        let added = top_up_clanker_tokens(&self.clanker_token, holder, swapper, needed).await?;
+        if added > U256::ZERO {
+            info!(
+                "Topped up swapper {} with {} clanker tokens from {} to cover a swap",
+                swapper, added, holder
+            );
+        }
+        Ok(())
+    }
+
+    // with exact approvals, approves the swap router for the swap's input
+    // plus the exact output slack. weth paid as native eth needs none
+    async fn approve_swap_input(&self, swapper: Address, swap: &Swap) -> Result<()> {
+        if self.approval_mode != ApprovalMode::Exact {
+            return Ok(());
+        }
+        let (token0, token1) = self.pool_config.tokens();
+        let (token_in, amount_in) = if swap.amount0 > I256::ZERO {
+            (token0, swap.amount0.unsigned_abs())
+        } else {
+            (token1, swap.amount1.unsigned_abs())
+        };
+        if self.swap_settings.native_weth == Some(token_in) {
+            return Ok(());
+        }
+        let slack =
+            amount_in * U256::from(self.swap_settings.exact_output_slack_bps) / U256::from(10_000);
+        approve_exact(
+            self.anvil_provider.clone(),
+            token_in,
+            swapper,
+            *self.swap_router.address(),
+            amount_in + slack,
+        )
+        .await
+    }
+
+    // with exact approvals, approves the position manager for the most a mint
+    // or increase can pull
+    async fn approve_mint_amounts(&self, amount0: U256, amount1: U256) -> Result<()> {
+        if self.approval_mode != ApprovalMode::Exact {
+            return Ok(());
+        }
+        let (token0, token1) = self.pool_config.tokens();
+        for (token, amount) in [(token0, amount0), (token1, amount1)] {
+            approve_exact(
+                self.anvil_provider.clone(),
+                token,
+                self.mint_account,
+                *self.nonfungible_position_manager.address(),
+                amount,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    fn direct_position_increase(&mut self, mint: &Mint) -> IncreaseLiquidityWithParams {
+        direct_mint_increase(&mut self.direct_positions, mint)
+    }
+
+    // the decrease for a burn made directly against the pool, none for
+    // zero-liquidity pokes and for positions minted before the replayed range
+    fn direct_position_decrease(
+        &mut self,
+        block: u64,
+        burn: &Burn,
+    ) -> Option<DecreaseLiquidityWithParams> {
+        if burn.amount == 0 {
+            return None;
+        }
+        let decrease = direct_burn_decrease(&self.direct_positions, burn);
+        if decrease.is_none() {
+            warn!(
+                "Burn by {} in [{}, {}] has no direct mint in the replayed events, skipping it",
+                burn.owner, burn.tickLower, burn.tickUpper
+            );
+            self.record_warning(
+                block,
+                RunWarningKind::UntrackedBurn,
+                format!(
+                    "burn by {} in [{}, {}] of liquidity {}",
+                    burn.owner, burn.tickLower, burn.tickUpper, burn.amount
+                ),
+            );
+        }
+        decrease
+    }
+
+    // records the pool's liquidity and price right after a replayed swap
+    async fn sample_pool(
+        &mut self,
+        block: u64,
+        sqrt_price_before: U160,
+        tick_before: I24,
+        swap: &Swap,
+    ) -> Result<()> {
+        let slot0 = self.pool.slot0().call().await?;
+        self.liquidity_depth.push(LiquiditySample {
+            block,
+            liquidity: self.pool.liquidity().call().await?._0,
+            sqrt_price_x96: slot0.sqrtPriceX96,
+            tick_before,
+            tick: slot0.tick,
+            weth_volume: if self.pool_config.clanker_is_token0() {
+                swap.amount1.unsigned_abs()
+            } else {
+                swap.amount0.unsigned_abs()
+            },
+            price_impact_bps: price_impact_bps(sqrt_price_before, swap.amount0, swap.amount1),
+        });
+        Ok(())
+    }
+
+    // values every position record at the pool's current spot price. closed
+    // records count towards realized fees, open ones by the tokens their
+    // liquidity holds right now (uncollected fees show up once realized)
+    async fn sample_pnl(&mut self, block: u64) -> Result<()> {
+        let sqrt_price = self.pool.slot0().call().await?.sqrtPriceX96;
+        let sample = PnlSample::new(
+            block,
+            self.position_info.values().flatten(),
+            sqrt_price,
+            self.pool_config.clanker_is_token0(),
+        );
+        push_pnl_sample(&mut self.pnl_timeseries, sample);
+        Ok(())
+    }
+
+    async fn record_fork_block(&mut self, block: u64) -> Result<()> {
+        let fork_block = self.anvil_provider.get_block_number().await?;
+        self.fork_blocks.insert(block, fork_block);
+        Ok(())
+    }
+
+    // the weth value at spot of the position first minted as
+    // `original_token_id`, as it stood after the replayed events of original
+    // block `block`, see value_records_at
+    pub(crate) async fn value_position_at(
+        &self,
+        original_token_id: U256,
+        block: u64,
+    ) -> Result<U256> {
+        if block > self.last_event_block {
+            bail!(
+                "Block {} is past the last replayed event at block {}",
+                block,
+                self.last_event_block
+            );
+        }
+        let token_id = self
+            .token_id_map
+            .get(&original_token_id)
+            .with_context(|| format!("Token id {} was not replayed", original_token_id))?;
+        // a seeded token id map can hold token ids this run never minted
+        let records = self
+            .position_info
+            .get(token_id)
+            .map_or(&[][..], Vec::as_slice);
+        value_records_at(
+            &self.backend,
+            &self.pool_config,
+            records,
+            &self.fork_blocks,
+            block,
+        )
+        .await
+    }
+
+    // values every replayed position at each of the configured blocks the
+    // replay reached, leaving out positions with nothing open
+    async fn value_positions_at_blocks(&self) -> Result<Vec<PositionValue>> {
+        let mut original_token_ids: Vec<U256> = self.token_id_map.keys().copied().collect();
+        original_token_ids.sort();
+        let mut values = Vec::new();
+        for block in self.value_positions_at_blocks.iter().copied() {
+            if self
+                .fork_blocks
+                .first_key_value()
+                .is_none_or(|(first, _)| block < *first)
+                || block > self.last_event_block
+            {
+                warn!(
+                    "Not valuing positions at block {}, outside the replayed blocks",
+                    block
+                );
+                continue;
+            }
+            for original_token_id in original_token_ids.iter().copied() {
+                let value_in_weth = self.value_position_at(original_token_id, block).await?;
+                if value_in_weth > U256::ZERO {
+                    values.push(PositionValue {
+                        block,
+                        original_token_id,
+                        value_in_weth,
+                    });
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    // replays events in order until they run out, ctrl-c is pressed, the run
+    // deadline passes, or the max events limit is hit
+    async fn replay_events(
+        &mut self,
+        event_iter: &mut Peekable<vec::IntoIter<SimulationEvent>>,
+        interrupted: &AtomicBool,
+        deadline: &RunDeadline,
+    ) -> Result<()> {
         let mut event_count = 0;
+        let mut first_block = None;
+        let mut block_times = None;
 
-        while let Some(event) = event_iter.next() {
-            info!("event: {:?}", event_count);
-            info!("event: {:?}", event);
+        let mut stop = None;
+        while let Some(event) = next_event(event_iter, &mut stop, || {
+            replay_stop(event_count, self.max_events, interrupted, deadline)
+        }) {
+            log_replayed_event(event_count, &event);
             event_count += 1;
+            first_block.get_or_insert(event.block);
+            self.block_clock.record(event.block, event.block_time);
+            let (first_time, _) = block_times.get_or_insert((event.block_time, event.block_time));
+            block_times = Some((*first_time, event.block_time));
 
             match event.event.clone() {
                 Event::PoolCreated(create_event) => {
@@ -245,38 +1718,51 @@ impl PoolAnalyzer {
                                 .next()
                                 .context("Pool initialize event not found")?
                         } else {
-                            bail!("Pool initialize event was not event after pool created");
+                            bail!(FeeAnalyzerError::EventOrdering(
+                                "Pool initialize event was not event after pool created"
+                                    .to_string()
+                            ));
                         }
                     } else {
-                        bail!("No events after pool created");
+                        bail!(FeeAnalyzerError::EventOrdering(
+                            "No events after pool created".to_string()
+                        ));
                     };
                     deploy_and_initialize_pool(
                         self.anvil_provider.clone(),
                         self.factory.clone(),
-                        self.clanker.clone(),
-                        self.weth.address().clone(),
+                        self.clanker,
+                        *self.weth.address(),
                         create_event,
                         initialize_event.try_into()?,
+                        &self.clanker_token_params,
+                        self.clone_token_from
+                            .as_deref()
+                            .map(|http_url| (http_url, event.block)),
                     )
                     .await?;
                 }
                 Event::Initialize(e) => {
                     error!("Pool initialize event found in wrong positiong: {:?}", e);
-                    bail!("Pool initialize events should be handled by pool created event");
+                    bail!(FeeAnalyzerError::EventOrdering(
+                        "Pool initialize events should be handled by pool created event"
+                            .to_string()
+                    ));
                 }
                 Event::Mint(e) => {
-                    warn!("Minting");
+                    debug!("Minting");
 
                     send_clanker_tokens(
                         self.clanker_token.clone(),
                         &self.pool_config,
-                        self.mint_account.clone(),
-                        &self.swap_account,
+                        self.mint_account,
+                        &self.token_holder.unwrap_or(self.swap_account),
                         &e,
                     )
                     .await?;
 
-                    // next event should be liquidity add
+                    // next event should be liquidity add, unless the mint was made directly
+                    // against the pool instead of through the position manager
                     let increase_liquidity_event: IncreaseLiquidityWithParams =
                         if let Some(sim_event) = event_iter.peek() {
                             if sim_event.event.event_type() == EventType::IncreaseLiquidity {
@@ -284,11 +1770,19 @@ impl PoolAnalyzer {
                                     .next()
                                     .context("Increase liquidity event not found")?
                                     .try_into()?
+                            } else if e.owner != *self.nonfungible_position_manager.address() {
+                                self.direct_position_increase(&e)
                             } else {
-                                bail!("Increase liquidity event was not event after mint");
+                                bail!(FeeAnalyzerError::EventOrdering(
+                                    "Increase liquidity event was not event after mint".to_string()
+                                ));
                             }
+                        } else if e.owner != *self.nonfungible_position_manager.address() {
+                            self.direct_position_increase(&e)
                         } else {
-                            bail!("No events after mint");
+                            bail!(FeeAnalyzerError::EventOrdering(
+                                "No events after mint".to_string()
+                            ));
                         };
 
                     // check if token id already exists, this means that it's a increaseLiqiudity call
@@ -298,143 +1792,328 @@ impl PoolAnalyzer {
                         .get(&increase_liquidity_event.event.tokenId)
                     {
                         // position already exists, increase liquidity
-                        pool_increase_liquidity(
+                        self.approve_mint_amounts(
+                            increase_liquidity_event.amount_0_desired,
+                            increase_liquidity_event.amount_1_desired,
+                        )
+                        .await?;
+                        let gas_used = pool_increase_liquidity(
                             self.nonfungible_position_manager.clone(),
-                            self.mint_account.clone(),
+                            self.mint_account,
                             &e,
                             &increase_liquidity_event,
-                            token_id.clone(),
+                            *token_id,
+                            self.mint_amount_tolerance,
+                            self.verification.checks_liquidity(),
                         )
                         .await?;
+                        self.gas_used.increase_liquidity += gas_used;
 
-                        // find position
+                        // find position, a seeded token id may have no record
                         let position = self
                             .position_info
-                            .get_mut(&token_id)
-                            .unwrap()
-                            .last_mut()
-                            .context("Position info not found for increase liquidity")?;
+                            .get_mut(token_id)
+                            .and_then(|position_infos| position_infos.last_mut())
+                            .ok_or_else(|| {
+                                FeeAnalyzerError::MintMismatch(format!(
+                                    "No position record to increase for token id {}",
+                                    token_id
+                                ))
+                            })?;
 
                         // update position pnl info as if new position was created
-                        let position_info = pool_collect_fees_post_increase_liquidity(
-                            self.nonfungible_position_manager.clone(),
-                            self.pool.clone(),
-                            self.swap_router.clone(),
+                        let mut position_info = pool_collect_fees_post_increase_liquidity(
+                            &self.backend,
+                            &self.valuer,
                             &self.pool_config,
-                            self.mint_account.clone(),
-                            self.swap_account.clone(),
-                            token_id.clone(),
+                            self.mint_account,
+                            self.fee_recipient,
+                            *token_id,
                             position,
                             event.block,
                             increase_liquidity_event,
                         )
                         .await?;
+                        self.gas_used.collect += position.gas_used_out;
+                        position_info.gas_used_in = gas_used;
+                        if let Some(owner) = self
+                            .position_owners
+                            .owner_at(position_info.original_token_id, &event)
+                        {
+                            position_info.owner = owner;
+                        }
+                        if let Some(writer) = self.csv_writer.as_mut() {
+                            annotate_from_samples(position, &self.liquidity_depth);
+                            annotate_fee_yields(
+                                position,
+                                &self.block_clock,
+                                self.pool_config.clanker_is_token0(),
+                            );
+                            writer.write(position)?;
+                        }
+                        self.warnings
+                            .extend(position_warnings(event.block, position));
 
                         // insert position info into map
-                        let position_info_vec = self.position_info.get_mut(&token_id).unwrap();
-                        position_info_vec.push(position_info);
+                        self.position_info
+                            .entry(*token_id)
+                            .or_default()
+                            .push(position_info);
                     } else {
                         // token id not found, this is a fresh mint
-                        let token_id = pool_mint(
+                        self.approve_mint_amounts(
+                            increase_liquidity_event.amount_0_desired,
+                            increase_liquidity_event.amount_1_desired,
+                        )
+                        .await?;
+                        let (token_id, gas_used) = pool_mint(
                             self.nonfungible_position_manager.clone(),
                             &self.pool_config,
-                            self.mint_account.clone(),
+                            self.mint_account,
                             &e,
                             &increase_liquidity_event,
+                            self.mint_amount_tolerance,
+                            self.verification.checks_liquidity(),
                         )
                         .await?;
+                        self.gas_used.mint += gas_used;
 
                         self.token_id_map
                             .insert(increase_liquidity_event.event.tokenId, token_id);
 
                         // create new position info
-                        let position = create_position_info_from_mint_event(
-                            self.pool.clone(),
+                        let mut position = create_position_info_from_mint_event(
+                            &self.backend,
                             &self.pool_config,
-                            self.swap_router.clone(),
-                            self.swap_account.clone(),
+                            &self.valuer,
                             event.clone(),
                             token_id,
                             increase_liquidity_event.event.tokenId,
                         )
                         .await?;
+                        position.gas_used_in = gas_used;
+                        if let Some(owner) = self
+                            .position_owners
+                            .owner_at(position.original_token_id, &event)
+                        {
+                            position.owner = owner;
+                        }
 
                         // insert position info into map
                         self.position_info.insert(token_id, vec![position]);
                     }
                 }
                 Event::Swap(e) => {
-                    info!("swapping");
-                    pool_swap(
+                    debug!("swapping");
+                    let slot0_before = self.pool.slot0().call().await?;
+                    let swapper = self.swapper(&event).await?;
+                    self.top_up_swap_input(swapper, &e.event).await?;
+                    self.approve_swap_input(swapper, &e.event).await?;
+                    let replayed = pool_swap(
                         self.pool.clone(),
                         self.swap_router.clone(),
                         self.quoter.clone(),
                         &e,
-                        self.swap_account,
+                        swapper,
+                        &self.swap_settings,
+                        &mut self.swap_stats,
                     )
                     .await?;
-                }
-                Event::Burn(e) => {
-                    warn!("Burn: {:?}", e);
-
-                    // burns are always followed by a collectPool or decreaseLiquidity event,
-                    // only want to replay the decreaseLiquidity event as the collect event is
-                    // a zero-liquditiy burn done to update the pool fees
-                    let next_event = if let Some(sim_event) = event_iter.peek() {
-                        if sim_event.event.event_type() == EventType::CollectPool
-                            || sim_event.event.event_type() == EventType::DecreaseLiquidity
-                        {
-                            event_iter.next().unwrap()
-                        } else {
-                            bail!("Next event is not a collectPool or decreaseLiquidity");
+
+                    match replayed {
+                        Some((gas_used, direction)) => {
+                            self.gas_used.swap += gas_used;
+                            if let Some(exact_output_swaps) = self.exact_output_swaps.as_mut() {
+                                exact_output_swaps
+                                    .extend(exact_output_swap(&event, &e.event, direction));
+                            }
+                            self.sample_pool(
+                                event.block,
+                                slot0_before.sqrtPriceX96,
+                                slot0_before.tick,
+                                &e.event,
+                            )
+                            .await?;
                         }
+                        None => self.record_warning(
+                            event.block,
+                            RunWarningKind::SkippedSwap,
+                            format!(
+                                "tx {} log {}: amount0 {}, amount1 {}",
+                                event.tx_hash, event.log_index, e.event.amount0, e.event.amount1
+                            ),
+                        ),
+                    }
+                }
+                Event::Burn(e) if e.amount == 0 => {
+                    debug!("Poking");
+                    trace!("Poke: {:?}", e);
+
+                    // a zero liquidity burn only updates the position's fees so they
+                    // can be collected. the collect is replayed into the open record,
+                    // the position itself doesn't change
+                    let original_token_id = if e.owner
+                        != *self.nonfungible_position_manager.address()
+                    {
+                        // the pool collect after a direct poke is left to the
+                        // unhandled arm like any other direct collect
+                        self.direct_positions
+                            .get(&(e.owner, e.tickLower, e.tickUpper))
+                            .copied()
                     } else {
-                        bail!("No events after burn");
+                        // the position manager's collect pokes, collects from the
+                        // pool, then logs its own collect naming the token id
+                        match event_iter.peek() {
+                            Some(next) if next.event.event_type() == EventType::CollectPool => {
+                                event_iter.next();
+                            }
+                            _ => bail!(FeeAnalyzerError::EventOrdering(
+                                "Zero liquidity burn is not followed by a collectPool".to_string()
+                            )),
+                        }
+                        match event_iter.next_if(|next| {
+                            next.tx_hash == event.tx_hash
+                                && next.event.event_type() == EventType::CollectNpm
+                        }) {
+                            Some(SimulationEvent {
+                                event: Event::CollectNpm(collect),
+                                ..
+                            }) => Some(collect.tokenId),
+                            _ => None,
+                        }
                     };
 
-                    if next_event.event.event_type() == EventType::DecreaseLiquidity {
-                        let decrease_liquidity_event: DecreaseLiquidityWithParams =
-                            next_event.try_into()?;
+                    let token_id = original_token_id
+                        .and_then(|token_id| self.token_id_map.get(&token_id).copied());
+                    let position = token_id
+                        .and_then(|token_id| self.position_info.get_mut(&token_id))
+                        .and_then(|records| records.last_mut())
+                        .filter(|position| !position.closed);
+                    match (token_id, position) {
+                        (Some(token_id), Some(position)) => {
+                            self.gas_used.collect += pool_poke_collect(
+                                &self.backend,
+                                &self.pool_config,
+                                self.mint_account,
+                                self.fee_recipient,
+                                token_id,
+                                position,
+                            )
+                            .await?;
+                        }
+                        _ => {
+                            // the fees stay in the simulated position and are
+                            // collected with its next change
+                            debug!(
+                                "Poke by {} in [{}, {}] has no open replayed position, skipping it",
+                                e.owner, e.tickLower, e.tickUpper
+                            );
+                        }
+                    }
+                }
+                Event::Burn(e) => {
+                    debug!("Burning");
+                    trace!("Burn: {:?}", e);
+
+                    let decrease_liquidity_event =
+                        if e.owner != *self.nonfungible_position_manager.address() {
+                            // burns made directly against the pool have no position manager
+                            // events to pair with, their collect is left to the unhandled arm
+                            self.direct_position_decrease(event.block, &e)
+                        } else {
+                            // burns of liquidity are always followed by the
+                            // decreaseLiquidity that sent them, zero liquidity pokes are
+                            // handled above
+                            let next_event = if let Some(sim_event) = event_iter.peek() {
+                                if sim_event.event.event_type() == EventType::DecreaseLiquidity {
+                                    event_iter.next().unwrap()
+                                } else {
+                                    bail!(FeeAnalyzerError::EventOrdering(
+                                        "Next event is not a decreaseLiquidity".to_string()
+                                    ));
+                                }
+                            } else {
+                                bail!(FeeAnalyzerError::EventOrdering(
+                                    "No events after burn".to_string()
+                                ));
+                            };
+
+                            Some(next_event.try_into()?)
+                        };
 
+                    if let Some(decrease_liquidity_event) = decrease_liquidity_event {
                         // process decrease liquidity event which triggered the burn event
                         let token_id = self
                             .token_id_map.get(&decrease_liquidity_event.event.tokenId)
                             .context("Token id not found for Burn, mismatch between burn and mint position manager events")?;
-                        pool_burn(
+                        let gas_used = pool_burn(
                             self.nonfungible_position_manager.clone(),
-                            token_id.clone(),
-                            self.mint_account.clone(),
+                            *token_id,
+                            self.mint_account,
                             &e,
                             &decrease_liquidity_event,
+                            self.verification.checks_liquidity(),
                         )
                         .await?;
+                        self.gas_used.decrease_liquidity += gas_used;
 
-                        // find the position info that should exist for the token id
+                        // find the position info that should exist for the token id,
+                        // a seeded token id may have no record
                         let position = self
                             .position_info
-                            .get_mut(&token_id)
-                            .unwrap()
-                            .last_mut()
-                            .context("Position info not found DL")?;
+                            .get_mut(token_id)
+                            .and_then(|position_infos| position_infos.last_mut())
+                            .ok_or_else(|| {
+                                FeeAnalyzerError::BurnMismatch(format!(
+                                    "No position record to decrease for token id {}",
+                                    token_id
+                                ))
+                            })?;
 
                         // process the position info pnl
-                        let position_info = pool_collect_fees_post_decrease_liquidity(
-                            self.nonfungible_position_manager.clone(),
-                            self.pool.clone(),
-                            self.swap_router.clone(),
+                        let decreased_liquidity = decrease_liquidity_event.event.liquidity;
+                        let mut position_info = pool_collect_fees_post_decrease_liquidity(
+                            &self.backend,
+                            &self.valuer,
                             &self.pool_config,
-                            self.mint_account.clone(),
-                            self.swap_account.clone(),
-                            token_id.clone(),
+                            self.mint_account,
+                            self.fee_recipient,
+                            *token_id,
                             position,
                             event.block,
                             decrease_liquidity_event,
                         )
                         .await?;
+                        self.gas_used.collect += position.gas_used_out;
+                        position_info.gas_used_in = gas_used;
+                        if let Some(owner) = self
+                            .position_owners
+                            .owner_at(position_info.original_token_id, &event)
+                        {
+                            position_info.owner = owner;
+                        }
+                        if let Some(writer) = self.csv_writer.as_mut() {
+                            annotate_from_samples(position, &self.liquidity_depth);
+                            annotate_fee_yields(
+                                position,
+                                &self.block_clock,
+                                self.pool_config.clanker_is_token0(),
+                            );
+                            writer.write(position)?;
+                        }
+                        self.warnings
+                            .extend(position_warnings(event.block, position));
+                        self.warnings.extend(partial_close_warning(
+                            event.block,
+                            position,
+                            decreased_liquidity,
+                        ));
 
                         // insert the new position into the map
-                        let position_info_vec = self.position_info.get_mut(&token_id).unwrap();
-                        position_info_vec.push(position_info);
+                        self.position_info
+                            .entry(*token_id)
+                            .or_default()
+                            .push(position_info);
                     }
                 }
                 Event::IncreaseLiquidity(e) => {
@@ -443,7 +2122,9 @@ impl PoolAnalyzer {
                         e
                     );
                     info!("tx hash: {:?}", event.tx_hash);
-                    bail!("Increase liquidity event not processed in mint handling");
+                    bail!(FeeAnalyzerError::EventOrdering(
+                        "Increase liquidity event not processed in mint handling".to_string()
+                    ));
                 }
                 Event::DecreaseLiquidity(e) => {
                     error!(
@@ -451,57 +2132,2880 @@ impl PoolAnalyzer {
                         e
                     );
                     info!("tx hash: {:?}", event.tx_hash);
-                    bail!("Decrease liquidity event not processed in burn handling");
+                    bail!(FeeAnalyzerError::EventOrdering(
+                        "Decrease liquidity event not processed in burn handling".to_string()
+                    ));
                 }
                 _ => {
                     // not handling collect events as we do it manually after
                     // liquidity position changes
-                    warn!("Unhandled event: {:?}", event);
+                    self.unhandled_events.record(&event);
+                    if UnhandledEvents::is_skipped(&event.event.event_type()) {
+                        debug!("Skipped event: {:?}", event);
+                    } else {
+                        warn!("Unhandled event: {:?}", event);
+                        self.record_warning(
+                            event.block,
+                            RunWarningKind::UnhandledEvent,
+                            format!("{:?} in tx {}", event.event.event_type(), event.tx_hash),
+                        );
+                    }
                 }
             }
+
+            if matches!(event.event, Event::Mint(_) | Event::Burn(_)) {
+                self.sample_pnl(event.block).await?;
+            }
+            self.last_event_block = event.block;
+            if event_iter
+                .peek()
+                .is_none_or(|next| next.block != event.block)
+            {
+                self.record_fork_block(event.block).await?;
+            }
+        }
+        match stop {
+            Some(ReplayStop::Interrupted) => info!("Stopping after {} events", event_count),
+            Some(ReplayStop::TimedOut(run_timeout)) => {
+                let detail = format!("the replay ran past the {:?} run timeout", run_timeout);
+                warn!(
+                    "Timed out after block {}, closing out positions: {}",
+                    self.last_event_block, detail
+                );
+                self.record_warning(self.last_event_block, RunWarningKind::TimedOut, detail);
+                self.timed_out = true;
+            }
+            Some(ReplayStop::MaxEvents) => info!(
+                "Stopping after {} events, closing out open positions",
+                event_count
+            ),
+            None => {}
+        }
+        // a replay stopped partway through a block keeps what it replayed of it
+        if first_block.is_some() {
+            self.record_fork_block(self.last_event_block).await?;
+        }
+        info!("Replayed {} events", event_count);
+        // from the events' own block times, or estimated from the block time
+        // setting for hand built events that don't have real ones
+        let elapsed = block_times
+            .and_then(|(first, last)| (last - first).to_std().ok())
+            .filter(|elapsed| !elapsed.is_zero())
+            .or_else(|| {
+                first_block.and_then(|block| self.l2_profile.elapsed(block, self.last_event_block))
+            });
+        if let Some(elapsed) = elapsed {
+            info!(
+                "The replayed blocks span about {:.1} hours of chain time",
+                elapsed.as_secs_f64() / 3600.0
+            );
         }
 
-        // close out all positions
-        for (token_id, position_infos) in self.position_info.iter_mut() {
-            let mut closed_found = false;
-            for position_info in position_infos.iter_mut() {
-                if !position_info.closed {
-                    if closed_found {
-                        bail!("Multiple positions found for token id: {}", token_id);
-                    } else {
-                        closed_found = true;
-                    }
-                    info!("closing position: ---");
-                    pool_close_out_position(
-                        self.nonfungible_position_manager.clone(),
-                        self.pool.clone(),
-                        self.swap_router.clone(),
-                        &self.pool_config,
-                        self.mint_account.clone(),
-                        self.swap_account.clone(),
-                        token_id.clone(),
-                        position_info,
-                        0,
-                    )
-                    .await?;
+        Ok(())
+    }
+
+    // runs the simulation, then compares its records with an earlier run's
+    // positions csv and fails if any record diverges by more than the verify
+    // tolerance or is only in one of them. the comparison is written to
+    // `<output>.verify_diff.csv`
+    pub async fn verify_against(&mut self, expected_csv: &Path) -> Result<Vec<PositionInfo>> {
+        let positions = self.run_simulation().await?;
+        let expected = read_positions_from_csv(expected_csv)?;
+        let diffs = diff_positions(&expected, &positions);
+
+        let diff_path = Path::new(&self.output_csv_file_path).with_extension("verify_diff.csv");
+        write_position_diffs_to_csv(&diffs, &diff_path)
+            .map_err(|e| eyre!("Failed to write verify diff to csv: {}", e))?;
+        let diverging: Vec<&PositionDiff> = diffs
+            .iter()
+            .filter(|diff| diff.diverges(self.verify_tolerance_wei))
+            .collect();
+        for diff in &diverging {
+            warn!(
+                "Token id {} record {} diverges (present in {}): token fees {:?}, weth fees {:?}, \
+                 net pnl {:?}",
+                diff.original_token_id,
+                diff.index,
+                diff.present_in(),
+                diff.fees_earned_token_delta(),
+                diff.fees_earned_weth_delta(),
+                diff.net_pnl_in_weth_delta()
+            );
+        }
+        if !diverging.is_empty() {
+            bail!(
+                "{} of {} records diverge from {:?} by more than {} wei, see {:?}",
+                diverging.len(),
+                diffs.len(),
+                expected_csv,
+                self.verify_tolerance_wei,
+                diff_path
+            );
+        }
+        info!(
+            "All {} records match {:?} within {} wei",
+            diffs.len(),
+            expected_csv,
+            self.verify_tolerance_wei
+        );
+        Ok(positions)
+    }
+
+    pub async fn run_simulation(&mut self) -> Result<Vec<PositionInfo>> {
+        // TODO: figure out how to make this prettier
+        let mut event_iter = filter_event_types(
+            self.pool_simulation_events.take().unwrap(),
+            &self.enabled_event_types,
+        )
+        .into_iter()
+        .peekable();
+        if self.incremental_csv {
+            self.csv_writer = Some(
+                PositionCsvWriter::create(&self.output_csv_file_path, self.output_dust_threshold())
+                    .map_err(|e| eyre!("Failed to create output csv: {}", e))?,
+            );
+        }
+
+        // skip first two event, they are pool created and initialize TODO clean up
+        event_iter.next();
+        event_iter.next();
+
+        // on ctrl-c stop before the next event and fall through to the close
+        // out below so an aborted run still writes its results. a second
+        // ctrl-c exits right away
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let ctrl_c_listener = {
+            let interrupted = interrupted.clone();
+            AbortOnDrop(tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    warn!(
+                        "Interrupted, closing out positions after the current event \
+                         (ctrl-c again to exit now)"
+                    );
+                    interrupted.store(true, Ordering::SeqCst);
+                }
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    std::process::exit(130);
+                }
+            }))
+        };
+
+        // the run timeout stops the replay between events like ctrl-c, and
+        // the close out below still runs. a single call past the rpc timeout
+        // fails the run, the fork can't be trusted to answer the close out
+        let deadline = RunDeadline::starting_now(self.run_timeout);
+        self.replay_events(&mut event_iter, &interrupted, &deadline)
+            .await
+            .map_err(|e| {
+                if is_rpc_timeout(&e) {
+                    e.wrap_err("A call to anvil ran past RPC_TIMEOUT_SECS")
+                } else {
+                    e
                 }
-                if position_info.liquidity_in > u128::try_from(0).unwrap() {
+            })?;
+
+        // before the close out, which moves the simulated pool's state
+        if self.final_state_check.is_some() {
+            if event_iter.peek().is_some() {
+                warn!("Replay stopped early, skipping the final pool state check");
+            } else {
+                self.check_final_pool_state().await?;
+            }
+        }
+        if !self.value_positions_at_blocks.is_empty() {
+            let values = self.value_positions_at_blocks().await?;
+            write_position_values_to_csv(&values, &self.output_csv_file_path)
+                .map_err(|e| eyre!("Failed to write position values to csv: {}", e))?;
+        }
+        let occupied_ticks = self
+            .position_info
+            .values()
+            .flatten()
+            .flat_map(|p| [p.lower_tick, p.upper_tick])
+            .collect();
+        let tick_fee_growth = fee_growth_by_tick_range(&self.pool, &occupied_ticks).await?;
+
+        // the end of run close out below only simulates closing, save the
+        // positions as they stand so a later block range can pick them up
+        let resume_path = Path::new(&self.output_csv_file_path).with_extension("resume.json");
+        // only a kept-alive fork outlives the run for a later one to resume on
+        let resume_fork = if self.keep_alive {
+            Some(ResumeFork {
+                anvil_endpoint: self.endpoint(),
+                block: self.anvil_provider.get_block_number().await?,
+                pool: *self.pool.address(),
+                deployer: self.deployer,
+                swap_account: self.swap_account,
+                mint_account: self.mint_account,
+            })
+        } else {
+            None
+        };
+        write_resume_state(
+            &resume_path,
+            &self.token_id_map,
+            &self.position_info,
+            resume_fork,
+        )?;
+        let output_path = Path::new(&self.output_csv_file_path);
+        write_address_map(
+            &output_path.with_extension("address_map.json"),
+            &self.address_map,
+        )?;
+        write_token_id_map(
+            &output_path.with_extension("token_id_map.json"),
+            &self.token_id_map,
+        )?;
+
+        let close_out_start = Instant::now();
+        let CloseOut {
+            closed_out,
+            collect_gas,
+            failures,
+        } = close_out_open_positions(
+            &self.backend,
+            &self.valuer,
+            &self.pool_config,
+            self.mint_account,
+            self.fee_recipient,
+            self.close_out_concurrency,
+            &mut self.position_info,
+        )
+        .await?;
+        self.gas_used.collect += collect_gas;
+        info!(
+            "Closed out {} positions in {:.1?}, {} failed",
+            closed_out.len() - failures.len(),
+            close_out_start.elapsed(),
+            failures.len()
+        );
+        for detail in failures {
+            self.record_warning(
+                self.last_event_block,
+                RunWarningKind::CloseOutFailed,
+                detail,
+            );
+        }
+
+        let mut token_ids: Vec<U256> = self.position_info.keys().copied().collect();
+        token_ids.sort();
+        for (token_id, index) in &closed_out {
+            let position_info = &mut self.position_info.get_mut(token_id).unwrap()[*index];
+            if let Some(writer) = self.csv_writer.as_mut() {
+                annotate_from_samples(position_info, &self.liquidity_depth);
+                annotate_fee_yields(
+                    position_info,
+                    &self.block_clock,
+                    self.pool_config.clanker_is_token0(),
+                );
+                writer.write(position_info)?;
+            }
+            if position_info.close_out_error.is_none() {
+                self.warnings
+                    .extend(position_warnings(self.last_event_block, position_info));
+            }
+        }
+        for token_id in &token_ids {
+            for position_info in &self.position_info[token_id] {
+                if position_info.liquidity_in > 0 {
                     info!("{}", position_info);
                 }
             }
         }
 
-        // filter out empty positions and write to csv
-        write_positions_to_csv(
+        if self.fee_invariant_check {
+            let violations = fee_invariant_violations(
+                self.position_info
+                    .values()
+                    .flatten()
+                    .filter(|p| p.close_out_error.is_none()),
+            );
+            if !violations.is_empty() {
+                for violation in &violations {
+                    error!("Fee invariant violated: {}", violation);
+                }
+                bail!(FeeAnalyzerError::FeeInvariant(format!(
+                    "{} tick ranges collected more fees than their fee growth allows",
+                    violations.len()
+                )));
+            }
+            info!("Fee invariant held for every tick range");
+        }
+
+        // every record is closed now, so this row's realized fees are the run's totals
+        self.sample_pnl(self.last_event_block).await?;
+
+        info!("{}", self.gas_used);
+        info!("{}", self.unhandled_events);
+        if self.unhandled_events.unexpected_count() > 0 {
+            warn!(
+                "{} events of unexpected types were not replayed",
+                self.unhandled_events.unexpected_count()
+            );
+        }
+        if self.swap_settings.optimistic_exact_input {
+            info!("{}", self.swap_stats);
+        }
+        info!(
+            "Positions: {} unique token ids, {} position records, {} open at end",
+            self.unique_token_ids(),
+            self.position_count(),
+            self.open_position_count()
+        );
+
+        for position_info in self.position_info.values_mut().flatten() {
+            annotate_from_samples(position_info, &self.liquidity_depth);
+            annotate_fee_yields(
+                position_info,
+                &self.block_clock,
+                self.pool_config.clanker_is_token0(),
+            );
+        }
+        info!(
+            "{}",
+            OwnerSummary::new(
+                self.position_info
+                    .values()
+                    .flatten()
+                    .filter(|p| !p.is_dust(self.dust_threshold_weth))
+                    .filter(|p| p.close_out_error.is_none())
+            )
+        );
+        let pnl_distribution = PnlDistribution::new(
             self.position_info
                 .values()
                 .flatten()
-                .filter(|p| p.liquidity_in > u128::try_from(0).unwrap())
-                .cloned()
-                .collect(),
+                .filter(|p| p.close_out_error.is_none()),
+            &self.pnl_histogram_boundaries,
+            self.dust_threshold_weth,
+        );
+        if pnl_distribution.dust_records > 0 {
+            info!(
+                "Dust positions: {} (starting below {} weth wei), left out of the pnl summaries",
+                pnl_distribution.dust_records, self.dust_threshold_weth
+            );
+        }
+        if self.pnl_histogram_chart {
+            info!("{}", pnl_distribution);
+        }
+        let top_fees_per_weth_block = rank_by_fees_per_weth_block(
+            self.position_info
+                .values()
+                .flatten()
+                .filter(|p| p.liquidity_in > 0 && !p.is_dust(self.dust_threshold_weth))
+                .filter(|p| p.close_out_error.is_none()),
+            self.capital_efficiency_top_n,
+        );
+        for (rank, position) in top_fees_per_weth_block.iter().enumerate() {
+            info!(
+                "Capital efficiency #{}: token id {} (record {}) earned {:e} weth per weth-block over {} blocks",
+                rank + 1,
+                position.original_token_id,
+                position.index,
+                position.fees_per_weth_block,
+                position.holding_blocks
+            );
+        }
+        let max_drawdown = MaxDrawdown::new(&self.pnl_timeseries);
+        info!("{}", max_drawdown);
+
+        // filter out empty positions and write to csv
+        let output_dust_threshold = self.output_dust_threshold();
+        let positions: Vec<PositionInfo> = token_ids
+            .iter()
+            .flat_map(|token_id| &self.position_info[token_id])
+            .filter(|p| p.liquidity_in > u128::try_from(0).unwrap())
+            .filter(|p| !p.is_dust(output_dust_threshold))
+            .cloned()
+            .collect();
+        // incremental runs already wrote every record as it was finalized
+        if self.csv_writer.take().is_some() {
+            write_output_metadata(
+                Path::new(&self.output_csv_file_path),
+                *self.weth.address(),
+                self.timed_out,
+            )
+            .map_err(|e| eyre!("Failed to write output metadata: {}", e))?;
+        } else {
+            write_positions_to_csv(
+                positions.clone(),
+                &self.output_csv_file_path,
+                *self.weth.address(),
+                self.timed_out,
+            )
+            .map_err(|e| eyre!("Failed to write positions to csv: {}", e))?;
+        }
+        write_liquidity_depth_to_csv(&self.liquidity_depth, &self.output_csv_file_path)
+            .map_err(|e| eyre!("Failed to write liquidity depth to csv: {}", e))?;
+        write_pnl_timeseries_to_csv(&self.pnl_timeseries, &self.output_csv_file_path)
+            .map_err(|e| eyre!("Failed to write pnl timeseries to csv: {}", e))?;
+        write_tick_fee_distribution_to_csv(
+            tick_fee_growth,
+            self.pool_config.clanker_is_token0(),
+            &self.output_csv_file_path,
+        )
+        .map_err(|e| eyre!("Failed to write tick fee distribution to csv: {}", e))?;
+        write_pnl_distribution_to_json(
+            &pnl_distribution,
+            &top_fees_per_weth_block,
+            &max_drawdown,
             &self.output_csv_file_path,
         )
-        .map_err(|e| eyre!("Failed to write positions to csv: {}", e))?;
+        .map_err(|e| eyre!("Failed to write pnl summary: {}", e))?;
+        if self.grouped_output {
+            write_grouped_positions_to_json(&positions, &self.output_csv_file_path)
+                .map_err(|e| eyre!("Failed to write grouped positions: {}", e))?;
+        }
+        if self.merge_reopened_positions {
+            let strategies = merge_reopened_positions(&positions);
+            info!(
+                "Merged {} token ids into {} strategies",
+                strategies.iter().map(|s| s.token_ids.len()).sum::<usize>(),
+                strategies.len()
+            );
+            write_strategies_to_csv(&strategies, &self.output_csv_file_path)
+                .map_err(|e| eyre!("Failed to write strategies to csv: {}", e))?;
+        }
+        if self.write_warnings_csv {
+            write_warnings_to_csv(&self.warnings, &self.output_csv_file_path)
+                .map_err(|e| eyre!("Failed to write warnings to csv: {}", e))?;
+        }
+        if let Some(exact_output_swaps) = &self.exact_output_swaps {
+            info!(
+                "{} swaps replayed as exact output",
+                exact_output_swaps.len()
+            );
+            write_exact_output_swaps_to_csv(exact_output_swaps, &self.output_csv_file_path)
+                .map_err(|e| eyre!("Failed to write exact output swaps to csv: {}", e))?;
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some((db_path, run)) = &self.sqlite_output {
+            sqlite_output_writer::write_positions_to_sqlite(
+                &positions,
+                db_path,
+                run,
+                self.timed_out,
+            )
+            .context("Failed to write positions to sqlite")?;
+            info!(
+                "Wrote {} records to {:?} as run {}",
+                positions.len(),
+                db_path,
+                run.run_id
+            );
+        }
+
+        // the keep alive wait below owns ctrl-c from here on. an early return
+        // drops the listener too
+        drop(ctrl_c_listener);
+
+        // keep the fork queryable for post-run inspection, the anvil
+        // instance is killed when the analyzer is dropped
+        if self.keep_alive {
+            info!(
+                "Simulation finished, anvil still running at {} (ctrl-c to exit)",
+                self.endpoint()
+            );
+            tokio::signal::ctrl_c()
+                .await
+                .context("Failed to listen for ctrl-c")?;
+        }
+
+        Ok(positions)
+    }
+}
+
+// formatting every event is slow on long runs, so the full dump is only at
+// trace
+fn log_replayed_event(event_count: usize, event: &SimulationEvent) {
+    debug!("event: {}", event_count);
+    trace!("event: {:?}", event);
+}
+
+// the block to fork at: right before the pool's first event with
+// `auto_fork_block`, otherwise the configured one. forking at or after the
+// pool's first event replays onto a chain where the pool may already exist,
+// which only attaching to it expects
+fn choose_fork_block(
+    events: &[SimulationEvent],
+    auto_fork_block: bool,
+    fork_block: Option<u64>,
+    attach_existing: bool,
+) -> Result<u64> {
+    let first_event_block = events
+        .first()
+        .map(|event| event.block)
+        .context("No events for the pool")?;
+    if attach_existing && auto_fork_block {
+        bail!(
+            "AUTO_FORK_BLOCK forks before the pool exists, POOL_SETUP_MODE=attach_existing \
+             needs a BLOCK_FORK_NUMBER after its creation"
+        );
+    }
+    if auto_fork_block {
+        let fork_block = first_event_block.saturating_sub(1);
+        info!(
+            "Forking at block {}, before the first event at block {}",
+            fork_block, first_event_block
+        );
+        return Ok(fork_block);
+    }
+    let fork_block = fork_block.context("Set BLOCK_FORK_NUMBER or AUTO_FORK_BLOCK=true")?;
+    if fork_block >= first_event_block && !attach_existing {
+        warn!(
+            "Fork block {} is not before the first event at block {}, the pool may already \
+             exist on the fork",
+            fork_block, first_event_block
+        );
+    }
+    Ok(fork_block)
+}
+
+// stands in for the position manager event a mint made directly against the
+// pool doesn't have. the position is replayed through the position manager,
+// only the liquidity it adds to the pool matters for the fees, and gets a
+// synthetic original token id counting down from U256::MAX so it can't collide
+// with a real one
+fn direct_mint_increase(
+    direct_positions: &mut HashMap<(Address, I24, I24), U256>,
+    mint: &Mint,
+) -> IncreaseLiquidityWithParams {
+    let next_token_id = U256::MAX - U256::from(direct_positions.len());
+    let token_id = *direct_positions
+        .entry((mint.owner, mint.tickLower, mint.tickUpper))
+        .or_insert(next_token_id);
+    warn!(
+        "Mint by {} in [{}, {}] was made directly against the pool, tracking it as token id {}",
+        mint.owner, mint.tickLower, mint.tickUpper, token_id
+    );
+    IncreaseLiquidityWithParams {
+        amount_0_desired: mint.amount0,
+        amount_1_desired: mint.amount1,
+        amount_0_min: U256::ZERO,
+        amount_1_min: U256::ZERO,
+        event: IncreaseLiquidity {
+            tokenId: token_id,
+            liquidity: mint.amount,
+            amount0: mint.amount0,
+            amount1: mint.amount1,
+        },
+    }
+}
+
+// the decrease of the direct position a burn made directly against the pool
+// takes liquidity from, none when its mint wasn't replayed
+fn direct_burn_decrease(
+    direct_positions: &HashMap<(Address, I24, I24), U256>,
+    burn: &Burn,
+) -> Option<DecreaseLiquidityWithParams> {
+    let token_id = direct_positions.get(&(burn.owner, burn.tickLower, burn.tickUpper))?;
+    Some(DecreaseLiquidityWithParams {
+        amount_0_min: U256::ZERO,
+        amount_1_min: U256::ZERO,
+        event: DecreaseLiquidity {
+            tokenId: *token_id,
+            liquidity: burn.amount,
+            amount0: burn.amount0,
+            amount1: burn.amount1,
+        },
+    })
+}
+
+// the swap for the exact output csv, none for swaps replayed as exact input
+fn exact_output_swap(
+    event: &SimulationEvent,
+    swap: &Swap,
+    direction: SwapDirection,
+) -> Option<ExactOutputSwap> {
+    (direction != SwapDirection::ExactInput).then(|| ExactOutputSwap {
+        block: event.block,
+        tx_hash: event.tx_hash,
+        log_index: event.log_index,
+        swap: swap.clone(),
+    })
+}
+
+// the warning a decrease that left part of the record's liquidity in place
+// raises
+fn partial_close_warning(
+    block: u64,
+    position: &PositionInfo,
+    decreased_liquidity: u128,
+) -> Option<RunWarning> {
+    (position.close_reason == Some(CloseReason::StillOpenPartial)).then(|| RunWarning {
+        block,
+        kind: RunWarningKind::PartialClose,
+        detail: format!(
+            "token id {} decreased by {} of {} liquidity",
+            position.original_token_id, decreased_liquidity, position.liquidity_in
+        ),
+    })
+}
+
+// the warnings any finalized record can raise, partial closes are recorded
+// by the decrease itself
+fn position_warnings(block: u64, position: &PositionInfo) -> Vec<RunWarning> {
+    let mut warnings = Vec::new();
+    let mut warn_about = |kind: RunWarningKind, detail: String| {
+        warnings.push(RunWarning {
+            block,
+            kind,
+            detail,
+        })
+    };
+    if position.liquidity_in > 0
+        && position.fees_earned_token == U256::ZERO
+        && position.fees_earned_weth == U256::ZERO
+    {
+        warn_about(
+            RunWarningKind::ZeroCollect,
+            format!("token id {} collected no fees", position.original_token_id),
+        );
+    }
+    if position
+        .fees_from_growth_token
+        .abs_diff(position.fees_earned_token)
+        > U256::from(1)
+        || position
+            .fees_from_growth_weth
+            .abs_diff(position.fees_earned_weth)
+            > U256::from(1)
+    {
+        warn_about(
+            RunWarningKind::FeeGrowthMismatch,
+            format!(
+                "token id {} fee growth fees ({}, {}) vs collected ({}, {})",
+                position.original_token_id,
+                position.fees_from_growth_token,
+                position.fees_from_growth_weth,
+                position.fees_earned_token,
+                position.fees_earned_weth
+            ),
+        );
+    }
+    warnings
+}
+
+// per tick range, the fees collected by every record against what the pool's
+// fee growth inside the range pays that liquidity, with a wei of position
+// manager rounding allowed per record. the inside growth is the range's share
+// of feeGrowthGlobal, so collecting more than it means a collect was counted
+// twice or credited to the wrong record
+fn fee_invariant_violations<'a>(positions: impl Iterator<Item = &'a PositionInfo>) -> Vec<String> {
+    #[derive(Default)]
+    struct RangeFees {
+        records: u64,
+        earned_token: U256,
+        earned_weth: U256,
+        growth_token: U256,
+        growth_weth: U256,
+    }
+
+    let mut ranges: BTreeMap<(I24, I24), RangeFees> = BTreeMap::new();
+    for position in positions.filter(|p| p.liquidity_in > 0) {
+        let range = ranges
+            .entry((position.lower_tick, position.upper_tick))
+            .or_default();
+        range.records += 1;
+        range.earned_token += position.fees_earned_token;
+        range.earned_weth += position.fees_earned_weth;
+        range.growth_token += position.fees_from_growth_token;
+        range.growth_weth += position.fees_from_growth_weth;
+    }
+    ranges
+        .into_iter()
+        .filter(|(_, range)| {
+            let slack = U256::from(range.records);
+            range.earned_token > range.growth_token + slack
+                || range.earned_weth > range.growth_weth + slack
+        })
+        .map(|((lower, upper), range)| {
+            format!(
+                "range [{}, {}) over {} records collected ({}, {}) but fee growth allows ({}, {})",
+                lower,
+                upper,
+                range.records,
+                range.earned_token,
+                range.earned_weth,
+                range.growth_token,
+                range.growth_weth
+            )
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResumeState {
+    // (original token id, simulated token id)
+    token_id_map: Vec<(U256, U256)>,
+    open_positions: Vec<PositionInfo>,
+    // unset unless the run kept its anvil alive
+    #[serde(default)]
+    fork: Option<ResumeFork>,
+}
+
+// where a resumed run picks up: the earlier run's kept-alive anvil at the block
+// its replay stopped at, before the end of run close out, and the simulated
+// pool and accounts there
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ResumeFork {
+    anvil_endpoint: String,
+    block: u64,
+    pool: Address,
+    deployer: Address,
+    swap_account: Address,
+    mint_account: Address,
+}
+
+// writes the token id map and the latest record of every still open position
+fn write_resume_state(
+    path: &Path,
+    token_id_map: &HashMap<U256, U256>,
+    position_info: &HashMap<U256, Vec<PositionInfo>>,
+    fork: Option<ResumeFork>,
+) -> Result<()> {
+    let resume_state = ResumeState {
+        fork,
+        token_id_map: token_id_map.iter().map(|(k, v)| (*k, *v)).collect(),
+        open_positions: position_info
+            .values()
+            .filter_map(|infos| infos.last())
+            .filter(|info| !info.closed)
+            .cloned()
+            .collect(),
+    };
+    std::fs::write(path, serde_json::to_string_pretty(&resume_state)?)
+        .with_context(|| format!("Failed to write resume state to {:?}", path))?;
+    Ok(())
+}
+
+fn read_resume_state(path: &Path) -> Result<ResumeState> {
+    serde_json::from_str(
+        &std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read resume state from {:?}", path))?,
+    )
+    .with_context(|| format!("Failed to parse resume state from {:?}", path))
+}
+
+// the token id map and the open positions, keyed by simulated token id, to
+// seed a resumed run with
+fn seed_resumed_positions(
+    resume_state: ResumeState,
+) -> (HashMap<U256, U256>, HashMap<U256, Vec<PositionInfo>>) {
+    let token_id_map = resume_state.token_id_map.into_iter().collect();
+    let position_info = resume_state
+        .open_positions
+        .into_iter()
+        .map(|info| (info.token_id, vec![info]))
+        .collect();
+    (token_id_map, position_info)
+}
+
+// a resumed position has to be the one the earlier run left on the fork: in
+// the resumed pool, over the record's ticks, and holding its liquidity
+fn check_resumed_position(
+    position: &PositionInfo,
+    pool_config: &PoolConfig,
+    on_fork: &INonfungiblePositionManager::positionsReturn,
+) -> Result<()> {
+    let expected = (
+        pool_config.tokens(),
+        pool_config.fee(),
+        position.lower_tick,
+        position.upper_tick,
+        position.liquidity_in,
+    );
+    let actual = (
+        (on_fork.token0, on_fork.token1),
+        on_fork.fee,
+        on_fork.tickLower,
+        on_fork.tickUpper,
+        on_fork.liquidity,
+    );
+    if actual != expected {
+        bail!(FeeAnalyzerError::PoolSetup(format!(
+            "Resumed token id {} is {:?} on the fork, the resume state expects {:?} as \
+             ((token0, token1), fee, lower tick, upper tick, liquidity)",
+            position.token_id, actual, expected
+        )));
+    }
+    Ok(())
+}
+
+// a resumed run keeps the earlier run's accounts, which hold its positions and
+// balances. an override naming a different account is an error
+fn resumed_account(name: &str, configured: Option<Address>, resumed: Address) -> Result<Address> {
+    match configured {
+        Some(configured) if configured != resumed => bail!(
+            "{} is {} but the resumed run used {}",
+            name,
+            configured,
+            resumed
+        ),
+        _ => Ok(resumed),
+    }
+}
+
+// original to simulated maps as json objects keyed by the original, sorted so
+// runs over the same events write the same file
+fn write_id_map<K, V>(path: &Path, map: &HashMap<K, V>) -> Result<()>
+where
+    K: Serialize + Ord,
+    V: Serialize,
+{
+    let sorted: BTreeMap<&K, &V> = map.iter().collect();
+    std::fs::write(path, serde_json::to_string_pretty(&sorted)?)
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    Ok(())
+}
+
+fn read_id_map<K, V>(path: &Path) -> Result<HashMap<K, V>>
+where
+    K: DeserializeOwned + Eq + Hash,
+    V: DeserializeOwned,
+{
+    serde_json::from_str(
+        &std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?,
+    )
+    .with_context(|| format!("Failed to parse {:?}", path))
+}
+
+// the accounts standing in for original addresses, the clanker deployer for now
+pub(crate) fn write_address_map(
+    path: &Path,
+    address_map: &HashMap<Address, Address>,
+) -> Result<()> {
+    write_id_map(path, address_map)
+}
+
+pub(crate) fn read_address_map(path: &Path) -> Result<HashMap<Address, Address>> {
+    read_id_map(path)
+}
+
+// every original token id seen so far and the simulated one minted for it
+pub(crate) fn write_token_id_map(path: &Path, token_id_map: &HashMap<U256, U256>) -> Result<()> {
+    write_id_map(path, token_id_map)
+}
+
+pub(crate) fn read_token_id_map(path: &Path) -> Result<HashMap<U256, U256>> {
+    read_id_map(path)
+}
+
+// per owner totals over the position records that held liquidity
+pub(crate) struct OwnerSummary {
+    // (owner, records, weth fees earned, net pnl in weth), sorted by owner
+    owners: Vec<(Address, usize, U256, I256)>,
+}
+
+impl OwnerSummary {
+    pub(crate) fn new<'a>(positions: impl Iterator<Item = &'a PositionInfo>) -> Self {
+        let mut totals: HashMap<Address, (usize, U256, I256)> = HashMap::new();
+        for position in positions.filter(|p| p.liquidity_in > 0) {
+            let entry = totals
+                .entry(position.owner)
+                .or_insert((0, U256::ZERO, I256::ZERO));
+            entry.0 += 1;
+            entry.1 += position.fees_earned_weth;
+            entry.2 += position.end_weth_gain_converted;
+        }
+
+        let mut owners: Vec<_> = totals
+            .into_iter()
+            .map(|(owner, (records, fees, pnl))| (owner, records, fees, pnl))
+            .collect();
+        owners.sort_by_key(|(owner, ..)| *owner);
+        Self { owners }
+    }
+}
+
+impl fmt::Display for OwnerSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\nPnL by Owner:")?;
+        for (i, (owner, records, fees, pnl)) in self.owners.iter().enumerate() {
+            let branch = if i + 1 == self.owners.len() {
+                "└─"
+            } else {
+                "├─"
+            };
+            write!(
+                f,
+                "\n{} {}: {} records, weth fees {}, net pnl in weth {}",
+                branch, owner, records, fees, pnl
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// one owner's position chains at one tick range, joined across full exits.
+// a token id that exited on chain and a later mint at the same range by the
+// same owner are taken for the same strategy, a token id minted while another
+// is still open starts a strategy of its own
+pub(crate) struct Strategy {
+    // numbered from 1 in order of the first mint
+    pub strategy_id: u64,
+    pub owner: Address,
+    pub lower_tick: I24,
+    pub upper_tick: I24,
+    // original token ids in mint order
+    pub token_ids: Vec<U256>,
+    pub records: usize,
+    pub block_in: u64,
+    pub block_out: u64,
+    pub fees_earned_token: U256,
+    pub fees_earned_weth: U256,
+    pub net_pnl_in_weth: I256,
+}
+
+// groups the records that held liquidity into strategies, records whose close
+// out failed are left out like in the other summaries
+pub(crate) fn merge_reopened_positions(positions: &[PositionInfo]) -> Vec<Strategy> {
+    let mut chains: BTreeMap<U256, Vec<&PositionInfo>> = BTreeMap::new();
+    for position in positions
+        .iter()
+        .filter(|p| p.liquidity_in > 0 && p.close_out_error.is_none())
+    {
+        chains
+            .entry(position.original_token_id)
+            .or_default()
+            .push(position);
+    }
+    let mut chains: Vec<Vec<&PositionInfo>> = chains
+        .into_values()
+        .map(|mut records| {
+            records.sort_by_key(|record| record.index);
+            records
+        })
+        .collect();
+    chains.sort_by_key(|records| {
+        let first = records[0];
+        (
+            first.owner,
+            first.lower_tick,
+            first.upper_tick,
+            first.block_in,
+            first.original_token_id,
+        )
+    });
+
+    let mut strategies: Vec<Strategy> = Vec::new();
+    // whether the last chain joined to each strategy exited on chain, and when
+    let mut exits: Vec<Option<u64>> = Vec::new();
+    for records in chains {
+        let first = records[0];
+        let last = records[records.len() - 1];
+        let reopens = strategies
+            .last()
+            .zip(exits.last())
+            .is_some_and(|(strategy, exit)| {
+                strategy.owner == first.owner
+                    && strategy.lower_tick == first.lower_tick
+                    && strategy.upper_tick == first.upper_tick
+                    && exit.is_some_and(|block_out| block_out <= first.block_in)
+            });
+        if !reopens {
+            strategies.push(Strategy {
+                strategy_id: 0,
+                owner: first.owner,
+                lower_tick: first.lower_tick,
+                upper_tick: first.upper_tick,
+                token_ids: Vec::new(),
+                records: 0,
+                block_in: first.block_in,
+                block_out: last.block_out,
+                fees_earned_token: U256::ZERO,
+                fees_earned_weth: U256::ZERO,
+                net_pnl_in_weth: I256::ZERO,
+            });
+            exits.push(None);
+        }
+        let strategy = strategies.last_mut().unwrap();
+        strategy.token_ids.push(first.original_token_id);
+        strategy.records += records.len();
+        strategy.block_out = last.block_out;
+        for record in &records {
+            strategy.fees_earned_token += record.fees_earned_token;
+            strategy.fees_earned_weth += record.fees_earned_weth;
+            strategy.net_pnl_in_weth += record.end_weth_gain_converted;
+        }
+        *exits.last_mut().unwrap() =
+            (last.close_reason == Some(CloseReason::OnChainFullExit)).then_some(last.block_out);
+    }
+
+    strategies.sort_by_key(|strategy| (strategy.block_in, strategy.token_ids[0]));
+    for (i, strategy) in strategies.iter_mut().enumerate() {
+        strategy.strategy_id = i as u64 + 1;
+    }
+    strategies
+}
+
+// distribution of net pnl in weth over the position records that held
+// liquidity, bucketed by the configured boundaries
+pub(crate) struct PnlDistribution {
+    pub records: usize,
+    // records left out as dust, not part of any of the fields below
+    pub dust_records: usize,
+    // boundaries.len() + 1 counts, bucket i holds pnl in
+    // [boundaries[i - 1], boundaries[i]) with the outer buckets unbounded
+    pub boundaries: Vec<I256>,
+    pub counts: Vec<usize>,
+    // nearest rank percentiles, None without records
+    pub p10: Option<I256>,
+    pub p50: Option<I256>,
+    pub p90: Option<I256>,
+}
+
+impl PnlDistribution {
+    pub(crate) fn new<'a>(
+        positions: impl Iterator<Item = &'a PositionInfo>,
+        boundaries: &[I256],
+        dust_threshold_weth: U256,
+    ) -> Self {
+        let (dust, positions): (Vec<_>, Vec<_>) = positions
+            .filter(|p| p.liquidity_in > 0)
+            .partition(|p| p.is_dust(dust_threshold_weth));
+        Self {
+            dust_records: dust.len(),
+            ..Self::from_pnls(
+                positions
+                    .iter()
+                    .map(|p| p.end_weth_gain_converted)
+                    .collect(),
+                boundaries,
+            )
+        }
+    }
+
+    fn from_pnls(mut pnls: Vec<I256>, boundaries: &[I256]) -> Self {
+        pnls.sort();
+
+        let mut counts = vec![0; boundaries.len() + 1];
+        for pnl in &pnls {
+            counts[boundaries.partition_point(|boundary| boundary <= pnl)] += 1;
+        }
+
+        let percentile = |p: usize| {
+            // smallest value with at least p percent of the records at or below it
+            let rank = (p * pnls.len()).div_ceil(100).max(1);
+            pnls.get(rank - 1).copied()
+        };
+        Self {
+            records: pnls.len(),
+            dust_records: 0,
+            boundaries: boundaries.to_vec(),
+            counts,
+            p10: percentile(10),
+            p50: percentile(50),
+            p90: percentile(90),
+        }
+    }
+
+    // the bucket's label, `[lower, upper)` with `..` for an unbounded side
+    fn bucket_label(&self, bucket: usize) -> String {
+        let lower = bucket
+            .checked_sub(1)
+            .map_or("..".to_string(), |i| self.boundaries[i].to_string());
+        let upper = self
+            .boundaries
+            .get(bucket)
+            .map_or("..".to_string(), |boundary| boundary.to_string());
+        format!("[{}, {})", lower, upper)
+    }
+}
+
+impl fmt::Display for PnlDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const BAR_WIDTH: usize = 40;
+        let labels: Vec<String> = (0..self.counts.len())
+            .map(|bucket| self.bucket_label(bucket))
+            .collect();
+        let label_width = labels.iter().map(String::len).max().unwrap_or(0);
+        let max_count = self.counts.iter().copied().max().unwrap_or(0).max(1);
+
+        write!(
+            f,
+            "\nNet PnL in WETH wei ({} records, {} dust left out):",
+            self.records, self.dust_records
+        )?;
+        for (label, count) in labels.iter().zip(&self.counts) {
+            write!(
+                f,
+                "\n{:>width$} {:<bar_width$} {}",
+                label,
+                "#".repeat(count * BAR_WIDTH / max_count),
+                count,
+                width = label_width,
+                bar_width = BAR_WIDTH
+            )?;
+        }
+        let show = |p: Option<I256>| p.map_or("-".to_string(), |p| p.to_string());
+        write!(
+            f,
+            "\np10 {}, p50 {}, p90 {}",
+            show(self.p10),
+            show(self.p50),
+            show(self.p90)
+        )
+    }
+}
+
+// fills in the fields derived from the pool samples taken while the record was
+// open: average pool liquidity, in range volume, and fees relative to it.
+// records closed out at the end of the run have a block out of 0.
+fn annotate_from_samples(position_info: &mut PositionInfo, samples: &[LiquiditySample]) {
+    let block_out = if position_info.block_out == 0 {
+        u64::MAX
+    } else {
+        position_info.block_out
+    };
+    let samples: Vec<&LiquiditySample> = samples
+        .iter()
+        .filter(|s| s.block >= position_info.block_in && s.block <= block_out)
+        .collect();
+
+    if !samples.is_empty() {
+        position_info.avg_pool_liquidity =
+            samples.iter().map(|s| s.liquidity).sum::<u128>() / samples.len() as u128;
+    }
+
+    // swaps that started or ended inside the position's range count
+    // towards the volume it could earn fees on
+    let in_range = |tick: I24| tick >= position_info.lower_tick && tick < position_info.upper_tick;
+    position_info.volume_in_range_weth = samples
+        .iter()
+        .filter(|s| in_range(s.tick_before) || in_range(s.tick))
+        .map(|s| s.weth_volume)
+        .sum();
+    if position_info.volume_in_range_weth > U256::ZERO {
+        position_info.fees_to_volume = f64::from(position_info.fees_earned_weth)
+            / f64::from(position_info.volume_in_range_weth);
+    }
+}
+
+// the record of a position's records that was open after `block`. a change
+// closes a record and opens the next in the same block, so it's the latest
+// one opened by then, unless that one was also closed by then
+fn record_open_after(records: &[PositionInfo], block: u64) -> Option<&PositionInfo> {
+    records
+        .iter()
+        .rev()
+        .find(|record| record.block_in <= block)
+        .filter(|record| !(record.closed && record.block_out <= block))
+}
+
+// the end of run close out of the records still open
+struct CloseOut {
+    // (token id, record index) of every record closed out, in the order
+    // they were closed out
+    closed_out: Vec<(U256, usize)>,
+    // gas used by the close out collects
+    collect_gas: u64,
+    // a warning detail per record whose close out failed
+    failures: Vec<String>,
+}
+
+// closes out the open record of every token id, in token id then record
+// order so runs over the same events close out and write rows in the same
+// order. the collects change the fork so they run one at a time, the
+// valuations after them don't and run up to `concurrency` at once. one
+// record failing to close out shouldn't cost the rest of the run's output,
+// it's marked closed with the error, written as is, and left out of the end
+// of run summaries
+async fn close_out_open_positions<B, V>(
+    backend: &B,
+    valuer: &V,
+    pool_config: &PoolConfig,
+    minter: Address,
+    fee_recipient: Address,
+    concurrency: usize,
+    position_info: &mut HashMap<U256, Vec<PositionInfo>>,
+) -> Result<CloseOut>
+where
+    B: PoolBackend + Clone + Send + Sync + 'static,
+    V: TokenValuation + Clone + Send + Sync + 'static,
+{
+    let mut token_ids: Vec<U256> = position_info.keys().copied().collect();
+    token_ids.sort();
+    let mut close_out = CloseOut {
+        closed_out: Vec::new(),
+        collect_gas: 0,
+        failures: Vec::new(),
+    };
+    let mut collected = Vec::new();
+    for token_id in &token_ids {
+        let position_infos = position_info.get_mut(token_id).unwrap();
+        position_infos.sort_by_key(|position_info| position_info.index);
+        let mut open = position_infos
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, position_info)| !position_info.closed);
+        let Some((index, position_info)) = open.next() else {
+            continue;
+        };
+        if open.next().is_some() {
+            bail!("Multiple positions found for token id: {}", token_id);
+        }
+        info!("closing position: ---");
+        close_out.closed_out.push((*token_id, index));
+        match pool_close_out_collect(
+            backend,
+            pool_config,
+            minter,
+            fee_recipient,
+            *token_id,
+            position_info,
+            0,
+        )
+        .await
+        {
+            Ok(()) => {
+                close_out.collect_gas += position_info.gas_used_out;
+                collected.push((*token_id, index));
+            }
+            Err(e) => close_out
+                .failures
+                .push(mark_close_out_failed(*token_id, position_info, e)),
+        }
+    }
+
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let mut tasks = JoinSet::new();
+    for (token_id, index) in collected {
+        let semaphore = semaphore.clone();
+        let backend = backend.clone();
+        let valuer = valuer.clone();
+        let pool_config = pool_config.clone();
+        let mut position_info = position_info[&token_id][index].clone();
+        tasks.spawn(async move {
+            let valued = async {
+                let _permit = semaphore.acquire_owned().await?;
+                pool_close_out_value(
+                    &backend,
+                    &valuer,
+                    &pool_config,
+                    minter,
+                    token_id,
+                    &mut position_info,
+                )
+                .await?;
+                Ok::<_, eyre::Report>(position_info)
+            }
+            .await;
+            (token_id, index, valued)
+        });
+    }
+    // valuations finish in any order, their failures are reported in token
+    // id order like the collects'
+    let mut valuation_failures = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        let (token_id, index, valued) = joined?;
+        let record = &mut position_info.get_mut(&token_id).unwrap()[index];
+        match valued {
+            Ok(valued) => *record = valued,
+            Err(e) => {
+                valuation_failures.push((token_id, mark_close_out_failed(token_id, record, e)))
+            }
+        }
+    }
+    valuation_failures.sort();
+    close_out
+        .failures
+        .extend(valuation_failures.into_iter().map(|(_, detail)| detail));
+    Ok(close_out)
+}
+
+// marks the record closed with the error, returns the warning detail
+fn mark_close_out_failed(
+    token_id: U256,
+    position_info: &mut PositionInfo,
+    error: eyre::Report,
+) -> String {
+    error!("Failed to close out token id {}: {:?}", token_id, error);
+    position_info.closed = true;
+    position_info.close_reason = Some(CloseReason::CloseOutFailed);
+    position_info.close_out_error = Some(format!("{:#}", error));
+    format!(
+        "token id {} failed to close out: {:#}",
+        position_info.original_token_id, error
+    )
+}
+
+// the weth value at spot of a position's records after original block
+// `block`: the tokens the record open then held at the simulated pool's price
+// after that block, read from the fork's history through `fork_blocks`, the
+// fork block each replayed original block ended at. uncollected fees aren't
+// included. zero when no record was open
+async fn value_records_at(
+    backend: &impl PoolBackend,
+    pool_config: &PoolConfig,
+    records: &[PositionInfo],
+    fork_blocks: &BTreeMap<u64, u64>,
+    block: u64,
+) -> Result<U256> {
+    let (_, fork_block) = fork_blocks
+        .range(..=block)
+        .next_back()
+        .with_context(|| format!("Block {} is before the replayed events", block))?;
+    let Some(record) = record_open_after(records, block) else {
+        return Ok(U256::ZERO);
+    };
+
+    let (sqrt_price_x96, _) = backend
+        .slot0_at(*fork_block)
+        .await
+        .with_context(|| format!("Failed to read the pool at fork block {}", fork_block))?;
+    let (token, weth) = record.amounts_at(sqrt_price_x96, pool_config.clanker_is_token0());
+    value_in_weth(&SpotValuation { sqrt_price_x96 }, pool_config, token, weth).await
+}
+
+// the `top_n` records earning the most weth fees per weth-block, best first.
+// records without a holding time or a starting value aren't ranked
+fn rank_by_fees_per_weth_block<'a>(
+    positions: impl Iterator<Item = &'a PositionInfo>,
+    top_n: usize,
+) -> Vec<&'a PositionInfo> {
+    let mut ranked: Vec<&PositionInfo> = positions
+        .filter(|p| p.holding_blocks > 0 && p.approx_starting_weth > U256::ZERO)
+        .collect();
+    ranked.sort_by(|a, b| {
+        b.fees_per_weth_block
+            .total_cmp(&a.fees_per_weth_block)
+            .then((a.original_token_id, a.index).cmp(&(b.original_token_id, b.index)))
+    });
+    ranked.truncate(top_n);
+    ranked
+}
+
+const SECONDS_PER_YEAR: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+// the record's fees against what it started with and how long it was held:
+// annualized per side, and weth fees per weth-block. a record without a
+// holding time or a starting value keeps zeros
+fn annotate_fee_yields(
+    position_info: &mut PositionInfo,
+    block_clock: &BlockClock,
+    token_is_token0: bool,
+) {
+    position_info.holding_blocks =
+        block_clock.blocks_between(position_info.block_in, position_info.block_out);
+    if position_info.holding_blocks > 0 && position_info.approx_starting_weth > U256::ZERO {
+        position_info.fees_per_weth_block = f64::from(position_info.fees_earned_weth)
+            / (f64::from(position_info.approx_starting_weth) * position_info.holding_blocks as f64);
+    }
+
+    let Some(holding_seconds) =
+        block_clock.seconds_between(position_info.block_in, position_info.block_out)
+    else {
+        return;
+    };
+    position_info.holding_seconds = holding_seconds;
+    if position_info.approx_starting_weth == U256::ZERO {
+        return;
+    }
+    let token_fees_in_weth = spot_value(
+        position_info.fees_earned_token,
+        position_info.sqrt_price_limit_x96_out,
+        token_is_token0,
+    );
+    let years = holding_seconds as f64 / SECONDS_PER_YEAR;
+    let starting_weth = f64::from(position_info.approx_starting_weth);
+    let apr = |fees: U256| f64::from(fees) / starting_weth / years;
+    position_info.token_fee_apr = apr(token_fees_in_weth);
+    position_info.weth_fee_apr = apr(position_info.fees_earned_weth);
+    position_info.fee_apr_in_weth = position_info.token_fee_apr + position_info.weth_fee_apr;
+}
+
+// runs independent pool simulations concurrently, each on its own task with its
+// own anvil fork, with at most `max_concurrency` running at once. every pool is
+// run to completion, failures are logged and the first one is returned.
+pub async fn run_many(
+    configs: Vec<PoolAnalyzerConfig>,
+    max_concurrency: usize,
+) -> Result<Vec<Vec<PositionInfo>>> {
+    run_concurrently(configs, max_concurrency, |pool_index, config| async move {
+        info!("Starting pool simulation {}", pool_index);
+        let verify_against = config.verify_against.clone();
+        let mut pool_analyzer = PoolAnalyzer::initialize(config).await?;
+        let positions = match verify_against {
+            Some(expected_csv) => pool_analyzer.verify_against(&expected_csv).await?,
+            None => pool_analyzer.run_simulation().await?,
+        };
+        info!(
+            "Pool simulation {} finished with {} warnings",
+            pool_index,
+            pool_analyzer.warnings().len()
+        );
+        Ok(positions)
+    })
+    .await
+}
+
+// runs `run` on each item on its own task, at most `max_concurrency` at once,
+// and returns the results in the items' order
+async fn run_concurrently<T, R, F, Fut>(
+    items: Vec<T>,
+    max_concurrency: usize,
+    run: F,
+) -> Result<Vec<R>>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(usize, T) -> Fut,
+    Fut: Future<Output = Result<R>> + Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(max_concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+
+    for (index, item) in items.into_iter().enumerate() {
+        let semaphore = semaphore.clone();
+        let task = run(index, item);
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            Ok::<_, eyre::Report>((index, task.await?))
+        });
+    }
+
+    let mut results = Vec::new();
+    let mut first_error = None;
+    while let Some(joined) = tasks.join_next().await {
+        match joined.map_err(eyre::Report::from).and_then(|result| result) {
+            Ok(result) => results.push(result),
+            Err(e) => {
+                error!("Pool simulation failed: {:?}", e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    if let Some(e) = first_error {
+        return Err(e);
+    }
+
+    // return results in the order the items were given
+    results.sort_by_key(|(index, _)| *index);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+// replays every pool in the input csvs. a single pool runs exactly like
+// before, several pools (like two fee tiers of the same pair) are each replayed
+// on their own fork into `<output>.<pool address>.csv`, followed by a combined
+// per pool summary logged and written to `<output>.pools.csv`
+pub async fn run_pools(config: PoolAnalyzerConfig, max_concurrency: usize) -> Result<()> {
+    if max_concurrency == 0 {
+        bail!("POOL_CONCURRENCY must be at least 1");
+    }
+    if config.pool_address.is_some() {
+        run_many(vec![config], 1).await?;
+        return Ok(());
+    }
+
+    let events = pool_events(config.config.clone())
+        .await
+        .context("Failed to get pool events from CSV")?;
+    let pools: Vec<PoolKey> = split_events_by_pool(events)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    if pools.len() <= 1 {
+        run_many(vec![config], 1).await?;
+        return Ok(());
+    }
+    if config.resume_path.is_some() || config.token_id_map_path.is_some() {
+        bail!("Resuming needs a single pool, set POOL_ADDRESS to pick one");
+    }
+    if config
+        .verify_against
+        .as_ref()
+        .is_some_and(|path| !path.to_string_lossy().contains("{pool_address}"))
+    {
+        bail!("VERIFY_AGAINST needs `{{pool_address}}` in the path to verify several pools");
+    }
+
+    info!("Replaying {} pools", pools.len());
+    // a template naming the pool already keeps the outputs apart
+    let templated = config.output_csv_file_path.contains("{pool_address}");
+    let configs = pools
+        .iter()
+        .map(|key| PoolAnalyzerConfig {
+            pool_address: Some(key.pool),
+            verify_against: config
+                .verify_against
+                .as_ref()
+                .map(|path| expand_output_path(&path.to_string_lossy(), Some(key)).into()),
+            output_csv_file_path: if templated {
+                config.output_csv_file_path.clone()
+            } else {
+                Path::new(&config.output_csv_file_path)
+                    .with_extension(format!("{}.csv", key.pool))
+                    .to_string_lossy()
+                    .into_owned()
+            },
+            ..config.clone()
+        })
+        .collect::<Vec<_>>();
+    let output_paths: Vec<String> = configs
+        .iter()
+        .zip(&pools)
+        .map(|(config, key)| expand_output_path(&config.output_csv_file_path, Some(key)))
+        .collect();
+
+    let results = run_many(configs, max_concurrency).await?;
+
+    let summary = PoolSummary::new(
+        pools.into_iter().zip(output_paths).zip(results),
+        config.dust_threshold_weth,
+    );
+    info!("{}", summary);
+    let output_path = expand_output_path(&config.output_csv_file_path, None);
+    write_pool_summary_to_csv(&summary, Path::new(&output_path))
+        .map_err(|e| eyre!("Failed to write pool summary: {}", e))?;
+    Ok(())
+}
+
+// fills in the pool's `{pool_address}` and `{fee_tier}` in an output path,
+// or `all` for both when the file covers every pool of a run
+pub(crate) fn expand_output_path(template: &str, pool: Option<&PoolKey>) -> String {
+    let (pool_address, fee_tier) = match pool {
+        Some(key) => (key.pool.to_string(), key.fee.to_string()),
+        None => ("all".to_string(), "all".to_string()),
+    };
+    template
+        .replace("{pool_address}", &pool_address)
+        .replace("{fee_tier}", &fee_tier)
+}
+
+// per pool totals over the position records that held liquidity, for
+// comparing pools replayed in the same run
+pub(crate) struct PoolSummary {
+    pub pools: Vec<PoolTotals>,
+}
+
+pub(crate) struct PoolTotals {
+    pub key: PoolKey,
+    pub output_csv: String,
+    pub records: usize,
+    pub fees_earned_weth: U256,
+    pub net_pnl_in_weth: I256,
+}
+
+impl PoolSummary {
+    fn new(
+        pools: impl Iterator<Item = ((PoolKey, String), Vec<PositionInfo>)>,
+        dust_threshold_weth: U256,
+    ) -> Self {
+        let pools = pools
+            .map(|((key, output_csv), positions)| {
+                let held_liquidity = || {
+                    positions
+                        .iter()
+                        .filter(|p| p.liquidity_in > 0 && !p.is_dust(dust_threshold_weth))
+                        .filter(|p| p.close_out_error.is_none())
+                };
+                PoolTotals {
+                    key,
+                    output_csv,
+                    records: held_liquidity().count(),
+                    fees_earned_weth: held_liquidity().map(|p| p.fees_earned_weth).sum(),
+                    net_pnl_in_weth: held_liquidity().map(|p| p.end_weth_gain_converted).sum(),
+                }
+            })
+            .collect();
+        Self { pools }
+    }
+}
+
+impl fmt::Display for PoolSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\nPnL by Pool:")?;
+        for (i, totals) in self.pools.iter().enumerate() {
+            let branch = if i + 1 == self.pools.len() {
+                "└─"
+            } else {
+                "├─"
+            };
+            write!(
+                f,
+                "\n{} {}: {} records, weth fees {}, net pnl in weth {}",
+                branch, totals.key, totals.records, totals.fees_earned_weth, totals.net_pnl_in_weth
+            )?;
+        }
         Ok(())
     }
 }
+
+// reads and normalizes the input csvs the way a run does, deduplicated and
+// sorted, and writes them back out to `dir` for reuse as input
+pub async fn export_events(config: CSVReaderConfig, dir: &Path) -> Result<()> {
+    let events = pool_events(config)
+        .await
+        .context("Failed to get pool events from CSV")?;
+    write_events_to_csv(&events, dir).context("Failed to export events")?;
+    Ok(())
+}
+
+// logs how many events of each type the input csvs hold and the block range they
+// cover, without connecting to anything
+pub async fn summarize_events(config: CSVReaderConfig) -> Result<()> {
+    let events = pool_events(config)
+        .await
+        .context("Failed to get pool events from CSV")?;
+
+    let mut counts: Vec<(EventType, usize)> = Vec::new();
+    for event in &events {
+        let event_type = event.event.event_type();
+        match counts.iter_mut().find(|(t, _)| *t == event_type) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((event_type, 1)),
+        }
+    }
+
+    info!("{} events", events.len());
+    if let (Some(first), Some(last)) = (events.first(), events.last()) {
+        info!("blocks {} to {}", first.block, last.block);
+    }
+    for (event_type, count) in counts {
+        info!("{:?}: {}", event_type, count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use alloy::primitives::aliases::{U24, U96};
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::chain_interactions::backend::mock::{self, pool_config, MockBackend, MockValuer};
+
+    // runs two trivial pools that each hold for a while, returning the most
+    // that ran at once
+    async fn peak_concurrency(max_concurrency: usize) -> usize {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let results = run_concurrently(vec!["a", "b"], max_concurrency, |index, pool| {
+            let running = running.clone();
+            let peak = peak.clone();
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(format!("{}{}", pool, index))
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(results, ["a0", "b1"]);
+        peak.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn pools_run_concurrently_up_to_the_limit() {
+        assert_eq!(peak_concurrency(2).await, 2);
+        assert_eq!(peak_concurrency(1).await, 1);
+    }
+
+    #[tokio::test]
+    async fn a_failed_pool_fails_the_run_after_the_rest_finish() {
+        let finished = Arc::new(AtomicUsize::new(0));
+        let error = run_concurrently(vec![true, false], 2, |_, fails| {
+            let finished = finished.clone();
+            async move {
+                if fails {
+                    bail!("pool failed");
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "pool failed");
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn address_and_token_id_maps_round_trip() {
+        let dir = std::env::temp_dir();
+        let address_map_path = dir.join(format!("address_map_{}.json", std::process::id()));
+        let token_id_map_path = dir.join(format!("token_id_map_{}.json", std::process::id()));
+        let address_map = HashMap::from([
+            (Address::repeat_byte(0x11), Address::repeat_byte(0x22)),
+            (Address::repeat_byte(0x33), Address::repeat_byte(0x44)),
+        ]);
+        // original ids are arbitrary, the simulated ones count up from 1
+        let token_id_map = HashMap::from([
+            (U256::from(1_487_610), U256::from(1)),
+            (U256::MAX, U256::from(2)),
+        ]);
+
+        write_address_map(&address_map_path, &address_map).unwrap();
+        write_token_id_map(&token_id_map_path, &token_id_map).unwrap();
+        let read_addresses = read_address_map(&address_map_path);
+        let read_token_ids = read_token_id_map(&token_id_map_path);
+        std::fs::remove_file(&address_map_path).unwrap();
+        std::fs::remove_file(&token_id_map_path).unwrap();
+
+        assert_eq!(read_addresses.unwrap(), address_map);
+        assert_eq!(read_token_ids.unwrap(), token_id_map);
+    }
+
+    const ORIGINAL_TOKEN_ID: U256 = U256::from_limbs([1_487_610, 0, 0, 0]);
+    const SIMULATED_TOKEN_ID: U256 = U256::from_limbs([7, 0, 0, 0]);
+
+    fn resume_fork() -> ResumeFork {
+        ResumeFork {
+            anvil_endpoint: "http://localhost:8545".to_string(),
+            block: 120,
+            pool: Address::repeat_byte(0xbb),
+            deployer: Address::repeat_byte(0x55),
+            swap_account: Address::repeat_byte(0x66),
+            mint_account: Address::repeat_byte(0x33),
+        }
+    }
+
+    // writes a resume state holding `position` and reads it back
+    fn resume_round_trip(position: &PositionInfo) -> ResumeState {
+        let path = std::env::temp_dir().join(format!(
+            "resume_{}_{}.json",
+            std::process::id(),
+            position.index
+        ));
+        write_resume_state(
+            &path,
+            &HashMap::from([(ORIGINAL_TOKEN_ID, SIMULATED_TOKEN_ID)]),
+            &HashMap::from([(SIMULATED_TOKEN_ID, vec![position.clone()])]),
+            Some(resume_fork()),
+        )
+        .unwrap();
+        let resume_state = read_resume_state(&path);
+        std::fs::remove_file(&path).unwrap();
+        resume_state.unwrap()
+    }
+
+    // what the position manager reports for `position`
+    fn on_fork(position: &PositionInfo) -> INonfungiblePositionManager::positionsReturn {
+        let (token0, token1) = pool_config(true).tokens();
+        INonfungiblePositionManager::positionsReturn {
+            nonce: U96::ZERO,
+            operator: Address::ZERO,
+            token0,
+            token1,
+            fee: pool_config(true).fee(),
+            tickLower: position.lower_tick,
+            tickUpper: position.upper_tick,
+            liquidity: position.liquidity_in,
+            feeGrowthInside0LastX128: U256::ZERO,
+            feeGrowthInside1LastX128: U256::ZERO,
+            tokensOwed0: 0,
+            tokensOwed1: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_state_records_the_fork_to_resume_on() {
+        let backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let position = mock::open_position(&backend, &valuer, SIMULATED_TOKEN_ID).await;
+
+        let resume_state = resume_round_trip(&position);
+        assert_eq!(resume_state.fork, Some(resume_fork()));
+        let (token_id_map, position_info) = seed_resumed_positions(resume_state);
+        assert_eq!(token_id_map[&ORIGINAL_TOKEN_ID], SIMULATED_TOKEN_ID);
+        assert_eq!(position_info[&SIMULATED_TOKEN_ID].len(), 1);
+
+        // a state written without a kept-alive fork has nothing to attach to
+        let resume_state: ResumeState =
+            serde_json::from_str(r#"{"token_id_map": [], "open_positions": []}"#).unwrap();
+        assert_eq!(resume_state.fork, None);
+    }
+
+    #[tokio::test]
+    async fn resumed_positions_have_to_match_the_fork() {
+        let backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let position = mock::open_position(&backend, &valuer, SIMULATED_TOKEN_ID).await;
+        check_resumed_position(&position, &pool_config(true), &on_fork(&position)).unwrap();
+
+        let mut other_ticks = on_fork(&position);
+        other_ticks.tickUpper = I24::try_from(1200).unwrap();
+        let mut other_pool = on_fork(&position);
+        other_pool.token0 = Address::repeat_byte(0x99);
+        let mut other_liquidity = on_fork(&position);
+        other_liquidity.liquidity = 999;
+        for on_fork in [other_ticks, other_pool, other_liquidity] {
+            let error =
+                check_resumed_position(&position, &pool_config(true), &on_fork).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<FeeAnalyzerError>(),
+                Some(FeeAnalyzerError::PoolSetup(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn resumed_accounts_keep_the_earlier_runs() {
+        let resumed = Address::repeat_byte(0x33);
+        assert_eq!(
+            resumed_account("MINT_ACCOUNT", None, resumed).unwrap(),
+            resumed
+        );
+        assert_eq!(
+            resumed_account("MINT_ACCOUNT", Some(resumed), resumed).unwrap(),
+            resumed
+        );
+        assert!(resumed_account("MINT_ACCOUNT", Some(Address::ZERO), resumed).is_err());
+    }
+
+    #[tokio::test]
+    async fn the_run_deadline_lets_a_slow_call_finish() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let mut position = mock::open_position(&backend, &valuer, SIMULATED_TOKEN_ID).await;
+        backend.collect = (U256::from(1), U256::from(2));
+        backend.collect_delay = Duration::from_millis(100);
+
+        let run_timeout = Duration::from_millis(20);
+        let deadline = RunDeadline::starting_now(Some(run_timeout));
+        assert_eq!(deadline.passed(), None);
+        // the call in flight runs past the deadline to completion, the replay
+        // only stops before the next event
+        pool_poke_collect(
+            &backend,
+            &pool_config(true),
+            Address::repeat_byte(0x33),
+            Address::repeat_byte(0x33),
+            SIMULATED_TOKEN_ID,
+            &mut position,
+        )
+        .await
+        .unwrap();
+        assert_eq!(backend.collects(), 1);
+        assert_eq!(position.fees_earned_weth, U256::from(2));
+        assert_eq!(deadline.passed(), Some(run_timeout));
+
+        assert_eq!(RunDeadline::default().passed(), None);
+    }
+
+    #[tokio::test]
+    async fn only_never_closed_records_are_forced_closed_at_the_end() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        // token id 1 exited on chain and was reopened, token id 2 never closed
+        let mut exited = mock::open_position(&backend, &valuer, U256::from(1)).await;
+        exited.closed = true;
+        exited.close_reason = Some(CloseReason::OnChainFullExit);
+        let mut reopened = mock::open_position(&backend, &valuer, U256::from(1)).await;
+        reopened.index = 1;
+        let never_closed = mock::open_position(&backend, &valuer, U256::from(2)).await;
+        assert_eq!(never_closed.close_reason, None);
+        let mut position_info = HashMap::from([
+            (U256::from(1), vec![exited, reopened]),
+            (U256::from(2), vec![never_closed]),
+        ]);
+        backend.collect = (U256::from(1), U256::from(2));
+        backend.decrease_quote = Some((U256::from(500), U256::from(300)));
+
+        let close_out = close_out_open_positions(
+            &backend,
+            &valuer,
+            &pool_config(true),
+            Address::repeat_byte(0x33),
+            Address::repeat_byte(0x33),
+            1,
+            &mut position_info,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            close_out.closed_out,
+            vec![(U256::from(1), 1), (U256::from(2), 0)]
+        );
+        assert!(close_out.failures.is_empty());
+        let reasons = |token_id: u64| {
+            position_info[&U256::from(token_id)]
+                .iter()
+                .map(|position| (position.closed, position.close_reason))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            reasons(1),
+            [
+                (true, Some(CloseReason::OnChainFullExit)),
+                (true, Some(CloseReason::ForcedAtSimEnd))
+            ]
+        );
+        assert_eq!(reasons(2), [(true, Some(CloseReason::ForcedAtSimEnd))]);
+    }
+
+    #[tokio::test]
+    async fn a_failing_close_out_still_writes_the_other_records() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let mut position_info = HashMap::new();
+        for token_id in 1..=3u64 {
+            let token_id = U256::from(token_id);
+            let position = mock::open_position(&backend, &valuer, token_id).await;
+            position_info.insert(token_id, vec![position]);
+        }
+        backend.collect = (U256::from(1), U256::from(2));
+        backend.decrease_quote = Some((U256::from(500), U256::from(300)));
+        // token id 2's valuation and token id 3's collect revert
+        backend.failing_quotes = vec![U256::from(2)];
+        backend.failing_collects = vec![U256::from(3)];
+
+        let close_out = close_out_open_positions(
+            &backend,
+            &valuer,
+            &pool_config(true),
+            Address::repeat_byte(0x33),
+            Address::repeat_byte(0x33),
+            2,
+            &mut position_info,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            close_out.closed_out,
+            vec![(U256::from(1), 0), (U256::from(2), 0), (U256::from(3), 0)]
+        );
+        assert_eq!(close_out.failures.len(), 2);
+        assert!(close_out.failures[0].starts_with("token id 3 failed to close out"));
+        assert!(close_out.failures[1].starts_with("token id 2 failed to close out"));
+        assert_eq!(backend.collects(), 2);
+
+        let closed = &position_info[&U256::from(1)][0];
+        assert!(closed.closed);
+        assert_eq!(closed.close_reason, Some(CloseReason::ForcedAtSimEnd));
+        assert_eq!(closed.close_out_error, None);
+        for token_id in [2u64, 3] {
+            let failed = &position_info[&U256::from(token_id)][0];
+            assert!(failed.closed);
+            assert_eq!(failed.close_reason, Some(CloseReason::CloseOutFailed));
+            assert!(failed.close_out_error.is_some());
+        }
+
+        let path = std::env::temp_dir().join(format!(
+            "uniswap_v3_analyze_fees_close_out_{}.csv",
+            std::process::id()
+        ));
+        let mut writer = PositionCsvWriter::create(path.to_str().unwrap(), U256::ZERO).unwrap();
+        for token_id in 1..=3u64 {
+            writer
+                .write(&position_info[&U256::from(token_id)][0])
+                .unwrap();
+        }
+        drop(writer);
+        let written = read_positions_from_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.len(), 3);
+        assert_eq!(
+            written
+                .iter()
+                .map(|position| position.close_reason)
+                .collect::<Vec<_>>(),
+            vec![
+                Some(CloseReason::ForcedAtSimEnd),
+                Some(CloseReason::CloseOutFailed),
+                Some(CloseReason::CloseOutFailed)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_close_out_matches_serial() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let mut position_info = HashMap::new();
+        for token_id in 1..=6u64 {
+            let token_id = U256::from(token_id);
+            let position = mock::open_position(&backend, &valuer, token_id).await;
+            position_info.insert(token_id, vec![position]);
+            // each record values differently so a result landing on the
+            // wrong record shows
+            backend.decrease_quotes.insert(
+                token_id,
+                (token_id * U256::from(100), token_id * U256::from(70)),
+            );
+        }
+        backend.collect = (U256::from(1), U256::from(2));
+
+        let mut closed = Vec::new();
+        for concurrency in [1, 4] {
+            let mut position_info = position_info.clone();
+            let close_out = close_out_open_positions(
+                &backend,
+                &valuer,
+                &pool_config(true),
+                Address::repeat_byte(0x33),
+                Address::repeat_byte(0x33),
+                concurrency,
+                &mut position_info,
+            )
+            .await
+            .unwrap();
+            assert!(close_out.failures.is_empty());
+            let records = close_out
+                .closed_out
+                .iter()
+                .map(|(token_id, index)| {
+                    serde_json::to_value(&position_info[token_id][*index]).unwrap()
+                })
+                .collect::<Vec<_>>();
+            closed.push((close_out.closed_out, close_out.collect_gas, records));
+        }
+        assert_eq!(closed[0], closed[1]);
+        assert_eq!(closed[0].0.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn two_runs_write_rows_in_the_same_order() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let mut positions = Vec::new();
+        for token_id in 1..=5u64 {
+            let token_id = U256::from(token_id);
+            let position = mock::open_position(&backend, &valuer, token_id).await;
+            positions.push((token_id, position));
+        }
+        backend.collect = (U256::from(1), U256::from(2));
+        backend.decrease_quote = Some((U256::from(500), U256::from(300)));
+
+        let mut runs = Vec::new();
+        for run in 0..2 {
+            // each run's map is filled in a different order, and hashes with
+            // its own random state
+            let mut position_info = HashMap::new();
+            let mut inserted = positions.clone();
+            if run == 1 {
+                inserted.reverse();
+            }
+            for (token_id, position) in inserted {
+                position_info.insert(token_id, vec![position]);
+            }
+            let close_out = close_out_open_positions(
+                &backend,
+                &valuer,
+                &pool_config(true),
+                Address::repeat_byte(0x33),
+                Address::repeat_byte(0x33),
+                2,
+                &mut position_info,
+            )
+            .await
+            .unwrap();
+
+            let path = std::env::temp_dir().join(format!(
+                "uniswap_v3_analyze_fees_row_order_{}_{}.csv",
+                std::process::id(),
+                run
+            ));
+            let mut writer = PositionCsvWriter::create(path.to_str().unwrap(), U256::ZERO).unwrap();
+            for (token_id, index) in &close_out.closed_out {
+                writer.write(&position_info[token_id][*index]).unwrap();
+            }
+            drop(writer);
+            let token_ids = read_positions_from_csv(&path)
+                .unwrap()
+                .iter()
+                .map(|position| position.original_token_id)
+                .collect::<Vec<_>>();
+            runs.push((std::fs::read_to_string(&path).unwrap(), token_ids));
+            std::fs::remove_file(&path).unwrap();
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert_eq!(runs[0].1, (1..=5u64).map(U256::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[traced_test]
+    fn info_output_leaves_out_the_event_dumps() {
+        let event = &simulation_events::one_event_of_each_type()[5];
+        log_replayed_event(7, event);
+        let dump = format!("{:?}", event);
+        logs_assert(|lines: &[&str]| {
+            if lines
+                .iter()
+                .any(|line| line.contains(" INFO ") && line.contains(&dump))
+            {
+                return Err("an event dump was logged at info".to_string());
+            }
+            if !lines
+                .iter()
+                .any(|line| line.contains("DEBUG") && line.contains("event: 7"))
+            {
+                return Err("no event counter at debug".to_string());
+            }
+            if !lines
+                .iter()
+                .any(|line| line.contains("TRACE") && line.contains(&dump))
+            {
+                return Err("no event dump at trace".to_string());
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn auto_fork_block_forks_right_before_the_first_event() {
+        let events = simulation_events::one_event_of_each_type()
+            .into_iter()
+            .map(|event| SimulationEvent {
+                block: event.block + 24_999_999,
+                ..event
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            choose_fork_block(&events, true, None, false).unwrap(),
+            24_999_999
+        );
+        // the configured block is ignored in auto mode
+        assert_eq!(
+            choose_fork_block(&events, true, Some(26_000_000), false).unwrap(),
+            24_999_999
+        );
+        // without it, the configured block is used even when it's too late
+        assert_eq!(
+            choose_fork_block(&events, false, Some(26_000_000), false).unwrap(),
+            26_000_000
+        );
+        assert!(choose_fork_block(&events, false, None, false).is_err());
+        assert!(choose_fork_block(&events, true, None, true).is_err());
+        assert!(choose_fork_block(&[], true, None, false).is_err());
+    }
+
+    #[test]
+    fn only_exact_output_swaps_are_written_to_their_csv() {
+        let events = simulation_events::one_event_of_each_type();
+        let Event::Swap(swap) = &events[5].event else {
+            panic!("expected the fixture's swap");
+        };
+        let exact_input = SimulationEvent {
+            tx_hash: TxHash::repeat_byte(0x0e),
+            ..events[5].clone()
+        };
+        let exact_output = SimulationEvent {
+            block: 4,
+            tx_hash: TxHash::repeat_byte(0x0f),
+            log_index: 7,
+            ..events[5].clone()
+        };
+        let swaps = [
+            (&exact_input, SwapDirection::ExactInput),
+            (&exact_output, SwapDirection::ExactOutput),
+        ]
+        .into_iter()
+        .filter_map(|(event, direction)| exact_output_swap(event, &swap.event, direction))
+        .collect::<Vec<_>>();
+
+        let positions_path = std::env::temp_dir().join(format!(
+            "uniswap_v3_analyze_fees_exact_output_{}.csv",
+            std::process::id()
+        ));
+        write_exact_output_swaps_to_csv(&swaps, positions_path.to_str().unwrap()).unwrap();
+        let path = positions_path.with_extension("exact_output_swaps.csv");
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        let rows = reader
+            .records()
+            .map(|record| record.unwrap())
+            .collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(&rows[0][0], "4");
+        assert_eq!(&rows[0][1], TxHash::repeat_byte(0x0f).to_string());
+        assert_eq!(&rows[0][2], "7");
+        assert_eq!(&rows[0][9], swap.event.tick.to_string());
+    }
+
+    #[test]
+    fn a_direct_pool_mint_is_tracked_under_a_synthetic_token_id() {
+        let owner = Address::repeat_byte(0x44);
+        let (lower, upper) = (I24::try_from(-600).unwrap(), I24::try_from(600).unwrap());
+        let mint = Mint {
+            sender: owner,
+            owner,
+            tickLower: lower,
+            tickUpper: upper,
+            amount: 1000,
+            amount0: U256::from(40),
+            amount1: U256::from(50),
+        };
+        let mut direct_positions = HashMap::new();
+        let increase = direct_mint_increase(&mut direct_positions, &mint);
+        assert_eq!(increase.event.tokenId, U256::MAX);
+        assert_eq!(increase.event.liquidity, 1000);
+        assert_eq!(
+            (increase.amount_0_desired, increase.amount_1_desired),
+            (U256::from(40), U256::from(50))
+        );
+        // adding to the same range keeps the id, another range gets the next
+        assert_eq!(
+            direct_mint_increase(&mut direct_positions, &mint)
+                .event
+                .tokenId,
+            U256::MAX
+        );
+        let other = Mint {
+            tickUpper: I24::try_from(1200).unwrap(),
+            ..mint.clone()
+        };
+        assert_eq!(
+            direct_mint_increase(&mut direct_positions, &other)
+                .event
+                .tokenId,
+            U256::MAX - U256::from(1)
+        );
+
+        let burn = Burn {
+            owner,
+            tickLower: lower,
+            tickUpper: upper,
+            amount: 400,
+            amount0: U256::from(16),
+            amount1: U256::from(20),
+        };
+        let decrease = direct_burn_decrease(&direct_positions, &burn).unwrap();
+        assert_eq!(decrease.event.tokenId, U256::MAX);
+        assert_eq!(decrease.event.liquidity, 400);
+        // a burn of a range nothing was minted to in the replay pairs with none
+        let untracked = Burn {
+            owner: Address::repeat_byte(0x55),
+            ..burn
+        };
+        assert!(direct_burn_decrease(&direct_positions, &untracked).is_none());
+    }
+
+    #[tokio::test]
+    async fn a_partial_close_records_a_warning() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let mut position = mock::open_position(&backend, &valuer, SIMULATED_TOKEN_ID).await;
+        backend.collect = (U256::from(203), U256::from(124));
+        backend.decrease_quote = Some((U256::from(300), U256::from(180)));
+        pool_collect_fees_post_decrease_liquidity(
+            &backend,
+            &valuer,
+            &pool_config(true),
+            Address::repeat_byte(0x33),
+            Address::repeat_byte(0x33),
+            SIMULATED_TOKEN_ID,
+            &mut position,
+            130,
+            DecreaseLiquidityWithParams {
+                amount_0_min: U256::ZERO,
+                amount_1_min: U256::ZERO,
+                event: DecreaseLiquidity {
+                    tokenId: SIMULATED_TOKEN_ID,
+                    liquidity: 400,
+                    amount0: U256::from(200),
+                    amount1: U256::from(120),
+                },
+            },
+        )
+        .await
+        .unwrap();
+
+        let warning = partial_close_warning(130, &position, 400).unwrap();
+        assert_eq!(warning.block, 130);
+        assert_eq!(warning.kind, RunWarningKind::PartialClose);
+        assert_eq!(
+            warning.detail,
+            format!(
+                "token id {} decreased by 400 of 1000 liquidity",
+                SIMULATED_TOKEN_ID
+            )
+        );
+
+        // a full decrease closes the record without one
+        position.close_reason = Some(CloseReason::OnChainFullExit);
+        assert!(partial_close_warning(130, &position, 1000).is_none());
+    }
+
+    #[tokio::test]
+    async fn decrease_for_a_seeded_token_id_is_handled() {
+        let mut backend = MockBackend::new(&pool_config(true));
+        let valuer = MockValuer { weth_per_token: 2 };
+        let position = mock::open_position(&backend, &valuer, SIMULATED_TOKEN_ID).await;
+        let (token_id_map, mut position_info) =
+            seed_resumed_positions(resume_round_trip(&position));
+
+        // the next chunk's decrease names the original token id, found through
+        // the seeded map the way the replay does
+        let token_id = token_id_map[&ORIGINAL_TOKEN_ID];
+        let seeded = position_info
+            .get_mut(&token_id)
+            .and_then(|position_infos| position_infos.last_mut())
+            .unwrap();
+        backend.collect = (U256::from(203), U256::from(124));
+        backend.decrease_quote = Some((U256::from(300), U256::from(180)));
+        let next = pool_collect_fees_post_decrease_liquidity(
+            &backend,
+            &valuer,
+            &pool_config(true),
+            Address::repeat_byte(0x33),
+            Address::repeat_byte(0x33),
+            token_id,
+            seeded,
+            130,
+            DecreaseLiquidityWithParams {
+                amount_0_min: U256::ZERO,
+                amount_1_min: U256::ZERO,
+                event: DecreaseLiquidity {
+                    tokenId: ORIGINAL_TOKEN_ID,
+                    liquidity: 400,
+                    amount0: U256::from(200),
+                    amount1: U256::from(120),
+                },
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(seeded.closed);
+        assert_eq!(seeded.close_reason, Some(CloseReason::StillOpenPartial));
+        assert_eq!(
+            (seeded.fees_earned_token, seeded.fees_earned_weth),
+            (U256::from(3), U256::from(4))
+        );
+        assert_eq!(next.liquidity_in, 600);
+        assert_eq!(next.token_id, SIMULATED_TOKEN_ID);
+        assert!(!next.closed);
+    }
+
+    #[test]
+    fn a_stopped_replay_leaves_the_next_event() {
+        let interrupted = AtomicBool::new(false);
+        let deadline = RunDeadline::default();
+        let mut events = vec![1, 2, 3].into_iter().peekable();
+        let mut stop = None;
+        let check = |count| replay_stop(count, Some(2), &interrupted, &deadline);
+
+        assert_eq!(next_event(&mut events, &mut stop, || check(0)), Some(1));
+        interrupted.store(true, Ordering::SeqCst);
+        assert_eq!(next_event(&mut events, &mut stop, || check(1)), None);
+        assert_eq!(stop, Some(ReplayStop::Interrupted));
+        assert_eq!(events.peek(), Some(&2));
+
+        interrupted.store(false, Ordering::SeqCst);
+        assert_eq!(next_event(&mut events, &mut stop, || check(2)), None);
+        assert_eq!(stop, Some(ReplayStop::MaxEvents));
+        assert_eq!(events.peek(), Some(&2));
+
+        let timed_out = RunDeadline::starting_now(Some(Duration::ZERO));
+        let check = || replay_stop(0, None, &interrupted, &timed_out);
+        assert_eq!(next_event(&mut events, &mut stop, check), None);
+        assert_eq!(stop, Some(ReplayStop::TimedOut(Duration::ZERO)));
+        assert_eq!(events.peek(), Some(&2));
+    }
+
+    #[tokio::test]
+    async fn fees_to_volume_is_the_share_of_the_in_range_volume() {
+        // open over [-600, 600) from block 10 to 20
+        let mut position = mock::record(1, 0).await;
+        position.block_out = 20;
+        position.fees_earned_weth = U256::from(12);
+        let sample = |block, (tick_before, tick), weth_volume: u64| LiquiditySample {
+            block,
+            liquidity: 1_000,
+            sqrt_price_x96: U160::from(1) << 96,
+            tick_before: I24::try_from(tick_before).unwrap(),
+            tick: I24::try_from(tick).unwrap(),
+            weth_volume: U256::from(weth_volume),
+            price_impact_bps: 0.0,
+        };
+        let samples = [
+            sample(9, (0, 100), 7_000),
+            sample(12, (0, 100), 1_000),
+            // above the range the whole way
+            sample(15, (700, 800), 5_000),
+            // ends in range
+            sample(18, (700, 500), 3_000),
+            sample(25, (0, 100), 9_000),
+        ];
+
+        annotate_from_samples(&mut position, &samples);
+        assert_eq!(position.volume_in_range_weth, U256::from(4_000));
+        assert_eq!(position.avg_pool_liquidity, 1_000);
+        // 12 of the 4000 in range
+        assert!((position.fees_to_volume - 0.003).abs() < 1e-12);
+    }
+
+    #[tokio::test]
+    async fn records_and_token_ids_are_counted_apart() {
+        // token id 1 opened then changed twice, token id 2 opened then exited
+        let mut exited = mock::record(2, 1).await;
+        exited.liquidity_in = 0;
+        let position_info = HashMap::from([
+            (
+                U256::from(1),
+                vec![
+                    mock::record(1, 0).await,
+                    mock::record(1, 1).await,
+                    mock::record(1, 2).await,
+                ],
+            ),
+            (U256::from(2), vec![mock::record(2, 0).await, exited]),
+        ]);
+        assert_eq!(position_info.len(), 2);
+        assert_eq!(record_count(&position_info), 5);
+        assert_eq!(open_token_id_count(&position_info), 1);
+        assert_eq!(record_count(&HashMap::new()), 0);
+    }
+
+    #[test]
+    fn fixed_accounts_must_differ() {
+        let (a, b) = (Address::repeat_byte(0x0a), Address::repeat_byte(0x0b));
+        check_distinct_accounts(None, None, None).unwrap();
+        check_distinct_accounts(Some(a), Some(b), None).unwrap();
+        check_distinct_accounts(None, Some(a), Some(b)).unwrap();
+
+        let error = check_distinct_accounts(Some(a), None, Some(a)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("The deployer and mint accounts must differ, both are {}", a)
+        );
+        let error = check_distinct_accounts(None, Some(b), Some(b)).unwrap_err();
+        assert!(error.to_string().starts_with("The swap and mint accounts"));
+    }
+
+    #[test]
+    fn max_events_stops_after_exactly_that_many() {
+        let interrupted = AtomicBool::new(false);
+        let deadline = RunDeadline::default();
+        let mut events = (1..=8).peekable();
+        let mut stop = None;
+        let mut replayed = Vec::new();
+        while let Some(event) = next_event(&mut events, &mut stop, || {
+            replay_stop(replayed.len(), Some(5), &interrupted, &deadline)
+        }) {
+            replayed.push(event);
+        }
+        assert_eq!(replayed, [1, 2, 3, 4, 5]);
+        assert_eq!(stop, Some(ReplayStop::MaxEvents));
+        assert_eq!(events.next(), Some(6));
+    }
+
+    #[test]
+    fn a_replay_that_ran_out_of_events_wasnt_stopped() {
+        let interrupted = AtomicBool::new(true);
+        let mut events = Vec::<u64>::new().into_iter().peekable();
+        let mut stop = None;
+        let next = next_event(&mut events, &mut stop, || {
+            replay_stop(0, None, &interrupted, &RunDeadline::default())
+        });
+        assert_eq!(next, None);
+        assert_eq!(stop, None);
+    }
+
+    #[tokio::test]
+    async fn the_ctrl_c_listener_is_aborted_when_dropped() {
+        let listener = AbortOnDrop(tokio::spawn(std::future::pending()));
+        let handle = listener.0.abort_handle();
+        drop(listener);
+        tokio::task::yield_now().await;
+        assert!(handle.is_finished());
+    }
+
+    #[test]
+    fn pnl_distribution_buckets_and_percentiles() {
+        let pnls = [3, -1, 0, -5, 4, 2, -3, 1, -2, -4].map(I256::try_from);
+        let pnls = pnls.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+        let boundaries = [I256::ZERO, I256::try_from(3).unwrap()];
+        let distribution = PnlDistribution::from_pnls(pnls, &boundaries);
+
+        assert_eq!(distribution.records, 10);
+        // a pnl on a boundary is in the bucket above it
+        assert_eq!(distribution.counts, [5, 3, 2]);
+        assert_eq!(distribution.p10, Some(I256::try_from(-5).unwrap()));
+        assert_eq!(distribution.p50, Some(I256::try_from(-1).unwrap()));
+        assert_eq!(distribution.p90, Some(I256::try_from(3).unwrap()));
+        assert_eq!(distribution.bucket_label(0), "[.., 0)");
+        assert_eq!(distribution.bucket_label(1), "[0, 3)");
+        assert_eq!(distribution.bucket_label(2), "[3, ..)");
+    }
+
+    #[test]
+    fn pnl_distribution_without_records() {
+        let distribution = PnlDistribution::from_pnls(Vec::new(), &[I256::ZERO]);
+        assert_eq!(distribution.records, 0);
+        assert_eq!(distribution.counts, [0, 0]);
+        assert_eq!(distribution.p10, None);
+        assert_eq!(distribution.p50, None);
+        assert_eq!(distribution.p90, None);
+    }
+
+    #[tokio::test]
+    async fn pnl_distribution_leaves_out_dust_records() {
+        let mut positions = Vec::new();
+        for (token_id, starting_weth, pnl) in
+            [(1, 5, -100), (2, 1_000, -2), (3, 2_000, 7), (4, 999, 50)]
+        {
+            let mut position = mock::record(token_id, 0).await;
+            position.approx_starting_weth = U256::from(starting_weth);
+            position.end_weth_gain_converted = I256::try_from(pnl).unwrap();
+            positions.push(position);
+        }
+        // never held liquidity, neither counted nor dust
+        let mut empty = mock::record(5, 0).await;
+        empty.liquidity_in = 0;
+        positions.push(empty);
+
+        let distribution = PnlDistribution::new(positions.iter(), &[I256::ZERO], U256::from(1_000));
+        assert_eq!(distribution.records, 2);
+        assert_eq!(distribution.dust_records, 2);
+        assert_eq!(distribution.counts, [1, 1]);
+        assert_eq!(distribution.p10, Some(I256::try_from(-2).unwrap()));
+        assert_eq!(distribution.p90, Some(I256::try_from(7).unwrap()));
+        assert!(distribution
+            .to_string()
+            .starts_with("\nNet PnL in WETH wei (2 records, 2 dust left out):"));
+
+        // a zero threshold makes nothing dust
+        let distribution = PnlDistribution::new(positions.iter(), &[I256::ZERO], U256::ZERO);
+        assert_eq!((distribution.records, distribution.dust_records), (4, 0));
+    }
+
+    #[tokio::test]
+    async fn token_only_fees_yield_only_a_token_apr() {
+        let mut block_clock = BlockClock::new(None);
+        block_clock.record(10, DateTime::UNIX_EPOCH);
+        block_clock.record(20, DateTime::from_timestamp(24 * 60 * 60, 0).unwrap());
+        let mut position = mock::record(1, 0).await;
+        position.block_out = 20;
+        position.approx_starting_weth = U256::from(10).pow(U256::from(18));
+        position.fees_earned_token = U256::from(10).pow(U256::from(18));
+        position.fees_earned_weth = U256::ZERO;
+        // a token is worth a weth
+        position.sqrt_price_limit_x96_out = U160::from(1) << 96;
+
+        annotate_fee_yields(&mut position, &block_clock, true);
+        assert_eq!(position.holding_blocks, 10);
+        assert_eq!(position.holding_seconds, 24 * 60 * 60);
+        // the whole starting value in fees over a day
+        assert!(
+            (position.token_fee_apr - 365.0).abs() < 1e-9,
+            "{}",
+            position.token_fee_apr
+        );
+        assert_eq!(position.weth_fee_apr, 0.0);
+        assert_eq!(position.fee_apr_in_weth, position.token_fee_apr);
+        assert_eq!(position.fees_per_weth_block, 0.0);
+    }
+
+    fn pnl_samples(pnls: &[(u64, i64)]) -> Vec<PnlSample> {
+        pnls.iter()
+            .map(|(block, pnl)| PnlSample {
+                block: *block,
+                pnl_in_weth: I256::try_from(*pnl).unwrap(),
+                ..PnlSample::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn max_drawdown_takes_the_worst_decline_from_an_earlier_peak() {
+        let samples = pnl_samples(&[(1, 100), (2, 40), (3, 150), (4, 60), (5, 120)]);
+        let drawdown = MaxDrawdown::new(&samples);
+        assert_eq!(drawdown.drawdown_in_weth, U256::from(90));
+        assert_eq!(drawdown.peak_block, Some(3));
+        assert_eq!(drawdown.peak_pnl_in_weth, I256::try_from(150).unwrap());
+        assert_eq!(drawdown.trough_block, Some(4));
+        assert_eq!(drawdown.trough_pnl_in_weth, I256::try_from(60).unwrap());
+        assert_eq!(drawdown.fraction_of_peak, Some(0.6));
+    }
+
+    #[test]
+    fn max_drawdown_from_the_runs_start() {
+        let drawdown = MaxDrawdown::new(&pnl_samples(&[(1, -50), (2, -20)]));
+        assert_eq!(drawdown.drawdown_in_weth, U256::from(50));
+        assert_eq!(drawdown.peak_block, None);
+        assert_eq!(drawdown.trough_block, Some(1));
+        assert_eq!(drawdown.fraction_of_peak, None);
+
+        // never below a peak
+        let rising = MaxDrawdown::new(&pnl_samples(&[(1, 10), (2, 20)]));
+        assert_eq!(rising, MaxDrawdown::default());
+        assert_eq!(MaxDrawdown::new(&[]), MaxDrawdown::default());
+    }
+
+    #[tokio::test]
+    async fn pnl_timeseries_ends_on_the_summary_totals() {
+        let sqrt_price = U160::from(1) << 96;
+        let mut positions = vec![mock::record(1, 0).await, mock::record(2, 0).await];
+        let mut timeseries = Vec::new();
+        let mut sample_at = |block, positions: &[PositionInfo]| {
+            let sample = PnlSample::new(block, positions.iter(), sqrt_price, true);
+            push_pnl_sample(&mut timeseries, sample);
+        };
+
+        sample_at(10, &positions);
+        sample_at(10, &positions);
+        for (position, (fees, pnl)) in positions.iter_mut().zip([(7, 40), (3, -90)]) {
+            position.closed = true;
+            position.fees_earned_weth = U256::from(fees);
+            position.end_weth_gain_converted = I256::try_from(pnl).unwrap();
+        }
+        sample_at(12, &positions);
+        // the end of run sample, after the close out in the same block
+        sample_at(12, &positions);
+
+        let blocks: Vec<u64> = timeseries.iter().map(|sample| sample.block).collect();
+        assert_eq!(blocks, [10, 12]);
+        assert_eq!(timeseries[0].open_positions, 2);
+        let pool = PoolKey {
+            token0: mock::TOKEN,
+            token1: mock::WETH,
+            fee: U24::from(10_000),
+            pool: Address::repeat_byte(0xab),
+        };
+        let summary = PoolSummary::new(
+            [((pool, "positions.csv".to_string()), positions)].into_iter(),
+            U256::ZERO,
+        );
+        let last = timeseries.last().unwrap();
+        assert_eq!(last.open_positions, 0);
+        assert_eq!(last.realized_weth_fees, summary.pools[0].fees_earned_weth);
+        assert_eq!(last.pnl_in_weth, summary.pools[0].net_pnl_in_weth);
+        assert_eq!(last.pnl_in_weth, I256::try_from(-50).unwrap());
+    }
+
+    // a record of the range the mock opens, held over [block_in, block_out]
+    async fn held_record(
+        token_id: u64,
+        index: u64,
+        (block_in, block_out): (u64, u64),
+        close_reason: CloseReason,
+        fees_earned_weth: u64,
+    ) -> PositionInfo {
+        let mut position = mock::record(token_id, index).await;
+        position.block_in = block_in;
+        position.block_out = block_out;
+        position.close_reason = Some(close_reason);
+        position.fees_earned_weth = U256::from(fees_earned_weth);
+        position
+    }
+
+    #[tokio::test]
+    async fn reopened_positions_merge_into_one_strategy() {
+        let full_exit = CloseReason::OnChainFullExit;
+        let mut other_range = held_record(3, 0, (12, 60), CloseReason::ForcedAtSimEnd, 1).await;
+        other_range.lower_tick = I24::try_from(-1200).unwrap();
+        let mut other_owner = held_record(4, 0, (30, 60), CloseReason::ForcedAtSimEnd, 1).await;
+        other_owner.owner = Address::repeat_byte(0x44);
+        let mut empty = held_record(5, 0, (10, 60), CloseReason::ForcedAtSimEnd, 1).await;
+        empty.liquidity_in = 0;
+        let mut failed = held_record(6, 0, (10, 60), CloseReason::CloseOutFailed, 1).await;
+        failed.close_out_error = Some("reverted".to_string());
+        let positions = [
+            held_record(2, 0, (25, 60), CloseReason::ForcedAtSimEnd, 7).await,
+            held_record(1, 1, (15, 20), full_exit, 3).await,
+            held_record(1, 0, (10, 15), CloseReason::StillOpenPartial, 2).await,
+            other_range,
+            other_owner,
+            empty,
+            failed,
+        ];
+
+        let strategies = merge_reopened_positions(&positions);
+        let summary: Vec<_> = strategies
+            .iter()
+            .map(|strategy| {
+                (
+                    strategy.strategy_id,
+                    strategy
+                        .token_ids
+                        .iter()
+                        .map(|id| id.to::<u64>())
+                        .collect::<Vec<_>>(),
+                    strategy.records,
+                    (strategy.block_in, strategy.block_out),
+                    strategy.fees_earned_weth.to::<u64>(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (1, vec![1, 2], 3, (10, 60), 12),
+                (2, vec![3], 1, (12, 60), 1),
+                (3, vec![4], 1, (30, 60), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn unhandled_collects_are_skipped_and_other_types_unexpected() {
+        let events = simulation_events::one_event_of_each_type();
+        let mut unhandled = UnhandledEvents::default();
+        for index in [8, 9, 9, 4, 1] {
+            unhandled.record(&events[index]);
+        }
+
+        assert_eq!(unhandled.unexpected_count(), 1);
+        assert_eq!(
+            unhandled.rows(true),
+            [
+                (
+                    "CollectNpm".to_string(),
+                    2,
+                    format!("block 3 tx {} log 3", TxHash::repeat_byte(3)).as_str()
+                ),
+                (
+                    "CollectPool".to_string(),
+                    1,
+                    format!("block 3 tx {} log 2", TxHash::repeat_byte(3)).as_str()
+                ),
+                (
+                    "Transfer".to_string(),
+                    1,
+                    format!("block 1 tx {} log 4", TxHash::repeat_byte(1)).as_str()
+                ),
+            ]
+        );
+        let unexpected = unhandled.rows(false);
+        assert_eq!(unexpected.len(), 1);
+        assert_eq!(
+            (unexpected[0].0.as_str(), unexpected[0].1),
+            ("Initialize", 1)
+        );
+        assert!(unhandled.to_string().ends_with(&format!(
+            "└─ Unexpected Initialize: 1 (first at block 1 tx {} log 1)",
+            TxHash::repeat_byte(1)
+        )));
+        assert!(UnhandledEvents::default()
+            .to_string()
+            .ends_with("├─ Skipped: none\n└─ Unexpected: none"));
+    }
+
+    #[tokio::test]
+    async fn a_position_is_valued_at_the_price_after_each_block() {
+        let pool_config = pool_config(true);
+        let mut backend = MockBackend::new(&pool_config);
+        // in range at fork block 100, above the range by fork block 200 so
+        // it's all weth
+        backend
+            .slot0_history
+            .insert(100, (U160::from(1) << 96, I24::ZERO));
+        backend
+            .slot0_history
+            .insert(200, (U160::from(2) << 96, I24::try_from(13_863).unwrap()));
+        let fork_blocks = BTreeMap::from([(10, 100), (20, 200)]);
+        let mut position = mock::record(1, 0).await;
+        position.closed = true;
+        position.block_out = 30;
+        let records = [position];
+        let value_at =
+            |block| value_records_at(&backend, &pool_config, &records, &fork_blocks, block);
+
+        assert_eq!(value_at(15).await.unwrap(), U256::from(58));
+        assert_eq!(value_at(25).await.unwrap(), U256::from(60));
+        assert_eq!(value_at(30).await.unwrap(), U256::ZERO);
+        let err = value_at(5).await.unwrap_err();
+        assert!(err.to_string().contains("before the replayed events"));
+    }
+
+    #[tokio::test]
+    async fn fees_past_the_range_fee_growth_are_violations() {
+        let with_fees = |mut position: PositionInfo, earned: u64, growth: u64| {
+            position.fees_earned_weth = U256::from(earned);
+            position.fees_from_growth_weth = U256::from(growth);
+            position
+        };
+        // a wei of rounding per record is allowed
+        let within = [
+            with_fees(mock::record(1, 0).await, 101, 100),
+            with_fees(mock::record(2, 0).await, 101, 100),
+        ];
+        assert!(fee_invariant_violations(within.iter()).is_empty());
+
+        let mut other_range = with_fees(mock::record(3, 0).await, 50, 50);
+        other_range.lower_tick = I24::try_from(-1200).unwrap();
+        let past = [
+            with_fees(mock::record(1, 0).await, 102, 100),
+            with_fees(mock::record(2, 0).await, 102, 100),
+            other_range,
+        ];
+        assert_eq!(
+            fee_invariant_violations(past.iter()),
+            ["range [-600, 600) over 2 records collected (0, 204) but fee growth allows (0, 200)"]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_mint_while_another_is_open_starts_its_own_strategy() {
+        let positions = [
+            held_record(1, 0, (10, 30), CloseReason::OnChainFullExit, 1).await,
+            held_record(2, 0, (20, 60), CloseReason::ForcedAtSimEnd, 1).await,
+            held_record(3, 0, (10, 60), CloseReason::ForcedAtSimEnd, 1).await,
+        ];
+        // 2 is minted before 1 exits
+        let strategies = merge_reopened_positions(&positions[..2]);
+        assert_eq!(strategies.len(), 2);
+        assert_eq!(strategies[1].token_ids, [U256::from(2)]);
+
+        // 3 never exits on chain
+        let strategies = merge_reopened_positions(&positions[1..]);
+        assert_eq!(strategies.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn records_rank_by_fees_per_weth_block() {
+        let mut positions = Vec::new();
+        for (token_id, index, holding_blocks, fees_per_weth_block) in [
+            (1, 0, 10, 0.5),
+            (2, 0, 10, 2.0),
+            (3, 0, 10, 0.5),
+            (1, 1, 10, 1.0),
+            // not held for a block
+            (4, 0, 0, 9.0),
+        ] {
+            let mut position = mock::record(token_id, index).await;
+            position.holding_blocks = holding_blocks;
+            position.fees_per_weth_block = fees_per_weth_block;
+            positions.push(position);
+        }
+        // no starting value
+        let mut unvalued = mock::record(5, 0).await;
+        unvalued.holding_blocks = 10;
+        unvalued.fees_per_weth_block = 9.0;
+        unvalued.approx_starting_weth = U256::ZERO;
+        positions.push(unvalued);
+
+        let ranked = |top_n| -> Vec<(u64, u64)> {
+            rank_by_fees_per_weth_block(positions.iter(), top_n)
+                .iter()
+                .map(|p| (p.original_token_id.to::<u64>(), p.index))
+                .collect()
+        };
+        // ties are broken by token id and index
+        assert_eq!(ranked(10), [(2, 0), (1, 1), (1, 0), (3, 0)]);
+        assert_eq!(ranked(2), [(2, 0), (1, 1)]);
+        assert!(ranked(0).is_empty());
+    }
+
+    #[test]
+    fn output_paths_expand_per_pool() {
+        let pool = PoolKey {
+            token0: mock::TOKEN,
+            token1: mock::WETH,
+            fee: U24::from(10_000),
+            pool: Address::repeat_byte(0xab),
+        };
+        let template = "out/{pool_address}_{fee_tier}/positions.csv";
+        assert_eq!(
+            expand_output_path(template, Some(&pool)),
+            format!("out/{}_10000/positions.csv", pool.pool)
+        );
+        assert_eq!(
+            expand_output_path(template, None),
+            "out/all_all/positions.csv"
+        );
+        assert_eq!(
+            expand_output_path("positions.csv", Some(&pool)),
+            "positions.csv"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::{collections::BTreeMap, fmt, path::Path};
+
+use alloy::primitives::{I256, U256};
+use eyre::{eyre, Result};
+use tracing::info;
+
+use crate::chain_interactions::collect::PositionInfo;
+
+use super::csv_output_writer::{read_positions_from_csv, write_position_diffs_to_csv};
+
+// the compared values of one record in one run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DiffedValues {
+    pub fees_earned_token: U256,
+    pub fees_earned_weth: U256,
+    pub net_pnl_in_weth: I256,
+}
+
+impl From<&PositionInfo> for DiffedValues {
+    fn from(position: &PositionInfo) -> Self {
+        DiffedValues {
+            fees_earned_token: position.fees_earned_token,
+            fees_earned_weth: position.fees_earned_weth,
+            net_pnl_in_weth: position.end_weth_gain_converted,
+        }
+    }
+}
+
+// one record of two runs over the same events, matched by original token id
+// and record index. a side is none when that run has no such record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PositionDiff {
+    pub original_token_id: U256,
+    pub index: u64,
+    pub a: Option<DiffedValues>,
+    pub b: Option<DiffedValues>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PresentIn {
+    Both,
+    OnlyA,
+    OnlyB,
+}
+
+impl fmt::Display for PresentIn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresentIn::Both => write!(f, "both"),
+            PresentIn::OnlyA => write!(f, "a"),
+            PresentIn::OnlyB => write!(f, "b"),
+        }
+    }
+}
+
+impl PositionDiff {
+    pub(crate) fn present_in(&self) -> PresentIn {
+        match (self.a, self.b) {
+            (Some(_), None) => PresentIn::OnlyA,
+            (None, Some(_)) => PresentIn::OnlyB,
+            _ => PresentIn::Both,
+        }
+    }
+
+    // `b` minus `a`, none unless both runs have the record
+    fn delta(&self, value: impl Fn(&DiffedValues) -> I256) -> Option<I256> {
+        Some(value(self.b.as_ref()?) - value(self.a.as_ref()?))
+    }
+
+    pub(crate) fn fees_earned_token_delta(&self) -> Option<I256> {
+        self.delta(|values| I256::from_raw(values.fees_earned_token))
+    }
+
+    pub(crate) fn fees_earned_weth_delta(&self) -> Option<I256> {
+        self.delta(|values| I256::from_raw(values.fees_earned_weth))
+    }
+
+    pub(crate) fn net_pnl_in_weth_delta(&self) -> Option<I256> {
+        self.delta(|values| values.net_pnl_in_weth)
+    }
+
+    // only one run has the record, or a compared value differs by more than
+    // `tolerance` wei
+    pub(crate) fn diverges(&self, tolerance: U256) -> bool {
+        self.present_in() != PresentIn::Both
+            || [
+                self.fees_earned_token_delta(),
+                self.fees_earned_weth_delta(),
+                self.net_pnl_in_weth_delta(),
+            ]
+            .into_iter()
+            .flatten()
+            .any(|delta| delta.unsigned_abs() > tolerance)
+    }
+}
+
+// pairs up the records of two runs by (original token id, index), in that
+// order, including the records only one of the runs has
+pub(crate) fn diff_positions(a: &[PositionInfo], b: &[PositionInfo]) -> Vec<PositionDiff> {
+    let mut diffs: BTreeMap<(U256, u64), PositionDiff> = BTreeMap::new();
+    for (run, positions) in [(0, a), (1, b)] {
+        for position in positions {
+            let diff = diffs
+                .entry((position.original_token_id, position.index))
+                .or_insert(PositionDiff {
+                    original_token_id: position.original_token_id,
+                    index: position.index,
+                    a: None,
+                    b: None,
+                });
+            let side = if run == 0 { &mut diff.a } else { &mut diff.b };
+            *side = Some(position.into());
+        }
+    }
+    diffs.into_values().collect()
+}
+
+// diffs two runs' positions csvs, for seeing what changing a setting did to
+// each position, and writes the result to `output_path`
+pub(crate) fn diff_position_csvs(a_path: &Path, b_path: &Path, output_path: &Path) -> Result<()> {
+    let a = read_positions_from_csv(a_path)?;
+    let b = read_positions_from_csv(b_path)?;
+    let diffs = diff_positions(&a, &b);
+
+    let count = |present_in| {
+        diffs
+            .iter()
+            .filter(|diff| diff.present_in() == present_in)
+            .count()
+    };
+    let changed = diffs
+        .iter()
+        .filter(|diff| diff.present_in() == PresentIn::Both && diff.a != diff.b)
+        .count();
+    info!(
+        "Compared {} records: {} in both ({} changed), {} only in {:?}, {} only in {:?}",
+        diffs.len(),
+        count(PresentIn::Both),
+        changed,
+        count(PresentIn::OnlyA),
+        a_path,
+        count(PresentIn::OnlyB),
+        b_path
+    );
+    let net_pnl_delta: I256 = diffs
+        .iter()
+        .filter_map(PositionDiff::net_pnl_in_weth_delta)
+        .fold(I256::ZERO, |total, delta| total + delta);
+    info!(
+        "Net PnL change over the records in both: {} wei WETH",
+        net_pnl_delta
+    );
+
+    write_position_diffs_to_csv(&diffs, output_path)
+        .map_err(|e| eyre!("Failed to write position diffs to csv: {}", e))?;
+    info!("Wrote the diff to {:?}", output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_interactions::backend::mock::record;
+
+    #[tokio::test]
+    async fn diff_pairs_records_by_token_id_and_index() {
+        let mut changed = record(1, 0).await;
+        changed.fees_earned_weth = U256::from(10);
+        let a = [record(2, 0).await, changed.clone(), record(1, 1).await];
+        changed.fees_earned_weth = U256::from(13);
+        let b = [changed, record(2, 0).await, record(3, 0).await];
+
+        let diffs = diff_positions(&a, &b);
+        let keys: Vec<_> = diffs
+            .iter()
+            .map(|diff| (diff.original_token_id.to::<u64>(), diff.index))
+            .collect();
+        assert_eq!(keys, [(1, 0), (1, 1), (2, 0), (3, 0)]);
+        let present: Vec<_> = diffs.iter().map(PositionDiff::present_in).collect();
+        assert_eq!(
+            present,
+            [
+                PresentIn::Both,
+                PresentIn::OnlyA,
+                PresentIn::Both,
+                PresentIn::OnlyB
+            ]
+        );
+
+        assert_eq!(
+            diffs[0].fees_earned_weth_delta(),
+            Some(I256::try_from(3).unwrap())
+        );
+        assert_eq!(diffs[0].fees_earned_token_delta(), Some(I256::ZERO));
+        assert_eq!(diffs[1].net_pnl_in_weth_delta(), None);
+        assert!(diffs[0].diverges(U256::from(2)));
+        assert!(!diffs[0].diverges(U256::from(3)));
+        assert!(!diffs[2].diverges(U256::ZERO));
+        // a record only one run has diverges whatever the tolerance
+        assert!(diffs[1].diverges(U256::MAX));
+    }
+}
@@ -0,0 +1,831 @@
+use std::collections::HashSet;
+
+use alloy::{
+    primitives::{
+        aliases::{I24, U24},
+        Address, TxHash, I256, U160, U256,
+    },
+    providers::{Provider, ProviderBuilder},
+};
+use chrono::DateTime;
+use clap::Parser;
+use eyre::{ContextCompat, Result};
+
+use super::{
+    simulation_events::{
+        Event, EventType, IncreaseLiquidityWithParams, SimulationEvent, SwapWithParams,
+    },
+    PoolAnalyzer, PoolAnalyzerConfig,
+};
+use crate::{
+    abi::{
+        INonfungiblePositionManager::IncreaseLiquidity,
+        IQuoterV2::QuoteExactInputSingleParams,
+        IUniswapV3Factory::PoolCreated,
+        UniswapV3Pool::{Initialize, Mint, Swap},
+    },
+    chain_interactions::{
+        valuation::{amounts_for_liquidity, sqrt_price_at_tick, TokenValuer, ValuationMethod},
+        PoolSetupMode, VerificationLevel,
+    },
+    cli::{Cli, Command},
+};
+
+const FEE: u32 = 10_000;
+const TICK_SPACING: i32 = 200;
+const LIQUIDITY: u128 = 100_000_000_000_000_000_000;
+const SWAP_IN: u64 = 1_000_000_000_000_000_000;
+const SWAP_OUT: u64 = 500_000_000_000_000_000;
+
+// replays a small built-in pool end to end on a fork of HTTP_URL: one
+// position minted around the starting price, swaps back and forth through it,
+// and the end of run close out. the position has to earn fees on both sides
+// and come out with usable pnl fields. needs an archive rpc and anvil, so it
+// only runs with `cargo test -- --ignored`
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn replays_built_in_pool_end_to_end() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the self test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("self_test")?;
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    let positions = pool_analyzer.run_simulation().await?;
+
+    let [position] = positions.as_slice() else {
+        panic!("expected one position record, got {}", positions.len());
+    };
+    assert!(
+        position.fees_earned_token > U256::ZERO && position.fees_earned_weth > U256::ZERO,
+        "no fees earned on one side: token {}, weth {}",
+        position.fees_earned_token,
+        position.fees_earned_weth
+    );
+    assert!(position.approx_starting_weth > U256::ZERO, "{}", position);
+    assert!(position.approx_ending_weth > U256::ZERO, "{}", position);
+    assert!(position.fees_to_volume.is_finite(), "{}", position);
+    assert_eq!(pool_analyzer.unique_token_ids(), 1);
+    assert_eq!(pool_analyzer.position_count(), 1);
+
+    // the endpoint is a url another client can reach the same fork on
+    let endpoint = pool_analyzer.endpoint();
+    let outside = ProviderBuilder::new().on_http(endpoint.parse()?);
+    assert_eq!(
+        outside.get_block_number().await?,
+        pool_analyzer.anvil_provider.get_block_number().await?,
+        "{}",
+        endpoint
+    );
+    Ok(())
+}
+
+// the swaps paying in weth still replay after the swap account's weth is
+// sent away, each tops the account back up first
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn swaps_top_up_a_drained_swap_account() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the drained account test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("drained_swap_account")?;
+    let weth = config.weth_address;
+    let weth_is_token0 = weth < Address::repeat_byte(0x11);
+    let events = events
+        .into_iter()
+        .filter(|event| match &event.event {
+            Event::Swap(swap) => (swap.event.amount0 > I256::ZERO) == weth_is_token0,
+            _ => true,
+        })
+        .collect();
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    let swap_account = pool_analyzer.swap_account;
+    let balance = pool_analyzer.weth.balanceOf(swap_account).call().await?._0;
+    pool_analyzer
+        .weth
+        .transfer(Address::repeat_byte(0xde), balance)
+        .from(swap_account)
+        .send()
+        .await?
+        .get_receipt()
+        .await?;
+    assert_eq!(
+        pool_analyzer.weth.balanceOf(swap_account).call().await?._0,
+        U256::ZERO
+    );
+
+    let positions = pool_analyzer.run_simulation().await?;
+    let [position] = positions.as_slice() else {
+        panic!("expected one position record, got {}", positions.len());
+    };
+    // only weth was swapped in, so the fees are all weth
+    assert!(position.fees_earned_weth > U256::ZERO, "{}", position);
+    Ok(())
+}
+
+// the built-in pool holds only the one position, so selling its token side
+// in one go walks the price down through its own liquidity and pays well
+// below the closing spot price
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn a_thin_pool_values_the_exit_below_spot() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the thin pool test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("thin_pool")?;
+    let events = events
+        .into_iter()
+        .filter(|event| !matches!(event.event, Event::Swap(_)))
+        .collect();
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    let positions = pool_analyzer.run_simulation().await?;
+    let [position] = positions.as_slice() else {
+        panic!("expected one position record, got {}", positions.len());
+    };
+    let spot = position.approx_ending_weth_spot;
+    let executable = position.approx_ending_weth_executable;
+    // at least a 2% haircut
+    assert!(
+        executable * U256::from(100) < spot * U256::from(98),
+        "spot {}, executable {}",
+        spot,
+        executable
+    );
+    Ok(())
+}
+
+// the quoter prices a sale of the pool's whole token side through the
+// position's liquidity, well below its spot value, while a small sale only
+// gives up about the fee
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn the_quoter_values_a_large_sale_below_spot() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the quoter valuation test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("quoter_valuation")?;
+    let events = events
+        .into_iter()
+        .filter(|event| !matches!(event.event, Event::Swap(_)))
+        .collect();
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    pool_analyzer.run_simulation().await?;
+    let valuer = |method| TokenValuer {
+        method,
+        slices: 1,
+        max_sale_bps: None,
+        ..pool_analyzer.valuer.clone()
+    };
+    let (quoter, spot) = (
+        valuer(ValuationMethod::Quoter),
+        valuer(ValuationMethod::Spot),
+    );
+    let pool_config = &pool_analyzer.pool_config;
+
+    let large = pool_analyzer
+        .clanker_token
+        .balanceOf(*pool_analyzer.pool.address())
+        .call()
+        .await?
+        ._0;
+    let large_quote = quoter.token_to_weth(pool_config, large).await?;
+    let large_spot = spot.token_to_weth(pool_config, large).await?;
+    assert!(
+        large_quote * U256::from(100) < large_spot * U256::from(90),
+        "quote {}, spot {}",
+        large_quote,
+        large_spot
+    );
+
+    let small = U256::from(1_000_000_000_u64);
+    let small_quote = quoter.token_to_weth(pool_config, small).await?;
+    let small_spot = spot.token_to_weth(pool_config, small).await?;
+    // the 1% fee and rounding, no real price impact
+    assert!(
+        small_quote <= small_spot && small_quote * U256::from(100) >= small_spot * U256::from(98),
+        "quote {}, spot {}",
+        small_quote,
+        small_spot
+    );
+    Ok(())
+}
+
+// a run of just the mint counts the mint's gas under mint and the close out
+// collect's under collect, matching what the position recorded, and leaves
+// the other counters at zero
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn a_mint_is_counted_in_the_gas_summary() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the gas summary test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("mint_gas")?;
+    let events = events
+        .into_iter()
+        .filter(|event| !matches!(event.event, Event::Swap(_)))
+        .collect();
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    assert_eq!(pool_analyzer.gas_used.mint, 0);
+    let positions = pool_analyzer.run_simulation().await?;
+    let [position] = positions.as_slice() else {
+        panic!("expected one position record, got {}", positions.len());
+    };
+    let gas_used = &pool_analyzer.gas_used;
+    assert!(gas_used.mint > 0, "{}", gas_used);
+    assert_eq!(gas_used.mint, position.gas_used_in);
+    assert!(gas_used.collect > 0, "{}", gas_used);
+    assert_eq!(gas_used.collect, position.gas_used_out);
+    assert_eq!(
+        (
+            gas_used.increase_liquidity,
+            gas_used.decrease_liquidity,
+            gas_used.swap
+        ),
+        (0, 0, 0)
+    );
+    Ok(())
+}
+
+// with per swapper accounts the swaps are sent from their original sender,
+// not from the shared swap account
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn per_swapper_accounts_swap_from_the_event_sender() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the per swapper test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("per_swapper")?;
+    let config = PoolAnalyzerConfig {
+        per_swapper_accounts: true,
+        ..config
+    };
+    let sender = Address::repeat_byte(0x55);
+    let events = events
+        .into_iter()
+        .map(|event| match event.event {
+            Event::Swap(_) => SimulationEvent {
+                from: sender,
+                ..event
+            },
+            _ => event,
+        })
+        .collect();
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    let swap_account = pool_analyzer.swap_account;
+    let swap_account_nonce = pool_analyzer
+        .anvil_provider
+        .get_transaction_count(swap_account)
+        .await?;
+    pool_analyzer.run_simulation().await?;
+
+    assert_eq!(pool_analyzer.swappers, Some(HashSet::from([sender])));
+    // the four swaps, after any approvals, went out from the sender
+    assert!(
+        pool_analyzer
+            .anvil_provider
+            .get_transaction_count(sender)
+            .await?
+            >= 4
+    );
+    assert_eq!(
+        pool_analyzer
+            .anvil_provider
+            .get_transaction_count(swap_account)
+            .await?,
+        swap_account_nonce
+    );
+    Ok(())
+}
+
+// with the original slippage bounds enforced, a swap whose bounds can't be
+// met reverts like it would have on chain and ends the run
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn a_too_tight_swap_bound_reverts() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the slippage bound test");
+        return Ok(());
+    }
+    let (config, mut events) = self_test_config_and_events("tight_bound")?;
+    let config = PoolAnalyzerConfig {
+        enforce_min_amounts: true,
+        ..config
+    };
+    // out of reach whichever direction the swap replays in
+    let Some(Event::Swap(swap)) = events
+        .iter_mut()
+        .map(|event| &mut event.event)
+        .find(|event| matches!(event, Event::Swap(_)))
+    else {
+        panic!("expected a swap in the self test events");
+    };
+    swap.amount_out_minimum = Some(U256::from(SWAP_IN) * U256::from(10));
+    swap.amount_in_maximum = Some(U256::from(1));
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    assert!(pool_analyzer.run_simulation().await.is_err());
+    Ok(())
+}
+
+// a pool in a fee tier the fork's factory doesn't have gets the tier enabled
+// by the factory owner, then replays like any other
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn a_missing_fee_tier_is_enabled_first() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the fee tier test");
+        return Ok(());
+    }
+    let (config, mut events) = self_test_config_and_events("fee_tier")?;
+    // not one of the standard tiers, and the range's ticks are multiples of 50
+    let (fee, tick_spacing) = (U24::from(2_500), I24::try_from(50)?);
+    let Some(Event::PoolCreated(created)) = events.first_mut().map(|event| &mut event.event) else {
+        panic!("expected the self test events to start with the pool created");
+    };
+    created.fee = fee;
+    created.tickSpacing = tick_spacing;
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    assert_eq!(
+        pool_analyzer
+            .factory
+            .feeAmountTickSpacing(fee)
+            .call()
+            .await?
+            .tickSpacing,
+        tick_spacing
+    );
+    assert_eq!(pool_analyzer.pool.fee().call().await?._0, fee);
+    let positions = pool_analyzer.run_simulation().await?;
+    assert_eq!(positions.len(), 1);
+    Ok(())
+}
+
+// fixed account addresses are the ones set up and impersonated, so every
+// transaction the replay sends goes out from one of them
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn fixed_accounts_are_the_ones_impersonated() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the fixed accounts test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("fixed_accounts")?;
+    let (deployer, swap, mint) = (
+        Address::repeat_byte(0xd1),
+        Address::repeat_byte(0xd2),
+        Address::repeat_byte(0xd3),
+    );
+    let config = PoolAnalyzerConfig {
+        deployer_account: Some(deployer),
+        swap_account: Some(swap),
+        mint_account: Some(mint),
+        ..config
+    };
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    assert_eq!(
+        (
+            pool_analyzer.deployer,
+            pool_analyzer.swap_account,
+            pool_analyzer.mint_account
+        ),
+        (deployer, swap, mint)
+    );
+    pool_analyzer.run_simulation().await?;
+    for account in [deployer, swap, mint] {
+        assert!(
+            pool_analyzer
+                .anvil_provider
+                .get_transaction_count(account)
+                .await?
+                > 0,
+            "nothing sent from {}",
+            account
+        );
+    }
+    Ok(())
+}
+
+// the same deposit over a narrower range holds more liquidity where the swaps
+// trade, so it earns more fees on both sides than over a wider one
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn a_narrow_counterfactual_earns_more_than_a_wide_one() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the counterfactual test");
+        return Ok(());
+    }
+    let counterfactual = |name: &'static str, half_width: i32| async move {
+        let (config, events) = self_test_config_and_events(name)?;
+        let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+        pool_analyzer
+            .simulate_counterfactual_position(
+                U256::from(1),
+                I24::try_from(-half_width * TICK_SPACING)?,
+                I24::try_from(half_width * TICK_SPACING)?,
+            )
+            .await
+    };
+    let narrow = counterfactual("counterfactual_narrow", 2).await?;
+    let wide = counterfactual("counterfactual_wide", 20).await?;
+
+    assert!(narrow.liquidity_in > wide.liquidity_in);
+    assert!(
+        narrow.fees_earned_token > wide.fees_earned_token
+            && narrow.fees_earned_weth > wide.fees_earned_weth,
+        "narrow {}, wide {}",
+        narrow,
+        wide
+    );
+    Ok(())
+}
+
+// with lazy transfers the supply stays with the deployer at setup, and the
+// mints and swaps selling the token are still funded, each pulling only what
+// it needs
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn lazy_transfers_fund_the_replay_on_demand() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the lazy transfers test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("lazy_transfers")?;
+    let config = PoolAnalyzerConfig {
+        lazy_token_transfers: true,
+        ..config
+    };
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    let token = pool_analyzer.clanker_token.clone();
+    let total_supply = token.totalSupply().call().await?._0;
+    let deployer = pool_analyzer.deployer;
+    assert_eq!(pool_analyzer.token_holder, Some(deployer));
+    assert_eq!(token.balanceOf(deployer).call().await?._0, total_supply);
+    assert_eq!(
+        token.balanceOf(pool_analyzer.swap_account).call().await?._0,
+        U256::ZERO
+    );
+
+    let positions = pool_analyzer.run_simulation().await?;
+    assert_eq!(positions.len(), 1);
+    // the mint and the token side swaps took a sliver of the supply
+    let left = token.balanceOf(deployer).call().await?._0;
+    assert!(
+        left > U256::ZERO && left < total_supply,
+        "deployer left with {} of {}",
+        left,
+        total_supply
+    );
+    Ok(())
+}
+
+// with the checks off, a mint whose event amounts are nowhere near what the
+// replay deposits still goes through, and the run writes its csv as usual
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn unverified_replay_still_writes_its_output() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the unverified replay test");
+        return Ok(());
+    }
+    let (config, mut events) = self_test_config_and_events("unverified")?;
+    assert_eq!(config.verification, VerificationLevel::None);
+    let output_csv = config.output_csv_file_path.clone();
+    let Some(Event::Mint(mint)) = events
+        .iter_mut()
+        .map(|event| &mut event.event)
+        .find(|event| matches!(event, Event::Mint(_)))
+    else {
+        panic!("expected a mint in the self test events");
+    };
+    mint.amount0 *= U256::from(2);
+    mint.amount1 *= U256::from(2);
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    let positions = pool_analyzer.run_simulation().await?;
+    assert_eq!(positions.len(), 1);
+    let rows = csv::Reader::from_path(&output_csv)?.records().count();
+    assert_eq!(rows, 1);
+    Ok(())
+}
+
+// with the optimistic path on, a swap whose event amounts are what exact
+// input produces replays without a quote and without falling back. the
+// event's amount out comes from quoting a run of the same events without
+// the swaps, which leaves the pool where the swap finds it
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn exact_input_swaps_skip_the_quote() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the optimistic exact input test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("optimistic_exact_input")?;
+    let (swaps, setup): (Vec<_>, Vec<_>) = events
+        .into_iter()
+        .partition(|event| matches!(event.event, Event::Swap(_)));
+
+    let mut quoting = PoolAnalyzer::initialize_with_events(config.clone(), setup.clone()).await?;
+    quoting.run_simulation().await?;
+    let quote = quoting
+        .quoter
+        .quoteExactInputSingle(QuoteExactInputSingleParams {
+            tokenIn: quoting.pool.token0().call().await?._0,
+            tokenOut: quoting.pool.token1().call().await?._0,
+            fee: U24::from(FEE),
+            amountIn: U256::from(SWAP_IN),
+            sqrtPriceLimitX96: U160::ZERO,
+        })
+        .call()
+        .await?;
+
+    let mut swap = swaps.into_iter().next().context("no swap in the events")?;
+    let Event::Swap(swap_event) = &mut swap.event else {
+        unreachable!()
+    };
+    swap_event.event.amount0 = I256::try_from(SWAP_IN)?;
+    swap_event.event.amount1 = -I256::try_from(quote.amountOut)?;
+    let config = PoolAnalyzerConfig {
+        optimistic_exact_input: true,
+        // the canned mint amounts still aren't a real pool's
+        verification: VerificationLevel::SwapsOnly,
+        ..config
+    };
+    let events = setup.into_iter().chain([swap]).collect();
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    pool_analyzer.run_simulation().await?;
+    assert_eq!(pool_analyzer.swap_stats.optimistic_hits, 1);
+    assert_eq!(pool_analyzer.swap_stats.optimistic_fallbacks, 0);
+    Ok(())
+}
+
+// a weth paying exact input swap with native eth swaps replays from the swap
+// account's eth, its weth is never touched. the event's amount out comes from
+// quoting a run without the swaps, like the optimistic exact input test
+#[tokio::test]
+#[ignore = "forks HTTP_URL with anvil"]
+async fn native_eth_pays_an_exact_input_weth_swap() -> Result<()> {
+    if std::env::var_os("HTTP_URL").is_none() {
+        eprintln!("HTTP_URL is not set, skipping the native eth swap test");
+        return Ok(());
+    }
+    let (config, events) = self_test_config_and_events("native_eth_swap")?;
+    let (swaps, setup): (Vec<_>, Vec<_>) = events
+        .into_iter()
+        .partition(|event| matches!(event.event, Event::Swap(_)));
+
+    let mut quoting = PoolAnalyzer::initialize_with_events(config.clone(), setup.clone()).await?;
+    quoting.run_simulation().await?;
+    let weth = config.weth_address;
+    let quote = quoting
+        .quoter
+        .quoteExactInputSingle(QuoteExactInputSingleParams {
+            tokenIn: weth,
+            tokenOut: *quoting.clanker_token.address(),
+            fee: U24::from(FEE),
+            amountIn: U256::from(SWAP_IN),
+            sqrtPriceLimitX96: U160::ZERO,
+        })
+        .call()
+        .await?;
+
+    let mut swap = swaps.into_iter().next().context("no swap in the events")?;
+    let Event::Swap(swap_event) = &mut swap.event else {
+        unreachable!()
+    };
+    let (weth_side, token_side) = if weth < *quoting.clanker_token.address() {
+        (&mut swap_event.event.amount0, &mut swap_event.event.amount1)
+    } else {
+        (&mut swap_event.event.amount1, &mut swap_event.event.amount0)
+    };
+    *weth_side = I256::try_from(SWAP_IN)?;
+    *token_side = -I256::try_from(quote.amountOut)?;
+    let config = PoolAnalyzerConfig {
+        native_eth: true,
+        write_exact_output_swaps: true,
+        // the canned mint amounts still aren't a real pool's
+        verification: VerificationLevel::SwapsOnly,
+        ..config
+    };
+    let events = setup.into_iter().chain([swap]).collect();
+
+    let mut pool_analyzer = PoolAnalyzer::initialize_with_events(config, events).await?;
+    let swap_account = pool_analyzer.swap_account;
+    let pool = *pool_analyzer.pool.address();
+    let weth_before = pool_analyzer.weth.balanceOf(swap_account).call().await?._0;
+    let eth_before = pool_analyzer
+        .anvil_provider
+        .get_balance(swap_account)
+        .await?;
+    let pool_weth_before = pool_analyzer.weth.balanceOf(pool).call().await?._0;
+
+    pool_analyzer.run_simulation().await?;
+    // sent as exact input
+    assert_eq!(
+        pool_analyzer.exact_output_swaps.as_ref().map(Vec::len),
+        Some(0)
+    );
+    assert_eq!(
+        pool_analyzer.weth.balanceOf(pool).call().await?._0 - pool_weth_before,
+        U256::from(SWAP_IN)
+    );
+    assert_eq!(
+        pool_analyzer.weth.balanceOf(swap_account).call().await?._0,
+        weth_before
+    );
+    // the input and the gas
+    let eth_spent = eth_before
+        - pool_analyzer
+            .anvil_provider
+            .get_balance(swap_account)
+            .await?;
+    assert!(eth_spent >= U256::from(SWAP_IN), "spent {} eth", eth_spent);
+    Ok(())
+}
+
+// the self test's config, from the env as for `run` with the output under
+// the temp dir, and its canned events
+fn self_test_config_and_events(name: &str) -> Result<(PoolAnalyzerConfig, Vec<SimulationEvent>)> {
+    let output_csv = std::env::temp_dir()
+        .join(format!("{}_{}.csv", name, std::process::id()))
+        .to_string_lossy()
+        .into_owned();
+    // the input csvs are never read
+    let Command::Run(args) = Cli::try_parse_from([
+        "uniswap_v3_analyze_fees",
+        "run",
+        "--unified-csv",
+        "unused.csv",
+        "--output-csv",
+        &output_csv,
+    ])?
+    .command
+    else {
+        unreachable!()
+    };
+    let config = PoolAnalyzerConfig::from(args);
+    // the canned amounts aren't a real pool's, only the replay machinery
+    // is under test
+    let config = PoolAnalyzerConfig {
+        verification: VerificationLevel::None,
+        pool_setup_mode: PoolSetupMode::DeployStandin,
+        pool_address: None,
+        resume_path: None,
+        max_events: None,
+        enabled_event_types: EventType::ALL.into_iter().collect(),
+        ..config
+    };
+    // the canned events are stamped after the fork block
+    let fork_block = config
+        .fork_block
+        .context("The self test needs BLOCK_FORK_NUMBER")?;
+    let events = self_test_events(
+        fork_block,
+        config.weth_address,
+        config.uniswap_v3_factory_address,
+        config.uniswap_v3_position_manager_address,
+    );
+    Ok((config, events))
+}
+
+// pool created, initialize at tick 0, a position manager mint over
+// [-2000, 2000), then swaps alternating direction so fees accrue on both sides
+fn self_test_events(
+    fork_block: u64,
+    weth: Address,
+    factory: Address,
+    position_manager: Address,
+) -> Vec<SimulationEvent> {
+    let pool = Address::repeat_byte(0x22);
+    let clanker = Address::repeat_byte(0x11);
+    let (token0, token1) = if clanker < weth {
+        (clanker, weth)
+    } else {
+        (weth, clanker)
+    };
+    let account = Address::repeat_byte(0x33);
+    let lower_tick = I24::try_from(-10 * TICK_SPACING).unwrap();
+    let upper_tick = I24::try_from(10 * TICK_SPACING).unwrap();
+    let sqrt_price_x96: U160 = sqrt_price_at_tick(I24::ZERO).to();
+    let (amount0, amount1) =
+        amounts_for_liquidity(LIQUIDITY, sqrt_price_x96, lower_tick, upper_tick);
+
+    let mut events = Vec::new();
+    let mut push = |block: u64, tx: u8, log_index: u64, pool_address: Address, event: Event| {
+        events.push(SimulationEvent {
+            block: fork_block + block,
+            tx_hash: TxHash::with_last_byte(tx),
+            log_index,
+            pool_address,
+            from: account,
+            // no real timestamps, the replay falls back to the configured
+            // block time when the span is zero
+            block_time: DateTime::UNIX_EPOCH,
+            event,
+        })
+    };
+
+    push(
+        1,
+        1,
+        0,
+        factory,
+        Event::PoolCreated(PoolCreated {
+            token0,
+            token1,
+            fee: U24::from(FEE),
+            tickSpacing: I24::try_from(TICK_SPACING).unwrap(),
+            pool,
+        }),
+    );
+    push(
+        1,
+        1,
+        1,
+        pool,
+        Event::Initialize(Initialize {
+            sqrtPriceX96: sqrt_price_x96,
+            tick: I24::ZERO,
+        }),
+    );
+    push(
+        2,
+        2,
+        0,
+        pool,
+        Event::Mint(Mint {
+            sender: position_manager,
+            owner: position_manager,
+            tickLower: lower_tick,
+            tickUpper: upper_tick,
+            amount: LIQUIDITY,
+            amount0,
+            amount1,
+        }),
+    );
+    push(
+        2,
+        2,
+        1,
+        position_manager,
+        Event::IncreaseLiquidity(IncreaseLiquidityWithParams {
+            amount_0_desired: amount0,
+            amount_1_desired: amount1,
+            amount_0_min: U256::ZERO,
+            amount_1_min: U256::ZERO,
+            event: IncreaseLiquidity {
+                tokenId: U256::from(1),
+                liquidity: LIQUIDITY,
+                amount0,
+                amount1,
+            },
+        }),
+    );
+
+    // asking for less out than the input buys makes every swap replay as
+    // exact output, which can't revert on the canned amounts
+    let amount_in = I256::try_from(SWAP_IN).unwrap();
+    let amount_out = -I256::try_from(SWAP_OUT).unwrap();
+    for swap in 0..4u8 {
+        let (amount0, amount1) = if swap % 2 == 0 {
+            (amount_in, amount_out)
+        } else {
+            (amount_out, amount_in)
+        };
+        push(
+            3 + u64::from(swap),
+            3 + swap,
+            0,
+            pool,
+            Event::Swap(SwapWithParams {
+                amount_out_minimum: None,
+                amount_in_maximum: None,
+                event: Swap {
+                    sender: account,
+                    recipient: account,
+                    amount0,
+                    amount1,
+                    sqrtPriceX96: sqrt_price_x96,
+                    liquidity: LIQUIDITY,
+                    tick: I24::ZERO,
+                },
+            }),
+        );
+    }
+
+    events
+}
@@ -1,42 +1,66 @@
-use std::{cmp::Ordering, fmt};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
 
-use alloy::primitives::{Address, TxHash, U256};
-use eyre::Result;
+use alloy::primitives::{aliases::U24, Address, TxHash, U256};
+use chrono::{DateTime, Utc};
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 
 use crate::abi::{
-    INonfungiblePositionManager::{Collect as CollectNpm, DecreaseLiquidity, IncreaseLiquidity},
+    INonfungiblePositionManager::{
+        Collect as CollectNpm, DecreaseLiquidity, IncreaseLiquidity, Transfer,
+    },
     IUniswapV3Factory::PoolCreated,
     UniswapV3Pool::{Burn, Collect as CollectPool, Initialize, Mint, Swap},
 };
+use crate::error::FeeAnalyzerError;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct IncreaseLiquidityWithParams {
     pub amount_0_desired: U256,
     pub amount_1_desired: U256,
+    // the original call's slippage bounds, zero when not given
+    pub amount_0_min: U256,
+    pub amount_1_min: U256,
     pub event: IncreaseLiquidity,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DecreaseLiquidityWithParams {
     pub amount_0_min: U256,
     pub amount_1_min: U256,
     pub event: DecreaseLiquidity,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+// the original swap call's slippage bound when known, the minimum output for
+// exact input swaps or the maximum input for exact output ones
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SwapWithParams {
+    pub amount_out_minimum: Option<U256>,
+    pub amount_in_maximum: Option<U256>,
+    pub event: Swap,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum Event {
     PoolCreated(PoolCreated),
     Mint(Mint),
     Burn(Burn),
-    Swap(Swap),
+    Swap(SwapWithParams),
     CollectPool(CollectPool),
     CollectNpm(CollectNpm),
     IncreaseLiquidity(IncreaseLiquidityWithParams),
     DecreaseLiquidity(DecreaseLiquidityWithParams),
     Initialize(Initialize),
+    Transfer(Transfer),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) enum EventType {
     PoolCreated,
     Mint,
@@ -47,15 +71,70 @@ pub(crate) enum EventType {
     IncreaseLiquidity,
     DecreaseLiquidity,
     Initialize,
+    Transfer,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl EventType {
+    #[cfg(test)]
+    pub(crate) const ALL: [EventType; 10] = [
+        EventType::PoolCreated,
+        EventType::Mint,
+        EventType::Burn,
+        EventType::Swap,
+        EventType::CollectPool,
+        EventType::CollectNpm,
+        EventType::IncreaseLiquidity,
+        EventType::DecreaseLiquidity,
+        EventType::Initialize,
+        EventType::Transfer,
+    ];
+    // set up the pool, replayed whatever is enabled
+    pub(crate) const SETUP: [EventType; 2] = [EventType::PoolCreated, EventType::Initialize];
+    // a mint or burn is replayed together with the position manager and
+    // collect events after it, so these are enabled all or none
+    pub(crate) const LIQUIDITY: [EventType; 6] = [
+        EventType::Mint,
+        EventType::Burn,
+        EventType::CollectPool,
+        EventType::CollectNpm,
+        EventType::IncreaseLiquidity,
+        EventType::DecreaseLiquidity,
+    ];
+}
+
+impl FromStr for EventType {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "pool_created" => Ok(EventType::PoolCreated),
+            "mint" => Ok(EventType::Mint),
+            "burn" => Ok(EventType::Burn),
+            "swap" => Ok(EventType::Swap),
+            "collect_pool" => Ok(EventType::CollectPool),
+            "collect_npm" => Ok(EventType::CollectNpm),
+            "increase_liquidity" => Ok(EventType::IncreaseLiquidity),
+            "decrease_liquidity" => Ok(EventType::DecreaseLiquidity),
+            "initialize" => Ok(EventType::Initialize),
+            "transfer" => Ok(EventType::Transfer),
+            _ => bail!(
+                "Unknown event type: {}, expected pool_created, mint, burn, swap, collect_pool, \
+                 collect_npm, increase_liquidity, decrease_liquidity, initialize, or transfer",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct SimulationEvent {
     pub block: u64,
     pub tx_hash: TxHash,
     pub log_index: u64,
     pub pool_address: Address,
     pub from: Address,
+    // the block's timestamp as exported, for turning blocks into time
+    pub block_time: DateTime<Utc>,
     pub event: Event,
 }
 
@@ -89,29 +168,366 @@ impl Event {
             Event::IncreaseLiquidity(_) => EventType::IncreaseLiquidity,
             Event::DecreaseLiquidity(_) => EventType::DecreaseLiquidity,
             Event::Initialize(_) => EventType::Initialize,
+            Event::Transfer(_) => EventType::Transfer,
+        }
+    }
+
+    // drops the original calls' slippage bounds so the replay runs with the
+    // permissive defaults
+    pub(crate) fn clear_min_amounts(&mut self) {
+        match self {
+            Event::Swap(e) => {
+                e.amount_out_minimum = None;
+                e.amount_in_maximum = None;
+            }
+            Event::IncreaseLiquidity(e) => {
+                e.amount_0_min = U256::ZERO;
+                e.amount_1_min = U256::ZERO;
+            }
+            Event::DecreaseLiquidity(e) => {
+                e.amount_0_min = U256::ZERO;
+                e.amount_1_min = U256::ZERO;
+            }
+            _ => {}
         }
     }
 }
 
 pub(crate) fn find_first_event(
-    events: &Vec<SimulationEvent>,
+    events: &[SimulationEvent],
     event_type: EventType,
 ) -> Result<SimulationEvent> {
     let event = events
         .iter()
         .find(|event| event.event.event_type() == event_type)
-        .ok_or_else(|| eyre::eyre!("Event not found"))?;
+        .ok_or_else(|| {
+            FeeAnalyzerError::EventOrdering(format!("No {:?} event found", event_type))
+        })?;
 
     Ok(event.clone())
 }
 
+// fails unless the liquidity events are all enabled or all disabled
+pub(crate) fn check_enabled_event_types(enabled: &HashSet<EventType>) -> Result<()> {
+    let (on, off): (Vec<_>, Vec<_>) = EventType::LIQUIDITY
+        .iter()
+        .partition(|event_type| enabled.contains(event_type));
+    if !on.is_empty() && !off.is_empty() {
+        bail!(
+            "ENABLED_EVENT_TYPES has {:?} but not {:?}, the liquidity events replay together",
+            on,
+            off
+        );
+    }
+    Ok(())
+}
+
+// drops the events whose type isn't enabled, keeping the setup events
+pub(crate) fn filter_event_types(
+    mut events: Vec<SimulationEvent>,
+    enabled: &HashSet<EventType>,
+) -> Vec<SimulationEvent> {
+    let before = events.len();
+    events.retain(|event| {
+        let event_type = event.event.event_type();
+        enabled.contains(&event_type) || EventType::SETUP.contains(&event_type)
+    });
+    if events.len() < before {
+        info!(
+            "Skipping {} events of types not in ENABLED_EVENT_TYPES",
+            before - events.len()
+        );
+    }
+    if !enabled.contains(&EventType::Mint) {
+        warn!(
+            "Liquidity events are disabled, no positions are replayed and there's no position pnl"
+        );
+    }
+    events
+}
+
+// suspicious hole in the event data, likely caused by an export that
+// missed a block range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EventGap {
+    // position changes happened over a long stretch of blocks without any swaps
+    NoSwaps { from_block: u64, to_block: u64 },
+    // a token id was modified before any mint for it was seen
+    UnknownTokenId { token_id: U256, block: u64 },
+}
+
+impl fmt::Display for EventGap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventGap::NoSwaps {
+                from_block,
+                to_block,
+            } => write!(
+                f,
+                "position changes but no swaps between blocks {} and {}",
+                from_block, to_block
+            ),
+            EventGap::UnknownTokenId { token_id, block } => write!(
+                f,
+                "token id {} modified at block {} but was never minted",
+                token_id, block
+            ),
+        }
+    }
+}
+
+// heuristic consistency check over sorted events, flags stretches longer than
+// `max_blocks_without_swap` that contain position changes but no swaps and
+// liquidity changes for token ids that were never minted
+pub(crate) fn find_event_gaps(
+    events: &[SimulationEvent],
+    max_blocks_without_swap: u64,
+) -> Vec<EventGap> {
+    let mut gaps = Vec::new();
+    let mut minted_token_ids = HashSet::new();
+    let mut last_swap_block: Option<u64> = None;
+    let mut position_change_since_swap = false;
+
+    for event in events {
+        match &event.event {
+            Event::Swap(_) => {
+                if let Some(from_block) = last_swap_block {
+                    if position_change_since_swap
+                        && event.block - from_block > max_blocks_without_swap
+                    {
+                        gaps.push(EventGap::NoSwaps {
+                            from_block,
+                            to_block: event.block,
+                        });
+                    }
+                }
+                last_swap_block = Some(event.block);
+                position_change_since_swap = false;
+            }
+            Event::IncreaseLiquidity(e) => {
+                // the first increase for a token id is the npm mint
+                minted_token_ids.insert(e.event.tokenId);
+                position_change_since_swap = true;
+            }
+            Event::DecreaseLiquidity(e) => {
+                if !minted_token_ids.contains(&e.event.tokenId) {
+                    gaps.push(EventGap::UnknownTokenId {
+                        token_id: e.event.tokenId,
+                        block: event.block,
+                    });
+                }
+                position_change_since_swap = true;
+            }
+            Event::CollectNpm(e) if !minted_token_ids.contains(&e.tokenId) => {
+                gaps.push(EventGap::UnknownTokenId {
+                    token_id: e.tokenId,
+                    block: event.block,
+                });
+            }
+            Event::Mint(_) | Event::Burn(_) => position_change_since_swap = true,
+            _ => {}
+        }
+    }
+
+    // position changes after the final swap
+    if let (Some(from_block), Some(last_event)) = (last_swap_block, events.last()) {
+        if position_change_since_swap && last_event.block - from_block > max_blocks_without_swap {
+            gaps.push(EventGap::NoSwaps {
+                from_block,
+                to_block: last_event.block,
+            });
+        }
+    }
+
+    for gap in &gaps {
+        warn!("Possible gap in event data: {}", gap);
+    }
+
+    gaps
+}
+
+// drops repeated copies of a log from sorted events, as left by overlapping
+// exports. a log is identified by its block and log index, so two events
+// sharing those with different transactions mean the export caught a block
+// that was later reorged out. which one is canonical can't be told from the
+// input alone, so that fails instead of replaying both
+pub(crate) fn dedup_events(events: Vec<SimulationEvent>) -> Result<Vec<SimulationEvent>> {
+    let mut deduped: Vec<SimulationEvent> = Vec::with_capacity(events.len());
+    let mut dropped = 0;
+    for event in events {
+        if let Some(previous) = deduped.last() {
+            if previous.block == event.block && previous.log_index == event.log_index {
+                if previous.tx_hash != event.tx_hash || previous.event != event.event {
+                    bail!(FeeAnalyzerError::EventOrdering(format!(
+                        "Block {} log index {} appears in transactions {} and {}, the input likely \
+                         holds logs from a reorged block, re-export it once the block is final",
+                        event.block, event.log_index, previous.tx_hash, event.tx_hash
+                    )));
+                }
+                dropped += 1;
+                continue;
+            }
+        }
+        deduped.push(event);
+    }
+    if dropped > 0 {
+        warn!("Dropped {} duplicated events", dropped);
+    }
+    Ok(deduped)
+}
+
+// identifies one pool among the pools in the input events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PoolKey {
+    pub token0: Address,
+    pub token1: Address,
+    pub fee: U24,
+    pub pool: Address,
+}
+
+impl fmt::Display for PoolKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}/{}, fee {})",
+            self.pool, self.token0, self.token1, self.fee
+        )
+    }
+}
+
+// splits sorted events into one event list per pool, ordered by pool creation.
+// pool events are routed by the emitting pool's address and pool created
+// events by the pool they created. position manager events are emitted by the
+// position manager, so they go to the pool of the last pool event in the same
+// transaction (its mint, burn, or collect always comes first). nft transfers
+// go to the pool their token id was minted in, transfers of token ids minted
+// in pools outside the input are dropped.
+pub(crate) fn split_events_by_pool(
+    events: Vec<SimulationEvent>,
+) -> Result<Vec<(PoolKey, Vec<SimulationEvent>)>> {
+    let mut pools: Vec<(PoolKey, Vec<SimulationEvent>)> = Vec::new();
+    let mut pool_index: HashMap<Address, usize> = HashMap::new();
+    for event in &events {
+        if let Event::PoolCreated(e) = &event.event {
+            if pool_index.contains_key(&e.pool) {
+                bail!(FeeAnalyzerError::EventOrdering(format!(
+                    "Pool {} was created more than once",
+                    e.pool
+                )));
+            }
+            pool_index.insert(e.pool, pools.len());
+            pools.push((
+                PoolKey {
+                    token0: e.token0,
+                    token1: e.token1,
+                    fee: e.fee,
+                    pool: e.pool,
+                },
+                Vec::new(),
+            ));
+        }
+    }
+
+    let mut last_pool_in_tx: Option<(TxHash, Address)> = None;
+    let mut token_id_pools: HashMap<U256, Address> = HashMap::new();
+    let mut dropped_transfers = 0;
+    for event in events {
+        let pool = match &event.event {
+            Event::PoolCreated(e) => e.pool,
+            Event::Transfer(e) => match (token_id_pools.get(&e.tokenId), last_pool_in_tx) {
+                (Some(pool), _) => *pool,
+                // a mint's transfer sits between the pool mint and the increase
+                (None, Some((tx_hash, pool))) if tx_hash == event.tx_hash => pool,
+                _ => {
+                    dropped_transfers += 1;
+                    continue;
+                }
+            },
+            Event::IncreaseLiquidity(_) | Event::DecreaseLiquidity(_) | Event::CollectNpm(_) => {
+                match last_pool_in_tx {
+                    Some((tx_hash, pool)) if tx_hash == event.tx_hash => pool,
+                    _ => bail!(FeeAnalyzerError::EventOrdering(format!(
+                        "No pool event before the position manager event in tx {}",
+                        event.tx_hash
+                    ))),
+                }
+            }
+            _ => event.pool_address,
+        };
+        last_pool_in_tx = Some((event.tx_hash, pool));
+        if let Event::IncreaseLiquidity(e) = &event.event {
+            token_id_pools.insert(e.event.tokenId, pool);
+        }
+
+        let index = *pool_index.get(&pool).ok_or_else(|| {
+            FeeAnalyzerError::EventOrdering(format!(
+                "Events for pool {} without a pool created event",
+                pool
+            ))
+        })?;
+        pools[index].1.push(event);
+    }
+    if dropped_transfers > 0 {
+        info!(
+            "Dropped {} transfers of token ids from pools outside the input",
+            dropped_transfers
+        );
+    }
+
+    Ok(pools)
+}
+
+// nft transfers by original token id in log order, kept apart from the replayed
+// events since they only decide who the records opened after them belong to
+#[derive(Debug, Default)]
+pub(crate) struct PositionOwners {
+    transfers: HashMap<U256, Vec<SimulationEvent>>,
+}
+
+impl PositionOwners {
+    // moves the transfer events out of sorted `events`
+    pub(crate) fn take_from(events: &mut Vec<SimulationEvent>) -> Self {
+        let mut transfers: HashMap<U256, Vec<SimulationEvent>> = HashMap::new();
+        events.retain(|event| match &event.event {
+            Event::Transfer(e) => {
+                transfers.entry(e.tokenId).or_default().push(event.clone());
+                false
+            }
+            _ => true,
+        });
+        Self { transfers }
+    }
+
+    pub(crate) fn transfer_count(&self) -> usize {
+        self.transfers.values().map(Vec::len).sum()
+    }
+
+    // the token id's holder as of `event`, counting every transfer in the same
+    // transaction so a mint picks up its own transfer. None when the input has
+    // no transfers for the token id
+    pub(crate) fn owner_at(&self, token_id: U256, event: &SimulationEvent) -> Option<Address> {
+        self.transfers
+            .get(&token_id)?
+            .iter()
+            .take_while(|transfer| *transfer <= event || transfer.tx_hash == event.tx_hash)
+            .filter_map(|transfer| match &transfer.event {
+                // burning the nft leaves the last holder as the owner
+                Event::Transfer(e) if e.to != Address::ZERO => Some(e.to),
+                _ => None,
+            })
+            .last()
+    }
+}
+
 impl TryFrom<SimulationEvent> for PoolCreated {
     type Error = eyre::Report;
 
     fn try_from(event: SimulationEvent) -> eyre::Result<Self> {
         match event.event {
             Event::PoolCreated(e) => Ok(e),
-            _ => Err(eyre::eyre!("Event is not PoolCreated")),
+            _ => {
+                Err(FeeAnalyzerError::EventOrdering("Event is not PoolCreated".to_string()).into())
+            }
         }
     }
 }
@@ -122,7 +538,7 @@ impl TryFrom<SimulationEvent> for Mint {
     fn try_from(event: SimulationEvent) -> eyre::Result<Self> {
         match event.event {
             Event::Mint(e) => Ok(e),
-            _ => Err(eyre::eyre!("Event is not Mint")),
+            _ => Err(FeeAnalyzerError::EventOrdering("Event is not Mint".to_string()).into()),
         }
     }
 }
@@ -133,7 +549,7 @@ impl TryFrom<SimulationEvent> for Burn {
     fn try_from(event: SimulationEvent) -> eyre::Result<Self> {
         match event.event {
             Event::Burn(e) => Ok(e),
-            _ => Err(eyre::eyre!("Event is not Burn")),
+            _ => Err(FeeAnalyzerError::EventOrdering("Event is not Burn".to_string()).into()),
         }
     }
 }
@@ -143,8 +559,8 @@ impl TryFrom<SimulationEvent> for Swap {
 
     fn try_from(event: SimulationEvent) -> eyre::Result<Self> {
         match event.event {
-            Event::Swap(e) => Ok(e),
-            _ => Err(eyre::eyre!("Event is not Swap")),
+            Event::Swap(e) => Ok(e.event),
+            _ => Err(FeeAnalyzerError::EventOrdering("Event is not Swap".to_string()).into()),
         }
     }
 }
@@ -155,7 +571,9 @@ impl TryFrom<SimulationEvent> for CollectPool {
     fn try_from(event: SimulationEvent) -> eyre::Result<Self> {
         match event.event {
             Event::CollectPool(e) => Ok(e),
-            _ => Err(eyre::eyre!("Event is not CollectPool")),
+            _ => {
+                Err(FeeAnalyzerError::EventOrdering("Event is not CollectPool".to_string()).into())
+            }
         }
     }
 }
@@ -166,7 +584,7 @@ impl TryFrom<SimulationEvent> for Initialize {
     fn try_from(event: SimulationEvent) -> eyre::Result<Self> {
         match event.event {
             Event::Initialize(e) => Ok(e),
-            _ => Err(eyre::eyre!("Event is not Initialize")),
+            _ => Err(FeeAnalyzerError::EventOrdering("Event is not Initialize".to_string()).into()),
         }
     }
 }
@@ -177,7 +595,7 @@ impl TryFrom<SimulationEvent> for CollectNpm {
     fn try_from(event: SimulationEvent) -> eyre::Result<Self> {
         match event.event {
             Event::CollectNpm(e) => Ok(e),
-            _ => Err(eyre::eyre!("Event is not CollectNpm")),
+            _ => Err(FeeAnalyzerError::EventOrdering("Event is not CollectNpm".to_string()).into()),
         }
     }
 }
@@ -188,7 +606,10 @@ impl TryFrom<SimulationEvent> for IncreaseLiquidityWithParams {
     fn try_from(event: SimulationEvent) -> eyre::Result<Self> {
         match event.event {
             Event::IncreaseLiquidity(e) => Ok(e),
-            _ => Err(eyre::eyre!("Event is not IncreaseLiquidity")),
+            _ => Err(
+                FeeAnalyzerError::EventOrdering("Event is not IncreaseLiquidity".to_string())
+                    .into(),
+            ),
         }
     }
 }
@@ -199,7 +620,10 @@ impl TryFrom<SimulationEvent> for DecreaseLiquidityWithParams {
     fn try_from(event: SimulationEvent) -> eyre::Result<Self> {
         match event.event {
             Event::DecreaseLiquidity(e) => Ok(e),
-            _ => Err(eyre::eyre!("Event is not DecreaseLiquidity")),
+            _ => Err(
+                FeeAnalyzerError::EventOrdering("Event is not DecreaseLiquidity".to_string())
+                    .into(),
+            ),
         }
     }
 }
@@ -247,3 +671,479 @@ impl fmt::Display for IncreaseLiquidityWithParams {
         )
     }
 }
+
+// one event of every type as a pool's first blocks would export them, in
+// block and log index order
+#[cfg(test)]
+pub(crate) fn one_event_of_each_type() -> Vec<SimulationEvent> {
+    use alloy::primitives::{aliases::I24, I256, U160};
+
+    let factory = Address::repeat_byte(0x33);
+    let pool = Address::repeat_byte(0xfd);
+    let npm = Address::repeat_byte(0x03);
+    let token = Address::repeat_byte(0x2f);
+    let weth = Address::repeat_byte(0x42);
+    let lp = Address::repeat_byte(0x88);
+    let (lower, upper) = (
+        I24::try_from(-230_400).unwrap(),
+        I24::try_from(887_200).unwrap(),
+    );
+    let event = |block: u64, log_index: u64, pool_address: Address, event: Event| SimulationEvent {
+        block,
+        tx_hash: TxHash::repeat_byte(block as u8),
+        log_index,
+        pool_address,
+        from: lp,
+        block_time: DateTime::from_timestamp(1_734_325_313 + 2 * block as i64, 0).unwrap(),
+        event,
+    };
+    vec![
+        event(
+            1,
+            0,
+            factory,
+            Event::PoolCreated(PoolCreated {
+                token0: token,
+                token1: weth,
+                fee: U24::from(10_000),
+                tickSpacing: I24::try_from(200).unwrap(),
+                pool,
+            }),
+        ),
+        event(
+            1,
+            1,
+            pool,
+            Event::Initialize(Initialize {
+                sqrtPriceX96: U160::from(787_149_618_249_685_149_291_181_u128),
+                tick: lower,
+            }),
+        ),
+        event(
+            1,
+            2,
+            pool,
+            Event::Mint(Mint {
+                sender: npm,
+                owner: npm,
+                tickLower: lower,
+                tickUpper: upper,
+                amount: 993_522_496_634_912_801_749_467,
+                amount0: U256::from(99_999_999_999_999_999_999_u128),
+                amount1: U256::ZERO,
+            }),
+        ),
+        event(
+            1,
+            3,
+            npm,
+            Event::IncreaseLiquidity(IncreaseLiquidityWithParams {
+                amount_0_desired: U256::from(100_000_000_000_000_000_000_u128),
+                amount_1_desired: U256::ZERO,
+                amount_0_min: U256::from(1),
+                amount_1_min: U256::ZERO,
+                event: IncreaseLiquidity {
+                    tokenId: U256::from(1_506_018),
+                    liquidity: 993_522_496_634_912_801_749_467,
+                    amount0: U256::from(99_999_999_999_999_999_999_u128),
+                    amount1: U256::ZERO,
+                },
+            }),
+        ),
+        event(
+            1,
+            4,
+            npm,
+            Event::Transfer(Transfer {
+                from: Address::ZERO,
+                to: lp,
+                tokenId: U256::from(1_506_018),
+            }),
+        ),
+        event(
+            2,
+            0,
+            pool,
+            Event::Swap(SwapWithParams {
+                amount_out_minimum: Some(U256::from(4_700_000_000_000_000_000_u128)),
+                amount_in_maximum: None,
+                event: Swap {
+                    sender: lp,
+                    recipient: lp,
+                    amount0: I256::try_from(-4_745_754_616_555_460_023_i128).unwrap(),
+                    amount1: I256::try_from(496_753_750_000_000_000_i128).unwrap(),
+                    sqrtPriceX96: U160::from(826_366_966_722_612_871_717_728_u128),
+                    liquidity: 993_522_496_634_912_801_749_467,
+                    tick: I24::try_from(-229_428).unwrap(),
+                },
+            }),
+        ),
+        event(
+            3,
+            0,
+            pool,
+            Event::Burn(Burn {
+                owner: npm,
+                tickLower: lower,
+                tickUpper: upper,
+                amount: 993_522_496_634_912_801_749_467,
+                amount0: U256::from(95_254_245_383_444_539_976_u128),
+                amount1: U256::from(491_786_212_500_000_000_u128),
+            }),
+        ),
+        event(
+            3,
+            1,
+            npm,
+            Event::DecreaseLiquidity(DecreaseLiquidityWithParams {
+                amount_0_min: U256::ZERO,
+                amount_1_min: U256::from(2),
+                event: DecreaseLiquidity {
+                    tokenId: U256::from(1_506_018),
+                    liquidity: 993_522_496_634_912_801_749_467,
+                    amount0: U256::from(95_254_245_383_444_539_976_u128),
+                    amount1: U256::from(491_786_212_500_000_000_u128),
+                },
+            }),
+        ),
+        event(
+            3,
+            2,
+            pool,
+            Event::CollectPool(CollectPool {
+                owner: npm,
+                recipient: lp,
+                tickLower: lower,
+                tickUpper: upper,
+                amount0: 95_254_245_383_444_539_976,
+                amount1: 496_753_750_000_000_000,
+            }),
+        ),
+        event(
+            3,
+            3,
+            npm,
+            Event::CollectNpm(CollectNpm {
+                tokenId: U256::from(1_506_018),
+                recipient: lp,
+                amount0: U256::from(95_254_245_383_444_539_976_u128),
+                amount1: U256::from(496_753_750_000_000_000_u128),
+            }),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::{aliases::I24, I256, U160};
+
+    use super::*;
+
+    fn event(block: u64, event: Event) -> SimulationEvent {
+        SimulationEvent {
+            block,
+            tx_hash: TxHash::ZERO,
+            log_index: 0,
+            pool_address: Address::ZERO,
+            from: Address::ZERO,
+            block_time: DateTime::UNIX_EPOCH,
+            event,
+        }
+    }
+
+    fn swap() -> Event {
+        Event::Swap(SwapWithParams {
+            amount_out_minimum: None,
+            amount_in_maximum: None,
+            event: Swap {
+                sender: Address::ZERO,
+                recipient: Address::ZERO,
+                amount0: I256::ZERO,
+                amount1: I256::ZERO,
+                sqrtPriceX96: U160::ZERO,
+                liquidity: 0,
+                tick: I24::ZERO,
+            },
+        })
+    }
+
+    fn burn() -> Event {
+        Event::Burn(Burn {
+            owner: Address::ZERO,
+            tickLower: I24::ZERO,
+            tickUpper: I24::ZERO,
+            amount: 0,
+            amount0: U256::ZERO,
+            amount1: U256::ZERO,
+        })
+    }
+
+    fn increase(token_id: u64) -> Event {
+        Event::IncreaseLiquidity(IncreaseLiquidityWithParams {
+            amount_0_desired: U256::ZERO,
+            amount_1_desired: U256::ZERO,
+            amount_0_min: U256::ZERO,
+            amount_1_min: U256::ZERO,
+            event: IncreaseLiquidity {
+                tokenId: U256::from(token_id),
+                liquidity: 0,
+                amount0: U256::ZERO,
+                amount1: U256::ZERO,
+            },
+        })
+    }
+
+    fn decrease(token_id: u64) -> Event {
+        Event::DecreaseLiquidity(DecreaseLiquidityWithParams {
+            amount_0_min: U256::ZERO,
+            amount_1_min: U256::ZERO,
+            event: DecreaseLiquidity {
+                tokenId: U256::from(token_id),
+                liquidity: 0,
+                amount0: U256::ZERO,
+                amount1: U256::ZERO,
+            },
+        })
+    }
+
+    fn collect(token_id: u64) -> Event {
+        Event::CollectNpm(CollectNpm {
+            tokenId: U256::from(token_id),
+            recipient: Address::ZERO,
+            amount0: U256::ZERO,
+            amount1: U256::ZERO,
+        })
+    }
+
+    #[test]
+    fn repeated_logs_are_dropped_and_reorged_ones_fail() {
+        let events = one_event_of_each_type();
+        let mut overlapping = [events.clone(), events[5..].to_vec()].concat();
+        overlapping.sort();
+        assert_eq!(dedup_events(overlapping).unwrap(), events);
+
+        // the swap's log again from another transaction
+        let mut reorged = events[5].clone();
+        reorged.tx_hash = TxHash::repeat_byte(0xee);
+        let mut with_reorged = [events.clone(), vec![reorged]].concat();
+        with_reorged.sort();
+        let error = dedup_events(with_reorged).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FeeAnalyzerError>(),
+            Some(FeeAnalyzerError::EventOrdering(_))
+        ));
+        assert!(error.to_string().contains("Block 2 log index 0"));
+    }
+
+    #[test]
+    fn two_fee_tiers_of_a_pair_split_into_their_own_pools() {
+        let one_percent = one_event_of_each_type();
+        let pool = one_percent[1].pool_address;
+        let other_pool = Address::repeat_byte(0xfe);
+        let other_token_id = U256::from(2_000_000);
+        // the same events in the pair's 0.3% pool, each block's after the
+        // 1% pool's
+        let point_three_percent: Vec<SimulationEvent> = one_percent
+            .iter()
+            .cloned()
+            .map(|mut event| {
+                event.tx_hash = TxHash::repeat_byte(0x10 + event.block as u8);
+                event.log_index += 10;
+                if event.pool_address == pool {
+                    event.pool_address = other_pool;
+                }
+                match &mut event.event {
+                    Event::PoolCreated(e) => {
+                        e.fee = U24::from(3_000);
+                        e.tickSpacing = I24::try_from(60).unwrap();
+                        e.pool = other_pool;
+                    }
+                    Event::IncreaseLiquidity(e) => e.event.tokenId = other_token_id,
+                    Event::DecreaseLiquidity(e) => e.event.tokenId = other_token_id,
+                    Event::CollectNpm(e) => e.tokenId = other_token_id,
+                    Event::Transfer(e) => e.tokenId = other_token_id,
+                    _ => {}
+                }
+                event
+            })
+            .collect();
+        let mut events = [one_percent.clone(), point_three_percent.clone()].concat();
+        events.sort();
+
+        let pools = split_events_by_pool(events).unwrap();
+        let [(one_percent_key, one_percent_events), (point_three_key, point_three_events)] =
+            pools.as_slice()
+        else {
+            panic!("expected two pools, got {}", pools.len());
+        };
+        assert_eq!(
+            (one_percent_key.pool, one_percent_key.fee),
+            (pool, U24::from(10_000))
+        );
+        assert_eq!(
+            (point_three_key.pool, point_three_key.fee),
+            (other_pool, U24::from(3_000))
+        );
+        assert_eq!(
+            (one_percent_key.token0, one_percent_key.token1),
+            (point_three_key.token0, point_three_key.token1)
+        );
+        assert_eq!(one_percent_events, &one_percent);
+        assert_eq!(point_three_events, &point_three_percent);
+    }
+
+    #[test]
+    fn the_owner_follows_a_transfer_between_two_increases() {
+        let mut events = one_event_of_each_type();
+        let first_increase = events[3].clone();
+        let Event::Transfer(minted) = events[4].event.clone() else {
+            panic!("expected the fixture's mint transfer");
+        };
+        let buyer = Address::repeat_byte(0x77);
+        // the position is sold after the swap, then the buyer adds to it
+        let sale = SimulationEvent {
+            block: 2,
+            log_index: 1,
+            tx_hash: TxHash::repeat_byte(0x21),
+            event: Event::Transfer(Transfer {
+                from: minted.to,
+                to: buyer,
+                tokenId: minted.tokenId,
+            }),
+            ..events[4].clone()
+        };
+        let second_increase = SimulationEvent {
+            block: 2,
+            log_index: 2,
+            tx_hash: TxHash::repeat_byte(0x22),
+            ..first_increase.clone()
+        };
+        events.extend([sale, second_increase.clone()]);
+        events.sort();
+
+        let owners = PositionOwners::take_from(&mut events);
+        assert_eq!(owners.transfer_count(), 2);
+        assert!(!events
+            .iter()
+            .any(|event| event.event.event_type() == EventType::Transfer));
+
+        // the mint's own transfer comes after its increase in the same tx
+        assert_eq!(
+            owners.owner_at(minted.tokenId, &first_increase),
+            Some(minted.to)
+        );
+        assert_eq!(
+            owners.owner_at(minted.tokenId, &second_increase),
+            Some(buyer)
+        );
+        let burn = events.last().unwrap();
+        assert_eq!(owners.owner_at(minted.tokenId, burn), Some(buyer));
+        assert_eq!(owners.owner_at(U256::from(1), burn), None);
+    }
+
+    #[test]
+    fn unenforced_bounds_are_cleared_to_permissive() {
+        let mut events = one_event_of_each_type();
+        for event in &mut events {
+            event.event.clear_min_amounts();
+        }
+        let Event::Swap(swap) = &events[5].event else {
+            panic!("expected the fixture's swap");
+        };
+        assert_eq!(
+            (swap.amount_out_minimum, swap.amount_in_maximum),
+            (None, None)
+        );
+        let Event::IncreaseLiquidity(increase) = &events[3].event else {
+            panic!("expected the fixture's increase");
+        };
+        assert_eq!(
+            (increase.amount_0_min, increase.amount_1_min),
+            (U256::ZERO, U256::ZERO)
+        );
+    }
+
+    #[test]
+    fn swaps_only_keeps_the_price_path_without_positions() {
+        let enabled = HashSet::from([EventType::Swap]);
+        check_enabled_event_types(&enabled).unwrap();
+        let events = one_event_of_each_type();
+        let filtered = filter_event_types(events.clone(), &enabled);
+
+        // the setup events always replay, then only the swap
+        assert_eq!(
+            filtered,
+            vec![events[0].clone(), events[1].clone(), events[5].clone()]
+        );
+        // nothing mints a position
+        assert!(!filtered.iter().any(|event| {
+            EventType::LIQUIDITY.contains(&event.event.event_type())
+                || event.event.event_type() == EventType::Transfer
+        }));
+        // and the swap still carries the pool's price after it
+        let Event::Swap(swap) = &filtered[2].event else {
+            panic!("expected the swap");
+        };
+        assert_eq!(swap.event.tick, I24::try_from(-229428).unwrap());
+
+        // the liquidity events can't be split up
+        assert!(
+            check_enabled_event_types(&HashSet::from([EventType::Swap, EventType::Mint])).is_err()
+        );
+    }
+
+    #[test]
+    fn finds_stretches_of_position_changes_without_swaps() {
+        let events = [
+            event(100, swap()),
+            event(105, burn()),
+            event(120, swap()),
+            // no position changes in between
+            event(200, swap()),
+            // exactly the limit
+            event(205, burn()),
+            event(210, swap()),
+            // after the final swap
+            event(215, burn()),
+            event(221, burn()),
+        ];
+        assert_eq!(
+            find_event_gaps(&events, 10),
+            [
+                EventGap::NoSwaps {
+                    from_block: 100,
+                    to_block: 120
+                },
+                EventGap::NoSwaps {
+                    from_block: 210,
+                    to_block: 221
+                },
+            ]
+        );
+        assert!(find_event_gaps(&events, 20).is_empty());
+    }
+
+    #[test]
+    fn finds_token_ids_that_were_never_minted() {
+        let events = [
+            event(1, increase(1)),
+            event(2, decrease(1)),
+            event(3, collect(1)),
+            event(4, decrease(2)),
+            event(5, collect(3)),
+        ];
+        assert_eq!(
+            find_event_gaps(&events, 10),
+            [
+                EventGap::UnknownTokenId {
+                    token_id: U256::from(2),
+                    block: 4
+                },
+                EventGap::UnknownTokenId {
+                    token_id: U256::from(3),
+                    block: 5
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use std::path::Path;
+
+use alloy::primitives::Address;
+use chrono::Utc;
+use eyre::{bail, eyre, Context, Result};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
+
+use crate::chain_interactions::collect::PositionInfo;
+
+use super::{
+    csv_output_writer::{position_csv_columns, position_csv_row, POSITION_CSV_SCHEMA_VERSION},
+    simulation_events::PoolKey,
+};
+
+// position columns stored as numbers so they compare and sort in sql. the
+// rest are text as in the csv, amounts because they can overflow an i64
+const INTEGER_COLUMNS: &[&str] = &[
+    "token_action_index",
+    "lower_tick",
+    "upper_tick",
+    "opening_block",
+    "tick_in",
+    "closing_block",
+    "tick_out",
+    "gas_used_in",
+    "gas_used_out",
+    "holding_seconds",
+    "holding_blocks",
+];
+const REAL_COLUMNS: &[&str] = &[
+    "fees_to_volume",
+    "token_fee_apr",
+    "weth_fee_apr",
+    "fee_apr_in_weth",
+    "fees_per_weth_block",
+];
+
+// the run a pool's records are written under, with what the runs table
+// records about it
+pub(crate) struct SqliteRun {
+    pub run_id: String,
+    pub pool: PoolKey,
+    pub denomination_token: Address,
+    pub output_csv: String,
+}
+
+fn column_type(column: &str) -> &'static str {
+    if INTEGER_COLUMNS.contains(&column) {
+        "INTEGER"
+    } else if REAL_COLUMNS.contains(&column) {
+        "REAL"
+    } else {
+        "TEXT"
+    }
+}
+
+fn column_value(column: &str, value: String) -> Result<Value> {
+    Ok(match column_type(column) {
+        "INTEGER" => Value::Integer(
+            value
+                .parse()
+                .with_context(|| format!("Column {} value {:?}", column, value))?,
+        ),
+        "REAL" => Value::Real(
+            value
+                .parse()
+                .with_context(|| format!("Column {} value {:?}", column, value))?,
+        ),
+        _ => Value::Text(value),
+    })
+}
+
+// appends the run's position records to the `positions` table of the
+// database at `db_path`, one row per record with the positions csv's columns
+// and the run id, and the run to the `runs` table. creates the database and
+// tables on first use. fails on a run id the database already has, and on a
+// database written with another positions csv schema version
+pub(crate) fn write_positions_to_sqlite(
+    positions: &[PositionInfo],
+    db_path: &Path,
+    run: &SqliteRun,
+    timed_out: bool,
+) -> Result<()> {
+    if let Some(parent) = db_path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    let mut connection = Connection::open(db_path)
+        .with_context(|| format!("Failed to open sqlite database {:?}", db_path))?;
+    let columns = position_csv_columns().map_err(|e| eyre!("{}", e))?;
+
+    let transaction = connection.transaction()?;
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS runs (
+            run_id TEXT PRIMARY KEY,
+            created_at TEXT NOT NULL,
+            crate_version TEXT NOT NULL,
+            schema_version INTEGER NOT NULL,
+            pool_address TEXT NOT NULL,
+            fee_tier INTEGER NOT NULL,
+            denomination_token TEXT NOT NULL,
+            output_csv TEXT NOT NULL,
+            timed_out INTEGER NOT NULL,
+            position_records INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    let other_schema: Option<u32> = transaction
+        .query_row(
+            "SELECT schema_version FROM runs WHERE schema_version != ?1 LIMIT 1",
+            [POSITION_CSV_SCHEMA_VERSION],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(other_schema) = other_schema {
+        bail!(
+            "Sqlite database {:?} holds runs with positions schema version {}, this build writes {}",
+            db_path,
+            other_schema,
+            POSITION_CSV_SCHEMA_VERSION
+        );
+    }
+    transaction.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS positions (
+                run_id TEXT NOT NULL REFERENCES runs(run_id),
+                {},
+                PRIMARY KEY (run_id, token_id, token_action_index)
+            )",
+            columns
+                .iter()
+                .map(|column| format!("{} {}", column, column_type(column)))
+                .collect::<Vec<_>>()
+                .join(",\n")
+        ),
+        [],
+    )?;
+
+    let inserted = transaction.execute(
+        "INSERT OR IGNORE INTO runs VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            run.run_id,
+            Utc::now().to_rfc3339(),
+            env!("CARGO_PKG_VERSION"),
+            POSITION_CSV_SCHEMA_VERSION,
+            run.pool.pool.to_string(),
+            run.pool.fee.to::<u32>(),
+            run.denomination_token.to_string(),
+            run.output_csv,
+            timed_out,
+            positions.len(),
+        ],
+    )?;
+    if inserted == 0 {
+        bail!(
+            "Sqlite database {:?} already has a run {:?}, set a distinct SQLITE_RUN_ID",
+            db_path,
+            run.run_id
+        );
+    }
+
+    {
+        let mut insert = transaction.prepare(&format!(
+            "INSERT INTO positions (run_id, {}) VALUES (?1, {})",
+            columns.join(", "),
+            (2..=columns.len() + 1)
+                .map(|i| format!("?{}", i))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))?;
+        for position in positions {
+            let row = position_csv_row(position.clone()).map_err(|e| eyre!("{}", e))?;
+            let values = std::iter::once(Ok(Value::Text(run.run_id.to_string())))
+                .chain(
+                    columns
+                        .iter()
+                        .zip(row)
+                        .map(|(column, value)| column_value(column, value)),
+                )
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| {
+                    format!(
+                        "Token id {} record {}",
+                        position.original_token_id, position.index
+                    )
+                })?;
+            insert.execute(params_from_iter(values))?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::aliases::U24;
+
+    use super::*;
+    use crate::chain_interactions::backend::mock;
+
+    fn run(run_id: &str) -> SqliteRun {
+        SqliteRun {
+            run_id: run_id.to_string(),
+            pool: PoolKey {
+                token0: mock::TOKEN,
+                token1: mock::WETH,
+                fee: U24::from(10_000),
+                pool: Address::repeat_byte(0x55),
+            },
+            denomination_token: mock::WETH,
+            output_csv: format!("{}.csv", run_id),
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_append_their_positions_under_their_run_id() {
+        let db_path =
+            std::env::temp_dir().join(format!("sqlite_output_runs_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        let first = [mock::record(1, 0).await, mock::record(1, 1).await];
+        let second = [mock::record(2, 0).await];
+
+        write_positions_to_sqlite(&first, &db_path, &run("first"), false).unwrap();
+        write_positions_to_sqlite(&second, &db_path, &run("second"), true).unwrap();
+        let err = write_positions_to_sqlite(&second, &db_path, &run("second"), false).unwrap_err();
+        assert!(err.to_string().contains("already has a run \"second\""));
+
+        let connection = Connection::open(&db_path).unwrap();
+        let runs = connection
+            .prepare(
+                "SELECT run_id, fee_tier, output_csv, timed_out, position_records
+                FROM runs ORDER BY run_id",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, bool>(3)?,
+                    row.get::<_, usize>(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            runs,
+            [
+                (
+                    "first".to_string(),
+                    10_000,
+                    "first.csv".to_string(),
+                    false,
+                    2
+                ),
+                (
+                    "second".to_string(),
+                    10_000,
+                    "second.csv".to_string(),
+                    true,
+                    1
+                ),
+            ]
+        );
+
+        let positions = connection
+            .prepare(
+                "SELECT run_id, token_id, token_action_index, lower_tick, upper_tick
+                FROM positions ORDER BY run_id, token_action_index",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            positions,
+            [
+                ("first".to_string(), "1".to_string(), 0, -600, 600),
+                ("first".to_string(), "1".to_string(), 1, -600, 600),
+                ("second".to_string(), "2".to_string(), 0, -600, 600),
+            ]
+        );
+        std::fs::remove_file(&db_path).unwrap();
+    }
+}
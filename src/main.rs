@@ -1,11 +1,18 @@
-use alloy::primitives::Address;
+use alloy::primitives::aliases::I24;
+use clap::Parser;
+use cli::{Cli, Command};
 use eyre::{Result, WrapErr};
-use fee_analyzer::{csv_input_reader::CSVReaderConfig, PoolAnalyzer, PoolAnalyzerConfig};
+use fee_analyzer::{
+    csv_input_reader::validate_csv, export_events, position_diff::diff_position_csvs, run_pools,
+    summarize_events, PoolAnalyzer,
+};
 use tracing::info;
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
 mod abi;
 mod chain_interactions;
+mod cli;
+mod error;
 mod fee_analyzer;
 
 #[tokio::main]
@@ -23,100 +30,42 @@ async fn main() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber)
         .context("Failed to set tracing subscriber")?;
 
-    // get http urls
-    let http_url = std::env::var("HTTP_URL").expect("HTTP_URL is required");
-
-    // get deployed addresses
-    let uniswap_v3_factory_address: Address = std::env::var("UNISWAP_V3_FACTORY_ADDRESS")
-        .expect("UNISWAP_V3_FACTORY_ADDRESS is required")
-        .parse()
-        .expect("UNISWAP_V3_FACTORY_ADDRESS must be a valid address");
-
-    let uniswap_v3_position_manager_address: Address =
-        std::env::var("UNISWAP_V3_POSITION_MANAGER_ADDRESS")
-            .expect("UNISWAP_V3_POSITION_MANAGER_ADDRESS is required")
-            .parse()
-            .expect("UNISWAP_V3_POSITION_MANAGER_ADDRESS must be a valid address");
-
-    let uniswap_v3_swap_router_address: Address = std::env::var("UNISWAP_V3_SWAP_ROUTER_ADDRESS")
-        .expect("UNISWAP_V3_SWAP_ROUTER_ADDRESS is required")
-        .parse()
-        .expect("UNISWAP_V3_SWAP_ROUTER_ADDRESS must be a valid address");
-
-    let uniswap_v3_quoter_address: Address = std::env::var("UNISWAP_V3_QUOTER_ADDRESS")
-        .expect("UNISWAP_V3_QUOTER_ADDRESS is required")
-        .parse()
-        .expect("UNISWAP_V3_QUOTER_ADDRESS must be a valid address");
-
-    let weth_address: Address = std::env::var("WETH_ADDRESS")
-        .expect("WETH_ADDRESS is required")
-        .parse()
-        .expect("WETH_ADDRESS must be a valid address");
-
-    let fork_block = std::env::var("BLOCK_FORK_NUMBER")
-        .expect("FORK_BLOCK is required")
-        .parse()
-        .expect("FORK_BLOCK must be a valid number");
-
-    // read csv file paths
-    let initialize_events_path =
-        std::env::var("INITIALIZE_CSV_FILE_PATH").expect("INITIALIZE_CSV_FILE_PATH is required");
-
-    let swap_events_path =
-        std::env::var("SWAP_CSV_FILE_PATH").expect("SWAP_CSV_FILE_PATH is required");
-
-    let mint_events_path =
-        std::env::var("MINT_CSV_FILE_PATH").expect("MINT_CSV_FILE_PATH is required");
-
-    let burn_events_path =
-        std::env::var("BURN_CSV_FILE_PATH").expect("BURN_CSV_FILE_PATH is required");
-
-    let pool_created_events_path = std::env::var("POOL_CREATED_CSV_FILE_PATH")
-        .expect("POOL_CREATED_CSV_FILE_PATH is required");
-
-    let collect_pool_events_path = std::env::var("COLLECT_POOL_CSV_FILE_PATH")
-        .expect("COLLECT_POOL_CSV_FILE_PATH is required");
-
-    let collect_npm_events_path =
-        std::env::var("COLLECT_NPM_CSV_FILE_PATH").expect("COLLECT_NPM_CSV_FILE_PATH is required");
-
-    let increase_liquidity_events_path = std::env::var("INCREASE_LIQUIDITY_CSV_FILE_PATH")
-        .expect("INCREASE_LIQUIDITY_CSV_FILE_PATH is required");
-
-    let decrease_liquidity_events_path = std::env::var("DECREASE_LIQUIDITY_CSV_FILE_PATH")
-        .expect("DECREASE_LIQUIDITY_CSV_FILE_PATH is required");
-
-    let output_csv_file_path =
-        std::env::var("OUTPUT_CSV_FILE_PATH").expect("OUTPUT_CSV_FILE_PATH is required");
-
-    let csv_reader_config = CSVReaderConfig {
-        initialize_events_path,
-        swap_events_path,
-        mint_events_path,
-        burn_events_path,
-        collect_pool_events_path,
-        collect_npm_events_path,
-        increase_liquidity_events_path,
-        decrease_liquidity_events_path,
-        pool_created_events_path,
-    };
-
-    let mut pool_analyzer = PoolAnalyzer::initialize(PoolAnalyzerConfig {
-        http_url,
-        fork_block,
-        uniswap_v3_factory_address,
-        uniswap_v3_position_manager_address,
-        uniswap_v3_swap_router_address,
-        uniswap_v3_quoter_address,
-        weth_address,
-        config: csv_reader_config,
-        output_csv_file_path,
-    })
-    .await?;
-
-    pool_analyzer.run_simulation().await?;
-
-    info!("Pool analysis complete");
+    // flags fall back to the env vars documented in local.env.example
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Run(args) => {
+            let pool_concurrency = args.pool_concurrency;
+            run_pools(args.into(), pool_concurrency).await?;
+            info!("Pool analysis complete");
+        }
+        Command::Validate(args) => {
+            PoolAnalyzer::initialize(args.into()).await?;
+            info!("Configuration is valid");
+        }
+        Command::Summary(args) => {
+            summarize_events(args.into()).await?;
+        }
+        Command::ExportEvents(args) => {
+            export_events(args.csv.into(), &args.dir).await?;
+        }
+        Command::ValidateCsv(args) => {
+            validate_csv(&args.into())?;
+            info!("Input CSVs are valid");
+        }
+        Command::Counterfactual(args) => {
+            let lower_tick = I24::try_from(args.lower_tick).context("Lower tick out of range")?;
+            let upper_tick = I24::try_from(args.upper_tick).context("Upper tick out of range")?;
+            let mut pool_analyzer = PoolAnalyzer::initialize(args.run.into()).await?;
+            let position = pool_analyzer
+                .simulate_counterfactual_position(args.token_id, lower_tick, upper_tick)
+                .await?;
+            info!("{}", position);
+        }
+        Command::DiffPositions(args) => {
+            diff_position_csvs(&args.a, &args.b, &args.output)?;
+        }
+    }
 
     Ok(())
 }